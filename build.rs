@@ -0,0 +1,31 @@
+//! Embed build information shown by `mihoro version` and `mihoro --version`.
+
+use std::env;
+use std::process::Command;
+
+/// Run a command and return its trimmed stdout, if it succeeds.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string()).filter(|stdout| output.status.success() && !stdout.is_empty())
+}
+
+fn main() {
+    // Builds from a crates.io package have no git checkout
+    let git_commit = command_output("git", &["rev-parse", "--short=9", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"])
+        .map(|version| version.trim_start_matches("rustc ").to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=MIHORO_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=MIHORO_RUSTC_VERSION={}", rustc_version);
+    println!(
+        "cargo:rustc-env=MIHORO_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
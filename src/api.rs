@@ -0,0 +1,438 @@
+use crate::cmd::{OutputFormat, ProxiesCommands};
+use crate::config::read_mihomo_controller;
+use crate::log::{debug, info};
+use crate::mihoro::Mihoro;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use colored::Colorize;
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Time mihomo is given to complete a delay test, in milliseconds.
+const DELAY_TEST_TIMEOUT_MS: u64 = 5000;
+
+/// Proxy groups that are not shown in `mihoro proxies list`.
+///
+/// `GLOBAL` is only used in `global` mode, and lists every other group and node.
+const HIDDEN_GROUPS: [&str; 1] = ["GLOBAL"];
+
+/// A proxy node or group, as returned by mihomo's `/proxies` endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxyInfo {
+    pub name: String,
+
+    /// Node protocol (e.g. `Shadowsocks`) or group type (e.g. `Selector`).
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Currently selected member, groups only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub now: Option<String>,
+
+    /// Members, groups only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub all: Vec<String>,
+
+    #[serde(default, skip_serializing)]
+    pub history: Vec<DelayHistory>,
+}
+
+/// A past delay test result, `delay` is 0 if the test failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DelayHistory {
+    pub delay: u32,
+}
+
+impl ProxyInfo {
+    pub fn is_group(&self) -> bool {
+        !self.all.is_empty()
+    }
+
+    /// Result of the latest delay test, `Some(0)` if it failed.
+    pub fn last_delay(&self) -> Option<u32> {
+        self.history.last().map(|history| history.delay)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ProxiesResponse {
+    proxies: BTreeMap<String, ProxyInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DelayResponse {
+    delay: u32,
+}
+
+/// Traffic totals since mihomo started and open connections, from the `/connections` endpoint.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionsSnapshot {
+    pub upload_total: u64,
+    pub download_total: u64,
+    #[serde(default, deserialize_with = "count_connections")]
+    pub connections: usize,
+}
+
+/// Count the connections listed, which mihomo sends as `null` when there are none.
+fn count_connections<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    let connections: Option<Vec<serde::de::IgnoredAny>> = Deserialize::deserialize(deserializer)?;
+    Ok(connections.map_or(0, |connections| connections.len()))
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    message: String,
+}
+
+/// Client for mihomo's RESTful API, served on `external-controller`.
+pub struct MihomoApi {
+    client: Client,
+    base_url: Url,
+    secret: Option<String>,
+}
+
+impl MihomoApi {
+    pub fn new(
+        base_url: Url,
+        secret: Option<String>,
+        timeout: Duration,
+        user_agent: &str,
+    ) -> Result<MihomoApi> {
+        // The API is local, and any configured proxy may well be mihomo itself
+        let client = Client::builder()
+            .no_proxy()
+            .timeout(timeout)
+            .user_agent(user_agent)
+            .build()?;
+        Ok(MihomoApi {
+            client,
+            base_url,
+            secret: secret.filter(|secret| !secret.is_empty()),
+        })
+    }
+
+    /// Connect to the `external-controller` declared in mihomo's live `config.yaml`.
+    pub fn from_mihoro(mihoro: &Mihoro) -> Result<MihomoApi> {
+        MihomoApi::from_mihoro_with_timeout(mihoro, mihoro.config.network_timeout())
+    }
+
+    /// Like `from_mihoro`, with requests failing after `timeout` instead of the network timeout.
+    pub fn from_mihoro_with_timeout(mihoro: &Mihoro, timeout: Duration) -> Result<MihomoApi> {
+        let controller = read_mihomo_controller(&mihoro.mihomo_target_config_path)?;
+        let Some((host, port)) = controller.host_port()? else {
+            bail!(
+                "`external-controller` not set in `{}`, set `external_controller` under `[mihomo_config]` and run `mihoro apply`",
+                mihoro.mihomo_target_config_path
+            );
+        };
+        let base_url = Url::parse(&format!("http://{}:{}", host, port))?;
+        MihomoApi::new(
+            base_url,
+            controller.secret,
+            timeout,
+            mihoro.config.user_agent(),
+        )
+    }
+
+    /// Build an endpoint url from path segments, which are percent-encoded as needed.
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("http urls have path segments")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// Send a request with the `secret` as bearer token, returning the body of a successful
+    /// response.
+    async fn send(&self, request: RequestBuilder) -> Result<String> {
+        let request = match &self.secret {
+            Some(secret) => request.bearer_auth(secret),
+            None => request,
+        };
+        let res = request.send().await.with_context(|| {
+            format!(
+                "failed to reach mihomo API at {}, is mihomo running?",
+                self.base_url
+            )
+        })?;
+
+        let status = res.status();
+        debug!("mihomo API {} returned {}", res.url(), status);
+        let body = res.text().await?;
+        if !status.is_success() {
+            let message = serde_json::from_str::<ErrorResponse>(&body)
+                .map(|error| error.message)
+                .unwrap_or(body);
+            bail!("mihomo API returned {}: {}", status, message.trim());
+        }
+        Ok(body)
+    }
+
+    /// List all proxy nodes and groups by name.
+    pub async fn proxies(&self) -> Result<BTreeMap<String, ProxyInfo>> {
+        let body = self.send(self.client.get(self.url(&["proxies"]))).await?;
+        let response: ProxiesResponse = serde_json::from_str(&body)?;
+        Ok(response.proxies)
+    }
+
+    /// Traffic totals and open connections.
+    pub async fn connections(&self) -> Result<ConnectionsSnapshot> {
+        let body = self
+            .send(self.client.get(self.url(&["connections"])))
+            .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Select `node` in the selector `group`.
+    pub async fn select(&self, group: &str, node: &str) -> Result<()> {
+        let request = self
+            .client
+            .put(self.url(&["proxies", group]))
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "name": node }).to_string());
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Hot-reload mihomo's config from the file at `path`, keeping open connections.
+    pub async fn reload_config(&self, path: &str) -> Result<()> {
+        let request = self
+            .client
+            .put(self.url(&["configs"]))
+            .query(&[("force", "true")])
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "path": path }).to_string());
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Test the delay of `node` by requesting `test_url` through it, in milliseconds.
+    pub async fn delay(&self, node: &str, test_url: &str) -> Result<u32> {
+        let timeout = DELAY_TEST_TIMEOUT_MS.to_string();
+        let request = self
+            .client
+            .get(self.url(&["proxies", node, "delay"]))
+            .query(&[("url", test_url), ("timeout", timeout.as_str())]);
+        let response: DelayResponse = serde_json::from_str(&self.send(request).await?)?;
+        Ok(response.delay)
+    }
+}
+
+/// Proxy groups to show, ordered as in the config when mihomo reports it through `GLOBAL`.
+fn visible_groups(proxies: &BTreeMap<String, ProxyInfo>) -> Vec<&ProxyInfo> {
+    let order: Vec<&String> = proxies
+        .get("GLOBAL")
+        .map(|global| global.all.iter().collect())
+        .unwrap_or_default();
+    let position = |name: &String| order.iter().position(|ordered| *ordered == name);
+
+    let mut groups: Vec<&ProxyInfo> = proxies
+        .values()
+        .filter(|proxy| proxy.is_group() && !HIDDEN_GROUPS.contains(&proxy.name.as_str()))
+        .collect();
+    // Groups missing from `GLOBAL` go last, in alphabetical order
+    groups.sort_by_key(|group| position(&group.name).unwrap_or(usize::MAX));
+    groups
+}
+
+fn format_delay(delay: Option<u32>) -> String {
+    match delay {
+        Some(0) => "timeout".to_string(),
+        Some(delay) => format!("{} ms", delay),
+        None => "-".to_string(),
+    }
+}
+
+/// Render proxy groups as rows of group, type, selected node and the node's latest delay, padded
+/// to align in columns.
+fn format_groups(proxies: &BTreeMap<String, ProxyInfo>) -> Vec<String> {
+    let mut rows = vec![[
+        "GROUP".to_string(),
+        "TYPE".to_string(),
+        "SELECTED".to_string(),
+        "DELAY".to_string(),
+    ]];
+    for group in visible_groups(proxies) {
+        let now = group.now.clone().unwrap_or_default();
+        let delay = proxies.get(&now).and_then(ProxyInfo::last_delay);
+        rows.push([
+            group.name.clone(),
+            group.kind.clone(),
+            now,
+            format_delay(delay),
+        ]);
+    }
+
+    let width = |column: usize| {
+        rows.iter()
+            .map(|row| row[column].chars().count())
+            .max()
+            .unwrap_or(0)
+    };
+    let widths = [width(0), width(1), width(2)];
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (cell, width) in row.iter().zip(widths) {
+                line.push_str(cell);
+                line.push_str(&" ".repeat(width - cell.chars().count() + 2));
+            }
+            line.push_str(&row[3]);
+            line
+        })
+        .collect()
+}
+
+pub async fn proxies_commands(mihoro: &Mihoro, command: &Option<ProxiesCommands>) -> Result<()> {
+    let api = MihomoApi::from_mihoro(mihoro)?;
+    match command {
+        Some(ProxiesCommands::List) => {
+            let proxies = api.proxies().await?;
+            match mihoro.output {
+                OutputFormat::Json => {
+                    let groups = visible_groups(&proxies);
+                    println!("{}", serde_json::to_string_pretty(&groups)?);
+                }
+                OutputFormat::Human => {
+                    let mut rows = format_groups(&proxies).into_iter();
+                    if let Some(header) = rows.next() {
+                        println!("{}", header.bold());
+                    }
+                    rows.for_each(|row| println!("{}", row));
+                }
+            }
+        }
+        Some(ProxiesCommands::Select { group, node })
+            if !mihoro.skip_in_dry_run(&format!("select {} in {}", node, group)) =>
+        {
+            api.select(group, node).await?;
+            info!(
+                "{} Selected {} in {}",
+                mihoro.prefix.green(),
+                node.bold(),
+                group.bold()
+            );
+        }
+        Some(ProxiesCommands::Delay { node, url }) => {
+            let delay = api
+                .delay(node, url)
+                .await
+                .map_err(|e| anyhow!("delay test of {} failed: {}", node, e))?;
+            match mihoro.output {
+                OutputFormat::Json => println!("{}", json!({ "name": node, "delay": delay })),
+                OutputFormat::Human => println!(
+                    "{} {}: {}",
+                    mihoro.prefix.cyan(),
+                    node.bold(),
+                    format_delay(Some(delay)).green()
+                ),
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_USER_AGENT;
+
+    const PROXIES_JSON: &str = r#"{
+        "proxies": {
+            "GLOBAL": {"name": "GLOBAL", "type": "Selector", "now": "DIRECT",
+                       "all": ["DIRECT", "🚀 Proxy", "Auto"], "history": []},
+            "Auto": {"name": "Auto", "type": "URLTest", "now": "HK 01",
+                     "all": ["HK 01", "JP 01"], "history": []},
+            "🚀 Proxy": {"name": "🚀 Proxy", "type": "Selector", "now": "JP 01",
+                         "all": ["Auto", "HK 01", "JP 01"], "history": []},
+            "DIRECT": {"name": "DIRECT", "type": "Direct", "udp": true, "history": []},
+            "HK 01": {"name": "HK 01", "type": "Shadowsocks", "history": [{"time": "t", "delay": 0}]},
+            "JP 01": {"name": "JP 01", "type": "Trojan",
+                      "history": [{"time": "t", "delay": 300}, {"time": "t", "delay": 120}]}
+        }
+    }"#;
+
+    fn proxies() -> BTreeMap<String, ProxyInfo> {
+        serde_json::from_str::<ProxiesResponse>(PROXIES_JSON)
+            .unwrap()
+            .proxies
+    }
+
+    #[test]
+    fn test_parse_proxies() {
+        let proxies = proxies();
+        assert_eq!(proxies.len(), 6);
+        assert!(proxies["Auto"].is_group());
+        assert!(!proxies["DIRECT"].is_group());
+        assert_eq!(proxies["JP 01"].last_delay(), Some(120));
+        assert_eq!(proxies["HK 01"].last_delay(), Some(0));
+        assert_eq!(proxies["DIRECT"].last_delay(), None);
+    }
+
+    #[test]
+    fn test_visible_groups_follow_global_order() {
+        let proxies = proxies();
+        let names: Vec<&str> = visible_groups(&proxies)
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect();
+        assert_eq!(names, ["🚀 Proxy", "Auto"]);
+    }
+
+    #[test]
+    fn test_format_groups() {
+        assert_eq!(
+            format_groups(&proxies()),
+            [
+                "GROUP    TYPE      SELECTED  DELAY",
+                "🚀 Proxy  Selector  JP 01     120 ms",
+                "Auto     URLTest   HK 01     timeout",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_connections() -> Result<()> {
+        let snapshot: ConnectionsSnapshot = serde_json::from_str(
+            r#"{"downloadTotal": 2048, "uploadTotal": 512, "memory": 1,
+                "connections": [{"id": "a"}, {"id": "b"}]}"#,
+        )?;
+        assert_eq!(
+            snapshot,
+            ConnectionsSnapshot {
+                upload_total: 512,
+                download_total: 2048,
+                connections: 2,
+            }
+        );
+
+        let idle: ConnectionsSnapshot =
+            serde_json::from_str(r#"{"downloadTotal": 0, "uploadTotal": 0, "connections": null}"#)?;
+        assert_eq!(idle.connections, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_url_encodes_names() -> Result<()> {
+        let api = MihomoApi::new(
+            Url::parse("http://127.0.0.1:9090")?,
+            Some(String::new()),
+            Duration::from_secs(1),
+            DEFAULT_USER_AGENT,
+        )?;
+        assert_eq!(api.secret, None);
+        assert_eq!(
+            api.url(&["proxies", "🚀 Proxy/1", "delay"]).as_str(),
+            "http://127.0.0.1:9090/proxies/%F0%9F%9A%80%20Proxy%2F1/delay"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::utils::{atomic_write, create_parent_dir, xdg_dir};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// `$XDG_CACHE_HOME/mihoro/config.sha256`, or `~/.cache/mihoro/config.sha256`, holding the SHA256
+/// of the `config.yaml` mihoro last (re)started mihomo with.
+pub fn path() -> Result<PathBuf> {
+    Ok(xdg_dir(&|var| env::var(var).ok(), "CACHE", ".cache")?.join("mihoro/config.sha256"))
+}
+
+/// The hash at `path`, or `None` if it is missing or unreadable.
+pub fn load(path: &Path) -> Option<String> {
+    let hash = fs::read_to_string(path).ok()?;
+    Some(hash.trim().to_string()).filter(|hash| !hash.is_empty())
+}
+
+pub fn save(path: &Path, hash: &str) -> Result<()> {
+    create_parent_dir(path)?;
+    atomic_write(path, format!("{}\n", hash))
+}
+
+/// Whether mihomo needs a restart to load a config hashing to `current`, given the hash of the
+/// config it was last started with, which is unknown before the first `--if-changed` run.
+pub fn needs_restart(applied: Option<&str>, current: &str) -> bool {
+    applied != Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sha256_file;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_needs_restart() -> Result<()> {
+        let dir = tempdir()?;
+        let config = dir.path().join("config.yaml");
+        let hash_path = dir.path().join("mihoro/config.sha256");
+
+        fs::write(&config, "port: 7890\n")?;
+        let hash = sha256_file(&config)?;
+        assert!(needs_restart(load(&hash_path).as_deref(), &hash));
+
+        save(&hash_path, &hash)?;
+        assert!(!needs_restart(load(&hash_path).as_deref(), &hash));
+
+        // Rewriting the same contents, as an update with an unchanged subscription does, is no
+        // change, but any edit is
+        fs::write(&config, "port: 7890\n")?;
+        assert!(!needs_restart(
+            load(&hash_path).as_deref(),
+            &sha256_file(&config)?
+        ));
+        fs::write(&config, "port: 7891\n")?;
+        assert!(needs_restart(
+            load(&hash_path).as_deref(),
+            &sha256_file(&config)?
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+/// Returns the backup file name prefix for a file, e.g. `config.yaml.bak.`.
+fn backup_prefix(file_name: &str) -> String {
+    format!("{}.bak.", file_name)
+}
+
+/// Copies `path` into `backup_dir` as `<file_name>.bak.<timestamp>`, keeping only the latest
+/// `keep` backups of that file.
+///
+/// Returns the path of the created backup, or `None` if `path` does not exist.
+pub fn backup_file(path: &Path, backup_dir: &Path, keep: usize) -> Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("invalid file name `{}`", path.to_string_lossy()))?
+        .to_string_lossy()
+        .to_string();
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    fs::create_dir_all(backup_dir)?;
+    let backup_path = backup_dir.join(format!("{}{}", backup_prefix(&file_name), timestamp));
+    fs::copy(path, &backup_path).with_context(|| {
+        format!(
+            "failed to back up `{}` to `{}`",
+            path.to_string_lossy(),
+            backup_path.to_string_lossy()
+        )
+    })?;
+
+    prune_backups(backup_dir, &file_name, keep)?;
+    Ok(Some(backup_path))
+}
+
+/// Lists backups of `file_name` under `backup_dir`, newest first.
+pub fn list_backups(backup_dir: &Path, file_name: &str) -> Result<Vec<PathBuf>> {
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let prefix = backup_prefix(file_name);
+    let mut backups: Vec<(u64, PathBuf)> = fs::read_dir(backup_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let timestamp = name.strip_prefix(&prefix)?.parse::<u64>().ok()?;
+            Some((timestamp, entry.path()))
+        })
+        .collect();
+
+    backups.sort_by_key(|(timestamp, _)| Reverse(*timestamp));
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Removes all but the latest `keep` backups of `file_name` under `backup_dir`.
+fn prune_backups(backup_dir: &Path, file_name: &str, keep: usize) -> Result<()> {
+    for stale in list_backups(backup_dir, file_name)?
+        .into_iter()
+        .skip(keep.max(1))
+    {
+        fs::remove_file(stale)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_backup_file_skips_missing_file() -> Result<()> {
+        let dir = tempdir()?;
+        let backup = backup_file(&dir.path().join("missing.yaml"), dir.path(), 3)?;
+        assert!(backup.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_file_copies_content() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("config.yaml");
+        fs::write(&file_path, "port: 7890")?;
+
+        let backup = backup_file(&file_path, dir.path(), 3)?.unwrap();
+        assert!(backup
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("config.yaml.bak."));
+        assert_eq!(fs::read_to_string(backup)?, "port: 7890");
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_and_prune_backups() -> Result<()> {
+        let dir = tempdir()?;
+        for timestamp in [100, 300, 200, 400] {
+            fs::write(dir.path().join(format!("mihomo.bak.{}", timestamp)), "")?;
+        }
+        fs::write(dir.path().join("config.yaml.bak.500"), "")?;
+
+        let backups = list_backups(dir.path(), "mihomo")?;
+        let names: Vec<String> = backups
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "mihomo.bak.400",
+                "mihomo.bak.300",
+                "mihomo.bak.200",
+                "mihomo.bak.100"
+            ]
+        );
+
+        prune_backups(dir.path(), "mihomo", 2)?;
+        assert_eq!(list_backups(dir.path(), "mihomo")?.len(), 2);
+        assert!(dir.path().join("mihomo.bak.400").exists());
+        assert!(!dir.path().join("mihomo.bak.100").exists());
+        assert!(dir.path().join("config.yaml.bak.500").exists());
+        Ok(())
+    }
+}
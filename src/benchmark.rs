@@ -0,0 +1,261 @@
+use crate::cmd::OutputFormat;
+use crate::config::set_github_mirror;
+use crate::log::{einfo, info};
+use crate::mihoro::Mihoro;
+use crate::resolve_mihomo_bin::mirror_github_url;
+use crate::subscription::format_bytes;
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use futures_util::StreamExt;
+use reqwest::header::RANGE;
+use reqwest::Client;
+use serde::Serialize;
+
+/// GitHub release asset downloaded from, served by GitHub and mirrors alike.
+const BENCHMARK_URL: &str =
+    "https://github.com/MetaCubeX/meta-rules-dat/releases/download/latest/geoip.dat";
+
+/// Bytes downloaded from each source, requested as a range so servers send no more.
+const BENCHMARK_BYTES: u64 = 256 * 1024;
+
+/// How fast a download source is, a row of `mihoro benchmark`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Mirror prefix, or `None` for GitHub itself.
+    mirror: Option<String>,
+    /// Time until the response headers arrived, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    /// Bytes downloaded per second.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    throughput: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Sample {
+    fn name(&self) -> &str {
+        self.mirror.as_deref().unwrap_or("GitHub")
+    }
+}
+
+/// Download sources to compare: GitHub itself, `github_mirror`, `github_mirrors` and `extra`,
+/// without duplicates.
+fn candidates(
+    github_mirror: Option<&str>,
+    github_mirrors: &[String],
+    extra: &[String],
+) -> Vec<Option<String>> {
+    let mut candidates = vec![None];
+    let mirrors = github_mirror
+        .into_iter()
+        .chain(github_mirrors.iter().map(String::as_str))
+        .chain(extra.iter().map(String::as_str))
+        .map(|mirror| mirror.trim().trim_end_matches('/'))
+        .filter(|mirror| !mirror.is_empty());
+    for mirror in mirrors {
+        if !candidates.iter().flatten().any(|known| known == mirror) {
+            candidates.push(Some(mirror.to_string()));
+        }
+    }
+    candidates
+}
+
+/// Download the first `BENCHMARK_BYTES` of `url`, returning the time until the response headers
+/// arrived, the time until the download finished and the bytes downloaded.
+async fn measure(
+    client: &Client,
+    url: &str,
+    timeout: Duration,
+) -> Result<(Duration, Duration, u64)> {
+    let start = Instant::now();
+    let response = client
+        .get(url)
+        .header(RANGE, format!("bytes=0-{}", BENCHMARK_BYTES - 1))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let latency = start.elapsed();
+
+    // Servers ignoring the range send the whole file, so stop reading after enough of it
+    let mut downloaded = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        downloaded += chunk?.len() as u64;
+        if downloaded >= BENCHMARK_BYTES {
+            break;
+        }
+    }
+    if downloaded == 0 {
+        bail!("empty response");
+    }
+    Ok((latency, start.elapsed(), downloaded))
+}
+
+/// Order samples fastest first, by throughput, with failed sources last.
+fn rank(samples: &mut [Sample]) {
+    samples.sort_by_key(|sample| {
+        (
+            sample.throughput.is_none(),
+            std::cmp::Reverse(sample.throughput),
+            sample.latency_ms,
+        )
+    });
+}
+
+/// Run `mihoro benchmark`, timing a small download from GitHub and each mirror one after another,
+/// so they don't compete for bandwidth, and print them fastest first. With `save`, the fastest is
+/// written to `github_mirror` in the mihoro config at `config_path`.
+pub async fn benchmark(
+    mihoro: &Mihoro,
+    extra: &[String],
+    save: bool,
+    config_path: &str,
+) -> Result<()> {
+    let client = mihoro.http_client()?;
+    let timeout = mihoro.config.network_timeout();
+    let mut samples = vec![];
+    for mirror in candidates(
+        mihoro.config.github_mirror.as_deref(),
+        &mihoro.config.github_mirrors,
+        extra,
+    ) {
+        let url = mirror_github_url(BENCHMARK_URL, mirror.as_deref());
+        einfo!(
+            "{} Timing {}...",
+            mihoro.prefix.cyan(),
+            mirror.as_deref().unwrap_or("GitHub")
+        );
+        let sample = match measure(&client, &url, timeout).await {
+            Ok((latency, total, bytes)) => Sample {
+                mirror,
+                latency_ms: Some(latency.as_millis() as u64),
+                throughput: Some((bytes as f64 / total.as_secs_f64().max(0.001)) as u64),
+                error: None,
+            },
+            Err(e) => Sample {
+                mirror,
+                latency_ms: None,
+                throughput: None,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        samples.push(sample);
+    }
+    rank(&mut samples);
+
+    if mihoro.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&samples)?);
+    } else {
+        let width = samples
+            .iter()
+            .map(|sample| sample.name().len())
+            .max()
+            .unwrap_or(0);
+        for (i, sample) in samples.iter().enumerate() {
+            let result = match (sample.latency_ms, sample.throughput) {
+                (Some(latency), Some(throughput)) => {
+                    format!("{:>6} ms  {}/s", latency, format_bytes(throughput))
+                }
+                _ => sample.error.clone().unwrap_or_default().red().to_string(),
+            };
+            println!(
+                "{}. {:width$}  {}",
+                i + 1,
+                sample.name(),
+                result,
+                width = width
+            );
+        }
+    }
+
+    if !save {
+        return Ok(());
+    }
+    let Some(fastest) = samples.first().filter(|sample| sample.throughput.is_some()) else {
+        bail!("no download source could be reached, not saving `github_mirror`");
+    };
+    if fastest.mirror.as_deref() == mihoro.config.github_mirror.as_deref() {
+        einfo!(
+            "{} {} is already the fastest, `github_mirror` unchanged",
+            mihoro.prefix.green(),
+            fastest.name()
+        );
+        return Ok(());
+    }
+    if mihoro.skip_in_dry_run(&format!("set `github_mirror` to {}", fastest.name())) {
+        return Ok(());
+    }
+    set_github_mirror(config_path, fastest.mirror.as_deref())?;
+    match &fastest.mirror {
+        Some(mirror) => info!(
+            "{} Set `github_mirror` to {} in {}",
+            mihoro.prefix.green().bold(),
+            mirror.underline(),
+            config_path
+        ),
+        None => info!(
+            "{} Removed `github_mirror` from {}, downloading from GitHub directly",
+            mihoro.prefix.green().bold(),
+            config_path
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::serve_once;
+
+    #[test]
+    fn test_candidates() {
+        let mirrors = vec!["https://ghfast.top/".to_string(), "".to_string()];
+        let extra = vec![
+            "https://gh.example.com".to_string(),
+            "https://ghfast.top".to_string(),
+        ];
+        assert_eq!(
+            candidates(Some("https://ghfast.top"), &mirrors, &extra),
+            [
+                None,
+                Some("https://ghfast.top".to_string()),
+                Some("https://gh.example.com".to_string()),
+            ]
+        );
+        assert_eq!(candidates(None, &[], &[]), [None]);
+    }
+
+    #[test]
+    fn test_rank() {
+        let sample = |mirror: &str, latency_ms: Option<u64>, throughput: Option<u64>| Sample {
+            mirror: Some(mirror.to_string()),
+            latency_ms,
+            throughput,
+            error: None,
+        };
+        let mut samples = vec![
+            sample("failed", None, None),
+            sample("slow", Some(50), Some(1_000)),
+            sample("fast", Some(300), Some(90_000)),
+        ];
+        rank(&mut samples);
+        let names: Vec<_> = samples.iter().map(Sample::name).collect();
+        assert_eq!(names, ["fast", "slow", "failed"]);
+    }
+
+    #[tokio::test]
+    async fn test_measure_requests_range() -> Result<()> {
+        let (url, head) = serve_once("geodata").await;
+        let (latency, total, bytes) = measure(&Client::new(), &url, Duration::from_secs(5)).await?;
+        assert_eq!(bytes, 7);
+        assert!(latency <= total);
+        let head = head.await?.to_lowercase();
+        assert!(head.contains(&format!("range: bytes=0-{}", BENCHMARK_BYTES - 1)));
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use crate::backup::backup_file;
+use crate::config::parse_config;
+use crate::log::{debug, info};
+use crate::mihoro::GEODATA_FILES;
+use crate::utils::{atomic_write_with, read_tar_gz};
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use tempfile::TempDir;
+
+/// mihoro config in a bundle, installed at the `--mihoro-config` path.
+pub const BUNDLE_CONFIG: &str = "mihoro.toml";
+/// mihomo binary in a bundle, installed at `mihomo_binary_path`.
+pub const BUNDLE_BINARY: &str = "mihomo";
+/// mihomo config in a bundle, installed into `mihomo_config_root`.
+pub const BUNDLE_MIHOMO_CONFIG: &str = "config.yaml";
+
+/// Backups of `mihoro.toml` kept when a bundle replaces it.
+const CONFIG_BACKUPS_KEPT: usize = 3;
+
+/// Architecture a binary is built for, from its ELF header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ElfTarget {
+    bits: u8,
+    little_endian: bool,
+    machine: u16,
+}
+
+impl ElfTarget {
+    /// Read the target from the first 20 bytes of an ELF file, or `None` if it isn't one.
+    fn parse(header: &[u8]) -> Option<ElfTarget> {
+        if header.len() < 20 || !header.starts_with(b"\x7fELF") {
+            return None;
+        }
+        let bits = match header[4] {
+            1 => 32,
+            2 => 64,
+            _ => return None,
+        };
+        let little_endian = header[5] == 1;
+        let machine = [header[18], header[19]];
+        let machine = match little_endian {
+            true => u16::from_le_bytes(machine),
+            false => u16::from_be_bytes(machine),
+        };
+        Some(ElfTarget {
+            bits,
+            little_endian,
+            machine,
+        })
+    }
+
+    /// Read the target of the executable at `path`.
+    fn of_file(path: &Path) -> Option<ElfTarget> {
+        let mut header = [0; 20];
+        File::open(path).ok()?.read_exact(&mut header).ok()?;
+        ElfTarget::parse(&header)
+    }
+
+    /// Architecture name as in Rust's `std::env::consts::ARCH`, for messages.
+    fn name(&self) -> String {
+        let endian = if self.little_endian { "le" } else { "" };
+        match (self.machine, self.bits) {
+            (3, _) => String::from("x86"),
+            (62, _) => String::from("x86_64"),
+            (40, _) => String::from("arm"),
+            (183, _) => String::from("aarch64"),
+            (8, 32) => format!("mips{}", endian),
+            (8, _) => format!("mips64{}", endian),
+            (21, _) => format!("powerpc64{}", endian),
+            (22, _) => String::from("s390x"),
+            (243, bits) => format!("riscv{}", bits),
+            (258, _) => String::from("loongarch64"),
+            (machine, bits) => format!("{}-bit ELF machine {}", bits, machine),
+        }
+    }
+}
+
+/// Check that the mihomo binary with ELF `header` runs on a machine running `host`, i.e. mihoro
+/// itself. Without a readable `host`, any ELF binary is accepted.
+fn check_arch(header: &[u8], host: Option<ElfTarget>) -> Result<()> {
+    let Some(target) = ElfTarget::parse(header) else {
+        bail!(
+            "`{}` in the bundle is not a Linux executable",
+            BUNDLE_BINARY
+        );
+    };
+    let Some(host) = host else {
+        debug!("host architecture unknown, assuming the bundle's mihomo matches");
+        return Ok(());
+    };
+    if target != host {
+        bail!(
+            "`{}` in the bundle is built for {}, but this machine is {}, restore it on a matching \
+             machine, or run `mihoro setup --overwrite` with the bundle's `{}` instead",
+            BUNDLE_BINARY,
+            target.name(),
+            host.name(),
+            BUNDLE_CONFIG
+        );
+    }
+    Ok(())
+}
+
+/// A mihoro state bundle for `setup --from-backup`: a gzipped tarball of `mihoro.toml`, the
+/// `mihomo` binary, `config.yaml` and any geodata files, at its top level. It is unpacked into a
+/// temporary directory, removed when dropped.
+pub struct Bundle {
+    /// Path of the tarball, for messages.
+    pub path: PathBuf,
+    dir: TempDir,
+    /// Geodata files in the bundle, among `GEODATA_FILES`.
+    pub geodata: Vec<&'static str>,
+}
+
+impl Bundle {
+    /// Unpack the bundle at `path`, checking that it holds nothing but the files mihoro installs,
+    /// that its configs parse, and that its binary runs on this machine.
+    pub fn open(path: &Path) -> Result<Bundle> {
+        let bytes = fs::read(path)
+            .with_context(|| format!("failed to read bundle `{}`", path.to_string_lossy()))?;
+        let files = read_tar_gz(&bytes).with_context(|| {
+            format!(
+                "failed to read bundle `{}`, expected a .tar.gz archive",
+                path.to_string_lossy()
+            )
+        })?;
+
+        let dir = TempDir::new()?;
+        let mut names = vec![];
+        let mut geodata = vec![];
+        for (file, contents) in files {
+            let name = file.trim_start_matches("./");
+            let geodata_file = GEODATA_FILES.iter().find(|geodata| **geodata == name);
+            if ![BUNDLE_CONFIG, BUNDLE_BINARY, BUNDLE_MIHOMO_CONFIG].contains(&name)
+                && geodata_file.is_none()
+            {
+                bail!(
+                    "unexpected `{}` in bundle, which may only hold {}, {}, {} and geodata files \
+                     ({}) at its top level",
+                    file,
+                    BUNDLE_CONFIG,
+                    BUNDLE_BINARY,
+                    BUNDLE_MIHOMO_CONFIG,
+                    GEODATA_FILES.join(", ")
+                );
+            }
+            fs::write(dir.path().join(name), contents)?;
+            geodata.extend(geodata_file);
+            names.push(name.to_string());
+        }
+        for required in [BUNDLE_CONFIG, BUNDLE_BINARY, BUNDLE_MIHOMO_CONFIG] {
+            if !names.iter().any(|name| name == required) {
+                bail!("bundle `{}` has no `{}`", path.to_string_lossy(), required);
+            }
+        }
+
+        let bundle = Bundle {
+            path: path.to_path_buf(),
+            dir,
+            geodata,
+        };
+        parse_config(&bundle.file(BUNDLE_CONFIG).to_string_lossy())
+            .with_context(|| format!("invalid `{}` in bundle", BUNDLE_CONFIG))?;
+        serde_yaml::from_slice::<serde_yaml::Mapping>(&fs::read(
+            bundle.file(BUNDLE_MIHOMO_CONFIG),
+        )?)
+        .with_context(|| format!("invalid `{}` in bundle", BUNDLE_MIHOMO_CONFIG))?;
+        check_arch(
+            &fs::read(bundle.file(BUNDLE_BINARY))?,
+            ElfTarget::of_file(Path::new("/proc/self/exe")),
+        )?;
+        Ok(bundle)
+    }
+
+    /// Path of the unpacked file `name`.
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Install the unpacked file `name` at `target`, replacing it atomically.
+    pub fn install(&self, name: &str, target: &Path, mode: Option<u32>) -> Result<()> {
+        let contents = fs::read(self.file(name))?;
+        atomic_write_with(target, mode, |file| Ok(file.write_all(&contents)?))
+            .with_context(|| format!("failed to install `{}`", target.to_string_lossy()))
+    }
+
+    /// Install the bundle's `mihoro.toml` at `config_path`, backing up a different one already
+    /// there next to it.
+    pub fn install_config(&self, config_path: &Path, prefix: &str) -> Result<()> {
+        let contents = fs::read(self.file(BUNDLE_CONFIG))?;
+        if fs::read(config_path).is_ok_and(|existing| existing == contents) {
+            return Ok(());
+        }
+        let backup_dir = config_path.parent().unwrap_or(Path::new("."));
+        if let Some(backup) = backup_file(config_path, backup_dir, CONFIG_BACKUPS_KEPT)? {
+            info!(
+                "{} Backed up {} to {}",
+                prefix.cyan(),
+                config_path.to_string_lossy().underline(),
+                backup.to_string_lossy().underline().yellow()
+            );
+        }
+        self.install(BUNDLE_CONFIG, config_path, None)?;
+        info!(
+            "{} Restored {} from {}",
+            prefix.green(),
+            config_path.to_string_lossy().underline().yellow(),
+            self.path.to_string_lossy()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tar_gz;
+    use tempfile::tempdir;
+
+    /// ELF header of an executable for `machine`, 64-bit little-endian.
+    fn elf_header(machine: u16) -> Vec<u8> {
+        let mut header = b"\x7fELF\x02\x01\x01".to_vec();
+        header.resize(18, 0);
+        header.extend(machine.to_le_bytes());
+        header.resize(64, 0);
+        header
+    }
+
+    /// ELF header of mihoro's own binary, standing in for a mihomo binary built for this machine.
+    fn host_binary() -> Vec<u8> {
+        let mut header = vec![0; 64];
+        File::open("/proc/self/exe")
+            .unwrap()
+            .read_exact(&mut header)
+            .unwrap();
+        header
+    }
+
+    #[test]
+    fn test_elf_target() {
+        let x86_64 = ElfTarget::parse(&elf_header(62)).unwrap();
+        assert_eq!(x86_64.name(), "x86_64");
+        assert_eq!(
+            ElfTarget::parse(&elf_header(183)).unwrap().name(),
+            "aarch64"
+        );
+
+        // 32-bit big-endian MIPS
+        let mut mips = b"\x7fELF\x01\x02\x01".to_vec();
+        mips.resize(18, 0);
+        mips.extend(8u16.to_be_bytes());
+        assert_eq!(ElfTarget::parse(&mips).unwrap().name(), "mips");
+
+        assert_eq!(ElfTarget::parse(b"#!/bin/sh\necho mihomo\n"), None);
+        assert_eq!(ElfTarget::parse(b"\x7fELF"), None);
+        // The tests run natively, so mihoro's own binary is an ELF file
+        assert!(ElfTarget::of_file(Path::new("/proc/self/exe")).is_some());
+    }
+
+    #[test]
+    fn test_check_arch() {
+        let host = ElfTarget::parse(&elf_header(62));
+        assert!(check_arch(&elf_header(62), host).is_ok());
+        assert!(check_arch(&elf_header(183), None).is_ok());
+
+        let err = check_arch(&elf_header(183), host).unwrap_err().to_string();
+        assert!(err.contains("built for aarch64, but this machine is x86_64"));
+        let err = check_arch(b"not a binary", host).unwrap_err().to_string();
+        assert!(err.contains("not a Linux executable"));
+    }
+
+    /// Write a bundle of `files` into `dir`, returning its path.
+    fn write_bundle(dir: &Path, files: &[(&str, &[u8])]) -> Result<PathBuf> {
+        let path = dir.join("bundle.tar.gz");
+        fs::write(&path, tar_gz(files))?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_open_bundle() -> Result<()> {
+        let dir = tempdir()?;
+        let binary = host_binary();
+        let config = br#"remote_config_url = "https://example.com/sub""#;
+        let path = write_bundle(
+            dir.path(),
+            &[
+                ("./mihoro.toml", config),
+                ("mihomo", &binary),
+                ("config.yaml", b"mixed-port: 7890\n"),
+                ("geoip.dat", b"geoip"),
+            ],
+        )?;
+        let bundle = Bundle::open(&path)?;
+        assert_eq!(bundle.geodata, ["geoip.dat"]);
+        assert_eq!(fs::read(bundle.file("geoip.dat"))?, b"geoip");
+
+        // A different mihoro.toml is backed up before it is replaced
+        let config_path = dir.path().join("config/mihoro.toml");
+        fs::create_dir_all(dir.path().join("config"))?;
+        fs::write(&config_path, "mihomo_channel = \"alpha\"\n")?;
+        bundle.install_config(&config_path, "mihoro:")?;
+        assert_eq!(fs::read(&config_path)?, config);
+        let backups = fs::read_dir(dir.path().join("config"))?.count();
+        assert_eq!(backups, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_invalid_bundle() -> Result<()> {
+        let dir = tempdir()?;
+        let binary = host_binary();
+        let open = |files: &[(&str, &[u8])]| -> String {
+            let path = write_bundle(dir.path(), files).unwrap();
+            Bundle::open(&path)
+                .err()
+                .map(|e| format!("{:#}", e))
+                .unwrap()
+        };
+        let config: &[u8] = br#"remote_config_url = "https://example.com/sub""#;
+
+        let err = open(&[("mihoro.toml", config), ("config.yaml", b"{}")]);
+        assert!(err.contains("has no `mihomo`"));
+        let err = open(&[
+            ("mihoro.toml", config),
+            ("mihomo", &binary),
+            ("config.yaml", b"{}"),
+            ("notes.txt", b""),
+        ]);
+        assert!(err.contains("unexpected `notes.txt` in bundle"));
+        let err = open(&[
+            ("mihoro.toml", config),
+            ("mihomo", &binary),
+            ("config.yaml", b"- not a mapping"),
+        ]);
+        assert!(err.contains("invalid `config.yaml` in bundle"));
+        let err = open(&[
+            ("mihoro.toml", config),
+            ("mihomo", &elf_header(0xffff)),
+            ("config.yaml", b"{}"),
+        ]);
+        assert!(err.contains("built for 64-bit ELF machine 65535"));
+
+        fs::write(dir.path().join("bundle.tar.gz"), "not a tarball")?;
+        assert!(Bundle::open(&dir.path().join("bundle.tar.gz")).is_err());
+        Ok(())
+    }
+}
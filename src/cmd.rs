@@ -1,17 +1,167 @@
-use clap::{Parser, Subcommand};
+use crate::config::{default_mihoro_config_path, MihomoChannel};
+
+use std::net::IpAddr;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+/// Build information embedded by `build.rs`, shown by `mihoro --version`.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("MIHORO_GIT_COMMIT"),
+    "\nrustc: ",
+    env!("MIHORO_RUSTC_VERSION"),
+    "\ntarget: ",
+    env!("MIHORO_TARGET"),
+);
 
 #[derive(Parser)]
-#[command(author, about, version, arg_required_else_help(true))]
+#[command(
+    author,
+    about,
+    version,
+    long_version = LONG_VERSION,
+    arg_required_else_help(true)
+)]
 pub struct Args {
-    /// Path to mihoro config file
-    #[clap(short, long, default_value = "~/.config/mihoro.toml")]
+    /// Path to mihoro config file [default: ~/.config/mihoro.toml, or under $XDG_CONFIG_HOME]
+    #[clap(short, long, default_value_t = default_mihoro_config_path(), hide_default_value = true)]
     pub mihoro_config: String,
+    /// Print actions that would be performed without executing them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// Only print errors and requested output
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Print debug details such as urls, paths and commands run, repeat for more (-vv)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Mirror prefix for GitHub downloads (e.g., https://ghfast.top), overrides `github_mirror`
+    #[arg(long, global = true)]
+    pub mirror: Option<String>,
+    /// Directory for the systemd unit file, overrides `systemd_unit_dir`
+    #[arg(long, global = true, value_name = "DIR")]
+    pub systemd_dir: Option<String>,
+    /// Connect directly, ignoring `download_proxy` and proxy environment variables
+    #[arg(long, global = true)]
+    pub no_proxy: bool,
+    /// Only connect over IPv4 for downloads, overrides `download_ip_version`
+    #[arg(long, global = true, conflicts_with = "force_ipv6")]
+    pub force_ipv4: bool,
+    /// Only connect over IPv6 for downloads, overrides `download_ip_version`
+    #[arg(long, global = true)]
+    pub force_ipv6: bool,
+    /// Timeout in seconds for network requests, overrides `network_timeout_secs`
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: Option<u64>,
+    /// Disable colored output, also disabled by `NO_COLOR` or when output is not a terminal
+    #[arg(long, global = true)]
+    pub no_color: bool,
+    /// Wait for another running mihoro operation to finish instead of exiting
+    #[arg(long, global = true)]
+    pub wait: bool,
+    /// Output format, `json` emits machine-readable output for status, version and upgrade checks
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Colored human-readable output
+    #[default]
+    Human,
+    /// Machine-readable JSON output
+    Json,
+}
+
+/// How mihomo is made to load an updated config, chosen with `--reload` or `--restart`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RestartMethod {
+    /// Hot-reload with `reload_method`, restarting the service if that fails
+    #[default]
+    Auto,
+    /// Only hot-reload with `reload_method`
+    Reload,
+    /// Always restart the service
+    Restart,
+}
+
+impl RestartMethod {
+    pub fn from_flags(reload: bool, restart: bool) -> Self {
+        match (reload, restart) {
+            (true, _) => RestartMethod::Reload,
+            (_, true) => RestartMethod::Restart,
+            _ => RestartMethod::Auto,
+        }
+    }
+}
+
+/// Hosted web dashboards for `mihoro dashboard`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum DashboardUi {
+    /// https://github.com/MetaCubeX/metacubexd
+    #[default]
+    Metacubexd,
+    /// https://github.com/MetaCubeX/Yacd-meta
+    Yacd,
+}
+
+/// Auto-update schedules for `mihoro cron enable --interval`, see `cron::INTERVAL_PRESETS`.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CronInterval {
+    /// Every hour, on the hour
+    Hourly,
+    /// Every 6 hours
+    #[value(name = "6h")]
+    SixHours,
+    /// Daily at 04:00
+    Daily,
+    /// Weekly on Sunday at 04:00
+    Weekly,
+}
+
+/// What scheduled auto-updates update, chosen with `mihoro cron enable --scope`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CronScope {
+    /// The remote config, only reloading mihomo if it changed
+    #[default]
+    Config,
+    /// Geodata
+    Geodata,
+    /// The mihomo core binary
+    Core,
+    /// Config, geodata and the mihomo core binary
+    All,
+}
+
+/// Components `mihoro uninstall` keeps, or asks about with a default of keeping them.
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct UninstallKeep {
+    /// Keep the mihomo binary
+    #[arg(long)]
+    pub keep_binary: bool,
+    /// Keep mihomo's config.yaml and mihoro.toml
+    #[arg(long)]
+    pub keep_config: bool,
+    /// Keep downloaded geodata
+    #[arg(long)]
+    pub keep_geodata: bool,
+    /// Keep the auto-update cron job or systemd timer
+    #[arg(long)]
+    pub keep_cron: bool,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
+    /// Write a commented default mihoro config
+    Init {
+        /// Overwrite an existing config
+        #[arg(long)]
+        force: bool,
+    },
     /// Setup mihoro by downloading mihomo binary and remote config
     Setup {
         /// Force download mihomo binary even if it already exists
@@ -25,8 +175,52 @@ pub enum Commands {
         /// arm64, armv5, armv6, armv7, loong64-abi1/abi2, mips-hardfloat,
         /// mips-softfloat, mips64, mips64le, mipsle-hardfloat, mipsle-softfloat,
         /// ppc64le, riscv64, s390x
+        ///
+        /// Run `mihoro arch list` to see them by family, with the one detected for this machine
         #[arg(long)]
         arch: Option<String>,
+
+        /// Pick an optimized amd64-v2/v3 build from CPU features (overridden by --arch)
+        #[arg(long)]
+        optimize_arch: bool,
+
+        /// Pin a specific mihomo release tag (e.g., v1.19.0) instead of the latest
+        #[arg(long)]
+        version: Option<String>,
+
+        /// Look up the latest mihomo version on GitHub, ignoring the cached one
+        #[arg(long)]
+        refresh: bool,
+
+        /// Skip SHA256 checksum verification of the downloaded mihomo binary
+        #[arg(long)]
+        no_verify: bool,
+
+        /// Download the config from this subscription url instead of `remote_config_url`, for
+        /// this run only
+        #[arg(long, value_name = "URL")]
+        config_url: Option<String>,
+
+        /// Only install the binary and config, skipping geodata downloads (see `skip_geodata`)
+        #[arg(long)]
+        minimal: bool,
+
+        /// Don't create or start a systemd service, e.g. in a container without systemd, and
+        /// print the command to run mihomo with instead (the default when systemd is missing)
+        #[arg(long)]
+        skip_service: bool,
+
+        /// Restore mihoro.toml, the mihomo binary, config.yaml and geodata from a .tar.gz bundle,
+        /// e.g. from another machine, instead of downloading them
+        #[arg(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = [
+                "overwrite", "arch", "optimize_arch", "version", "refresh", "no_verify",
+                "config_url", "minimal",
+            ]
+        )]
+        from_backup: Option<String>,
     },
     /// Update mihomo components (config by default)
     Update {
@@ -53,39 +247,254 @@ pub enum Commands {
         /// arm64, armv5, armv6, armv7, loong64-abi1/abi2, mips-hardfloat,
         /// mips-softfloat, mips64, mips64le, mipsle-hardfloat, mipsle-softfloat,
         /// ppc64le, riscv64, s390x
+        ///
+        /// Run `mihoro arch list` to see them by family, with the one detected for this machine
         #[arg(long)]
         arch: Option<String>,
+
+        /// Pick an optimized amd64-v2/v3 build from CPU features (overridden by --arch)
+        #[arg(long)]
+        optimize_arch: bool,
+
+        /// Pin a specific mihomo release tag (e.g., v1.19.0) instead of the latest (used with
+        /// --core or --all)
+        #[arg(long)]
+        version: Option<String>,
+
+        /// Look up the latest mihomo version on GitHub, ignoring the cached one (used with
+        /// --core or --all)
+        #[arg(long)]
+        refresh: bool,
+
+        /// Skip SHA256 checksum verification of the downloaded mihomo binary
+        #[arg(long)]
+        no_verify: bool,
+
+        /// Restart mihomo.service even if the updated config fails validation, and re-download
+        /// geodata and the mihomo core even if unchanged since the last update
+        #[arg(long)]
+        force: bool,
+
+        /// Only reload mihomo if the updated config.yaml differs from the one it last loaded (used
+        /// with --config)
+        #[arg(long, conflicts_with_all = ["core", "geodata", "all"])]
+        if_changed: bool,
+
+        /// Hot-reload the updated config without falling back to a restart (used with --config)
+        #[arg(long, conflicts_with_all = ["restart", "core", "geodata", "all"])]
+        reload: bool,
+
+        /// Restart mihomo.service instead of hot-reloading the config (used with --config)
+        #[arg(long, conflicts_with_all = ["core", "geodata", "all"])]
+        restart: bool,
+
+        /// Download and write the updates without reloading or restarting mihomo.service, e.g. to
+        /// restart later with `mihoro apply --no-download`
+        #[arg(long, conflicts_with_all = ["if_changed", "reload", "restart"])]
+        no_restart: bool,
+
+        /// Download the config from this subscription url instead of `remote_config_url`, for
+        /// this run only (used with --config or --all)
+        #[arg(long, value_name = "URL")]
+        config_url: Option<String>,
+
+        /// Send a desktop notification with the result (overrides `notify_on_update`)
+        #[arg(long, conflicts_with = "no_notify")]
+        notify: bool,
+
+        /// Don't send a desktop notification (overrides `notify_on_update`)
+        #[arg(long)]
+        no_notify: bool,
     },
     /// Apply mihomo config overrides and restart mihomo.service
-    Apply,
+    Apply {
+        /// Restart mihomo.service even if the config fails validation
+        #[arg(long)]
+        force: bool,
+
+        /// Show the changes to config.yaml as a unified diff, without writing it or restarting
+        #[arg(long, conflicts_with = "force")]
+        diff: bool,
+
+        /// Only reload mihomo if config.yaml differs from the one it last loaded
+        #[arg(long, conflicts_with = "diff")]
+        if_changed: bool,
+
+        /// Hot-reload the config without falling back to a restart
+        #[arg(long, conflicts_with_all = ["restart", "diff"])]
+        reload: bool,
+
+        /// Restart mihomo.service instead of hot-reloading the config
+        #[arg(long, conflicts_with = "diff")]
+        restart: bool,
+
+        /// Deep-merge this YAML file into config.yaml after `merge_files` and before
+        /// `[overrides]`, can be repeated
+        #[arg(long, value_name = "PATH")]
+        merge_file: Vec<String>,
+
+        /// Load what `update --no-restart` wrote, without downloading anything: apply overrides
+        /// to the config.yaml on disk and restart mihomo.service onto it and the installed core,
+        /// rolling back to the latest binary backup if it fails to start
+        #[arg(long, conflicts_with_all = ["diff", "if_changed", "reload", "restart"])]
+        no_download: bool,
+    },
     /// Start mihomo.service with systemctl
     Start,
+    /// Run the installed mihomo in the foreground with its config, e.g. to see startup errors or
+    /// where systemd is not available
+    Run {
+        /// Extra arguments passed to mihomo, after `--`
+        #[arg(last = true, value_name = "MIHOMO_ARGS")]
+        args: Vec<String>,
+    },
     /// Check mihomo.service status with systemctl
-    Status,
+    Status {
+        /// Refresh service state, uptime and live traffic every few seconds until Ctrl-C
+        #[arg(long)]
+        watch: bool,
+    },
     /// Stop mihomo.service with systemctl
     Stop,
     /// Restart mihomo.service with systemctl
     Restart,
     /// Check mihomo.service logs with journalctl
     #[clap(visible_alias("logs"))]
-    Log,
+    Log {
+        /// Number of most recent lines to show [default: 10, or 200 with --export]
+        #[arg(short = 'n', long)]
+        lines: Option<u32>,
+
+        /// Print the lines and exit instead of following new logs
+        #[arg(long)]
+        no_follow: bool,
+
+        /// Only show logs since this time, passed to `journalctl --since` (e.g. "1 hour ago")
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Write a bug report with logs, versions, redacted config and service status to a file
+        #[arg(long, value_name = "PATH")]
+        export: Option<String>,
+    },
     /// Output proxy export commands
     Proxy {
+        /// Shell syntax to generate commands for (detected from $SHELL by default)
+        #[arg(long, global = true, value_enum)]
+        shell: Option<Shell>,
+
+        /// Don't copy the generated commands to the clipboard
+        #[arg(long, global = true)]
+        no_clipboard: bool,
+
+        /// Export IPv6 addresses, `[::1]` or the LAN IPv6 address, instead of IPv4
+        #[arg(long, global = true)]
+        ipv6: bool,
+
         #[clap(subcommand)]
         proxy: Option<ProxyCommands>,
     },
-    /// Uninstall and remove mihoro and config
-    Uninstall,
-    /// Generate shell completions for mihoro
+    /// List config and binary backups, or restore one and restart mihomo.service
+    Restore {
+        /// Name of the backup to restore, lists available backups if omitted
+        backup: Option<String>,
+    },
+    /// Stop mihomo and remove its binary, config, geodata, systemd unit and auto-update
+    Uninstall {
+        /// Remove everything not kept with --keep-* without asking
+        #[arg(short = 'y', long)]
+        yes: bool,
+
+        #[command(flatten)]
+        keep: UninstallKeep,
+
+        /// Also remove config backups, cached subscription info, and mihoro's cache and state
+        /// directories
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Show mihoro build info, the installed mihomo version and the release channel
+    Version,
+    /// Diagnose common problems with the mihomo installation
+    Doctor,
+    /// Check that a subscription url returns a mihomo config, without writing anything
+    TestUrl {
+        /// Subscription url to check [default: the active `remote_config_url`]
+        url: Option<String>,
+    },
+    /// Print the path of the running mihoro binary and whether `upgrade` can replace it
+    #[command(name = "where", alias = "self-path")]
+    Where,
+    /// List mihomo release architectures
+    Arch {
+        #[clap(subcommand)]
+        arch: Option<ArchCommands>,
+    },
+    /// Switch proxy groups and test nodes through mihomo's API
+    Proxies {
+        #[clap(subcommand)]
+        proxies: Option<ProxiesCommands>,
+    },
+    /// Print a web dashboard url connected to mihomo's external controller
+    Dashboard {
+        /// Hosted dashboard to use
+        #[arg(long, value_enum, default_value_t = DashboardUi::Metacubexd)]
+        ui: DashboardUi,
+
+        /// Open the dashboard in the browser with xdg-open
+        #[arg(long)]
+        open: bool,
+    },
+    /// Time a small download from GitHub and each mirror, and list them fastest first
+    Benchmark {
+        /// Mirror prefixes to time as well, besides `github_mirror` and `github_mirrors`
+        #[arg(value_name = "MIRROR")]
+        mirrors: Vec<String>,
+
+        /// Write the fastest mirror to `github_mirror`, or remove it if GitHub is fastest
+        #[arg(long)]
+        save: bool,
+    },
+    /// Open mihoro.toml in $EDITOR and validate it on save
+    Edit {
+        /// Edit mihomo's config.yaml instead, and offer to apply it
+        #[arg(long)]
+        mihomo: bool,
+    },
+    /// Generate shell completions for mihoro (detected from $SHELL if omitted)
     Completions {
+        /// Write the completions to the shell's per-user completions directory instead of stdout
+        #[arg(long, global = true)]
+        install: bool,
+
         #[clap(subcommand)]
         shell: Option<ClapShell>,
     },
+    /// Manage mihomo config
+    Config {
+        #[clap(subcommand)]
+        config: Option<ConfigCommands>,
+    },
+    /// Switch the mihomo release channel and reinstall mihomo from it
+    Channel {
+        #[arg(value_enum)]
+        channel: ClapChannel,
+    },
+    /// Manage named subscription profiles
+    Profile {
+        #[clap(subcommand)]
+        profile: Option<ProfileCommands>,
+    },
     /// Manage auto-update cron job
     Cron {
         #[clap(subcommand)]
         cron: Option<CronCommands>,
     },
+    /// Inspect geodata files
+    Geodata {
+        #[clap(subcommand)]
+        geodata: Option<GeodataCommands>,
+    },
     #[cfg_attr(not(feature = "self_update"), command(hide = true))]
     /// Upgrade mihoro to the latest version
     Upgrade {
@@ -93,13 +502,22 @@ pub enum Commands {
         #[arg(short = 'y', long)]
         yes: bool,
 
-        /// Only check for updates, don't install
+        /// Only check for updates, don't install (with --to, check that version is available)
         #[arg(long)]
         check: bool,
 
+        /// Install this mihoro version (e.g., v0.9.0) instead of the latest, allowing downgrades
+        #[arg(long, value_name = "VERSION")]
+        to: Option<String>,
+
         /// Override target triple (e.g., x86_64-unknown-linux-gnu)
         #[arg(long)]
         target: Option<String>,
+
+        /// Verify the release signature with the public key embedded in this build, aborting the
+        /// upgrade if it does not match
+        #[arg(long)]
+        verify_signature: bool,
     },
 }
 
@@ -109,13 +527,24 @@ pub enum ProxyCommands {
     /// Output and copy proxy export shell commands
     Export,
     /// Output and copy proxy export shell commands for LAN access
-    ExportLan,
+    ExportLan {
+        /// LAN IP to export instead of the detected primary one, IPv4 or IPv6
+        #[arg(long, value_name = "IP")]
+        bind: Option<IpAddr>,
+    },
+    /// Output and copy SOCKS5 proxy export shell commands (`all_proxy` only)
+    ExportSocks,
+    /// Output and copy SOCKS5 proxy export shell commands for LAN access
+    ExportSocksLan {
+        /// LAN IP to export instead of the detected primary one, IPv4 or IPv6
+        #[arg(long, value_name = "IP")]
+        bind: Option<IpAddr>,
+    },
     /// Output and copy proxy unset shell commands
     Unset,
 }
 
-#[derive(Subcommand)]
-#[command(arg_required_else_help(true))]
+#[derive(Subcommand, Clone, Copy)]
 pub enum ClapShell {
     /// Generate bash completions
     Bash,
@@ -123,19 +552,221 @@ pub enum ClapShell {
     Fish,
     /// Generate zsh completions
     Zsh,
-    // #[command(about = "Generate powershell completions")]
-    // Powershell,
-    // #[command(about = "Generate elvish completions")]
-    // Elvish,
+    /// Generate powershell completions
+    Powershell,
+    /// Generate elvish completions
+    Elvish,
+}
+
+impl Args {
+    /// Whether the command must hold the lock. Dry runs write nothing, as every command taking
+    /// the lock honors `--dry-run`, so they run alongside others.
+    pub fn takes_lock(&self) -> bool {
+        !self.dry_run && self.command.as_ref().is_some_and(Commands::takes_lock)
+    }
+}
+
+impl Commands {
+    /// Whether the command changes installed files, and so must not run alongside another one.
+    pub fn takes_lock(&self) -> bool {
+        match self {
+            Commands::Setup { .. }
+            | Commands::Update { .. }
+            | Commands::Restore { .. }
+            | Commands::Channel { .. }
+            | Commands::Uninstall { .. }
+            | Commands::Init { .. } => true,
+            Commands::Apply { diff, .. } => !diff,
+            Commands::Upgrade { check, .. } => !check,
+            Commands::Benchmark { save, .. } => *save,
+            Commands::Profile { profile } => matches!(profile, Some(ProfileCommands::Use { .. })),
+            Commands::Cron { cron } => matches!(
+                cron,
+                Some(CronCommands::Enable { .. } | CronCommands::Disable)
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ClapChannel {
+    /// Tagged mihomo releases
+    Stable,
+    /// Prerelease builds from the Prerelease-Alpha tag
+    Alpha,
+}
+
+impl From<ClapChannel> for MihomoChannel {
+    fn from(channel: ClapChannel) -> Self {
+        match channel {
+            ClapChannel::Stable => MihomoChannel::Stable,
+            ClapChannel::Alpha => MihomoChannel::Alpha,
+        }
+    }
+}
+
+impl From<ClapShell> for Shell {
+    fn from(shell: ClapShell) -> Self {
+        match shell {
+            ClapShell::Bash => Shell::Bash,
+            ClapShell::Fish => Shell::Fish,
+            ClapShell::Zsh => Shell::Zsh,
+            ClapShell::Powershell => Shell::PowerShell,
+            ClapShell::Elvish => Shell::Elvish,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum ConfigCommands {
+    /// Validate mihomo config with `mihomo -t`
+    Validate,
+    /// Print the resolved mihoro config, with defaults, includes and environment variables
+    /// applied, and secrets redacted
+    Show {
+        /// Show secrets, such as tokens and subscription url parameters, as is
+        #[arg(long)]
+        reveal: bool,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum ProxiesCommands {
+    /// List proxy groups and their selected nodes
+    List,
+    /// Select a node in a proxy group
+    Select {
+        /// Name of the proxy group
+        group: String,
+
+        /// Name of the node to select
+        node: String,
+    },
+    /// Test the delay of a node or proxy group
+    Delay {
+        /// Name of the node or proxy group
+        node: String,
+
+        /// Url requested through the node to measure delay
+        #[arg(long, default_value = "https://www.gstatic.com/generate_204")]
+        url: String,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum ProfileCommands {
+    /// List defined profiles
+    List,
+    /// Switch to a profile, update its config and restart mihomo.service
+    Use {
+        /// Name of the profile under `[profiles.<name>]`
+        name: String,
+
+        /// Restart mihomo.service even if the updated config fails validation
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show the active profile
+    Current,
 }
 
 #[derive(Subcommand)]
 #[command(arg_required_else_help(true))]
 pub enum CronCommands {
     /// Enable auto-update cron job
-    Enable,
+    Enable {
+        /// Cron expression to run updates on, e.g. "0 4 * * *" (overrides `cron_schedule`)
+        #[arg(long)]
+        schedule: Option<String>,
+
+        /// Run updates on a preset schedule instead of a cron expression
+        #[arg(long, value_enum, conflicts_with = "schedule")]
+        interval: Option<CronInterval>,
+
+        /// What scheduled updates update
+        #[arg(long, value_enum, default_value_t = CronScope::Config)]
+        scope: CronScope,
+    },
     /// Disable auto-update cron job
     Disable,
     /// Show auto-update cron job status
     Status,
 }
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum ArchCommands {
+    /// List supported architectures by family, and the one detected for this machine
+    List,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum GeodataCommands {
+    /// Show the size and age of each geodata file, and whether a newer version is available
+    Status {
+        /// Only show local files, without asking the servers for newer versions
+        #[arg(long)]
+        offline: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that commands changing files take the lock, and read-only ones don't
+    #[test]
+    fn test_takes_lock() {
+        let takes_lock = |args: &[&str]| {
+            let args = Args::try_parse_from([&["mihoro"], args].concat()).unwrap();
+            args.command.unwrap().takes_lock()
+        };
+        for args in [
+            &["setup"][..],
+            &["update", "--all"],
+            &["apply"],
+            &["init"],
+            &["benchmark", "--save"],
+            &["profile", "use", "work"],
+            &["cron", "enable", "--interval", "daily"],
+            &["cron", "disable"],
+        ] {
+            assert!(takes_lock(args), "{:?}", args);
+        }
+        for args in [
+            &["status"][..],
+            &["apply", "--diff"],
+            &["benchmark"],
+            &["profile", "list"],
+            &["cron", "status"],
+            &["config", "show"],
+        ] {
+            assert!(!takes_lock(args), "{:?}", args);
+        }
+    }
+
+    /// Test that dry runs, which write nothing, skip the lock, including `init` and `upgrade`
+    #[test]
+    fn test_takes_lock_dry_run() {
+        let takes_lock = |args: &[&str]| {
+            Args::try_parse_from([&["mihoro"], args].concat())
+                .unwrap()
+                .takes_lock()
+        };
+        for args in [
+            &["init", "--force"][..],
+            &["upgrade"],
+            &["upgrade", "--to", "0.1.0"],
+            &["update", "--core"],
+        ] {
+            assert!(takes_lock(args), "{:?}", args);
+            assert!(!takes_lock(&[&["--dry-run"], args].concat()), "{:?}", args);
+        }
+        assert!(!takes_lock(&["upgrade", "--check"]));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::cmd::Args;
+use crate::config::Config;
+use crate::log::info;
+use crate::mihoro::Mihoro;
+use crate::resolve_mihomo_bin::SUPPORTED_ARCHS;
+use crate::utils::{atomic_write, create_parent_dir, expand_path, xdg_dir};
+
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use clap::CommandFactory;
+use clap_complete::{generate, Shell};
+use colored::Colorize;
+
+/// Loader scripts installed by bash-completion, which reads completions from the user's data
+/// directory since 2.x.
+const BASH_COMPLETION_SCRIPTS: [&str; 2] = [
+    "/usr/share/bash-completion/bash_completion",
+    "/etc/bash_completion",
+];
+
+/// Completes `--arch` and `profile use` through `mihoro _complete`, falling back to the generated
+/// `_mihoro` for everything else.
+const BASH_DYNAMIC: &str = r#"
+_mihoro_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ ${prev} == --arch ]]; then
+        COMPREPLY=($(compgen -W "$(mihoro _complete arch 2>/dev/null)" -- "${cur}"))
+    elif [[ ${COMP_CWORD} -eq 3 && ${COMP_WORDS[1]} == profile && ${COMP_WORDS[2]} == use && ${cur} != -* ]]; then
+        COMPREPLY=($(compgen -W "$(mihoro _complete profiles 2>/dev/null)" -- "${cur}"))
+    else
+        _mihoro "$@"
+    fi
+}
+
+complete -F _mihoro_dynamic -o bashdefault -o default mihoro
+"#;
+
+/// Completion functions for the zsh specs that `wire_zsh` points at them.
+const ZSH_DYNAMIC: &str = r#"(( $+functions[_mihoro_arches] )) ||
+_mihoro_arches() {
+    compadd -- ${(f)"$(mihoro _complete arch 2>/dev/null)"}
+}
+(( $+functions[_mihoro_profiles] )) ||
+_mihoro_profiles() {
+    compadd -- ${(f)"$(mihoro _complete profiles 2>/dev/null)"}
+}
+
+"#;
+
+/// Additional fish completions, fish merges them with the generated ones for the same option.
+const FISH_DYNAMIC: &str = r#"
+complete -c mihoro -n "__fish_mihoro_using_subcommand setup; or __fish_mihoro_using_subcommand update" -l arch -r -f -a "(mihoro _complete arch 2>/dev/null)"
+complete -c mihoro -n "__fish_mihoro_using_subcommand profile; and __fish_seen_subcommand_from use" -f -a "(mihoro _complete profiles 2>/dev/null)"
+"#;
+
+/// Values completed at runtime by `mihoro _complete <kind>`, which static completions can't list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompletionKind {
+    /// Architectures accepted by `--arch`
+    Arch,
+    /// Profiles defined in mihoro.toml, for `profile use`
+    Profiles,
+}
+
+/// The candidates requested by `args` (including the program name), if they are
+/// `_complete <kind>`.
+pub fn requested_candidates(args: impl Iterator<Item = String>) -> Option<CompletionKind> {
+    let args: Vec<String> = args.skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["_complete", "arch"] => Some(CompletionKind::Arch),
+        ["_complete", "profiles"] => Some(CompletionKind::Profiles),
+        _ => None,
+    }
+}
+
+/// Print the candidates for `kind`, one per line.
+///
+/// Profiles come from the config at `config_path`, none are printed if it can't be read, as
+/// errors would end up in the middle of the shell's completions.
+pub fn print_candidates(kind: CompletionKind, config_path: &str) {
+    let candidates: Vec<String> = match kind {
+        CompletionKind::Arch => SUPPORTED_ARCHS
+            .iter()
+            .map(|arch| arch.to_string())
+            .collect(),
+        CompletionKind::Profiles => Config::setup_from(&expand_path(config_path).to_string_lossy())
+            .map(|config| config.profiles.into_keys().collect())
+            .unwrap_or_default(),
+    };
+    // The shell may stop reading early, which is no error worth reporting
+    let mut stdout = io::stdout().lock();
+    for candidate in candidates {
+        if writeln!(stdout, "{}", candidate).is_err() {
+            break;
+        }
+    }
+}
+
+/// Completion script for `shell`, with dynamic values like profile names completed by calling
+/// `mihoro _complete` for bash, zsh and fish.
+pub fn completion_script(shell: Shell) -> Vec<u8> {
+    let mut generated = vec![];
+    generate(shell, &mut Args::command(), "mihoro", &mut generated);
+    let script = String::from_utf8_lossy(&generated).into_owned();
+    match shell {
+        Shell::Bash => script + BASH_DYNAMIC,
+        Shell::Zsh => wire_zsh(&script),
+        Shell::Fish => script + FISH_DYNAMIC,
+        _ => script,
+    }
+    .into_bytes()
+}
+
+/// Point the zsh specs of `--arch` and the `profile use` name at `ZSH_DYNAMIC`'s functions,
+/// defined before the script calls `_mihoro`.
+fn wire_zsh(script: &str) -> String {
+    let script = script
+        .lines()
+        .map(|line| {
+            if line.starts_with("'--arch=") {
+                line.replace(":ARCH:_default'", ":ARCH:_mihoro_arches'")
+            } else if line.starts_with("':name -- Name of the profile") {
+                line.replace(":_default'", ":_mihoro_profiles'")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let entry = "if [ \"$funcstack[1]\" = \"_mihoro\" ]; then";
+    match script.find(entry) {
+        Some(at) => format!("{}{}{}\n", &script[..at], ZSH_DYNAMIC, &script[at..]),
+        None => format!("{}\n{}", script, ZSH_DYNAMIC),
+    }
+}
+
+/// Conventional per-user location of the completion file for `shell`.
+fn completion_path(shell: Shell, get_env: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    match shell {
+        Shell::Bash => {
+            Ok(xdg_dir(&get_env, "DATA", ".local/share")?
+                .join("bash-completion/completions/mihoro"))
+        }
+        Shell::Fish => {
+            Ok(xdg_dir(&get_env, "CONFIG", ".config")?.join("fish/completions/mihoro.fish"))
+        }
+        Shell::Zsh => {
+            Ok(xdg_dir(&get_env, "DATA", ".local/share")?.join("zsh/site-functions/_mihoro"))
+        }
+        shell => bail!(
+            "installing {} completions is not supported, add the output of `mihoro completions {}` \
+             to your shell profile instead",
+            shell,
+            shell
+        ),
+    }
+}
+
+/// Hint for making the shell load completions from `path`, if it may not already.
+fn completion_path_hint(shell: Shell, path: &Path) -> Option<String> {
+    match shell {
+        Shell::Bash
+            if !BASH_COMPLETION_SCRIPTS
+                .iter()
+                .any(|s| Path::new(s).exists()) =>
+        {
+            Some("bash-completion is not installed, install it to load completions".to_string())
+        }
+        Shell::Zsh => {
+            // zsh does not export `fpath`, but `FPATH` mirrors it if exported
+            let dir = path.parent()?;
+            let on_fpath = env::var("FPATH")
+                .is_ok_and(|fpath| env::split_paths(&fpath).any(|entry| entry == dir));
+            (!on_fpath).then(|| {
+                format!(
+                    "add `fpath+=({})` before `compinit` in ~/.zshrc to load completions",
+                    dir.to_string_lossy()
+                )
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Write completions for `shell` to its conventional per-user location.
+pub fn install_completions(mihoro: &Mihoro, shell: Shell) -> Result<()> {
+    let path = completion_path(shell, |var| env::var(var).ok())?;
+    if mihoro.skip_in_dry_run(&format!(
+        "write {} completions to {}",
+        shell,
+        path.to_string_lossy()
+    )) {
+        return Ok(());
+    }
+
+    create_parent_dir(&path)?;
+    atomic_write(&path, completion_script(shell))?;
+    info!(
+        "{} Installed {} completions to {}",
+        mihoro.prefix.green(),
+        shell,
+        path.to_string_lossy().underline().yellow()
+    );
+    if let Some(hint) = completion_path_hint(shell, &path) {
+        info!("{} {}", "warning:".yellow(), hint);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completion_path() -> Result<()> {
+        let home = |var: &str| (var == "HOME").then(|| "/home/me".to_string());
+        assert_eq!(
+            completion_path(Shell::Bash, home)?,
+            Path::new("/home/me/.local/share/bash-completion/completions/mihoro")
+        );
+        assert_eq!(
+            completion_path(Shell::Fish, home)?,
+            Path::new("/home/me/.config/fish/completions/mihoro.fish")
+        );
+        assert_eq!(
+            completion_path(Shell::Zsh, home)?,
+            Path::new("/home/me/.local/share/zsh/site-functions/_mihoro")
+        );
+
+        let xdg = |var: &str| match var {
+            "XDG_DATA_HOME" => Some("/data".to_string()),
+            "XDG_CONFIG_HOME" => Some(String::new()),
+            "HOME" => Some("/home/me".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            completion_path(Shell::Zsh, xdg)?,
+            Path::new("/data/zsh/site-functions/_mihoro")
+        );
+        assert_eq!(
+            completion_path(Shell::Fish, xdg)?,
+            Path::new("/home/me/.config/fish/completions/mihoro.fish")
+        );
+        assert!(completion_path(Shell::Elvish, home).is_err());
+        Ok(())
+    }
+
+    /// Test that dynamic values are wired into the generated scripts, which breaks silently if
+    /// the generated specs change
+    #[test]
+    fn test_completion_script_completes_dynamic_values() {
+        let script = |shell| String::from_utf8(completion_script(shell)).unwrap();
+
+        let zsh = script(Shell::Zsh);
+        assert_eq!(zsh.matches(":ARCH:_mihoro_arches'").count(), 2);
+        assert_eq!(zsh.matches(":_mihoro_profiles'").count(), 1);
+        // The functions must be defined before `_mihoro` is called
+        assert!(zsh.find("_mihoro_profiles() {") < zsh.find("    _mihoro \"$@\""));
+
+        let bash = script(Shell::Bash);
+        assert!(bash
+            .trim_end()
+            .ends_with("complete -F _mihoro_dynamic -o bashdefault -o default mihoro"));
+        assert!(script(Shell::Fish).contains("(mihoro _complete profiles 2>/dev/null)"));
+        // `_complete` is not a subcommand, so it is not completed itself
+        assert!(!bash.contains("_complete)"));
+    }
+
+    #[test]
+    fn test_requested_candidates() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            requested_candidates(args(&["mihoro", "_complete", "profiles"]).into_iter()),
+            Some(CompletionKind::Profiles)
+        );
+        assert_eq!(
+            requested_candidates(args(&["mihoro", "_complete", "arch"]).into_iter()),
+            Some(CompletionKind::Arch)
+        );
+        assert_eq!(
+            requested_candidates(args(&["mihoro", "profile", "use", "work"]).into_iter()),
+            None
+        );
+        assert_eq!(
+            requested_candidates(args(&["mihoro", "_complete"]).into_iter()),
+            None
+        );
+    }
+}
@@ -1,37 +1,467 @@
-use crate::utils::create_parent_dir;
+use crate::cron::validate_schedule;
+use crate::error::MihoroError;
+use crate::geodata::GeoipFormat;
+use crate::utils::{atomic_write, create_parent_dir, expand_path, xdg_default_path};
 
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use colored::Colorize;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use toml_edit::DocumentMut;
 
 /// Mihomo release channel for automatic binary fetching.
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub enum MihomoChannel {
     #[default]
-    #[serde(alias = "stable", rename(serialize = "stable"))]
+    #[serde(rename = "stable", alias = "Stable")]
     Stable,
-    #[serde(alias = "alpha", rename(serialize = "alpha"))]
+    #[serde(rename = "alpha", alias = "Alpha")]
     Alpha,
 }
 
+impl MihomoChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MihomoChannel::Stable => "stable",
+            MihomoChannel::Alpha => "alpha",
+        }
+    }
+}
+
+/// Systemd service manager scope that mihomo runs under.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum SystemdMode {
+    #[default]
+    #[serde(rename = "user", alias = "User")]
+    User,
+    #[serde(rename = "system", alias = "System")]
+    System,
+}
+
+/// How `apply` and `update` hot-reload config.yaml, unless restarting is forced with `--restart`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum ReloadMethod {
+    /// `PUT /configs` on mihomo's external controller
+    #[default]
+    #[serde(rename = "api", alias = "Api")]
+    Api,
+    /// `systemctl reload`, which sends mihomo `SIGHUP` through the unit's `ExecReload=`
+    #[serde(rename = "signal", alias = "Signal")]
+    Signal,
+}
+
+/// Format subscriptions are served in, converted to YAML for mihomo if JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConfigFormat {
+    /// JSON if the config starts with `{` and parses as JSON, YAML otherwise
+    #[default]
+    #[serde(rename = "auto", alias = "Auto")]
+    Auto,
+    #[serde(rename = "yaml", alias = "Yaml")]
+    Yaml,
+    #[serde(rename = "json", alias = "Json")]
+    Json,
+}
+
+/// IP version mihoro's own downloads connect over.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum IpVersion {
+    /// Whichever the resolver returns, trying both
+    #[default]
+    #[serde(rename = "any", alias = "Any")]
+    Any,
+    #[serde(rename = "ipv4", alias = "Ipv4")]
+    Ipv4,
+    #[serde(rename = "ipv6", alias = "Ipv6")]
+    Ipv6,
+}
+
+impl IpVersion {
+    /// Unspecified local address to bind connections to, which restricts them to that IP version.
+    pub fn local_address(&self) -> Option<IpAddr> {
+        match self {
+            IpVersion::Any => None,
+            IpVersion::Ipv4 => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpVersion::Ipv6 => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        }
+    }
+}
+
+/// Scheduler used to run auto-updates.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum CronBackend {
+    #[default]
+    #[serde(rename = "crontab", alias = "Crontab")]
+    Crontab,
+    #[serde(rename = "systemd-timer", alias = "SystemdTimer")]
+    SystemdTimer,
+}
+
+/// Default `mihoro_user_agent`, e.g. `mihoro/0.10.0`.
+pub const DEFAULT_USER_AGENT: &str = concat!("mihoro/", env!("CARGO_PKG_VERSION"));
+
+/// Multiple of `network_timeout_secs` allowed for file downloads.
+const DOWNLOAD_TIMEOUT_FACTOR: u32 = 10;
+
+/// Commented default `mihoro.toml`, written by `mihoro init` and on first run.
+///
+/// Uncommented values must match `Config::default()`.
+const DEFAULT_CONFIG_TEMPLATE: &str = r#"# mihoro config, see https://github.com/spencerwooo/mihoro for details.
+# Paths may start with `~` and reference environment variables, e.g. `$XDG_CONFIG_HOME`.
+
+# Other config files merged into this one, e.g. a base shared across machines. Later files override
+# earlier ones and this file overrides them all. Relative paths are resolved from this file.
+# include = ["~/.config/mihoro/base.toml"]
+
+# Your mihomo or clash subscription url, required.
+remote_config_url = ""
+
+# Authorization for subscription urls that require it, a bearer token or basic auth. Values
+# starting with `$` are read from that environment variable instead.
+# config_auth = { bearer = "$MIHORO_SUB_TOKEN" }
+# config_auth = { basic = { user = "me", pass = "$MIHORO_SUB_PASS" } }
+
+# Format the subscription is served in, "auto" to detect JSON, or "yaml" or "json" to force one.
+# JSON configs are converted to YAML for mihomo.
+# config_format = "auto"
+
+# mihomo release channel to download, "stable" or "alpha".
+mihomo_channel = "stable"
+
+# Pin a mihomo release architecture instead of detecting it, e.g. "arm64" or "amd64-v3".
+# mihomo_arch = "amd64-compatible"
+
+# Pick an optimized amd64-v2/v3 build from CPU features when `mihomo_arch` is unset.
+arch_autotune = false
+
+# Download mihomo from this url instead of the GitHub release, as .gz, .tar.gz or .zip.
+# remote_mihomo_binary_url = "https://example.com/mihomo-linux-amd64.gz"
+
+# Mirror prefix for GitHub downloads.
+# github_mirror = "https://ghfast.top"
+# Mirrors `mihoro benchmark` times alongside GitHub and `github_mirror`, `--save` picks the fastest.
+# github_mirrors = ["https://ghfast.top", "https://gh-proxy.com"]
+
+# GitHub token to raise API rate limits, only sent to GitHub itself and not to `github_mirror`.
+# GITHUB_TOKEN is used if unset.
+# github_token = "ghp_..."
+
+# Proxy for mihoro's own downloads, `https_proxy`/`all_proxy` are used if unset.
+# download_proxy = "http://proxy.example.com:8080"
+# Connect over "ipv4" or "ipv6" only, e.g. "ipv4" where AAAA records exist but IPv6 is broken.
+# download_ip_version = "ipv4"
+# Extra root certificates (PEM) to trust for downloads, e.g. of a corporate TLS-inspecting proxy.
+# tls_ca_cert = "~/.config/mihoro/ca.pem"
+# Accept any TLS certificate for downloads. Insecure, only for testing.
+# tls_danger_accept_invalid_certs = false
+
+# Install locations, under $XDG_BIN_HOME and $XDG_CONFIG_HOME instead if those are set.
+# mihomo_binary_path = "~/.local/bin/mihomo"
+# mihomo_config_root = "~/.config/mihomo"
+# user_systemd_root = "~/.config/systemd/user"
+
+# "user" for a per-user service, or "system" for a system-wide service (run mihoro as root).
+systemd_mode = "user"
+# Write the unit file elsewhere than the default for `systemd_mode`, it is then linked into systemd.
+# systemd_unit_dir = "~/.local/share/systemd/user"
+service_name = "mihomo.service"
+# How config changes are hot-reloaded, "api" through mihomo's external controller, or "signal"
+# with `systemctl reload`, which needs no external-controller. Either falls back to a restart.
+reload_method = "api"
+
+# User agent for all requests, `mihoro/<version>` by default. Some subscription providers return
+# different configs per client, set `subscription_user_agent` (e.g. "clash.meta") to send another
+# one for subscription downloads only.
+# mihoro_user_agent = "mihoro"
+# subscription_user_agent = "clash.meta"
+download_retries = 3
+# Timeout for small requests, file downloads are allowed 10 times as long.
+network_timeout_secs = 30
+# Reuse the latest mihomo version looked up on GitHub for this long, 0 to look it up every time.
+version_cache_ttl_secs = 3600
+
+# Back up config.yaml and the mihomo binary before updating, keeping the latest `backup_keep`.
+backup_before_update = true
+backup_keep = 3
+
+# Auto-update interval in hours for `mihoro cron enable` (1-24), 0 to disable.
+auto_update_interval = 12
+# Cron expression for auto-updates, takes precedence over `auto_update_interval`.
+# cron_schedule = "0 4 * * *"
+# "crontab", or "systemd-timer" on systems without a cron daemon.
+cron_backend = "crontab"
+
+# Shell commands run before and after `mihoro update`, with MIHORO_UPDATED_COMPONENT (config,
+# core, geodata or all), MIHORO_VERSION and MIHOMO_VERSION set. Failures are only warned about.
+# pre_update_hook = "echo updating $MIHORO_UPDATED_COMPONENT"
+# post_update_hook = "systemctl --user reload my-downstream.service"
+
+# Send a desktop notification with `notify-send` when `mihoro update` finishes or fails, e.g. for
+# scheduled updates. Nothing is sent where `notify-send` is not installed.
+# notify_on_update = false
+
+# Skip geodata downloads in `setup` and `update --all`, as `mihoro setup --minimal` does, and turn
+# off mihomo's `geo-auto-update`. mihomo still downloads geodata itself if GEOIP/GEOSITE rules
+# need it on startup.
+# skip_geodata = false
+
+# Force `allow-lan: true` into config.yaml after all overrides, including profiles' and the
+# subscription's own, for machines sharing their proxy with the LAN (`mihoro proxy export-lan`).
+# ensure_allow_lan = false
+
+# YAML files deep-merged into config.yaml on every `update` and `apply`, in order, e.g. custom
+# rules or a DNS block. `[overrides]` are merged after them. Relative paths are resolved from
+# this file.
+# merge_files = ["~/.config/mihoro/dns.yaml"]
+
+# Rules inserted at the top of config.yaml's `rules`, or at the bottom (before a final `MATCH`
+# rule), after all other overrides, without replacing the subscription's rules.
+# extra_rules_prepend = ["DOMAIN-SUFFIX,internal.example.com,DIRECT"]
+# extra_rules_append = ["GEOIP,LAN,DIRECT"]
+
+# Overrides applied to mihomo's config.yaml on every `update` and `apply`.
+[mihomo_config]
+port = 7891
+socks_port = 7892
+mixed_port = 7890
+allow_lan = false
+bind_address = "*"
+mode = "rule"
+log_level = "info"
+ipv6 = true
+external_controller = "0.0.0.0:9090"
+external_ui = "ui"
+# secret = ""
+geodata_mode = false
+geo_auto_update = true
+geo_update_interval = 24
+
+[mihomo_config.geox_url]
+geoip = "https://testingcf.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/geoip.dat"
+geosite = "https://testingcf.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/geosite.dat"
+mmdb = "https://testingcf.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/country.mmdb"
+
+# Geodata downloaded by `mihoro update --geodata`, instead of the `geox_url` files used in
+# `geodata_mode`. Set a source to "" to skip downloading it.
+# [geodata]
+# geosite = "https://testingcf.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/geosite.dat"
+# asn = "https://testingcf.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/GeoLite2-ASN.mmdb"
+
+# Other mihomo config fields, deep-merged into config.yaml.
+# [overrides.dns]
+# enable = true
+
+# Named subscriptions, switched between with `mihoro profile use <name>`.
+# active_profile = "home"
+# [profiles.home]
+# remote_config_url = "https://home.example.com/config.yaml"
+"#;
+
 /// `mihoro` configurations.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub remote_config_url: String,
+    /// Config files merged underneath this one, resolved by `Config::setup_from`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_auth: Option<ConfigAuth>,
+    pub config_format: ConfigFormat,
     pub mihomo_channel: MihomoChannel,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote_mihomo_binary_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mihomo_arch: Option<String>,
+    /// Pick an optimized `amd64-v2`/`amd64-v3` build from CPU features when `mihomo_arch` is unset.
+    pub arch_autotune: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github_mirror: Option<String>,
+    /// Other mirrors `mihoro benchmark` compares with GitHub and `github_mirror`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub github_mirrors: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github_token: Option<String>,
+    /// Proxy for mihoro's own downloads, `https_proxy`/`all_proxy` are used if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_proxy: Option<String>,
+    pub download_ip_version: IpVersion,
+    /// PEM bundle of extra root certificates trusted for mihoro's own downloads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_ca_cert: Option<String>,
+    /// Skip TLS certificate verification for mihoro's own downloads, for testing only.
+    pub tls_danger_accept_invalid_certs: bool,
     pub mihomo_binary_path: String,
     pub mihomo_config_root: String,
     pub user_systemd_root: String,
+    pub systemd_mode: SystemdMode,
+    /// Directory the unit file is written to, overrides `user_systemd_root` and
+    /// `/etc/systemd/system`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub systemd_unit_dir: Option<String>,
+    pub service_name: String,
+    pub reload_method: ReloadMethod,
     pub mihoro_user_agent: String,
+    /// User agent for subscription downloads, instead of `mihoro_user_agent`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_user_agent: Option<String>,
+    pub download_retries: u32,
+    /// Timeout for small requests like version lookups, file downloads get a multiple of it.
+    pub network_timeout_secs: u64,
+    /// Seconds the latest mihomo version looked up on GitHub is reused for.
+    pub version_cache_ttl_secs: u64,
+    pub backup_before_update: bool,
+    pub backup_keep: usize,
     pub auto_update_interval: u16,
+    /// Cron expression for auto-updates, takes precedence over `auto_update_interval`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cron_schedule: Option<String>,
+    pub cron_backend: CronBackend,
+    /// Shell command run before `mihoro update` downloads anything.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_update_hook: Option<String>,
+    /// Shell command run after `mihoro update` succeeded and restarted the service.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_update_hook: Option<String>,
+    /// Send a desktop notification after `mihoro update`, see `notify::notify_update`.
+    pub notify_on_update: bool,
+    /// Skip geodata downloads in `setup` and `update --all`, for metered connections.
+    pub skip_geodata: bool,
+    /// Force `allow-lan: true` into mihomo's config after all overrides.
+    pub ensure_allow_lan: bool,
+    /// YAML files deep-merged into mihomo's config before `overrides`, see `Mihoro::merge_files`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub merge_files: Vec<String>,
+    /// Rules inserted at the top of mihomo's `rules`, see `rules::insert_rules`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_rules_prepend: Vec<String>,
+    /// Rules inserted at the bottom of mihomo's `rules`, before a final `MATCH` rule.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_rules_append: Vec<String>,
     pub mihomo_config: MihomoConfig,
+    #[serde(skip_serializing_if = "GeodataConfig::is_empty")]
+    pub geodata: GeodataConfig,
+    /// Free-form mihomo config fields deep-merged into `config.yaml` after `mihomo_config`.
+    #[serde(skip_serializing_if = "toml::Table::is_empty")]
+    pub overrides: toml::Table,
+    /// Name of the profile in `profiles` to use, top-level `remote_config_url` is used if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named subscription under `[profiles.<name>]`, switched between with `mihoro profile use`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub remote_config_url: String,
+    /// Authorization for this profile's `remote_config_url`, the top-level one is not used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_auth: Option<ConfigAuth>,
+    /// Deep-merged into `config.yaml` after the top-level `overrides`.
+    #[serde(skip_serializing_if = "toml::Table::is_empty")]
+    pub overrides: toml::Table,
+}
+
+/// Authorization sent with subscription requests, `config_auth = { bearer = "token" }` or
+/// `config_auth = { basic = { user = "me", pass = "secret" } }`.
+///
+/// Values starting with `$`, e.g. `"$MIHORO_SUB_TOKEN"` or `"${MIHORO_SUB_TOKEN}"`, are read from
+/// the environment when the request is made, so secrets need not be stored in `mihoro.toml`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ConfigAuth {
+    Bearer(String),
+    Basic { user: String, pass: String },
+}
+
+/// Resolve a `$VAR` reference to the environment variable's value, and `${VAR}` references as
+/// `substitute_env` does, other values are returned as is.
+fn resolve_env_ref(value: &str) -> Result<String> {
+    if value.contains("${") {
+        return substitute_env_str(value, &|var| env::var(var).ok())
+            .map_err(|err| anyhow!("invalid `config_auth`: {}", err));
+    }
+    let Some(name) = value.strip_prefix('$') else {
+        return Ok(value.to_string());
+    };
+    env::var(name).map_err(|_| {
+        anyhow!(
+            "`config_auth` references environment variable `{}`, which is not set",
+            name
+        )
+    })
+}
+
+impl ConfigAuth {
+    /// The `Authorization` header for this auth, marked sensitive so it is never printed.
+    pub fn header(&self) -> Result<HeaderMap> {
+        let value = match self {
+            ConfigAuth::Bearer(token) => format!("Bearer {}", resolve_env_ref(token)?),
+            ConfigAuth::Basic { user, pass } => {
+                let credentials = format!("{}:{}", resolve_env_ref(user)?, resolve_env_ref(pass)?);
+                format!("Basic {}", BASE64_STANDARD.encode(credentials))
+            }
+        };
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| anyhow!("`config_auth` contains characters invalid in a header"))?;
+        value.set_sensitive(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value);
+        Ok(headers)
+    }
+
+    /// Describe the auth for debug output, without its secret.
+    pub fn describe(&self) -> String {
+        match self {
+            ConfigAuth::Bearer(_) => "bearer token ***".to_string(),
+            ConfigAuth::Basic { user, .. } => format!("basic auth as `{}`", user),
+        }
+    }
+}
+
+/// Geodata sources downloaded by `mihoro update --geodata`, under `[geodata]`.
+///
+/// Unset sources fall back to `mihomo_config.geox_url` for the files used in the configured
+/// `geodata_mode`, and sources set to `""` are not downloaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeodataConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geoip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geosite: Option<String>,
+    /// Saved as `geoip.metadb` if the url ends with `.metadb`, or else as `country.mmdb`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mmdb: Option<String>,
+    /// ASN database, used by `IP-ASN` rules, only downloaded if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<String>,
+}
+
+impl GeodataConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == GeodataConfig::default()
+    }
+}
+
+/// A geodata file in the mihomo config root and the url it is downloaded from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeodataSource {
+    pub file: &'static str,
+    pub url: String,
 }
 
 // Serde defaults for Config
@@ -41,13 +471,48 @@ impl Default for Config {
             remote_mihomo_binary_url: None,
             mihomo_channel: MihomoChannel::default(),
             mihomo_arch: None,
+            arch_autotune: false,
+            github_mirror: None,
+            github_mirrors: vec![],
+            github_token: None,
+            download_proxy: None,
+            download_ip_version: IpVersion::default(),
+            tls_ca_cert: None,
+            tls_danger_accept_invalid_certs: false,
             remote_config_url: String::from(""),
-            mihomo_binary_path: String::from("~/.local/bin/mihomo"),
-            mihomo_config_root: String::from("~/.config/mihomo"),
-            user_systemd_root: String::from("~/.config/systemd/user"),
-            mihoro_user_agent: String::from("mihoro"),
+            include: vec![],
+            config_auth: None,
+            config_format: ConfigFormat::default(),
+            mihomo_binary_path: xdg_default_path("BIN", ".local/bin", "mihomo"),
+            mihomo_config_root: xdg_default_path("CONFIG", ".config", "mihomo"),
+            user_systemd_root: xdg_default_path("CONFIG", ".config", "systemd/user"),
+            systemd_mode: SystemdMode::default(),
+            systemd_unit_dir: None,
+            service_name: String::from("mihomo.service"),
+            reload_method: ReloadMethod::default(),
+            mihoro_user_agent: String::from(DEFAULT_USER_AGENT),
+            subscription_user_agent: None,
+            download_retries: 3,
+            network_timeout_secs: 30,
+            version_cache_ttl_secs: 3600,
+            backup_before_update: true,
+            backup_keep: 3,
             auto_update_interval: 12,
+            cron_schedule: None,
+            cron_backend: CronBackend::default(),
+            pre_update_hook: None,
+            post_update_hook: None,
+            notify_on_update: false,
+            skip_geodata: false,
+            ensure_allow_lan: false,
+            merge_files: vec![],
+            extra_rules_prepend: vec![],
+            extra_rules_append: vec![],
             mihomo_config: MihomoConfig::default(),
+            geodata: GeodataConfig::default(),
+            overrides: toml::Table::new(),
+            active_profile: None,
+            profiles: BTreeMap::new(),
         }
     }
 }
@@ -56,7 +521,7 @@ impl Default for Config {
 ///
 /// Referenced from https://wiki.metacubex.one/config
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct MihomoConfig {
     pub port: u16,
     pub socks_port: u16,
@@ -109,29 +574,30 @@ impl Default for MihomoConfig {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MihomoMode {
-    #[serde(alias = "global", rename(serialize = "global"))]
+    #[serde(rename = "global", alias = "Global")]
     Global,
-    #[serde(alias = "rule", rename(serialize = "rule"))]
+    #[serde(rename = "rule", alias = "Rule")]
     Rule,
-    #[serde(alias = "direct", rename(serialize = "direct"))]
+    #[serde(rename = "direct", alias = "Direct")]
     Direct,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MihomoLogLevel {
-    #[serde(alias = "silent", rename(serialize = "silent"))]
+    #[serde(rename = "silent", alias = "Silent")]
     Silent,
-    #[serde(alias = "error", rename(serialize = "error"))]
+    #[serde(rename = "error", alias = "Error")]
     Error,
-    #[serde(alias = "warning", rename(serialize = "warning"))]
+    #[serde(rename = "warning", alias = "Warning")]
     Warning,
-    #[serde(alias = "info", rename(serialize = "info"))]
+    #[serde(rename = "info", alias = "Info")]
     Info,
-    #[serde(alias = "debug", rename(serialize = "debug"))]
+    #[serde(rename = "debug", alias = "Debug")]
     Debug,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct GeoxUrl {
     pub geoip: String,
     pub geosite: String,
@@ -139,21 +605,419 @@ pub struct GeoxUrl {
 }
 
 impl Config {
-    pub fn new() -> Config {
-        Config::default()
+    /// Timeout for small requests, such as version and checksum lookups.
+    pub fn network_timeout(&self) -> Duration {
+        Duration::from_secs(self.network_timeout_secs)
+    }
+
+    /// Timeout for file downloads (binary, config and geodata), which may be large.
+    pub fn download_timeout(&self) -> Duration {
+        self.network_timeout() * DOWNLOAD_TIMEOUT_FACTOR
+    }
+
+    /// The profile named by `active_profile`, if any.
+    pub fn current_profile(&self) -> Option<&Profile> {
+        self.active_profile
+            .as_ref()
+            .and_then(|name| self.profiles.get(name))
+    }
+
+    /// Remote config URL of the active profile, falling back to the top-level `remote_config_url`.
+    pub fn active_remote_config_url(&self) -> &str {
+        match self.current_profile() {
+            Some(profile) => &profile.remote_config_url,
+            None => &self.remote_config_url,
+        }
+    }
+
+    /// Authorization for the active profile's subscription, or the top-level `config_auth`.
+    pub fn active_config_auth(&self) -> Option<&ConfigAuth> {
+        match self.current_profile() {
+            Some(profile) => profile.config_auth.as_ref(),
+            None => self.config_auth.as_ref(),
+        }
+    }
+
+    /// User agent for mihoro's requests, `mihoro_user_agent` or `mihoro/<version>` if it is empty.
+    pub fn user_agent(&self) -> &str {
+        match self.mihoro_user_agent.trim() {
+            "" => DEFAULT_USER_AGENT,
+            user_agent => user_agent,
+        }
+    }
+
+    /// User agent for subscription downloads, if `subscription_user_agent` is set and not empty.
+    pub fn subscription_user_agent(&self) -> Option<&str> {
+        self.subscription_user_agent
+            .as_deref()
+            .map(str::trim)
+            .filter(|user_agent| !user_agent.is_empty())
+    }
+
+    /// Proxy for mihoro's own downloads, if `download_proxy` is set and not empty.
+    pub fn download_proxy(&self) -> Option<&str> {
+        self.download_proxy
+            .as_deref()
+            .filter(|proxy| !proxy.is_empty())
+    }
+
+    /// Token for GitHub requests, `github_token` or the `GITHUB_TOKEN` environment variable.
+    pub fn github_token(&self) -> Option<String> {
+        self.github_token
+            .clone()
+            .or_else(|| env::var("GITHUB_TOKEN").ok())
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Geodata files to download, see `GeodataConfig`.
+    pub fn geodata_sources(&self) -> Vec<GeodataSource> {
+        let geodata_mode = self.geodata_mode();
+        let geox_url = self.mihomo_config.geox_url.as_ref();
+        let dat_fallback = geox_url.filter(|_| geodata_mode);
+        let mmdb_fallback = geox_url.filter(|_| !geodata_mode);
+
+        [
+            (
+                "geoip.dat",
+                &self.geodata.geoip,
+                dat_fallback.map(|geox_url| &geox_url.geoip),
+            ),
+            (
+                "geosite.dat",
+                &self.geodata.geosite,
+                dat_fallback.map(|geox_url| &geox_url.geosite),
+            ),
+            (
+                "country.mmdb",
+                &self.geodata.mmdb,
+                mmdb_fallback.map(|geox_url| &geox_url.mmdb),
+            ),
+            ("ASN.mmdb", &self.geodata.asn, None),
+        ]
+        .into_iter()
+        .filter_map(|(file, url, fallback)| {
+            let url = url.as_ref().or(fallback)?;
+            let file = match file {
+                "country.mmdb" => GeoipFormat::of_mmdb_url(url).file(),
+                _ => file,
+            };
+            (!url.is_empty()).then(|| GeodataSource {
+                file,
+                url: url.clone(),
+            })
+        })
+        .collect()
+    }
+
+    /// An `overrides` value of the active profile, or else of the top level.
+    fn override_value(&self, key: &str) -> Option<&toml::Value> {
+        self.current_profile()
+            .and_then(|profile| profile.overrides.get(key))
+            .or_else(|| self.overrides.get(key))
+    }
+
+    /// Whether mihomo matches `GEOIP` rules against `geoip.dat` rather than an MMDB, by
+    /// `geodata-mode` in `overrides`, or else `mihomo_config.geodata_mode`.
+    pub fn geodata_mode(&self) -> bool {
+        self.override_value("geodata-mode")
+            .and_then(toml::Value::as_bool)
+            .or(self.mihomo_config.geodata_mode)
+            .unwrap_or(false)
+    }
+
+    /// The `geodata-loader` set in `overrides`, if any.
+    pub fn geodata_loader(&self) -> Option<&str> {
+        self.override_value("geodata-loader")
+            .and_then(toml::Value::as_str)
+    }
+
+    /// Values that must not be shared, e.g. in bug reports: subscription urls, which embed access
+    /// tokens, and the mihomo API `secret`.
+    pub fn secrets(&self) -> Vec<&str> {
+        let mut secrets = vec![self.remote_config_url.as_str()];
+        secrets.extend(
+            self.profiles
+                .values()
+                .map(|profile| profile.remote_config_url.as_str()),
+        );
+        let auths = self
+            .profiles
+            .values()
+            .map(|profile| &profile.config_auth)
+            .chain([&self.config_auth]);
+        for auth in auths.flatten() {
+            match auth {
+                ConfigAuth::Bearer(token) => secrets.push(token),
+                ConfigAuth::Basic { pass, .. } => secrets.push(pass),
+            }
+        }
+        secrets.extend(self.github_token.as_deref());
+        secrets.extend(self.mihomo_config.secret.as_deref());
+        secrets.extend(
+            self.overrides
+                .get("secret")
+                .and_then(|value| value.as_str()),
+        );
+        secrets.retain(|secret| !secret.is_empty());
+        secrets
     }
 
-    /// Read raw config string from path and parse with crate toml.
+    /// Read raw config string from path and parse with crate toml, merging the files it
+    /// `include`s underneath it.
     pub fn setup_from(path: &str) -> Result<Config> {
-        let raw_config = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&raw_config)?;
-        Ok(config)
+        let table = load_config_table(Path::new(path), &mut vec![])?;
+        let mut value = toml::Value::Table(table);
+        substitute_env(&mut value, "", &|var| env::var(var).ok())?;
+        Ok(value.try_into()?)
     }
 
-    pub fn write(&mut self, path: &Path) -> Result<()> {
-        let serialized_config = toml::to_string(&self)?;
-        fs::write(path, serialized_config)?;
-        Ok(())
+    /// Write the commented default config template to `path`.
+    pub fn write_default(path: &Path) -> Result<()> {
+        create_parent_dir(path)?;
+        atomic_write(path, DEFAULT_CONFIG_TEMPLATE)
+    }
+}
+
+/// Default `mihoro.toml` path, `$XDG_CONFIG_HOME/mihoro.toml` or `~/.config/mihoro.toml`.
+pub fn default_mihoro_config_path() -> String {
+    xdg_default_path("CONFIG", ".config", "mihoro.toml")
+}
+
+/// Parse the config file at `path` into a table, with the files listed in its `include` merged
+/// underneath it in order.
+///
+/// `including` holds the files whose includes are being resolved, to detect include cycles.
+fn load_config_table(path: &Path, including: &mut Vec<PathBuf>) -> Result<toml::Table> {
+    let display_path = path.to_string_lossy();
+    let raw_config =
+        fs::read_to_string(path).with_context(|| format!("failed to read `{}`", display_path))?;
+    // Parse each file on its own first, so errors point at the line in the file they are in
+    let config: Config = toml::from_str(&raw_config).map_err(|e| {
+        anyhow!(
+            "`{}` {}",
+            display_path,
+            describe_toml_error(&raw_config, &e)
+        )
+    })?;
+    let mut table: toml::Table = toml::from_str(&raw_config)?;
+    table.remove("include");
+    if config.include.is_empty() {
+        return Ok(table);
+    }
+
+    let canonical_path = fs::canonicalize(path)?;
+    if including.contains(&canonical_path) {
+        let cycle: Vec<String> = including
+            .iter()
+            .skip_while(|included| **included != canonical_path)
+            .chain([&canonical_path])
+            .map(|included| included.to_string_lossy().into_owned())
+            .collect();
+        bail!("include cycle: {}", cycle.join(" -> "));
+    }
+    including.push(canonical_path);
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = toml::Table::new();
+    for include in &config.include {
+        let include_table = load_config_table(&dir.join(expand_path(include)), including)
+            .with_context(|| format!("failed to include `{}` from `{}`", include, display_path))?;
+        merge_tables(&mut merged, include_table);
+    }
+    including.pop();
+
+    merge_tables(&mut merged, table);
+    Ok(merged)
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in every string of a config value with the environment
+/// variable `VAR`, or `default` if it is unset or empty, so secrets like subscription urls can be
+/// kept out of `mihoro.toml`. `$${` is a literal `${`.
+///
+/// `key` is the dotted key of `value`, naming the setting in errors.
+///
+/// `config_auth` is left as is, as its references are resolved when the subscription is fetched,
+/// so commands that don't fetch it run without its variables set.
+fn substitute_env(
+    value: &mut toml::Value,
+    key: &str,
+    get_env: &impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    if key == "config_auth" || key.ends_with(".config_auth") {
+        return Ok(());
+    }
+    let child_key = |child: &str| match key {
+        "" => child.to_string(),
+        _ => format!("{}.{}", key, child),
+    };
+    match value {
+        toml::Value::String(raw) if raw.contains("${") => {
+            *raw = substitute_env_str(raw, get_env).with_context(|| format!("in `{}`", key))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                substitute_env(item, &child_key(&i.to_string()), get_env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, item) in table.iter_mut() {
+                substitute_env(item, &child_key(name), get_env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Substitute the `${VAR}` and `${VAR:-default}` references in `raw`, see `substitute_env`.
+fn substitute_env_str(raw: &str, get_env: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unterminated `${{` in `{}`", raw);
+        };
+        let reference = &rest[start + 2..start + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            bail!(
+                "invalid environment variable reference `${{{}}}`",
+                reference
+            );
+        }
+        let value = match (get_env(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => default.to_string(),
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => bail!(
+                "environment variable `{}` is not set, set it or give a default with `${{{}:-...}}`",
+                name,
+                name
+            ),
+        };
+        result.push_str(&value);
+        rest = &rest[start + len + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Deep-merge `overlay` into `base`, merging nested tables and replacing other values.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Edit distance between two strings, counting single character insertions, deletions and
+/// substitutions.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The candidate closest to `word`, if close enough to likely be what was meant.
+fn closest_match<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let max_distance = (word.chars().count() / 3).max(2);
+    candidates
+        .iter()
+        .map(|candidate| (levenshtein(word, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Line number of the first line at or after byte `offset` that assigns `key`, if any.
+fn find_key_line(raw_config: &str, offset: usize, key: &str) -> Option<usize> {
+    let start_line = raw_config[..offset].matches('\n').count() + 1;
+    raw_config[offset..]
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with(['=', '.']))
+        })
+        .map(|index| start_line + index)
+}
+
+/// Rewrite a TOML deserialization error as `line <n>: <message>`, naming the offending key and
+/// suggesting the closest valid key or value for unknown ones.
+///
+/// Relies on serde's messages, e.g. "unknown field `prot`, expected one of `port`, `socks_port`".
+fn describe_toml_error(raw_config: &str, error: &toml::de::Error) -> String {
+    let message = error.message().trim();
+    let Some(span) = error.span() else {
+        return message.to_string();
+    };
+    let line_number = raw_config[..span.start].matches('\n').count() + 1;
+
+    let unknown = ["unknown field ", "unknown variant "]
+        .iter()
+        .find_map(|prefix| message.strip_prefix(prefix));
+    let Some((word, expected)) = unknown.and_then(|rest| rest.split_once(", expected ")) else {
+        // Name the key of the offending value, e.g. for type errors
+        let line = raw_config.lines().nth(line_number - 1).unwrap_or_default();
+        return match line.split_once('=') {
+            Some((key, _)) => format!("line {}: `{}`: {}", line_number, key.trim(), message),
+            None => format!("line {}: {}", line_number, message),
+        };
+    };
+
+    let word = word.trim_matches('`');
+    let candidates: Vec<&str> = expected.split('`').skip(1).step_by(2).collect();
+    let (kind, line_number) = if message.starts_with("unknown field") {
+        // Unknown keys are reported at the enclosing table, find the key itself
+        let key_line = find_key_line(raw_config, span.start, word);
+        ("key", key_line.unwrap_or(line_number))
+    } else {
+        ("value", line_number)
+    };
+    match closest_match(word, &candidates) {
+        Some(suggestion) => format!(
+            "line {}: unknown {} `{}`, did you mean `{}`?",
+            line_number, kind, word, suggestion
+        ),
+        None => format!(
+            "line {}: unknown {} `{}`, expected {}",
+            line_number, kind, word, expected
+        ),
+    }
+}
+
+/// Check `url` is an absolute http(s) url.
+pub fn validate_url(field: &str, url: &str) -> Result<()> {
+    match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        Ok(_) => bail!("`{}` must be an http(s) url, got `{}`", field, url),
+        Err(e) => bail!("`{}` is not a valid url ({}), got `{}`", field, e, url),
     }
 }
 
@@ -161,26 +1025,49 @@ impl Config {
 ///
 /// * If config file does not exist, creates default config file to path and returns error.
 /// * If found, tries to parse the file and returns error if parse fails or fields found undefined.
+///
+/// Errors are `MihoroError::Config`.
 pub fn parse_config(path: &str) -> Result<Config> {
+    read_config(path).map_err(|e| MihoroError::Config(format!("{:#}", e)).into())
+}
+
+fn read_config(path: &str) -> Result<Config> {
     // Create mihoro default config if not exists
     let config_path = Path::new(path);
     create_parent_dir(config_path)?;
 
     if !config_path.exists() {
-        Config::new().write(config_path)?;
+        Config::write_default(config_path)?;
         bail!(
-            "created default config at `{path}`, run again to finish setup",
+            "created default config at `{path}`, fill in `remote_config_url` (e.g. with `mihoro edit`) and run again",
             path = path.underline()
         );
     }
 
     // Parse config file
     let config = Config::setup_from(path)?;
+
+    // Validate profiles, `active_profile` must name a defined profile
+    for (name, profile) in &config.profiles {
+        if profile.remote_config_url.is_empty() {
+            bail!("`profiles.{}.remote_config_url` undefined", name)
+        }
+    }
+    if let Some(name) = &config.active_profile {
+        if !config.profiles.contains_key(name) {
+            bail!("`active_profile` `{}` not found in `[profiles]`", name)
+        }
+    }
+
     let required_urls = [
-        ("remote_config_url", &config.remote_config_url),
+        (
+            "remote_config_url",
+            &config.active_remote_config_url().to_string(),
+        ),
         ("mihomo_binary_path", &config.mihomo_binary_path),
         ("mihomo_config_root", &config.mihomo_config_root),
         ("user_systemd_root", &config.user_systemd_root),
+        ("service_name", &config.service_name),
     ];
 
     // Validate if urls are defined
@@ -190,9 +1077,101 @@ pub fn parse_config(path: &str) -> Result<Config> {
         }
     }
 
+    // Validate urls are well-formed
+    let urls = [
+        ("remote_config_url", Some(&config.remote_config_url)),
+        (
+            "remote_mihomo_binary_url",
+            config.remote_mihomo_binary_url.as_ref(),
+        ),
+        ("geodata.geoip", config.geodata.geoip.as_ref()),
+        ("geodata.geosite", config.geodata.geosite.as_ref()),
+        ("geodata.mmdb", config.geodata.mmdb.as_ref()),
+        ("geodata.asn", config.geodata.asn.as_ref()),
+    ];
+    for (field, url) in urls {
+        if let Some(url) = url.filter(|url| !url.is_empty()) {
+            validate_url(field, url)?;
+        }
+    }
+    for (name, profile) in &config.profiles {
+        validate_url(
+            &format!("profiles.{}.remote_config_url", name),
+            &profile.remote_config_url,
+        )?;
+    }
+
+    // Validate service name is a systemd service unit
+    if !config.service_name.ends_with(".service") || config.service_name == ".service" {
+        bail!(
+            "`service_name` must end with `.service`, got `{}`",
+            config.service_name
+        )
+    }
+
+    if config.network_timeout_secs == 0 {
+        bail!("`network_timeout_secs` must be greater than 0")
+    }
+
+    if let Some(schedule) = &config.cron_schedule {
+        validate_schedule(schedule).map_err(|e| anyhow!("`cron_schedule`: {}", e))?;
+    }
+
     Ok(config)
 }
 
+/// Write the default config template to `path`, refusing to overwrite an existing config unless
+/// `force` is set. A dry run only prints where it would be written.
+pub fn init_config(path: &Path, force: bool, dry_run: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!(
+            "`{}` already exists, pass --force to overwrite it",
+            path.to_string_lossy()
+        );
+    }
+    if dry_run {
+        println!(
+            "{} would: write default config to {}",
+            "mihoro:".cyan(),
+            path.to_string_lossy()
+        );
+        return Ok(());
+    }
+    Config::write_default(path)
+}
+
+/// Set a top-level string `key` in the mihoro config at `path`, preserving its formatting and
+/// comments.
+fn set_config_string(path: &str, key: &str, value: &str) -> Result<()> {
+    let raw_config = fs::read_to_string(path)?;
+    let mut document: DocumentMut = raw_config.parse()?;
+    document[key] = toml_edit::value(value);
+    atomic_write(Path::new(path), document.to_string())?;
+    Ok(())
+}
+
+/// Set `active_profile` in the mihoro config at `path`.
+pub fn set_active_profile(path: &str, name: &str) -> Result<()> {
+    set_config_string(path, "active_profile", name)
+}
+
+/// Set `mihomo_channel` in the mihoro config at `path`.
+pub fn set_mihomo_channel(path: &str, channel: &str) -> Result<()> {
+    set_config_string(path, "mihomo_channel", channel)
+}
+
+/// Set `github_mirror` in the mihoro config at `path`, or remove it to download from GitHub
+/// directly.
+pub fn set_github_mirror(path: &str, mirror: Option<&str>) -> Result<()> {
+    let Some(mirror) = mirror else {
+        let raw_config = fs::read_to_string(path)?;
+        let mut document: DocumentMut = raw_config.parse()?;
+        document.remove("github_mirror");
+        return atomic_write(Path::new(path), document.to_string());
+    };
+    set_config_string(path, "github_mirror", mirror)
+}
+
 /// `mihomoYamlConfig` is defined to support serde serialization and deserialization of arbitrary
 /// mihomo `config.yaml`, with support for fields defined in `mihomoConfig` for overrides and also
 /// extra fields that are not managed by `mihoro` by design (namely `proxies`, `proxy-groups`,
@@ -252,7 +1231,105 @@ pub struct MihomoYamlConfig {
     extra: HashMap<String, serde_yaml::Value>,
 }
 
-/// Apply config overrides to mihomo's `config.yaml`.
+/// Proxy ports declared in mihomo's live `config.yaml`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct MihomoPorts {
+    pub port: Option<u16>,
+
+    #[serde(rename = "socks-port")]
+    pub socks_port: Option<u16>,
+
+    #[serde(rename = "mixed-port")]
+    pub mixed_port: Option<u16>,
+}
+
+impl MihomoPorts {
+    /// Resolve the `(http, socks)` ports to export, falling back to `mihoro.toml` values for
+    /// ports the YAML does not declare.
+    ///
+    /// Within each source, `mixed-port` takes precedence over separate `port` and `socks-port`.
+    pub fn resolve(&self, fallback: &MihomoConfig) -> (u16, u16) {
+        let http_port = self
+            .mixed_port
+            .or(self.port)
+            .or(fallback.mixed_port)
+            .unwrap_or(fallback.port);
+        let socks_port = self
+            .mixed_port
+            .or(self.socks_port)
+            .or(fallback.mixed_port)
+            .unwrap_or(fallback.socks_port);
+        (http_port, socks_port)
+    }
+}
+
+/// Read proxy ports from mihomo's `config.yaml`.
+pub fn read_mihomo_ports(path: &str) -> Result<MihomoPorts> {
+    let raw_mihomo_yaml = fs::read_to_string(path)?;
+    let ports: MihomoPorts = serde_yaml::from_str(&raw_mihomo_yaml)?;
+    Ok(ports)
+}
+
+/// LAN access settings declared in mihomo's `config.yaml`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct MihomoLan {
+    #[serde(rename = "allow-lan")]
+    pub allow_lan: Option<bool>,
+    #[serde(rename = "bind-address")]
+    pub bind_address: Option<String>,
+}
+
+/// Parse the LAN access settings from the contents of mihomo's `config.yaml`.
+pub fn parse_mihomo_lan(raw_mihomo_yaml: &str) -> Result<MihomoLan> {
+    Ok(serde_yaml::from_str(raw_mihomo_yaml)?)
+}
+
+/// Read `allow-lan` from mihomo's `config.yaml`, `None` if it is not declared.
+pub fn read_mihomo_allow_lan(path: &str) -> Result<Option<bool>> {
+    Ok(parse_mihomo_lan(&fs::read_to_string(path)?)?.allow_lan)
+}
+
+/// External controller (RESTful API) settings declared in mihomo's live `config.yaml`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct MihomoController {
+    #[serde(rename = "external-controller")]
+    pub external_controller: Option<String>,
+
+    pub secret: Option<String>,
+}
+
+impl MihomoController {
+    /// Split `external-controller`, e.g. `127.0.0.1:9090`, into a host and port reachable from
+    /// this machine, or `None` if the controller is disabled.
+    ///
+    /// Unspecified addresses (`0.0.0.0:9090`, `[::]:9090` or `:9090`) listen on all interfaces, so
+    /// they are reached through `127.0.0.1`.
+    pub fn host_port(&self) -> Result<Option<(String, u16)>> {
+        let Some(address) = self.external_controller.as_deref() else {
+            return Ok(None);
+        };
+        let invalid = || anyhow!("invalid `external-controller` address `{}`", address);
+
+        let (host, port) = address.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let port: u16 = port.parse().map_err(|_| invalid())?;
+        let host = match host.trim_start_matches('[').trim_end_matches(']') {
+            "" | "0.0.0.0" | "::" => "127.0.0.1".to_string(),
+            host if host.contains(':') => format!("[{}]", host),
+            host => host.to_string(),
+        };
+        Ok(Some((host, port)))
+    }
+}
+
+/// Read external controller settings from mihomo's `config.yaml`.
+pub fn read_mihomo_controller(path: &str) -> Result<MihomoController> {
+    let raw_mihomo_yaml = fs::read_to_string(path)
+        .with_context(|| format!("failed to read `{}`, run `mihoro setup` first", path))?;
+    let controller: MihomoController = serde_yaml::from_str(&raw_mihomo_yaml)?;
+    Ok(controller)
+}
+
+/// Apply config overrides to the contents of mihomo's `config.yaml`, returning the result.
 ///
 /// Only a subset of mihomo's config fields are supported, as defined in `mihomoConfig`.
 ///
@@ -260,9 +1337,11 @@ pub struct MihomoYamlConfig {
 /// * Fields defined in `mihoro.toml` will override the downloaded remote `config.yaml`.
 /// * Fields undefined will be removed from the downloaded `config.yaml`.
 /// * Fields not supported by `mihoro` will be kept as is.
-pub fn apply_mihomo_override(path: &str, override_config: &MihomoConfig) -> Result<()> {
-    let raw_mihomo_yaml = fs::read_to_string(path)?;
-    let mut mihomo_yaml: MihomoYamlConfig = serde_yaml::from_str(&raw_mihomo_yaml)?;
+pub fn override_mihomo_yaml(
+    raw_mihomo_yaml: &str,
+    override_config: &MihomoConfig,
+) -> Result<String> {
+    let mut mihomo_yaml: MihomoYamlConfig = serde_yaml::from_str(raw_mihomo_yaml)?;
 
     // Apply config overrides
     mihomo_yaml.port = Some(override_config.port);
@@ -281,10 +1360,7 @@ pub fn apply_mihomo_override(path: &str, override_config: &MihomoConfig) -> Resu
     mihomo_yaml.geo_update_interval = override_config.geo_update_interval;
     mihomo_yaml.geox_url = override_config.geox_url.clone();
 
-    // Write to file
-    let serialized_mihomo_yaml = serde_yaml::to_string(&mihomo_yaml)?;
-    fs::write(path, serialized_mihomo_yaml)?;
-    Ok(())
+    Ok(serde_yaml::to_string(&mihomo_yaml)?)
 }
 
 #[cfg(test)]
@@ -305,14 +1381,328 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_default_config_template_matches_defaults() -> Result<()> {
+        let config: Config = toml::from_str(DEFAULT_CONFIG_TEMPLATE)?;
+        assert_eq!(
+            toml::to_string(&config)?,
+            toml::to_string(&Config::default())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_config() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("nested/mihoro.toml");
+
+        init_config(&config_path, false, false)?;
+        assert_eq!(fs::read_to_string(&config_path)?, DEFAULT_CONFIG_TEMPLATE);
+
+        // Existing configs are only overwritten with `force`
+        fs::write(&config_path, "remote_config_url = \"http://example.com\"")?;
+        let err = init_config(&config_path, false, false).unwrap_err();
+        assert!(err.to_string().contains("--force"));
+        assert!(fs::read_to_string(&config_path)?.contains("example.com"));
+
+        init_config(&config_path, true, false)?;
+        assert_eq!(fs::read_to_string(&config_path)?, DEFAULT_CONFIG_TEMPLATE);
+        Ok(())
+    }
+
+    /// Test that a dry-run init writes nothing, with or without `force`
+    #[test]
+    fn test_init_config_dry_run() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("nested/mihoro.toml");
+
+        init_config(&config_path, false, true)?;
+        init_config(&config_path, true, true)?;
+        assert!(!config_path.exists());
+
+        fs::create_dir_all(config_path.parent().unwrap())?;
+        fs::write(&config_path, "remote_config_url = \"http://example.com\"")?;
+        assert!(init_config(&config_path, false, true).is_err());
+        init_config(&config_path, true, true)?;
+        assert_eq!(
+            fs::read_to_string(&config_path)?,
+            "remote_config_url = \"http://example.com\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_substitute_env_str() -> Result<()> {
+        let get_env = |var: &str| match var {
+            "MIHORO_SUB" => Some("https://example.com/sub?token=abc".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let substitute = |raw| substitute_env_str(raw, &get_env);
+
+        assert_eq!(
+            substitute("${MIHORO_SUB}")?,
+            "https://example.com/sub?token=abc"
+        );
+        assert_eq!(substitute("a-${EMPTY}-b")?, "a--b");
+        assert_eq!(
+            substitute("no references, $HOME kept")?,
+            "no references, $HOME kept"
+        );
+
+        // Defaults apply to unset or empty variables
+        assert_eq!(
+            substitute("${UNSET:-~/.config}/mihomo")?,
+            "~/.config/mihomo"
+        );
+        assert_eq!(substitute("${EMPTY:-fallback}")?, "fallback");
+        assert_eq!(
+            substitute("${MIHORO_SUB:-fallback}")?,
+            "https://example.com/sub?token=abc"
+        );
+
+        // `$${` escapes a literal `${`
+        assert_eq!(substitute("$${MIHORO_SUB}")?, "${MIHORO_SUB}");
+
+        let err = substitute("${UNSET}").unwrap_err().to_string();
+        assert!(
+            err.contains("environment variable `UNSET` is not set"),
+            "{}",
+            err
+        );
+        assert!(substitute("${1BAD}").is_err());
+        assert!(substitute("${UNTERMINATED").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_env_substitution() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro.toml");
+        fs::write(
+            &path,
+            r#"
+            remote_config_url = "${MIHORO_TEST_SUB_9F2C}"
+            [profiles.work]
+            remote_config_url = "${MIHORO_TEST_WORK_9F2C:-https://example.com/work.yaml}"
+            "#,
+        )?;
+
+        let err = Config::setup_from(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "in `remote_config_url`: environment variable `MIHORO_TEST_SUB_9F2C` is not set, set \
+             it or give a default with `${MIHORO_TEST_SUB_9F2C:-...}`"
+        );
+
+        let mut value: toml::Value = toml::from_str(&fs::read_to_string(&path)?)?;
+        substitute_env(&mut value, "", &|var| {
+            (var == "MIHORO_TEST_SUB_9F2C").then(|| "https://example.com/sub.yaml".to_string())
+        })?;
+        let config: Config = value.try_into()?;
+        assert_eq!(config.remote_config_url, "https://example.com/sub.yaml");
+        assert_eq!(
+            config.profiles["work"].remote_config_url,
+            "https://example.com/work.yaml"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("port", "port"), 0);
+        assert_eq!(levenshtein("prot", "port"), 2);
+        assert_eq!(levenshtein("remote_config_ur", "remote_config_url"), 1);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(
+            closest_match("stabel", &["stable", "alpha"]),
+            Some("stable")
+        );
+        assert_eq!(
+            closest_match("systemd", &["crontab", "systemd-timer"]),
+            None
+        );
+    }
+
+    /// Parse a config, returning the error message
+    fn parse_error(dir: &Path, toml_content: &str) -> String {
+        let config_path = dir.join("mihoro.toml");
+        fs::write(&config_path, toml_content).unwrap();
+        parse_config(config_path.to_str().unwrap())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn test_parse_config_suggests_keys_and_values() -> Result<()> {
+        let dir = tempdir()?;
+
+        let err = parse_error(dir.path(), "remote_config_ur = \"http://example.com\"\n");
+        assert!(err
+            .contains("line 1: unknown key `remote_config_ur`, did you mean `remote_config_url`?"));
+
+        let err = parse_error(
+            dir.path(),
+            r#"
+            remote_config_url = "http://example.com"
+
+            [mihomo_config]
+            port = 7891
+            scoks_port = 7892
+            "#,
+        );
+        assert!(err.contains("line 6: unknown key `scoks_port`, did you mean `socks_port`?"));
+
+        let err = parse_error(
+            dir.path(),
+            "remote_config_url = \"http://example.com\"\nmihomo_channel = \"stabel\"\n",
+        );
+        assert!(err.contains("line 2: unknown value `stabel`, did you mean `stable`?"));
+
+        // Nothing close enough, so all valid keys are listed
+        let err = parse_error(
+            dir.path(),
+            "remote_config_url = \"http://example.com\"\nfoo = 1\n",
+        );
+        assert!(err.contains("unknown key `foo`, expected one of `remote_config_url`"));
+
+        // Wrong types name the key
+        let err = parse_error(
+            dir.path(),
+            "remote_config_url = \"http://example.com\"\ndownload_retries = \"3\"\n",
+        );
+        assert!(err.contains("line 2: `download_retries`: invalid type: string \"3\""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_validates_urls() -> Result<()> {
+        let dir = tempdir()?;
+
+        let err = parse_error(dir.path(), "remote_config_url = \"example.com/sub\"\n");
+        assert!(err.contains("`remote_config_url` is not a valid url"));
+
+        let err = parse_error(
+            dir.path(),
+            "remote_config_url = \"ftp://example.com/sub\"\n",
+        );
+        assert!(err.contains("`remote_config_url` must be an http(s) url"));
+
+        let err = parse_error(
+            dir.path(),
+            r#"
+            remote_config_url = "http://example.com"
+
+            [profiles.work]
+            remote_config_url = "work"
+            "#,
+        );
+        assert!(err.contains("`profiles.work.remote_config_url` is not a valid url"));
+
+        // Capitalized enum values are still accepted
+        let config_path = dir.path().join("mihoro.toml");
+        fs::write(
+            &config_path,
+            "remote_config_url = \"https://example.com\"\nmihomo_channel = \"Alpha\"\n",
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.mihomo_channel, MihomoChannel::Alpha);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_include_precedence() -> Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir(dir.path().join("hosts"))?;
+        fs::write(
+            dir.path().join("base.toml"),
+            r#"
+            remote_config_url = "http://example.com/base.yaml"
+            mihomo_arch = "amd64"
+            download_retries = 5
+
+            [mihomo_config]
+            port = 1000
+            log_level = "debug"
+            "#,
+        )?;
+        // Relative includes resolve from the including file's directory
+        fs::write(
+            dir.path().join("hosts/pi.toml"),
+            r#"
+            include = ["../base.toml"]
+            mihomo_arch = "arm64"
+
+            [mihomo_config]
+            port = 2000
+            "#,
+        )?;
+        let config_path = dir.path().join("mihoro.toml");
+        fs::write(
+            &config_path,
+            r#"
+            include = ["base.toml", "hosts/pi.toml"]
+            download_retries = 1
+
+            [mihomo_config]
+            log_level = "warning"
+            "#,
+        )?;
+
+        let config = Config::setup_from(config_path.to_str().unwrap())?;
+        assert!(config.include.is_empty());
+        assert_eq!(config.remote_config_url, "http://example.com/base.yaml");
+        // Later includes override earlier ones, the main file overrides all, tables merge deeply
+        assert_eq!(config.mihomo_arch.as_deref(), Some("arm64"));
+        assert_eq!(config.download_retries, 1);
+        assert_eq!(config.mihomo_config.port, 2000);
+        assert!(matches!(
+            config.mihomo_config.log_level,
+            MihomoLogLevel::Warning
+        ));
+        assert_eq!(config.mihomo_config.socks_port, 7892);
+
+        // Errors point at the included file
+        fs::write(dir.path().join("base.toml"), "scoks_port = 1")?;
+        let err = format!(
+            "{:#}",
+            Config::setup_from(config_path.to_str().unwrap()).unwrap_err()
+        );
+        assert!(err.contains("base.toml` line 1: unknown key `scoks_port`"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_include_cycle() -> Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.toml");
+        fs::write(&a, "include = [\"b.toml\"]")?;
+        fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]")?;
+        let err = format!("{:#}", Config::setup_from(a.to_str().unwrap()).unwrap_err());
+        assert!(err.contains("include cycle"));
+        assert!(err.contains("a.toml -> "));
+
+        fs::write(&a, "include = [\"a.toml\"]")?;
+        let err = format!("{:#}", Config::setup_from(a.to_str().unwrap()).unwrap_err());
+        assert!(err.contains("include cycle"));
+
+        // Including the same file twice without a cycle is fine
+        fs::write(dir.path().join("b.toml"), "download_retries = 2")?;
+        fs::write(&a, "include = [\"b.toml\", \"b.toml\"]")?;
+        assert_eq!(Config::setup_from(a.to_str().unwrap())?.download_retries, 2);
+        Ok(())
+    }
+
     #[test]
     fn test_config_write_and_read() -> Result<()> {
         let dir = tempdir()?;
         let config_path = dir.path().join("test.toml");
 
-        let mut config = Config::new();
-        config.remote_config_url = "http://example.com/config.yaml".to_string();
-        config.write(&config_path)?;
+        let config = Config {
+            remote_config_url: "http://example.com/config.yaml".to_string(),
+            ..Default::default()
+        };
+        fs::write(&config_path, toml::to_string(&config)?)?;
 
         let read_config = Config::setup_from(config_path.to_str().unwrap())?;
         assert_eq!(
@@ -323,6 +1713,394 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_config_systemd_mode() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            systemd_mode = "system"
+        "#;
+        fs::write(&config_path, toml_content)?;
+
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.systemd_mode, SystemdMode::System);
+        assert_eq!(Config::default().systemd_mode, SystemdMode::User);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_service_name() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        fs::write(
+            &config_path,
+            r#"remote_config_url = "http://example.com/config.yaml""#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.service_name, "mihomo.service");
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            service_name = "mihomo-work"
+            "#,
+        )?;
+        let result = parse_config(config_path.to_str().unwrap());
+        assert!(result.unwrap_err().to_string().contains("service_name"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_cron_schedule() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            cron_schedule = "0 4 * * *"
+            "#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.cron_schedule.as_deref(), Some("0 4 * * *"));
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            cron_schedule = "every day"
+            "#,
+        )?;
+        let result = parse_config(config_path.to_str().unwrap());
+        assert!(result.unwrap_err().to_string().contains("cron_schedule"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_download_ip_version() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            download_ip_version = "ipv4"
+            "#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.download_ip_version, IpVersion::Ipv4);
+        assert_eq!(
+            config.download_ip_version.local_address(),
+            Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        );
+        assert_eq!(Config::default().download_ip_version.local_address(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_reload_method() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            reload_method = "signal"
+            "#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.reload_method, ReloadMethod::Signal);
+        assert_eq!(Config::default().reload_method, ReloadMethod::Api);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_cron_backend() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        fs::write(
+            &config_path,
+            r#"remote_config_url = "http://example.com/config.yaml""#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.cron_backend, CronBackend::Crontab);
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            cron_backend = "systemd-timer"
+            "#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.cron_backend, CronBackend::SystemdTimer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_profiles() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        // Without profiles, the top-level URL is used
+        fs::write(
+            &config_path,
+            r#"remote_config_url = "http://example.com/config.yaml""#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert!(config.current_profile().is_none());
+        assert_eq!(
+            config.active_remote_config_url(),
+            "http://example.com/config.yaml"
+        );
+
+        // The active profile's URL takes precedence, top-level URL may be omitted
+        fs::write(
+            &config_path,
+            r#"
+            active_profile = "work"
+
+            [profiles.home]
+            remote_config_url = "http://home.example.com/config.yaml"
+
+            [profiles.work]
+            remote_config_url = "http://work.example.com/config.yaml"
+            overrides = { mode = "global" }
+            "#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(
+            config.active_remote_config_url(),
+            "http://work.example.com/config.yaml"
+        );
+        assert_eq!(config.current_profile().unwrap().overrides.len(), 1);
+
+        // Unknown active profile
+        fs::write(
+            &config_path,
+            r#"
+            active_profile = "school"
+
+            [profiles.home]
+            remote_config_url = "http://home.example.com/config.yaml"
+            "#,
+        )?;
+        let result = parse_config(config_path.to_str().unwrap());
+        assert!(result.unwrap_err().to_string().contains("school"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_active_profile_preserves_comments() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+        fs::write(
+            &config_path,
+            r#"# my subscriptions
+remote_config_url = "http://example.com/config.yaml"
+
+[profiles.home]
+remote_config_url = "http://home.example.com/config.yaml"
+"#,
+        )?;
+
+        set_active_profile(config_path.to_str().unwrap(), "home")?;
+        let raw_config = fs::read_to_string(&config_path)?;
+        assert!(raw_config.starts_with("# my subscriptions"));
+
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.active_profile.as_deref(), Some("home"));
+        assert_eq!(
+            config.active_remote_config_url(),
+            "http://home.example.com/config.yaml"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_mihomo_channel() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+        fs::write(
+            &config_path,
+            "remote_config_url = \"http://example.com/config.yaml\"\nmihomo_channel = \"alpha\" # nightly\n",
+        )?;
+
+        set_mihomo_channel(config_path.to_str().unwrap(), "stable")?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.mihomo_channel, MihomoChannel::Stable);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_network_timeout() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            network_timeout_secs = 5
+            "#,
+        )?;
+        let config = parse_config(config_path.to_str().unwrap())?;
+        assert_eq!(config.network_timeout(), Duration::from_secs(5));
+        assert_eq!(config.download_timeout(), Duration::from_secs(50));
+
+        fs::write(
+            &config_path,
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            network_timeout_secs = 0
+            "#,
+        )?;
+        let result = parse_config(config_path.to_str().unwrap());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("network_timeout_secs"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_auth_header() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            config_auth = { bearer = "abc" }
+            [profiles.work]
+            remote_config_url = "https://work.example.com/sub"
+            config_auth = { basic = { user = "me", pass = "${MIHORO_TEST_SUB_PASS}" } }
+            "#,
+        )?;
+        let header = config.active_config_auth().unwrap().header()?;
+        assert_eq!(header[AUTHORIZATION], "Bearer abc");
+        assert!(header[AUTHORIZATION].is_sensitive());
+
+        let mut config = config;
+        config.active_profile = Some("work".to_string());
+        let auth = config.active_config_auth().unwrap();
+        assert!(auth
+            .header()
+            .unwrap_err()
+            .to_string()
+            .contains("`MIHORO_TEST_SUB_PASS`"));
+        env::set_var("MIHORO_TEST_SUB_PASS", "pa:ss");
+        // base64 of `me:pa:ss`
+        assert_eq!(auth.header()?[AUTHORIZATION], "Basic bWU6cGE6c3M=");
+        assert_eq!(auth.describe(), "basic auth as `me`");
+        assert!(config.secrets().contains(&"${MIHORO_TEST_SUB_PASS}"));
+        Ok(())
+    }
+
+    /// Test that `${VAR}` substitution on load leaves `config_auth` to be resolved on request
+    #[test]
+    fn test_config_auth_with_env_substitution() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro.toml");
+        fs::write(
+            &path,
+            r#"
+            remote_config_url = "${MIHORO_TEST_SUB_7D1E:-https://example.com/sub.yaml}"
+            config_auth = { bearer = "${MIHORO_TEST_TOKEN_7D1E}" }
+            [profiles.work]
+            remote_config_url = "https://work.example.com/sub"
+            config_auth = { bearer = "${MIHORO_TEST_WORK_TOKEN_7D1E:-t0ken}" }
+            "#,
+        )?;
+
+        // An unset `config_auth` variable doesn't fail loading, only fetching the subscription
+        let mut config = Config::setup_from(path.to_str().unwrap())?;
+        assert_eq!(config.remote_config_url, "https://example.com/sub.yaml");
+        let auth = config.active_config_auth().unwrap();
+        assert_eq!(
+            auth,
+            &ConfigAuth::Bearer("${MIHORO_TEST_TOKEN_7D1E}".to_string())
+        );
+        let err = format!("{:#}", auth.header().unwrap_err());
+        assert!(
+            err.contains("`MIHORO_TEST_TOKEN_7D1E` is not set"),
+            "{}",
+            err
+        );
+
+        // Defaults work in `config_auth` too
+        config.active_profile = Some("work".to_string());
+        let header = config.active_config_auth().unwrap().header()?;
+        assert_eq!(header[AUTHORIZATION], "Bearer t0ken");
+        Ok(())
+    }
+
+    #[test]
+    fn test_geodata_sources() -> Result<()> {
+        let files = |config: &Config| -> Vec<&str> {
+            config
+                .geodata_sources()
+                .iter()
+                .map(|source| source.file)
+                .collect()
+        };
+
+        // Without `[geodata]`, the `geox_url` files of the `geodata_mode` are used
+        let mut config = Config::default();
+        assert_eq!(files(&config), ["country.mmdb"]);
+        config.mihomo_config.geodata_mode = Some(true);
+        assert_eq!(files(&config), ["geoip.dat", "geosite.dat"]);
+
+        let config: Config = toml::from_str(
+            r#"
+            [geodata]
+            geoip = ""
+            mmdb = "https://example.com/country.mmdb"
+            asn = "https://example.com/asn.mmdb"
+            [mihomo_config]
+            geodata_mode = true
+            "#,
+        )?;
+        assert_eq!(
+            config.geodata_sources(),
+            [
+                GeodataSource {
+                    file: "geosite.dat",
+                    url: config.mihomo_config.geox_url.clone().unwrap().geosite,
+                },
+                GeodataSource {
+                    file: "country.mmdb",
+                    url: "https://example.com/country.mmdb".to_string(),
+                },
+                GeodataSource {
+                    file: "ASN.mmdb",
+                    url: "https://example.com/asn.mmdb".to_string(),
+                },
+            ]
+        );
+
+        // A `.metadb` url is saved as geoip.metadb, and `geodata-mode` in overrides wins
+        let mut config = Config::default();
+        config.geodata.mmdb = Some("https://example.com/geoip.metadb".to_string());
+        assert_eq!(files(&config), ["geoip.metadb"]);
+        config.overrides = toml::from_str("geodata-mode = true")?;
+        assert_eq!(files(&config), ["geoip.dat", "geosite.dat", "geoip.metadb"]);
+        Ok(())
+    }
+
     #[test]
     fn test_parse_config_validates_required_fields() -> Result<()> {
         let dir = tempdir()?;
@@ -346,10 +2124,107 @@ mod tests {
     }
 
     #[test]
-    fn test_apply_mihomo_override() -> Result<()> {
+    fn test_read_mihomo_ports() -> Result<()> {
         let dir = tempdir()?;
         let yaml_path = dir.path().join("config.yaml");
+        fs::write(&yaml_path, "port: 8080\nsocks-port: 8081\nmode: rule\n")?;
+
+        let ports = read_mihomo_ports(yaml_path.to_str().unwrap())?;
+        assert_eq!(
+            ports,
+            MihomoPorts {
+                port: Some(8080),
+                socks_port: Some(8081),
+                mixed_port: None,
+            }
+        );
 
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_mihomo_controller() -> Result<()> {
+        let dir = tempdir()?;
+        let yaml_path = dir.path().join("config.yaml");
+        fs::write(
+            &yaml_path,
+            "external-controller: 0.0.0.0:9090\nsecret: s3cret\nport: 7890\n",
+        )?;
+
+        let controller = read_mihomo_controller(yaml_path.to_str().unwrap())?;
+        assert_eq!(
+            controller,
+            MihomoController {
+                external_controller: Some("0.0.0.0:9090".to_string()),
+                secret: Some("s3cret".to_string()),
+            }
+        );
+
+        fs::write(&yaml_path, "port: 7890\n")?;
+        let controller = read_mihomo_controller(yaml_path.to_str().unwrap())?;
+        assert_eq!(controller, MihomoController::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mihomo_controller_host_port() -> Result<()> {
+        let host_port = |address: &str| {
+            MihomoController {
+                external_controller: Some(address.to_string()),
+                secret: None,
+            }
+            .host_port()
+        };
+
+        let local = |port| Some(("127.0.0.1".to_string(), port));
+        assert_eq!(host_port("127.0.0.1:9090")?, local(9090));
+        assert_eq!(host_port("0.0.0.0:9090")?, local(9090));
+        assert_eq!(host_port(":9097")?, local(9097));
+        assert_eq!(host_port("[::]:9090")?, local(9090));
+        assert_eq!(host_port("[::1]:9090")?, Some(("[::1]".to_string(), 9090)));
+        assert_eq!(
+            host_port("192.168.1.2:9090")?,
+            Some(("192.168.1.2".to_string(), 9090))
+        );
+        assert!(host_port("9090").is_err());
+        assert!(host_port("127.0.0.1:http").is_err());
+        assert_eq!(MihomoController::default().host_port()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mihomo_ports_resolve() {
+        let fallback = MihomoConfig::default();
+
+        // mixed-port in YAML is used for both
+        let ports = MihomoPorts {
+            port: Some(8080),
+            socks_port: Some(8081),
+            mixed_port: Some(8888),
+        };
+        assert_eq!(ports.resolve(&fallback), (8888, 8888));
+
+        // Separate port and socks-port in YAML without mixed-port
+        let ports = MihomoPorts {
+            port: Some(8080),
+            socks_port: Some(8081),
+            mixed_port: None,
+        };
+        assert_eq!(ports.resolve(&fallback), (8080, 8081));
+
+        // Nothing in YAML falls back to mihoro.toml mixed_port
+        assert_eq!(MihomoPorts::default().resolve(&fallback), (7890, 7890));
+
+        // Nothing in YAML and no mixed_port falls back to mihoro.toml port/socks_port
+        let fallback = MihomoConfig {
+            mixed_port: None,
+            ..Default::default()
+        };
+        assert_eq!(MihomoPorts::default().resolve(&fallback), (7891, 7892));
+    }
+
+    #[test]
+    fn test_override_mihomo_yaml() -> Result<()> {
         let yaml_content = r#"
             port: 8080
             socks-port: 8081
@@ -363,17 +2238,13 @@ mod tests {
                 server: example.com
                 port: 443
         "#;
-        fs::write(&yaml_path, yaml_content)?;
-
         let override_config = MihomoConfig {
             port: 7891,
             socks_port: 7892,
             ..Default::default()
         };
 
-        apply_mihomo_override(yaml_path.to_str().unwrap(), &override_config)?;
-
-        let updated_content = fs::read_to_string(&yaml_path)?;
+        let updated_content = override_mihomo_yaml(yaml_content, &override_config)?;
         assert!(updated_content.contains("port: 7891"));
         assert!(updated_content.contains("socks-port: 7892"));
         assert!(updated_content.contains("proxies:"));
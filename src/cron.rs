@@ -1,4 +1,9 @@
-use anyhow::{anyhow, Result};
+use crate::cmd::{CronInterval, CronScope};
+use crate::log::info;
+use crate::systemctl::Systemctl;
+use crate::utils::create_parent_dir;
+
+use anyhow::{anyhow, bail, Context, Result};
 use colored::Colorize;
 use std::env;
 use std::fs;
@@ -7,6 +12,11 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 
+/// Systemd service unit running `mihoro update`, triggered by [`TIMER_UNIT`].
+const SERVICE_UNIT: &str = "mihoro-update.service";
+/// Systemd timer unit scheduling auto-updates with the `systemd-timer` backend.
+const TIMER_UNIT: &str = "mihoro-update.timer";
+
 /// Get the path to the user's crontab file
 fn crontab_path() -> PathBuf {
     let run_dir = env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| {
@@ -25,36 +35,271 @@ fn mihoro_bin_path() -> Result<String> {
         .ok_or_else(|| anyhow!("Failed to get mihoro binary path"))
 }
 
+/// Cron `@` shorthands accepted in place of the five schedule fields.
+const CRON_MACROS: [&str; 7] = [
+    "@hourly",
+    "@daily",
+    "@midnight",
+    "@weekly",
+    "@monthly",
+    "@yearly",
+    "@annually",
+];
+
+/// Allowed value ranges of the five cron fields: minute, hour, day of month, month, day of week.
+const CRON_FIELDS: [(&str, u32, u32); 5] = [
+    ("minute", 0, 59),
+    ("hour", 0, 23),
+    ("day of month", 1, 31),
+    ("month", 1, 12),
+    ("day of week", 0, 7),
+];
+
+/// Names and cron schedules of the `--interval` presets. Timers run them at the `OnCalendar` times
+/// `to_on_calendar` translates them to.
+const INTERVAL_PRESETS: [(CronInterval, &str, &str); 4] = [
+    (CronInterval::Hourly, "hourly", "0 * * * *"),
+    (CronInterval::SixHours, "6h", "0 */6 * * *"),
+    (CronInterval::Daily, "daily", "0 4 * * *"),
+    (CronInterval::Weekly, "weekly", "0 4 * * 0"),
+];
+
+impl CronInterval {
+    /// Cron schedule the preset expands to.
+    pub fn schedule(self) -> &'static str {
+        INTERVAL_PRESETS
+            .iter()
+            .find(|(interval, _, _)| *interval == self)
+            .map(|(_, _, schedule)| *schedule)
+            .expect("every interval has a preset")
+    }
+}
+
+/// Name of the `--interval` preset a cron schedule or `OnCalendar` expression was expanded from.
+fn preset_name(schedule: &str) -> Option<&'static str> {
+    INTERVAL_PRESETS
+        .iter()
+        .find(|(_, _, cron)| {
+            *cron == schedule || to_on_calendar(cron).is_ok_and(|calendar| calendar == schedule)
+        })
+        .map(|(_, name, _)| *name)
+}
+
+/// Names and `mihoro update` arguments of each `--scope`, only reloading mihomo for the config if
+/// it changed.
+const SCOPE_ARGS: [(CronScope, &str, &str); 4] = [
+    (CronScope::Config, "config", "--config --if-changed"),
+    (CronScope::Geodata, "geodata", "--geodata"),
+    (CronScope::Core, "core", "--core"),
+    (CronScope::All, "all", "--all"),
+];
+
+impl CronScope {
+    /// Arguments of `mihoro update` updating the scope.
+    fn update_args(self) -> &'static str {
+        SCOPE_ARGS
+            .iter()
+            .find(|(scope, _, _)| *scope == self)
+            .map(|(_, _, args)| *args)
+            .expect("every scope has update arguments")
+    }
+
+    pub fn name(self) -> &'static str {
+        SCOPE_ARGS
+            .iter()
+            .find(|(scope, _, _)| *scope == self)
+            .map(|(_, name, _)| *name)
+            .expect("every scope has a name")
+    }
+}
+
+/// Scope of the `mihoro update` command in a cron entry or `ExecStart=` line. Entries without a
+/// component flag, as installed before `--scope`, update the config.
+fn parse_scope(command: &str) -> CronScope {
+    let args: Vec<&str> = command
+        .split_whitespace()
+        .skip_while(|arg| *arg != "update")
+        .collect();
+    [CronScope::All, CronScope::Core, CronScope::Geodata]
+        .into_iter()
+        .find(|scope| args.contains(&format!("--{}", scope.name()).as_str()))
+        .unwrap_or(CronScope::Config)
+}
+
+/// Cron schedule equivalent to running every `interval_hours` hours.
+pub fn interval_schedule(interval_hours: u16) -> String {
+    format!("0 */{} * * *", interval_hours)
+}
+
+/// Validate a cron expression, either five space-separated fields or an `@` shorthand.
+///
+/// Each field may be `*`, a number, a range `a-b` or a comma-separated list of those, each
+/// optionally followed by a `/step`.
+pub fn validate_schedule(schedule: &str) -> Result<()> {
+    let schedule = schedule.trim();
+    if schedule.starts_with('@') {
+        if CRON_MACROS.contains(&schedule) {
+            return Ok(());
+        }
+        bail!("unknown cron shorthand `{}`", schedule);
+    }
+
+    let fields: Vec<&str> = schedule.split_whitespace().collect();
+    if fields.len() != CRON_FIELDS.len() {
+        bail!(
+            "cron schedule `{}` must have 5 fields (minute hour day month weekday), got {}",
+            schedule,
+            fields.len()
+        );
+    }
+
+    for (field, (name, min, max)) in fields.iter().zip(CRON_FIELDS) {
+        validate_field(field, min, max).map_err(|e| {
+            anyhow!(
+                "invalid {} field `{}` in `{}`: {}",
+                name,
+                field,
+                schedule,
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Validate a single cron field against its allowed range.
+fn validate_field(field: &str, min: u32, max: u32) -> Result<()> {
+    let parse = |value: &str| -> Result<u32> {
+        let value: u32 = value
+            .parse()
+            .map_err(|_| anyhow!("`{}` is not a number", value))?;
+        if value < min || value > max {
+            bail!("`{}` is out of range {}-{}", value, min, max);
+        }
+        Ok(value)
+    };
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+
+        if let Some(step) = step {
+            match step.parse::<u32>() {
+                Ok(step) if step > 0 => {}
+                _ => bail!("step `{}` must be a positive number", step),
+            }
+        }
+
+        if range == "*" {
+            continue;
+        }
+        match range.split_once('-') {
+            Some((start, end)) => {
+                if parse(start)? > parse(end)? {
+                    bail!("range `{}` is reversed", range);
+                }
+            }
+            None => {
+                parse(range)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extract the schedule from an installed cron entry, i.e. everything before the command.
+fn parse_schedule(entry: &str) -> Option<String> {
+    let entry = entry.trim();
+    if entry.starts_with('@') {
+        return entry.split_whitespace().next().map(String::from);
+    }
+
+    let fields: Vec<&str> = entry.split_whitespace().take(CRON_FIELDS.len()).collect();
+    (fields.len() == CRON_FIELDS.len()).then(|| fields.join(" "))
+}
+
+/// Describe common cron schedules in plain words, e.g. `0 */6 * * *` as "every 6 hours".
+fn describe_schedule(schedule: &str) -> Option<String> {
+    let description = match schedule {
+        "@hourly" => String::from("every hour"),
+        "@daily" | "@midnight" => String::from("daily at 00:00"),
+        "@weekly" => String::from("weekly on Sunday at 00:00"),
+        "@monthly" => String::from("monthly on day 1 at 00:00"),
+        "@yearly" | "@annually" => String::from("yearly on January 1 at 00:00"),
+        _ => {
+            let fields: Vec<&str> = schedule.split_whitespace().collect();
+            let [minute, hour, "*", "*", "*"] = fields[..] else {
+                return None;
+            };
+            let minute: u32 = minute.parse().ok()?;
+            match hour {
+                "*" => format!("every hour at minute {}", minute),
+                "*/1" if minute == 0 => String::from("every hour"),
+                _ => match hour.strip_prefix("*/") {
+                    Some(step) if minute == 0 => {
+                        format!("every {} hours", step.parse::<u32>().ok()?)
+                    }
+                    Some(_) => return None,
+                    None => format!("daily at {:02}:{:02}", hour.parse::<u32>().ok()?, minute),
+                },
+            }
+        }
+    };
+    Some(description)
+}
+
 /// Generate cron entry for auto-update
-fn generate_cron_entry(interval_hours: u16) -> Result<String> {
+fn generate_cron_entry(schedule: &str, scope: CronScope) -> Result<String> {
     let bin_path = mihoro_bin_path()?;
     Ok(format!(
-        "0 */{} * * * {} update\n",
-        interval_hours, bin_path
+        "{} {} update {}\n",
+        schedule,
+        bin_path,
+        scope.update_args()
     ))
 }
 
 /// Generate the crontab content with mihoro entry
-fn generate_crontab(interval_hours: u16) -> Result<String> {
-    let mihoro_entry = generate_cron_entry(interval_hours)?;
+fn generate_crontab(schedule: &str, scope: CronScope) -> Result<String> {
+    let mihoro_entry = generate_cron_entry(schedule, scope)?;
     Ok(mihoro_entry)
 }
 
+/// Resolve the auto-update schedule, returning `None` if auto-update is disabled.
+///
+/// An explicit `schedule` (from `--schedule` or `cron_schedule`) takes precedence over
+/// `interval_hours`.
+fn resolve_schedule(schedule: Option<&str>, interval_hours: u16) -> Result<Option<String>> {
+    if let Some(schedule) = schedule {
+        validate_schedule(schedule)?;
+        return Ok(Some(schedule.trim().to_string()));
+    }
+
+    match interval_hours {
+        0 => Ok(None),
+        1..=24 => Ok(Some(interval_schedule(interval_hours))),
+        _ => bail!("Auto-update interval must be between 1 and 24 hours"),
+    }
+}
+
 /// Enable auto-update by installing cron job
-pub fn enable_auto_update(interval_hours: u16, prefix: &str) -> Result<()> {
-    if interval_hours == 0 {
-        println!(
+pub fn enable_auto_update(
+    schedule: Option<&str>,
+    interval_hours: u16,
+    scope: CronScope,
+    prefix: &str,
+) -> Result<()> {
+    let Some(schedule) = resolve_schedule(schedule, interval_hours)? else {
+        info!(
             "{} Auto-update interval is 0, disabling auto-update",
             prefix.yellow()
         );
         return disable_auto_update(prefix);
-    }
-
-    if interval_hours > 24 {
-        anyhow::bail!("Auto-update interval must be between 1 and 24 hours");
-    }
+    };
 
-    let crontab_content = generate_crontab(interval_hours)?;
+    let crontab_content = generate_crontab(&schedule, scope)?;
     let crontab_file = crontab_path();
 
     // Write crontab to runtime directory for reference
@@ -69,15 +314,15 @@ pub fn enable_auto_update(interval_hours: u16, prefix: &str) -> Result<()> {
         anyhow::bail!("Failed to install crontab");
     }
 
-    println!(
-        "{} Auto-update enabled with interval: {} hours",
+    info!(
+        "{} Auto-update enabled with schedule: {}",
         prefix.green().bold(),
-        interval_hours.to_string().yellow()
+        schedule_label(&schedule).yellow()
     );
-    println!(
+    info!(
         "{} Cron entry: {}",
         "->".dimmed(),
-        generate_cron_entry(interval_hours)?.trim()
+        generate_cron_entry(&schedule, scope)?.trim()
     );
 
     Ok(())
@@ -97,12 +342,12 @@ pub fn disable_auto_update(prefix: &str) -> Result<()> {
 
     match status {
         Ok(status) if status.success() => {
-            println!("{} Auto-update disabled", prefix.green().bold());
+            info!("{} Auto-update disabled", prefix.green().bold());
             Ok(())
         }
         Ok(_) => {
             // crontab -r returns non-zero if no crontab exists, which is fine
-            println!(
+            info!(
                 "{} Auto-update disabled (no active cron job)",
                 prefix.yellow()
             );
@@ -128,6 +373,16 @@ fn format_datetime(secs: u64) -> String {
     }
 }
 
+/// Name of the `--interval` preset the schedule matches, e.g. `daily (0 4 * * *)`, or else the
+/// schedule followed by its plain description when known, e.g. `0 4 * * 1-5`.
+fn schedule_label(schedule: &str) -> String {
+    match (preset_name(schedule), describe_schedule(schedule)) {
+        (Some(name), _) => format!("{} ({})", name, schedule),
+        (None, Some(description)) => format!("{} ({})", schedule, description),
+        (None, None) => schedule.to_string(),
+    }
+}
+
 /// Get current cron status
 pub fn get_cron_status(_prefix: &str, mihomo_config_path: &str) -> Result<()> {
     let crontab_file = crontab_path();
@@ -141,9 +396,26 @@ pub fn get_cron_status(_prefix: &str, mihomo_config_path: &str) -> Result<()> {
     let cron_entry = content.lines().next().unwrap_or("");
 
     println!("{} Auto-update is enabled", "status:".green().bold());
+    if let Some(schedule) = parse_schedule(cron_entry) {
+        println!(
+            "{} Schedule: {}",
+            "->".dimmed(),
+            schedule_label(&schedule).dimmed()
+        );
+    }
+    println!(
+        "{} Scope: {}",
+        "->".dimmed(),
+        parse_scope(cron_entry).name().dimmed()
+    );
     println!("{} {}", "->".dimmed(), cron_entry.dimmed());
+    print_last_updated(mihomo_config_path);
 
-    // Show last updated time from mihomo config file
+    Ok(())
+}
+
+/// Show last updated time from mihomo config file
+fn print_last_updated(mihomo_config_path: &str) {
     let config_path = Path::new(mihomo_config_path);
     if let Ok(metadata) = fs::metadata(config_path) {
         if let Ok(modified) = metadata.modified() {
@@ -155,6 +427,241 @@ pub fn get_cron_status(_prefix: &str, mihomo_config_path: &str) -> Result<()> {
             }
         }
     }
+}
+
+/// Translate a cron expression into a systemd `OnCalendar` expression.
+///
+/// Cron runs a job when either the day of month or the day of week matches if both are
+/// restricted, which `OnCalendar` cannot express, so such schedules are rejected.
+pub fn to_on_calendar(schedule: &str) -> Result<String> {
+    validate_schedule(schedule)?;
+    let schedule = schedule.trim();
+
+    let calendar = match schedule {
+        "@hourly" => "hourly",
+        "@daily" | "@midnight" => "daily",
+        "@weekly" => "weekly",
+        "@monthly" => "monthly",
+        "@yearly" | "@annually" => "yearly",
+        _ => {
+            let fields: Vec<&str> = schedule.split_whitespace().collect();
+            let [minute, hour, day, month, weekday] = fields[..] else {
+                unreachable!("validated cron schedule has 5 fields");
+            };
+            if day != "*" && weekday != "*" {
+                bail!(
+                    "cron schedule `{}` restricts both day of month and day of week, which a systemd timer cannot express",
+                    schedule
+                );
+            }
+
+            let date = format!(
+                "*-{}-{}",
+                calendar_field(month, 1)?,
+                calendar_field(day, 1)?
+            );
+            let time = format!(
+                "{}:{}:00",
+                calendar_field(hour, 0)?,
+                calendar_field(minute, 0)?
+            );
+            return Ok(match weekday {
+                "*" => format!("{} {}", date, time),
+                _ => format!("{} {} {}", calendar_weekday(weekday)?, date, time),
+            });
+        }
+    };
+    Ok(calendar.to_string())
+}
+
+/// Translate a numeric cron field into `OnCalendar` syntax, where `*/n` starts from `first`.
+fn calendar_field(field: &str, first: u32) -> Result<String> {
+    let pad = |value: &str| match value.parse::<u32>() {
+        Ok(value) => format!("{:02}", value),
+        Err(_) => value.to_string(),
+    };
+
+    let parts = field
+        .split(',')
+        .map(|part| match part.split_once('/') {
+            Some(("*", step)) => Ok(format!("{:02}/{}", first, step)),
+            Some((start, step)) if !start.contains('-') => Ok(format!("{}/{}", pad(start), step)),
+            Some(_) => bail!("stepped range `{}` cannot be used in a systemd timer", part),
+            None => Ok(match part.split_once('-') {
+                Some((start, end)) => format!("{}..{}", pad(start), pad(end)),
+                None => pad(part),
+            }),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join(","))
+}
+
+/// Translate a cron day of week field into `OnCalendar` weekday names.
+fn calendar_weekday(field: &str) -> Result<String> {
+    const WEEKDAYS: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    let name = |day: &str| day.parse::<usize>().map(|day| WEEKDAYS[day]);
+
+    let parts = field
+        .split(',')
+        .map(|part| {
+            if part.contains('/') || part == "*" {
+                bail!("day of week `{}` cannot be used in a systemd timer", part);
+            }
+            Ok(match part.split_once('-') {
+                Some((start, end)) => format!("{}..{}", name(start)?, name(end)?),
+                None => name(part)?.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join(","))
+}
+
+/// Generate the `mihoro-update.service` and `mihoro-update.timer` unit contents.
+fn generate_timer_units(on_calendar: &str, scope: CronScope) -> Result<(String, String)> {
+    let bin_path = mihoro_bin_path()?;
+    let service = format!(
+        "[Unit]
+Description=mihoro auto-update of mihomo config
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart={} update {}
+",
+        bin_path,
+        scope.update_args()
+    );
+    let timer = format!(
+        "[Unit]
+Description=mihoro auto-update timer
+
+[Timer]
+OnCalendar={}
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+",
+        on_calendar
+    );
+    Ok((service, timer))
+}
+
+/// Extract the `OnCalendar` expression from a timer unit.
+fn parse_on_calendar(timer: &str) -> Option<&str> {
+    timer
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("OnCalendar="))
+}
+
+/// Enable auto-update by installing and starting a systemd timer under `unit_dir`.
+pub fn enable_timer_auto_update(
+    schedule: Option<&str>,
+    interval_hours: u16,
+    scope: CronScope,
+    unit_dir: &Path,
+    system: bool,
+    prefix: &str,
+) -> Result<()> {
+    let Some(schedule) = resolve_schedule(schedule, interval_hours)? else {
+        info!(
+            "{} Auto-update interval is 0, disabling auto-update",
+            prefix.yellow()
+        );
+        return disable_timer_auto_update(unit_dir, system, prefix);
+    };
+
+    let on_calendar = to_on_calendar(&schedule)?;
+    let (service, timer) = generate_timer_units(&on_calendar, scope)?;
+    let timer_path = unit_dir.join(TIMER_UNIT);
+    create_parent_dir(&timer_path)?;
+    fs::write(unit_dir.join(SERVICE_UNIT), service)?;
+    fs::write(&timer_path, timer)?;
+
+    Systemctl::new().system(system).daemon_reload().execute()?;
+    Systemctl::new()
+        .system(system)
+        .enable_now(TIMER_UNIT)
+        .execute_checked()
+        .with_context(|| format!("failed to enable {}", TIMER_UNIT))?;
+
+    info!(
+        "{} Auto-update enabled with schedule: {}",
+        prefix.green().bold(),
+        schedule_label(&schedule).yellow()
+    );
+    info!(
+        "{} Timer: {} (OnCalendar={})",
+        "->".dimmed(),
+        timer_path.to_string_lossy().underline(),
+        on_calendar
+    );
+
+    Ok(())
+}
+
+/// Disable auto-update by stopping the systemd timer and removing its units.
+pub fn disable_timer_auto_update(unit_dir: &Path, system: bool, prefix: &str) -> Result<()> {
+    let timer_path = unit_dir.join(TIMER_UNIT);
+    if !timer_path.exists() {
+        info!(
+            "{} Auto-update disabled (no active systemd timer)",
+            prefix.yellow()
+        );
+        return Ok(());
+    }
+
+    Systemctl::new()
+        .system(system)
+        .disable_now(TIMER_UNIT)
+        .execute()?;
+    fs::remove_file(&timer_path)?;
+    let service_path = unit_dir.join(SERVICE_UNIT);
+    if service_path.exists() {
+        fs::remove_file(service_path)?;
+    }
+    Systemctl::new().system(system).daemon_reload().execute()?;
+
+    info!("{} Auto-update disabled", prefix.green().bold());
+    Ok(())
+}
+
+/// Get current systemd timer status
+pub fn get_timer_status(unit_dir: &Path, system: bool, mihomo_config_path: &str) -> Result<()> {
+    let timer_path = unit_dir.join(TIMER_UNIT);
+    if !timer_path.exists() {
+        println!("{} Auto-update is disabled", "status:".yellow().bold());
+        return Ok(());
+    }
+
+    let timer = fs::read_to_string(&timer_path)?;
+    let state = Systemctl::new().system(system).active_state(TIMER_UNIT)?;
+    println!("{} Auto-update is enabled", "status:".green().bold());
+    if let Some(on_calendar) = parse_on_calendar(&timer) {
+        let label = match preset_name(on_calendar) {
+            Some(name) => format!("{} ({})", name, on_calendar),
+            None => on_calendar.to_string(),
+        };
+        println!("{} Schedule: {}", "->".dimmed(), label.dimmed());
+    }
+    if let Ok(service) = fs::read_to_string(unit_dir.join(SERVICE_UNIT)) {
+        let exec_start = service
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("ExecStart="))
+            .unwrap_or_default();
+        println!(
+            "{} Scope: {}",
+            "->".dimmed(),
+            parse_scope(exec_start).name().dimmed()
+        );
+    }
+    println!(
+        "{} {} is {}",
+        "->".dimmed(),
+        TIMER_UNIT.dimmed(),
+        state.dimmed()
+    );
+    print_last_updated(mihomo_config_path);
 
     Ok(())
 }
@@ -165,14 +672,174 @@ mod tests {
 
     #[test]
     fn test_generate_cron_entry() {
-        let entry = generate_cron_entry(12).unwrap();
+        let entry = generate_cron_entry(&interval_schedule(12), CronScope::Config).unwrap();
         assert!(entry.contains("0 */12 * * *"));
-        assert!(entry.contains("update"));
+        assert!(entry.ends_with(" update --config --if-changed\n"));
+    }
+
+    /// Test the update command installed for each `--scope`, and reading it back
+    #[test]
+    fn test_cron_scope() {
+        let cases = [
+            (CronScope::Config, " update --config --if-changed\n"),
+            (CronScope::Geodata, " update --geodata\n"),
+            (CronScope::Core, " update --core\n"),
+            (CronScope::All, " update --all\n"),
+        ];
+        for (scope, command) in cases {
+            let entry = generate_cron_entry("0 4 * * *", scope).unwrap();
+            assert!(entry.ends_with(command), "{}", entry);
+            assert_eq!(parse_scope(&entry), scope);
+
+            let (service, _) = generate_timer_units("daily", scope).unwrap();
+            assert!(service.contains(command), "{}", service);
+        }
+
+        // Entries installed before `--scope` update the config
+        assert_eq!(
+            parse_scope("0 */12 * * * /usr/bin/mihoro update --if-changed"),
+            CronScope::Config
+        );
+        // Flags before `update` are not the scope
+        assert_eq!(
+            parse_scope("@daily /home/u/--core/mihoro update --geodata"),
+            CronScope::Geodata
+        );
     }
 
     #[test]
     fn test_generate_crontab() {
-        let crontab = generate_crontab(6).unwrap();
+        let crontab = generate_crontab(&interval_schedule(6), CronScope::Config).unwrap();
         assert!(crontab.contains("0 */6 * * *"));
+
+        let crontab = generate_crontab("0 4 * * *", CronScope::All).unwrap();
+        assert!(crontab.starts_with("0 4 * * * "));
+    }
+
+    #[test]
+    fn test_validate_schedule() {
+        for schedule in [
+            "0 4 * * *",
+            "0 */6 * * *",
+            "30 2 * * 1-5",
+            "0,30 8-18/2 1,15 * 0",
+            "@daily",
+            " @hourly ",
+        ] {
+            assert!(validate_schedule(schedule).is_ok(), "{}", schedule);
+        }
+
+        for schedule in [
+            "",
+            "0 4 * *",
+            "0 4 * * * *",
+            "60 * * * *",
+            "0 24 * * *",
+            "0 0 0 * *",
+            "0 */0 * * *",
+            "0 5-3 * * *",
+            "a * * * *",
+            "@often",
+        ] {
+            assert!(validate_schedule(schedule).is_err(), "{}", schedule);
+        }
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(
+            parse_schedule("0 */12 * * * /usr/bin/mihoro update"),
+            Some(String::from("0 */12 * * *"))
+        );
+        assert_eq!(
+            parse_schedule("@daily /usr/bin/mihoro update"),
+            Some(String::from("@daily"))
+        );
+        assert_eq!(parse_schedule("0 4"), None);
+    }
+
+    #[test]
+    fn test_to_on_calendar() {
+        let cases = [
+            ("0 */12 * * *", "*-*-* 00/12:00:00"),
+            ("0 4 * * *", "*-*-* 04:00:00"),
+            ("30 2 * * 1-5", "Mon..Fri *-*-* 02:30:00"),
+            ("0,30 8-18 1,15 * *", "*-*-01,15 08..18:00,30:00"),
+            ("0 0 * */3 *", "*-01/3-* 00:00:00"),
+            ("0 0 * * 0,7", "Sun,Sun *-*-* 00:00:00"),
+            ("@daily", "daily"),
+            ("@annually", "yearly"),
+        ];
+        for (schedule, expected) in cases {
+            assert_eq!(to_on_calendar(schedule).unwrap(), expected, "{}", schedule);
+        }
+
+        assert!(to_on_calendar("0 4 1 * 1").is_err());
+        assert!(to_on_calendar("0 8-18/2 * * *").is_err());
+        assert!(to_on_calendar("0 4 * * */2").is_err());
+        assert!(to_on_calendar("0 25 * * *").is_err());
+    }
+
+    #[test]
+    fn test_generate_timer_units() {
+        let (service, timer) = generate_timer_units("*-*-* 04:00:00", CronScope::Config).unwrap();
+        assert!(service.contains("Type=oneshot"));
+        assert!(service.contains(" update --config --if-changed\n"));
+        assert!(timer.contains("WantedBy=timers.target"));
+        assert_eq!(parse_on_calendar(&timer), Some("*-*-* 04:00:00"));
+    }
+
+    /// Test the cron schedule and `OnCalendar` expression of each `--interval` preset
+    #[test]
+    fn test_interval_presets() {
+        let cases = [
+            (CronInterval::Hourly, "0 * * * *", "*-*-* *:00:00", "hourly"),
+            (
+                CronInterval::SixHours,
+                "0 */6 * * *",
+                "*-*-* 00/6:00:00",
+                "6h",
+            ),
+            (CronInterval::Daily, "0 4 * * *", "*-*-* 04:00:00", "daily"),
+            (
+                CronInterval::Weekly,
+                "0 4 * * 0",
+                "Sun *-*-* 04:00:00",
+                "weekly",
+            ),
+        ];
+        for (interval, cron, calendar, name) in cases {
+            assert_eq!(interval.schedule(), cron);
+            assert_eq!(to_on_calendar(interval.schedule()).unwrap(), calendar);
+            assert_eq!(preset_name(cron), Some(name));
+            assert_eq!(preset_name(calendar), Some(name));
+        }
+
+        assert_eq!(preset_name("0 5 * * *"), None);
+        assert_eq!(schedule_label("0 4 * * *"), "daily (0 4 * * *)");
+        assert_eq!(schedule_label("0 5 * * *"), "0 5 * * * (daily at 05:00)");
+    }
+
+    #[test]
+    fn test_describe_schedule() {
+        let cases = [
+            ("0 */6 * * *", Some("every 6 hours")),
+            ("0 */1 * * *", Some("every hour")),
+            ("15 * * * *", Some("every hour at minute 15")),
+            ("0 4 * * *", Some("daily at 04:00")),
+            ("30 23 * * *", Some("daily at 23:30")),
+            ("@daily", Some("daily at 00:00")),
+            ("@weekly", Some("weekly on Sunday at 00:00")),
+            ("0 4 * * 1", None),
+            ("15 */6 * * *", None),
+        ];
+        for (schedule, expected) in cases {
+            assert_eq!(
+                describe_schedule(schedule).as_deref(),
+                expected,
+                "{}",
+                schedule
+            );
+        }
     }
 }
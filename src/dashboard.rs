@@ -0,0 +1,84 @@
+use crate::cmd::DashboardUi;
+use crate::config::read_mihomo_controller;
+use crate::log::einfo;
+use crate::mihoro::Mihoro;
+
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+
+/// Percent-encode a URL query value, keeping only unreserved characters as is.
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Build the URL of a hosted dashboard, pre-filled to connect to the given controller.
+fn dashboard_url(ui: DashboardUi, host: &str, port: u16, secret: Option<&str>) -> String {
+    let base = match ui {
+        DashboardUi::Metacubexd => "https://metacubex.github.io/metacubexd/#/setup",
+        DashboardUi::Yacd => "https://yacd.metacubex.one/",
+    };
+    format!(
+        "{}?hostname={}&port={}&secret={}",
+        base,
+        encode_query_value(host),
+        port,
+        encode_query_value(secret.unwrap_or_default())
+    )
+}
+
+/// Print the URL of a hosted dashboard connected to mihomo's external controller, and open it
+/// with `xdg-open` if `open` is set.
+pub fn dashboard(mihoro: &Mihoro, ui: DashboardUi, open: bool) -> Result<()> {
+    let controller = read_mihomo_controller(&mihoro.mihomo_target_config_path)?;
+    let Some((host, port)) = controller.host_port()? else {
+        bail!(
+            "`external-controller` not set in `{}`, set `external_controller` under `[mihomo_config]` and run `mihoro apply`",
+            mihoro.mihomo_target_config_path
+        );
+    };
+    let url = dashboard_url(ui, &host, port, controller.secret.as_deref());
+    println!("{}", url);
+
+    if open {
+        let opened = Command::new("xdg-open")
+            .arg(&url)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !opened {
+            einfo!(
+                "{} Failed to open the dashboard with `xdg-open`, open the url above manually",
+                mihoro.prefix.yellow()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dashboard_url() {
+        assert_eq!(
+            dashboard_url(DashboardUi::Metacubexd, "127.0.0.1", 9090, Some("a b&c")),
+            "https://metacubex.github.io/metacubexd/#/setup?hostname=127.0.0.1&port=9090&secret=a%20b%26c"
+        );
+        assert_eq!(
+            dashboard_url(DashboardUi::Yacd, "[::1]", 9090, None),
+            "https://yacd.metacubex.one/?hostname=%5B%3A%3A1%5D&port=9090&secret="
+        );
+    }
+}
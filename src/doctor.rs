@@ -0,0 +1,318 @@
+use crate::cmd::OutputFormat;
+use crate::config::read_mihomo_ports;
+use crate::mihoro::Mihoro;
+use crate::resolve_mihomo_bin::{detect_arch, detect_arch_optimized, validate_arch};
+use crate::utils::{expand_path, unix_now};
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use serde::Serialize;
+
+/// Timeout for checking whether a configured port accepts connections.
+const PORT_CHECK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Outcome of a single `mihoro doctor` check.
+#[derive(Serialize, Debug)]
+pub struct Check {
+    name: &'static str,
+    passed: bool,
+    /// Whether a failure of this check makes `mihoro doctor` exit non-zero.
+    critical: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, critical: bool, result: Result<String>) -> Check {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+        Check {
+            name,
+            passed,
+            critical,
+            detail,
+        }
+    }
+}
+
+/// Run all diagnostic checks against the mihoro config at `config_path`.
+///
+/// The config is never created if missing, unlike other commands.
+pub fn run_checks(config_path: &str) -> Vec<Check> {
+    let expanded = expand_path(config_path).to_string_lossy().into_owned();
+    if !Path::new(&expanded).exists() {
+        let missing = Err(anyhow!(
+            "not found at `{}`, run `mihoro setup` to create it",
+            expanded
+        ));
+        return vec![Check::new("mihoro config", true, missing)];
+    }
+
+    match Mihoro::new(config_path) {
+        Ok(mihoro) => {
+            let mut checks = vec![Check::new("mihoro config", true, Ok(expanded))];
+            checks.extend(mihoro_checks(&mihoro));
+            checks
+        }
+        Err(e) => vec![Check::new("mihoro config", true, Err(e))],
+    }
+}
+
+/// Checks that require a parsed mihoro config.
+fn mihoro_checks(mihoro: &Mihoro) -> Vec<Check> {
+    let service = &mihoro.config.service_name;
+    vec![
+        Check::new(
+            "architecture",
+            false,
+            match &mihoro.config.mihomo_arch {
+                Some(arch) => validate_arch(arch),
+                None if mihoro.config.arch_autotune => detect_arch_optimized(),
+                None => detect_arch(),
+            },
+        ),
+        Check::new("mihomo binary", true, check_binary(mihoro)),
+        Check::new("config.yaml", true, check_mihomo_config(mihoro)),
+        Check::new("geodata", false, check_geodata(mihoro)),
+        Check::new(
+            "systemd service",
+            true,
+            check_exists(&mihoro.mihomo_target_service_path),
+        ),
+        Check::new(
+            "service enabled",
+            false,
+            match mihoro.systemctl().is_enabled(service) {
+                Ok(true) => Ok(format!("{} is enabled", service)),
+                Ok(false) => Err(anyhow!(
+                    "{} is not enabled, it won't start on boot",
+                    service
+                )),
+                Err(e) => Err(e),
+            },
+        ),
+        Check::new(
+            "service active",
+            true,
+            match mihoro.systemctl().is_active(service) {
+                Ok(true) => Ok(format!("{} is running", service)),
+                Ok(false) => Err(anyhow!("{} is not running, check `mihoro log`", service)),
+                Err(e) => Err(e),
+            },
+        ),
+        Check::new("proxy ports", true, check_ports(mihoro)),
+    ]
+}
+
+fn check_exists(path: &str) -> Result<String> {
+    if !Path::new(path).exists() {
+        bail!("`{}` not found, run `mihoro setup`", path);
+    }
+    Ok(path.to_string())
+}
+
+/// Check the mihomo binary exists, is executable and reports its version.
+fn check_binary(mihoro: &Mihoro) -> Result<String> {
+    let path = check_exists(&mihoro.mihomo_target_binary_path)?;
+    if fs::metadata(&path)?.permissions().mode() & 0o111 == 0 {
+        bail!("`{}` is not executable", path);
+    }
+    match mihoro.mihomo_version() {
+        Some(version) => Ok(match mihoro.install_state() {
+            Some(state) => format!(
+                "{}, installed by mihoro: {}",
+                version,
+                state.summary(unix_now())
+            ),
+            None => version,
+        }),
+        None => bail!(
+            "`{} -v` failed, the binary may be corrupt or built for another architecture",
+            path
+        ),
+    }
+}
+
+/// Check the mihomo `config.yaml` exists and parses.
+fn check_mihomo_config(mihoro: &Mihoro) -> Result<String> {
+    let path = check_exists(&mihoro.mihomo_target_config_path)?;
+    read_mihomo_ports(&path).map_err(|e| anyhow!("`{}` is invalid: {}", path, e))?;
+    Ok(path)
+}
+
+/// Check the geodata files downloaded by `mihoro update --geodata` are present.
+fn check_geodata(mihoro: &Mihoro) -> Result<String> {
+    let sources = mihoro.config.geodata_sources();
+    let files: Vec<&str> = sources.iter().map(|source| source.file).collect();
+    if files.is_empty() {
+        bail!("no geodata sources, set `geox_url` or `[geodata]`");
+    }
+
+    let root = Path::new(&mihoro.mihomo_target_config_root);
+    let missing: Vec<&str> = files
+        .iter()
+        .copied()
+        .filter(|file| !root.join(file).exists())
+        .collect();
+    if !missing.is_empty() && mihoro.config.skip_geodata {
+        return Ok(format!(
+            "{} not downloaded (`skip_geodata`), mihomo downloads it if rules need it",
+            missing.join(", ")
+        ));
+    }
+    if !missing.is_empty() {
+        bail!(
+            "{} missing, run `mihoro update --geodata`",
+            missing.join(", ")
+        );
+    }
+    Ok(files.join(", "))
+}
+
+/// Check the configured proxy ports accept connections on localhost.
+fn check_ports(mihoro: &Mihoro) -> Result<String> {
+    let live_ports = read_mihomo_ports(&mihoro.mihomo_target_config_path).unwrap_or_default();
+    let (port, socks_port) = live_ports.resolve(&mihoro.config.mihomo_config);
+
+    let mut ports = vec![port, socks_port];
+    ports.dedup();
+    let closed: Vec<String> = ports
+        .iter()
+        .filter(|port| {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, **port));
+            TcpStream::connect_timeout(&addr, PORT_CHECK_TIMEOUT).is_err()
+        })
+        .map(|port| port.to_string())
+        .collect();
+    if !closed.is_empty() {
+        bail!("nothing listening on port {}", closed.join(", "));
+    }
+
+    let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+    Ok(format!("listening on {}", ports.join(", ")))
+}
+
+/// Run `mihoro doctor`, printing a checklist and failing if any critical check failed.
+pub fn doctor(config_path: &str, output: OutputFormat) -> Result<()> {
+    let checks = run_checks(config_path);
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&checks)?),
+        OutputFormat::Human => {
+            for check in &checks {
+                let mark = match (check.passed, check.critical) {
+                    (true, _) => "✔".green(),
+                    (false, true) => "✘".red(),
+                    (false, false) => "!".yellow(),
+                };
+                println!(
+                    "{} {}: {}",
+                    mark.bold(),
+                    check.name.bold(),
+                    check.detail.dimmed()
+                );
+            }
+        }
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.critical && !check.passed)
+        .count();
+    if failed > 0 {
+        bail!("{} critical check(s) failed", failed);
+    }
+    if output == OutputFormat::Human {
+        println!("{} All critical checks passed", "mihoro:".green());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn find<'a>(checks: &'a [Check], name: &str) -> &'a Check {
+        checks.iter().find(|check| check.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_run_checks_missing_config() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("mihoro.toml");
+
+        let checks = run_checks(config_path.to_str().unwrap());
+        assert_eq!(checks.len(), 1);
+        assert!(!checks[0].passed);
+        assert!(checks[0].detail.contains("mihoro setup"));
+
+        // Doctor must not create a default config as a side effect
+        assert!(!config_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_checks_missing_install() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().to_str().unwrap();
+        let config_path = dir.path().join("mihoro.toml");
+        fs::write(
+            &config_path,
+            format!(
+                r#"
+                remote_config_url = "http://example.com/config.yaml"
+                mihomo_binary_path = "{root}/mihomo"
+                mihomo_config_root = "{root}"
+                user_systemd_root = "{root}"
+                "#
+            ),
+        )?;
+        fs::write(dir.path().join("mihomo"), "")?;
+        fs::write(dir.path().join("country.mmdb"), "")?;
+
+        let checks = run_checks(config_path.to_str().unwrap());
+        assert!(find(&checks, "mihoro config").passed);
+        assert!(find(&checks, "geodata").passed);
+
+        let binary = find(&checks, "mihomo binary");
+        assert!(!binary.passed && binary.critical);
+        assert!(binary.detail.contains("not executable"));
+
+        assert!(!find(&checks, "config.yaml").passed);
+        assert!(!find(&checks, "systemd service").passed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_checks_skip_geodata() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().to_str().unwrap();
+        let config_path = dir.path().join("mihoro.toml");
+        let config = format!(
+            r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "{root}/mihomo"
+            mihomo_config_root = "{root}"
+            user_systemd_root = "{root}"
+            "#
+        );
+        fs::write(&config_path, &config)?;
+        assert!(!find(&run_checks(config_path.to_str().unwrap()), "geodata").passed);
+
+        // Missing geodata is expected with `skip_geodata`
+        fs::write(&config_path, format!("skip_geodata = true\n{}", config))?;
+        let checks = run_checks(config_path.to_str().unwrap());
+        let geodata = find(&checks, "geodata");
+        assert!(geodata.passed);
+        assert!(geodata.detail.contains("skip_geodata"));
+        Ok(())
+    }
+}
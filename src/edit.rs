@@ -0,0 +1,177 @@
+use crate::config::{parse_config, Config};
+use crate::log::info;
+use crate::mihoro::Mihoro;
+use crate::utils::{confirm, expand_path};
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use colored::Colorize;
+
+/// Editors tried in order when `$EDITOR` is unset.
+const FALLBACK_EDITORS: [&str; 2] = ["vi", "nano"];
+
+/// Resolve the editor command from `$EDITOR`, falling back to the first of `vi` and `nano` that
+/// is installed.
+fn editor_command(
+    get_env: impl Fn(&str) -> Option<String>,
+    is_installed: impl Fn(&str) -> bool,
+) -> Option<String> {
+    get_env("EDITOR")
+        .filter(|editor| !editor.trim().is_empty())
+        .or_else(|| {
+            FALLBACK_EDITORS
+                .into_iter()
+                .find(|editor| is_installed(editor))
+                .map(String::from)
+        })
+}
+
+/// Whether `program` is found in one of the directories on `$PATH`.
+fn is_installed(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+/// Open `path` in `editor` and wait for it to exit.
+///
+/// `editor` may carry arguments, e.g. `code --wait`.
+fn open_editor(editor: &str, path: &Path) -> Result<()> {
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or_else(|| anyhow!("`$EDITOR` is empty"))?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("failed to run editor `{}`", editor))?;
+    if !status.success() {
+        bail!("editor `{}` exited with {}", editor, status);
+    }
+    Ok(())
+}
+
+/// Open `path` in the editor until `check` passes, or the user gives up on fixing it.
+fn edit_until_valid(path: &Path, prefix: &str, check: impl Fn() -> Result<()>) -> Result<()> {
+    let editor = editor_command(|key| env::var(key).ok(), is_installed)
+        .ok_or_else(|| anyhow!("no editor found, set `$EDITOR`"))?;
+
+    loop {
+        open_editor(&editor, path)?;
+        match check() {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("{} {:#}", prefix.red(), e);
+                if !confirm("Reopen the editor to fix it?", true)? {
+                    bail!("`{}` is left invalid", path.to_string_lossy());
+                }
+            }
+        }
+    }
+}
+
+/// Edit the mihoro config at `config_path`, creating a default one if missing, and re-parse it
+/// after the editor exits.
+///
+/// Runs without a parsed config, so a broken config can be fixed with it.
+pub fn edit_mihoro_config(config_path: &str, prefix: &str) -> Result<()> {
+    let config_path = expand_path(config_path).to_string_lossy().into_owned();
+    let path = Path::new(&config_path);
+    if !path.exists() {
+        Config::write_default(path)?;
+        info!(
+            "{} Created default config at {}",
+            prefix.cyan(),
+            config_path.underline().yellow()
+        );
+    }
+
+    edit_until_valid(path, prefix, || parse_config(&config_path).map(|_| ()))?;
+    info!(
+        "{} Validated mihoro config at {}",
+        prefix.green(),
+        config_path.underline().yellow()
+    );
+    info!(
+        "{} Run {} to apply changes",
+        "->".dimmed(),
+        "mihoro apply".bold().underline()
+    );
+    Ok(())
+}
+
+/// Check that mihomo's `config.yaml` is valid YAML, then validate it with `mihomo -t` if the
+/// binary is installed.
+fn check_mihomo_config(mihoro: &Mihoro) -> Result<()> {
+    let raw_mihomo_yaml = fs::read_to_string(&mihoro.mihomo_target_config_path)?;
+    serde_yaml::from_str::<serde_yaml::Value>(&raw_mihomo_yaml)
+        .map_err(|e| anyhow!("invalid YAML: {}", e))?;
+
+    if Path::new(&mihoro.mihomo_target_binary_path).exists() {
+        mihoro.validate_config()?;
+    }
+    Ok(())
+}
+
+/// Edit mihomo's `config.yaml`, validate it after the editor exits, then offer to apply overrides
+/// and restart the service.
+pub async fn edit_mihomo_config(mihoro: &Mihoro) -> Result<()> {
+    let path = Path::new(&mihoro.mihomo_target_config_path);
+    if !path.exists() {
+        bail!(
+            "`{}` not found, run `mihoro setup` first",
+            mihoro.mihomo_target_config_path
+        );
+    }
+
+    edit_until_valid(path, &mihoro.prefix, || check_mihomo_config(mihoro))?;
+    if confirm(
+        &format!("Apply and restart {}?", mihoro.config.service_name),
+        false,
+    )? {
+        mihoro.apply(false, false).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_editor_command() {
+        let env = |editor: Option<&str>| {
+            let editor = editor.map(String::from);
+            move |key: &str| (key == "EDITOR").then(|| editor.clone()).flatten()
+        };
+
+        assert_eq!(
+            editor_command(env(Some("code --wait")), |_| true),
+            Some("code --wait".to_string())
+        );
+        assert_eq!(editor_command(env(None), |_| true), Some("vi".to_string()));
+        assert_eq!(
+            editor_command(env(Some(" ")), |editor| editor == "nano"),
+            Some("nano".to_string())
+        );
+        assert_eq!(editor_command(env(None), |_| false), None);
+    }
+
+    #[test]
+    fn test_open_editor() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro.toml");
+        fs::write(&path, "")?;
+
+        // Arguments in `$EDITOR` are passed before the file
+        open_editor("sh -c true", &path)?;
+
+        let err = open_editor("false", &path).unwrap_err();
+        assert!(err.to_string().contains("exited with"));
+        assert!(open_editor("mihoro-nonexistent-editor", &path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::process::ExitStatus;
+
+use thiserror::Error;
+
+/// Failures mihoro recognizes by their type rather than their wording, carried as causes of an
+/// `anyhow::Error` and mapped to a hint and exit code in `main`.
+#[derive(Debug, Error)]
+pub enum MihoroError {
+    /// Not allowed to write a file or directory, e.g. where a binary is installed.
+    #[error("permission denied: {0}")]
+    Permission(String),
+    /// A request failed to connect or timed out, after any retries.
+    #[error("{0}")]
+    Network(String),
+    /// A download does not match the SHA256 checksum published for it.
+    #[error("checksum mismatch for `{path}`: expected {expected}, got {actual}")]
+    Checksum {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    /// No mihomo release is built for the architecture.
+    #[error("unsupported architecture: {0}")]
+    Arch(String),
+    /// `mihoro.toml` is missing, unreadable or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// A systemctl command exited unsuccessfully, with what it printed to stderr.
+    #[error("`{command}` failed ({status}){}{stderr}", if stderr.is_empty() { "" } else { ": " })]
+    Systemctl {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// systemd cannot manage services here, e.g. in a container or WSL without systemd.
+    #[error("systemd is not available: {0}")]
+    NoSystemd(String),
+}
+
+/// Kind of failure behind an error, see `kind`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Permission,
+    Network,
+    Checksum,
+    Arch,
+    Config,
+    Systemctl,
+    NoSystemd,
+}
+
+impl MihoroError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            MihoroError::Permission(_) => ErrorKind::Permission,
+            MihoroError::Network(_) => ErrorKind::Network,
+            MihoroError::Checksum { .. } => ErrorKind::Checksum,
+            MihoroError::Arch(_) => ErrorKind::Arch,
+            MihoroError::Config(_) => ErrorKind::Config,
+            MihoroError::Systemctl { .. } => ErrorKind::Systemctl,
+            MihoroError::NoSystemd(_) => ErrorKind::NoSystemd,
+        }
+    }
+}
+
+/// Exit code of failures without a recognized kind. clap exits with 2 for invalid usage.
+pub const EXIT_FAILURE: i32 = 1;
+
+impl ErrorKind {
+    /// Exit code for the failure, following `sysexits.h`. These are documented in the README, so
+    /// scripts can rely on them and must not change.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Checksum => 65,                         // EX_DATAERR
+            ErrorKind::Network => 69,                          // EX_UNAVAILABLE
+            ErrorKind::Systemctl | ErrorKind::NoSystemd => 71, // EX_OSERR
+            ErrorKind::Arch => 64,                             // EX_USAGE, pass --arch instead
+            ErrorKind::Permission => 77,                       // EX_NOPERM
+            ErrorKind::Config => 78,                           // EX_CONFIG
+        }
+    }
+
+    /// What to try next, unless the error message already says.
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            ErrorKind::Permission => {
+                Some("check that you own the path, or run `mihoro where` for the mihoro binary")
+            }
+            ErrorKind::Network => {
+                Some("check your connection, `download_proxy` (or `--no-proxy`) and `--timeout`")
+            }
+            ErrorKind::Checksum => {
+                Some("the download may be corrupted, try again, or pass `--no-verify` to skip it")
+            }
+            ErrorKind::Systemctl => Some("check `mihoro status` and `mihoro log`"),
+            ErrorKind::NoSystemd => Some(
+                "run `mihoro setup --skip-service` to install without a service, and start mihomo \
+                 with the command it prints",
+            ),
+            ErrorKind::Arch | ErrorKind::Config => None,
+        }
+    }
+}
+
+/// Find the kind of failure behind `err`: a `MihoroError` attached anywhere in it, or otherwise an
+/// I/O permission error or a failed HTTP request among its causes.
+pub fn kind(err: &anyhow::Error) -> Option<ErrorKind> {
+    if let Some(e) = err.downcast_ref::<MihoroError>() {
+        return Some(e.kind());
+    }
+    err.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<MihoroError>() {
+            Some(e.kind())
+        } else if let Some(e) = cause.downcast_ref::<io::Error>() {
+            (e.kind() == io::ErrorKind::PermissionDenied).then_some(ErrorKind::Permission)
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            (e.is_connect() || e.is_timeout() || e.is_request()).then_some(ErrorKind::Network)
+        } else {
+            None
+        }
+    })
+}
+
+/// Exit code for `err`, by the kind of failure behind it.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    kind(err).map_or(EXIT_FAILURE, ErrorKind::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_kind() {
+        let checksum = anyhow::Error::new(MihoroError::Checksum {
+            path: "mihomo.gz".to_string(),
+            expected: "aa".to_string(),
+            actual: "bb".to_string(),
+        })
+        .context("failed to update mihomo");
+        assert_eq!(kind(&checksum), Some(ErrorKind::Checksum));
+        assert_eq!(
+            format!("{:#}", checksum),
+            "failed to update mihomo: checksum mismatch for `mihomo.gz`: expected aa, got bb"
+        );
+
+        // Attached as context to an untyped error
+        let network = anyhow!("connection reset")
+            .context(MihoroError::Network("failed to reach GitHub".to_string()));
+        assert_eq!(kind(&network), Some(ErrorKind::Network));
+
+        // Recognized by the io::Error kind, whatever the locale says
+        let permission = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("failed to write `/usr/local/bin/mihomo`")
+            .unwrap_err();
+        assert_eq!(kind(&permission), Some(ErrorKind::Permission));
+
+        let not_found = anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(kind(&not_found), None);
+        assert_eq!(kind(&anyhow!("something else")), None);
+    }
+
+    /// Test the documented exit code of each error variant
+    #[test]
+    fn test_exit_code() {
+        let code = |e: MihoroError| exit_code(&anyhow::Error::new(e).context("failed to update"));
+        assert_eq!(code(MihoroError::Arch("riscv32".to_string())), 64);
+        assert_eq!(
+            code(MihoroError::Checksum {
+                path: "mihomo.gz".to_string(),
+                expected: "aa".to_string(),
+                actual: "bb".to_string(),
+            }),
+            65
+        );
+        assert_eq!(code(MihoroError::Network("timed out".to_string())), 69);
+        assert_eq!(
+            code(MihoroError::Systemctl {
+                command: "systemctl --user restart mihomo.service".to_string(),
+                status: std::os::unix::process::ExitStatusExt::from_raw(1 << 8),
+                stderr: String::new(),
+            }),
+            71
+        );
+        assert_eq!(
+            code(MihoroError::NoSystemd("`systemctl` not found".to_string())),
+            71
+        );
+        assert_eq!(code(MihoroError::Permission("/usr/bin".to_string())), 77);
+        assert_eq!(
+            code(MihoroError::Config("`service_name` undefined".to_string())),
+            78
+        );
+
+        assert_eq!(
+            exit_code(&anyhow::Error::new(io::Error::from(
+                io::ErrorKind::PermissionDenied
+            ))),
+            77
+        );
+        assert_eq!(exit_code(&anyhow!("something else")), EXIT_FAILURE);
+    }
+}
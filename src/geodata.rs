@@ -0,0 +1,611 @@
+use crate::cmd::OutputFormat;
+use crate::config::{Config, GeodataSource};
+use crate::install_state::format_age;
+use crate::mihoro::{Mihoro, GEODATA_FILES};
+use crate::subscription::format_bytes;
+use crate::utils::unix_now;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use colored::Colorize;
+use futures_util::future::join_all;
+use reqwest::header::{
+    HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Cache validators of a downloaded geodata file, sent back on the next update so the server can
+/// answer 304 Not Modified instead of sending an unchanged file again.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CacheValidators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    /// Read validators from response headers.
+    pub fn from_headers(headers: &HeaderMap) -> CacheValidators {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(String::from)
+        };
+        CacheValidators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    /// Headers making a request conditional on the file having changed.
+    pub fn conditional_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let values = [
+            (IF_NONE_MATCH, &self.etag),
+            (IF_MODIFIED_SINCE, &self.last_modified),
+        ];
+        for (name, value) in values {
+            if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+
+    /// Path validators of the file at `path` are stored at, e.g. `geoip.dat.cache.json`.
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut cache_path = path.as_os_str().to_owned();
+        cache_path.push(".cache.json");
+        PathBuf::from(cache_path)
+    }
+
+    /// Load the validators stored for the file at `path`, or none if the file or its validators
+    /// are missing.
+    pub fn load(path: &Path) -> CacheValidators {
+        if !path.exists() {
+            return CacheValidators::default();
+        }
+        fs::read_to_string(CacheValidators::path_for(path))
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    /// Store validators for the file at `path`, removing stale ones if the server sent none.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let cache_path = CacheValidators::path_for(path);
+        if *self == CacheValidators::default() {
+            if cache_path.exists() {
+                fs::remove_file(cache_path)?;
+            }
+            return Ok(());
+        }
+        fs::write(cache_path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Format of the database mihomo matches `GEOIP` rules against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoipFormat {
+    /// `geoip.dat`, used in `geodata-mode`.
+    Dat,
+    /// `country.mmdb`, a MaxMind country database.
+    Mmdb,
+    /// `geoip.metadb`, the MetaCubeX MMDB, which can map an IP to several countries.
+    Metadb,
+}
+
+/// A mihomo release version, e.g. `(1, 19, 0)` for `v1.19.0`.
+pub type MihomoVersion = (u32, u32, u32);
+
+/// First mihomo version loading each `GEOIP` database format.
+const GEOIP_FORMATS: [(GeoipFormat, MihomoVersion); 3] = [
+    (GeoipFormat::Dat, (0, 0, 0)),
+    (GeoipFormat::Mmdb, (0, 0, 0)),
+    (GeoipFormat::Metadb, (1, 17, 0)),
+];
+
+/// Values of `geodata-loader`, which picks how mihomo reads `.dat` files in `geodata-mode`.
+const GEODATA_LOADERS: [&str; 2] = ["memconservative", "standard"];
+
+impl GeoipFormat {
+    /// File the database is saved as in the mihomo config root.
+    pub fn file(self) -> &'static str {
+        match self {
+            GeoipFormat::Dat => "geoip.dat",
+            GeoipFormat::Mmdb => "country.mmdb",
+            GeoipFormat::Metadb => "geoip.metadb",
+        }
+    }
+
+    /// Format of the MMDB downloaded from `url`, by its extension.
+    pub fn of_mmdb_url(url: &str) -> GeoipFormat {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        if path.ends_with(".metadb") {
+            GeoipFormat::Metadb
+        } else {
+            GeoipFormat::Mmdb
+        }
+    }
+
+    /// First mihomo version loading the format.
+    pub fn since(self) -> MihomoVersion {
+        GEOIP_FORMATS
+            .iter()
+            .find(|(format, _)| *format == self)
+            .map_or((0, 0, 0), |(_, since)| *since)
+    }
+
+    /// Whether mihomo `version` loads the format, assumed for unknown versions such as alpha builds.
+    pub fn supported_by(self, version: Option<MihomoVersion>) -> bool {
+        version.is_none_or(|version| version >= self.since())
+    }
+}
+
+/// Parse the release version from what `mihomo -v` prints, e.g. `Mihomo Meta v1.19.0 linux amd64`,
+/// or `None` for builds without one, such as `alpha-1a2b3c4`.
+pub fn parse_mihomo_version(version: &str) -> Option<MihomoVersion> {
+    version.split_whitespace().find_map(|word| {
+        let mut parts = word.strip_prefix('v')?.splitn(3, '.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let patch = parts.next()?;
+        let digits = patch
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(patch.len());
+        Some((major, minor, patch[..digits].parse().ok()?))
+    })
+}
+
+/// Drop geodata `sources` the installed mihomo `version` cannot load, and return warnings about
+/// them and about the files mihomo needs for the configured `geodata-mode` and `geodata-loader`
+/// that are neither downloaded nor present in `root`.
+pub fn check_formats(
+    config: &Config,
+    sources: &mut Vec<GeodataSource>,
+    version: Option<&str>,
+    root: &Path,
+) -> Vec<String> {
+    let mut warnings = vec![];
+    let parsed = version.and_then(parse_mihomo_version);
+    sources.retain(|source| {
+        let format = match source.file {
+            "geoip.metadb" => GeoipFormat::Metadb,
+            _ => return true,
+        };
+        if format.supported_by(parsed) {
+            return true;
+        }
+        let (major, minor, patch) = format.since();
+        warnings.push(format!(
+            "skipped {}, which needs mihomo v{}.{}.{} or later, not {}, set `[geodata] mmdb` to a \
+             country.mmdb url instead",
+            source.file,
+            major,
+            minor,
+            patch,
+            version.unwrap_or_default()
+        ));
+        false
+    });
+
+    let available =
+        |file: &str| sources.iter().any(|source| source.file == file) || root.join(file).exists();
+    let geodata_mode = config.geodata_mode();
+    if geodata_mode {
+        for file in ["geoip.dat", "geosite.dat"] {
+            if !available(file) {
+                warnings.push(format!(
+                    "`geodata_mode` needs {}, which is not downloaded, set it under `[geodata]`",
+                    file
+                ));
+            }
+        }
+    } else if !available("country.mmdb") && !available("geoip.metadb") {
+        warnings.push(
+            "GEOIP rules need country.mmdb or geoip.metadb, which is not downloaded, set \
+             `[geodata] mmdb`"
+                .to_string(),
+        );
+    }
+    let downloads_metadb = sources.iter().any(|source| source.file == "geoip.metadb");
+    if downloads_metadb && root.join("country.mmdb").exists() {
+        warnings.push(
+            "mihomo loads country.mmdb rather than geoip.metadb while it exists, remove it to \
+             use geoip.metadb"
+                .to_string(),
+        );
+    }
+
+    match config.geodata_loader() {
+        Some(loader) if !GEODATA_LOADERS.contains(&loader) => warnings.push(format!(
+            "unknown `geodata-loader` \"{}\", expected one of: {}",
+            loader,
+            GEODATA_LOADERS.join(", ")
+        )),
+        Some(_) if !geodata_mode => warnings
+            .push("`geodata-loader` only applies in `geodata_mode`, which is off".to_string()),
+        _ => {}
+    }
+    warnings
+}
+
+/// Whether the server has a newer version of an installed geodata file.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Freshness {
+    UpToDate,
+    UpdateAvailable,
+    /// No validators were stored for the file, or the server sent none to compare.
+    Unknown,
+}
+
+/// Compare the answer to a conditional HEAD request with the validators stored for the file.
+///
+/// Servers ignoring the conditional headers answer 200 anyway, so their validators are compared
+/// directly as well.
+pub fn freshness(status: StatusCode, headers: &HeaderMap, stored: &CacheValidators) -> Freshness {
+    if status == StatusCode::NOT_MODIFIED {
+        return Freshness::UpToDate;
+    }
+    let remote = CacheValidators::from_headers(headers);
+    let compared = [
+        (&stored.etag, &remote.etag),
+        (&stored.last_modified, &remote.last_modified),
+    ];
+    let mut freshness = Freshness::Unknown;
+    for (stored, remote) in compared {
+        if let (Some(stored), Some(remote)) = (stored, remote) {
+            if stored != remote {
+                return Freshness::UpdateAvailable;
+            }
+            freshness = Freshness::UpToDate;
+        }
+    }
+    freshness
+}
+
+/// A row of `mihoro geodata status`.
+#[derive(Serialize, Debug)]
+pub struct GeodataStatus {
+    file: &'static str,
+    installed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Last modified time as a Unix timestamp.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<u64>,
+    /// Url the file is downloaded from, if configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freshness: Option<Freshness>,
+    /// Why checking for a newer version failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Describe the configured geodata files and any others present in `root`, without network access.
+fn local_status(root: &Path, sources: &[GeodataSource]) -> Vec<GeodataStatus> {
+    GEODATA_FILES
+        .iter()
+        .filter_map(|&file| {
+            let url = sources
+                .iter()
+                .find(|source| source.file == file)
+                .map(|source| source.url.clone());
+            let metadata = fs::metadata(root.join(file)).ok();
+            if url.is_none() && metadata.is_none() {
+                return None;
+            }
+            let modified = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs());
+            Some(GeodataStatus {
+                file,
+                installed: metadata.is_some(),
+                size: metadata.map(|metadata| metadata.len()),
+                modified,
+                url,
+                freshness: None,
+                error: None,
+            })
+        })
+        .collect()
+}
+
+/// Run `mihoro geodata status`, listing the size and age of each geodata file and, unless
+/// `offline`, whether the server has a newer version of it.
+pub async fn status(mihoro: &Mihoro, offline: bool) -> Result<()> {
+    let root = Path::new(&mihoro.mihomo_target_config_root);
+    let mut statuses = local_status(root, &mihoro.config.geodata_sources());
+
+    if !offline {
+        let client = &mihoro.http_client()?;
+        let checks = statuses.iter().map(|status| async move {
+            let url = status.url.as_deref().filter(|_| status.installed)?;
+            let validators = CacheValidators::load(&root.join(status.file));
+            let response = client
+                .head(url)
+                .headers(validators.conditional_headers())
+                .timeout(mihoro.config.network_timeout())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            Some(
+                response
+                    .map(|response| freshness(response.status(), response.headers(), &validators)),
+            )
+        });
+        let checks = join_all(checks).await;
+        for (status, check) in statuses.iter_mut().zip(checks) {
+            match check {
+                Some(Ok(freshness)) => status.freshness = Some(freshness),
+                Some(Err(e)) => status.error = Some(format!("{:#}", anyhow::Error::from(e))),
+                None => {}
+            }
+        }
+    }
+
+    match mihoro.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&statuses)?),
+        OutputFormat::Human => {
+            if statuses.is_empty() {
+                println!(
+                    "{} No geodata sources, set `geox_url` or `[geodata]`",
+                    mihoro.prefix.yellow()
+                );
+            }
+            let now = unix_now();
+            for status in &statuses {
+                let (Some(size), Some(modified)) = (status.size, status.modified) else {
+                    println!("{:<13} {}", status.file.bold(), "not installed".red());
+                    continue;
+                };
+                let remote = match (status.freshness, &status.error) {
+                    (Some(Freshness::UpToDate), _) => "up to date".green(),
+                    (Some(Freshness::UpdateAvailable), _) => {
+                        "update available, run `mihoro update --geodata`".yellow()
+                    }
+                    (Some(Freshness::Unknown), _) => "update unknown".normal(),
+                    (None, Some(e)) => format!("check failed: {}", e).red(),
+                    (None, None) if status.url.is_none() => "no source configured".normal(),
+                    (None, None) => "".normal(),
+                };
+                println!(
+                    "{:<13} {:>10}  modified {}  {}",
+                    status.file.bold(),
+                    format_bytes(size),
+                    format_age(now.saturating_sub(modified)),
+                    remote
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_conditional_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        let validators = CacheValidators::from_headers(&headers);
+        assert_eq!(validators.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(validators.last_modified, None);
+
+        let conditional = validators.conditional_headers();
+        assert_eq!(conditional.get(IF_NONE_MATCH).unwrap(), "\"abc\"");
+        assert!(conditional.get(IF_MODIFIED_SINCE).is_none());
+        assert!(CacheValidators::default().conditional_headers().is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("geoip.dat");
+        let validators = CacheValidators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2026 07:28:00 GMT".to_string()),
+        };
+
+        // Validators are ignored while the file itself is missing
+        validators.save(&path)?;
+        assert_eq!(CacheValidators::load(&path), CacheValidators::default());
+
+        fs::write(&path, "geoip")?;
+        assert_eq!(CacheValidators::load(&path), validators);
+
+        CacheValidators::default().save(&path)?;
+        assert!(!dir.path().join("geoip.dat.cache.json").exists());
+        assert_eq!(CacheValidators::load(&path), CacheValidators::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_freshness() {
+        let stored = CacheValidators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+        };
+        let headers = |etag: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ETAG, HeaderValue::from_static(etag));
+            headers
+        };
+        let check = |status, headers: &HeaderMap, stored| freshness(status, headers, stored);
+
+        assert_eq!(
+            check(StatusCode::NOT_MODIFIED, &HeaderMap::new(), &stored),
+            Freshness::UpToDate
+        );
+        // A server ignoring If-None-Match still sends the same ETag
+        assert_eq!(
+            check(StatusCode::OK, &headers("\"abc\""), &stored),
+            Freshness::UpToDate
+        );
+        assert_eq!(
+            check(StatusCode::OK, &headers("\"def\""), &stored),
+            Freshness::UpdateAvailable
+        );
+        assert_eq!(
+            check(StatusCode::OK, &HeaderMap::new(), &stored),
+            Freshness::Unknown
+        );
+        assert_eq!(
+            check(
+                StatusCode::OK,
+                &headers("\"abc\""),
+                &CacheValidators::default()
+            ),
+            Freshness::Unknown
+        );
+    }
+
+    #[test]
+    fn test_local_status() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("geoip.dat"), "geoip")?;
+        fs::write(dir.path().join("ASN.mmdb"), "asn")?;
+        let sources = [
+            GeodataSource {
+                file: "geoip.dat",
+                url: "https://example.com/geoip.dat".to_string(),
+            },
+            GeodataSource {
+                file: "geosite.dat",
+                url: "https://example.com/geosite.dat".to_string(),
+            },
+        ];
+
+        let statuses = local_status(dir.path(), &sources);
+        let files: Vec<_> = statuses.iter().map(|status| status.file).collect();
+        assert_eq!(files, ["geoip.dat", "geosite.dat", "ASN.mmdb"]);
+
+        assert!(statuses[0].installed);
+        assert_eq!(statuses[0].size, Some(5));
+        assert!(statuses[0].modified.is_some());
+
+        // Missing files are reported as not installed
+        assert!(!statuses[1].installed);
+        assert_eq!(statuses[1].size, None);
+
+        // Files present without a configured source are listed too
+        assert!(statuses[2].installed);
+        assert_eq!(statuses[2].url, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mihomo_version() {
+        assert_eq!(
+            parse_mihomo_version("Mihomo Meta v1.19.0 linux amd64 with go1.23.4"),
+            Some((1, 19, 0))
+        );
+        assert_eq!(
+            parse_mihomo_version("Clash Meta v1.16.0-rc1 linux amd64"),
+            Some((1, 16, 0))
+        );
+        assert_eq!(
+            parse_mihomo_version("Mihomo Meta alpha-1a2b3c4 linux amd64"),
+            None
+        );
+    }
+
+    /// Test which mihomo versions load each `GEOIP` format
+    #[test]
+    fn test_geoip_format_version() {
+        let cases = [
+            (GeoipFormat::Dat, Some((1, 10, 0)), true),
+            (GeoipFormat::Mmdb, Some((1, 10, 0)), true),
+            (GeoipFormat::Metadb, Some((1, 16, 9)), false),
+            (GeoipFormat::Metadb, Some((1, 17, 0)), true),
+            (GeoipFormat::Metadb, Some((1, 19, 0)), true),
+            (GeoipFormat::Metadb, None, true),
+        ];
+        for (format, version, supported) in cases {
+            assert_eq!(
+                format.supported_by(version),
+                supported,
+                "{:?} {:?}",
+                format,
+                version
+            );
+        }
+
+        assert_eq!(
+            GeoipFormat::of_mmdb_url("https://example.com/geoip.metadb?token=1"),
+            GeoipFormat::Metadb
+        );
+        assert_eq!(
+            GeoipFormat::of_mmdb_url("https://example.com/country.mmdb"),
+            GeoipFormat::Mmdb
+        );
+    }
+
+    #[test]
+    fn test_check_formats() -> Result<()> {
+        let dir = tempdir()?;
+        let metadb = GeodataSource {
+            file: "geoip.metadb",
+            url: "https://example.com/geoip.metadb".to_string(),
+        };
+
+        // Too old for geoip.metadb, which is dropped
+        let config = Config::default();
+        let mut sources = vec![metadb.clone()];
+        let warnings = check_formats(
+            &config,
+            &mut sources,
+            Some("Clash Meta v1.16.0 linux amd64"),
+            dir.path(),
+        );
+        assert!(sources.is_empty());
+        assert!(warnings[0].contains("needs mihomo v1.17.0 or later"));
+        assert!(warnings[1].contains("GEOIP rules need country.mmdb"));
+
+        // A leftover country.mmdb shadows geoip.metadb
+        fs::write(dir.path().join("country.mmdb"), "mmdb")?;
+        let mut sources = vec![metadb];
+        let warnings = check_formats(&config, &mut sources, None, dir.path());
+        assert_eq!(sources.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("remove it"));
+
+        // `geodata-mode` set in overrides needs the .dat files
+        let config: Config = toml::from_str(
+            r#"
+            [overrides]
+            geodata-mode = true
+            geodata-loader = "fast"
+            "#,
+        )?;
+        let mut sources = vec![];
+        let warnings = check_formats(&config, &mut sources, None, dir.path());
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("needs geoip.dat"));
+        assert!(warnings[1].contains("needs geosite.dat"));
+        assert!(warnings[2].contains("unknown `geodata-loader` \"fast\""));
+
+        let config: Config = toml::from_str("overrides = { geodata-loader = \"standard\" }")?;
+        let warnings = check_formats(&config, &mut vec![], None, dir.path());
+        assert_eq!(
+            warnings,
+            ["`geodata-loader` only applies in `geodata_mode`, which is off"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::log::info;
+use crate::mihoro::Mihoro;
+
+use std::process::Command;
+
+use colored::Colorize;
+
+/// When an update hook runs around `mihoro update`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookStage {
+    /// Before anything is downloaded, `pre_update_hook`
+    PreUpdate,
+    /// After the update succeeded and the service restarted, `post_update_hook`
+    PostUpdate,
+}
+
+impl HookStage {
+    /// Config key holding the hook's shell command.
+    fn key(self) -> &'static str {
+        match self {
+            HookStage::PreUpdate => "pre_update_hook",
+            HookStage::PostUpdate => "post_update_hook",
+        }
+    }
+}
+
+/// Build the shell command running a hook, with the updated `component` (`config`, `core`,
+/// `geodata` or `all`), the mihoro version and the installed mihomo version in its environment.
+fn hook_command(command: &str, component: &str, mihomo_version: Option<&str>) -> Command {
+    let mut hook = Command::new("sh");
+    hook.arg("-c")
+        .arg(command)
+        .env("MIHORO_UPDATED_COMPONENT", component)
+        .env("MIHORO_VERSION", env!("CARGO_PKG_VERSION"));
+    if let Some(mihomo_version) = mihomo_version {
+        hook.env("MIHOMO_VERSION", mihomo_version);
+    }
+    hook
+}
+
+/// Run the configured hook for `stage`, if any, printing its output.
+///
+/// A hook that fails to start or exits non-zero only prints a warning, it never fails the update.
+pub fn run_update_hook(mihoro: &Mihoro, stage: HookStage, component: &str) {
+    let hook = match stage {
+        HookStage::PreUpdate => &mihoro.config.pre_update_hook,
+        HookStage::PostUpdate => &mihoro.config.post_update_hook,
+    };
+    let Some(command) = hook.as_deref().filter(|command| !command.trim().is_empty()) else {
+        return;
+    };
+    if mihoro.skip_in_dry_run(&format!("run {} `{}`", stage.key(), command)) {
+        return;
+    }
+
+    info!("{} Running {}...", mihoro.prefix.cyan(), stage.key().bold());
+    let mihomo_version = mihoro.mihomo_version();
+    let output = match hook_command(command, component, mihomo_version.as_deref()).output() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!(
+                "{} Failed to run {}: {}",
+                mihoro.prefix.yellow(),
+                stage.key(),
+                e
+            );
+            return;
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().chain(stderr.lines()) {
+        info!("{} {}", "->".dimmed(), line);
+    }
+    if !output.status.success() {
+        eprintln!(
+            "{} {} exited with {}, continuing",
+            mihoro.prefix.yellow(),
+            stage.key(),
+            output.status
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_command_env() {
+        let output = hook_command(
+            "echo $MIHORO_UPDATED_COMPONENT $MIHORO_VERSION $MIHOMO_VERSION",
+            "core",
+            Some("Mihomo Meta v1.19.0"),
+        )
+        .output()
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("core {} Mihomo Meta v1.19.0\n", env!("CARGO_PKG_VERSION"))
+        );
+
+        let output = hook_command("exit 3", "config", None).output().unwrap();
+        assert_eq!(output.status.code(), Some(3));
+    }
+}
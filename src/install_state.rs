@@ -0,0 +1,251 @@
+use crate::config::MihomoChannel;
+use crate::utils::{atomic_write, create_parent_dir, xdg_dir};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// The mihomo binary mihoro last installed, the release channel, version and architecture it came
+/// from, and when it was installed, as a Unix timestamp.
+///
+/// Version strings can't tell the channels apart reliably, so this is what detects a switch of
+/// `mihomo_channel` that needs a reinstall, and lets `update --core` skip a binary that is already
+/// current.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstallState {
+    pub binary: String,
+    pub channel: MihomoChannel,
+    /// Release tag, unknown for binaries from `remote_mihomo_binary_url`
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub arch: Option<String>,
+    #[serde(default)]
+    pub installed_at: u64,
+}
+
+impl InstallState {
+    /// `$XDG_STATE_HOME/mihoro/install.json`, or `~/.local/state/mihoro/install.json`.
+    pub fn path() -> Result<PathBuf> {
+        Ok(
+            xdg_dir(&|var| env::var(var).ok(), "STATE", ".local/state")?
+                .join("mihoro/install.json"),
+        )
+    }
+
+    /// Load the state at `path`, or `None` if it is missing or unreadable.
+    pub fn load(path: &Path) -> Option<InstallState> {
+        let raw = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&raw).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        create_parent_dir(path)?;
+        atomic_write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// The channel `binary` was installed from, if it differs from `configured`.
+    ///
+    /// Binaries installed before the channel was recorded, or by something else, are assumed to
+    /// match, as there is nothing to compare.
+    pub fn changed_channel(
+        &self,
+        binary: &str,
+        configured: &MihomoChannel,
+    ) -> Option<&MihomoChannel> {
+        (self.binary == binary && self.channel != *configured).then_some(&self.channel)
+    }
+
+    /// One line summary like `v1.19.0 (stable, amd64), installed 3 days ago`, at `now`.
+    pub fn summary(&self, now: u64) -> String {
+        let mut details = vec![self.channel.as_str()];
+        details.extend(self.arch.as_deref());
+        let mut summary = format!(
+            "{} ({})",
+            self.version.as_deref().unwrap_or("unknown version"),
+            details.join(", ")
+        );
+        if self.installed_at > 0 {
+            summary.push_str(&format!(
+                ", installed {}",
+                format_age(now.saturating_sub(self.installed_at))
+            ));
+        }
+        summary
+    }
+}
+
+/// Whether the installed binary already is version `latest` for `arch` from `channel`, so
+/// `update --core` can skip downloading it.
+///
+/// The binary's `state` is trusted if recorded, otherwise `reported`, the output of `mihomo -v`,
+/// must mention `latest`.
+pub fn is_current(
+    state: Option<&InstallState>,
+    reported: Option<&str>,
+    channel: &MihomoChannel,
+    latest: &str,
+    arch: Option<&str>,
+) -> bool {
+    match state {
+        Some(state) => {
+            state.channel == *channel
+                && state.version.as_deref() == Some(latest)
+                && (arch.is_none() || state.arch.as_deref() == arch)
+        }
+        None => {
+            reported.is_some_and(|reported| reported.split_whitespace().any(|word| word == latest))
+        }
+    }
+}
+
+/// Rough age like `just now`, `5 minutes ago` or `3 days ago`.
+pub fn format_age(secs: u64) -> String {
+    let (count, unit) = match secs {
+        0..60 => return String::from("just now"),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn state(channel: MihomoChannel, version: Option<&str>, arch: Option<&str>) -> InstallState {
+        InstallState {
+            binary: "/home/me/.local/bin/mihomo".to_string(),
+            channel,
+            version: version.map(String::from),
+            arch: arch.map(String::from),
+            installed_at: 1000,
+        }
+    }
+
+    #[test]
+    fn test_changed_channel() {
+        let state = state(MihomoChannel::Alpha, None, None);
+        assert_eq!(
+            state.changed_channel("/home/me/.local/bin/mihomo", &MihomoChannel::Stable),
+            Some(&MihomoChannel::Alpha)
+        );
+        assert_eq!(
+            state.changed_channel("/home/me/.local/bin/mihomo", &MihomoChannel::Alpha),
+            None
+        );
+        // Another binary's channel is unknown
+        assert_eq!(
+            state.changed_channel("/usr/local/bin/mihomo", &MihomoChannel::Stable),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_current() {
+        let stable = MihomoChannel::Stable;
+        let installed = state(MihomoChannel::Stable, Some("v1.19.0"), Some("amd64"));
+        assert!(is_current(
+            Some(&installed),
+            None,
+            &stable,
+            "v1.19.0",
+            Some("amd64")
+        ));
+        assert!(is_current(Some(&installed), None, &stable, "v1.19.0", None));
+        assert!(!is_current(
+            Some(&installed),
+            None,
+            &stable,
+            "v1.19.1",
+            Some("amd64")
+        ));
+        // Another architecture or channel needs a download, even with the same version
+        assert!(!is_current(
+            Some(&installed),
+            None,
+            &stable,
+            "v1.19.0",
+            Some("arm64")
+        ));
+        assert!(!is_current(
+            Some(&installed),
+            None,
+            &MihomoChannel::Alpha,
+            "v1.19.0",
+            Some("amd64")
+        ));
+        // A binary from a custom url has no known version
+        let custom = state(MihomoChannel::Stable, None, None);
+        assert!(!is_current(Some(&custom), None, &stable, "v1.19.0", None));
+
+        // Without a record, the version reported by `mihomo -v` is used
+        let reported = "Mihomo Meta v1.19.0 linux amd64 with go1.23.4";
+        assert!(is_current(
+            None,
+            Some(reported),
+            &stable,
+            "v1.19.0",
+            Some("amd64")
+        ));
+        assert!(!is_current(
+            None,
+            Some(reported),
+            &stable,
+            "v1.19.1",
+            Some("amd64")
+        ));
+        assert!(!is_current(None, None, &stable, "v1.19.0", Some("amd64")));
+    }
+
+    #[test]
+    fn test_summary() {
+        let installed = state(MihomoChannel::Alpha, Some("alpha-abc123"), Some("arm64"));
+        assert_eq!(
+            installed.summary(1000 + 3 * 86400),
+            "alpha-abc123 (alpha, arm64), installed 3 days ago"
+        );
+        assert_eq!(
+            installed.summary(1030),
+            "alpha-abc123 (alpha, arm64), installed just now"
+        );
+        let legacy = InstallState {
+            installed_at: 0,
+            ..state(MihomoChannel::Stable, None, None)
+        };
+        assert_eq!(legacy.summary(1000), "unknown version (stable)");
+        assert_eq!(format_age(3600), "1 hour ago");
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro/install.json");
+        assert_eq!(InstallState::load(&path), None);
+
+        let installed = state(MihomoChannel::Stable, Some("v1.19.0"), Some("amd64"));
+        installed.save(&path)?;
+        assert_eq!(InstallState::load(&path), Some(installed));
+
+        // Records from before versions were tracked still load
+        fs::write(
+            &path,
+            r#"{"binary": "/usr/bin/mihomo", "channel": "alpha"}"#,
+        )?;
+        assert_eq!(
+            InstallState::load(&path).map(|state| state.channel),
+            Some(MihomoChannel::Alpha)
+        );
+        Ok(())
+    }
+}
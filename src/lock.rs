@@ -0,0 +1,95 @@
+use crate::log::info;
+use crate::utils::{create_parent_dir, xdg_dir};
+
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+
+/// An advisory `flock` on the lockfile, held while a command changes files.
+///
+/// The lock is released when this is dropped, or by the kernel if mihoro exits without unwinding.
+#[derive(Debug)]
+pub struct OperationLock {
+    _file: File,
+}
+
+/// `$XDG_CACHE_HOME/mihoro/mihoro.lock`, or `~/.cache/mihoro/mihoro.lock`.
+pub fn path() -> Result<PathBuf> {
+    Ok(xdg_dir(&|var| env::var(var).ok(), "CACHE", ".cache")?.join("mihoro/mihoro.lock"))
+}
+
+/// Take the lock at `path`, waiting for its holder to release it if `wait`, or failing otherwise.
+pub fn acquire(path: &Path, wait: bool) -> Result<OperationLock> {
+    create_parent_dir(path)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("failed to open lockfile {}", path.to_string_lossy()))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let holder = holder_pid(path)
+                .map(|pid| format!(" (pid {})", pid))
+                .unwrap_or_default();
+            if !wait {
+                bail!(
+                    "another mihoro operation is in progress{}, retry when it finishes or pass \
+                     --wait",
+                    holder
+                );
+            }
+            info!(
+                "{} Waiting for another mihoro operation{} to finish...",
+                "mihoro:".yellow(),
+                holder
+            );
+            file.lock()
+                .with_context(|| format!("failed to lock {}", path.to_string_lossy()))?;
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("failed to lock {}", path.to_string_lossy()))
+        }
+    }
+
+    // Record the holder for the message above, the lock itself does not depend on it
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(OperationLock { _file: file })
+}
+
+/// Pid of the process holding the lock at `path`, as recorded by `acquire`.
+fn holder_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_acquire_excludes_and_releases() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro/mihoro.lock");
+
+        let lock = acquire(&path, false)?;
+        assert_eq!(holder_pid(&path), Some(std::process::id()));
+        let err = acquire(&path, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("another mihoro operation is in progress"));
+
+        // Dropping the lock, as on any return from `cli`, releases it
+        drop(lock);
+        acquire(&path, false)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much status output to print, set once from `-q/--quiet` and `-v/--verbose`.
+///
+/// Errors are always printed. Requested output, such as `mihoro status` or `mihoro proxy export`,
+/// is printed regardless of the level, only status messages around it are affected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Verbosity {
+    /// Errors only (`-q`)
+    Quiet,
+    /// Status messages, the default
+    Normal,
+    /// Resolved urls, file paths, HTTP statuses and executed commands (`-v`)
+    Debug,
+    /// Additionally, output captured from commands and response headers (`-vv`)
+    Trace,
+}
+
+impl Verbosity {
+    /// Map the `-q` flag and the number of `-v` flags to a level.
+    pub fn from_flags(quiet: bool, verbose: u8) -> Verbosity {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Debug,
+            (false, _) => Verbosity::Trace,
+        }
+    }
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Whether messages at `level` are printed.
+pub fn enabled(level: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Whether colored output is used, given `--no-color` and whether both stdout and stderr are
+/// terminals.
+///
+/// Colors are off with `--no-color`, a non-empty `NO_COLOR`, or when output is captured, e.g. by
+/// journald or CI, unless `CLICOLOR_FORCE` asks for them anyway.
+pub fn use_color(no_color: bool, get_env: impl Fn(&str) -> Option<String>, terminal: bool) -> bool {
+    let set = |var| get_env(var).is_some_and(|value| !value.is_empty());
+    if no_color || set("NO_COLOR") {
+        return false;
+    }
+    terminal || get_env("CLICOLOR_FORCE").is_some_and(|value| value != "0" && !value.is_empty())
+}
+
+/// Print a status message to stdout, unless `--quiet`.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Verbosity::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Print a status message to stderr, unless `--quiet`, keeping stdout clean for requested output.
+macro_rules! einfo {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Verbosity::Normal) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Print a debug message to stderr with `-v`.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Verbosity::Debug) {
+            eprintln!("{} {}", colored::Colorize::dimmed("debug:"), format_args!($($arg)*));
+        }
+    };
+}
+
+/// Print a trace message to stderr with `-vv`.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Verbosity::Trace) {
+            eprintln!("{} {}", colored::Colorize::dimmed("trace:"), format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {debug, einfo, info, trace};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_from_flags() {
+        assert_eq!(Verbosity::from_flags(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(false, 1), Verbosity::Debug);
+        assert_eq!(Verbosity::from_flags(false, 2), Verbosity::Trace);
+        assert_eq!(Verbosity::from_flags(false, 5), Verbosity::Trace);
+        assert_eq!(Verbosity::from_flags(true, 0), Verbosity::Quiet);
+        assert!(Verbosity::Quiet < Verbosity::Normal && Verbosity::Debug < Verbosity::Trace);
+    }
+
+    #[test]
+    fn test_use_color() {
+        let unset = |_: &str| None;
+        assert!(use_color(false, unset, true));
+        assert!(!use_color(true, unset, true));
+        assert!(!use_color(false, unset, false));
+
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert!(!use_color(false, env(&[("NO_COLOR", "1")]), true));
+        // An empty NO_COLOR is ignored, per https://no-color.org
+        assert!(use_color(false, env(&[("NO_COLOR", "")]), true));
+        assert!(use_color(false, env(&[("CLICOLOR_FORCE", "1")]), false));
+        assert!(!use_color(false, env(&[("CLICOLOR_FORCE", "0")]), false));
+        assert!(!use_color(
+            false,
+            env(&[("CLICOLOR_FORCE", "1"), ("NO_COLOR", "1")]),
+            false
+        ));
+    }
+}
@@ -1,44 +1,203 @@
+mod api;
+mod applied_config;
+mod backup;
+mod benchmark;
+mod bundle;
 mod cmd;
+mod completions;
 mod config;
 mod cron;
+mod dashboard;
+mod doctor;
+mod edit;
+mod error;
+mod geodata;
+mod hooks;
+mod install_state;
+mod lock;
+mod log;
 mod mihoro;
+mod notify;
 mod proxy;
+mod report;
 mod resolve_mihomo_bin;
+mod rules;
+mod runner;
+mod self_path;
+mod subscription;
 mod systemctl;
 #[cfg(feature = "self_update")]
 mod upgrade;
 mod utils;
+mod version_cache;
+mod watch;
 
-use anyhow::Result;
-use clap::{CommandFactory, Parser};
-use clap_complete::{
-    generate,
-    shells::{Bash, Fish, Zsh},
-};
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use clap_complete::Shell;
 use colored::Colorize;
-use reqwest::Client;
-use std::{io, process::Command};
+use serde_json::json;
+use std::env;
+use std::io::{self, IsTerminal, Write};
 
-use cmd::{Args, ClapShell, Commands};
+use bundle::{Bundle, BUNDLE_CONFIG};
+use cmd::{ArchCommands, Args, Commands, GeodataCommands, OutputFormat, RestartMethod};
+use config::{default_mihoro_config_path, init_config};
+use error::ErrorKind;
+use hooks::HookStage;
+use log::{debug, info, Verbosity};
 use mihoro::Mihoro;
-use systemctl::Systemctl;
+use subscription::{load_subscription_info, print_subscription_info};
+use utils::expand_path;
 
 #[tokio::main]
 async fn main() {
     if let Err(err) = cli().await {
-        eprintln!("{} {}", "error:".bright_red().bold(), err);
-        std::process::exit(1);
+        // Include the causes, e.g. why a download behind "failed to fetch version" failed
+        eprintln!("{} {:#}", "error:".bright_red().bold(), err);
+        if let Some(hint) = error::kind(&err).and_then(ErrorKind::hint) {
+            eprintln!("{} {}", "hint:".cyan().bold(), hint);
+        }
+        std::process::exit(error::exit_code(&err));
     }
 }
 
+/// Print a value as pretty JSON to stdout.
+fn print_json(value: &serde_json::Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
 async fn cli() -> Result<()> {
+    // Run by the completion scripts on every tab press, so it must not fail or create a config.
+    // It is not a subcommand, as generated completions would offer even a hidden one.
+    if let Some(kind) = completions::requested_candidates(env::args()) {
+        completions::print_candidates(kind, &default_mihoro_config_path());
+        return Ok(());
+    }
+
     let args = Args::parse();
-    let client = Client::new();
-    let mihoro = Mihoro::new(&args.mihoro_config)?;
+    log::set_verbosity(Verbosity::from_flags(args.quiet, args.verbose));
+
+    // Suppress colored output for machine-readable output and non-terminals. This also covers
+    // errors printed by `main`, as the override is global.
+    let terminal = io::stdout().is_terminal() && io::stderr().is_terminal();
+    colored::control::set_override(
+        args.output == OutputFormat::Human
+            && log::use_color(args.no_color, |var| env::var(var).ok(), terminal),
+    );
+
+    // Doctor diagnoses the config itself, so it must run before the config is parsed
+    if let Some(Commands::Doctor) = &args.command {
+        return doctor::doctor(&args.mihoro_config, args.output);
+    }
+
+    // Nor does locating mihoro itself, which helps when `upgrade` cannot replace it
+    if let Some(Commands::Where) = &args.command {
+        return self_path::print_self_path(args.output);
+    }
+
+    // Nor does listing architectures, which helps to fill in `mihomo_arch`
+    if let Some(Commands::Arch { arch }) = &args.command {
+        return match arch {
+            Some(ArchCommands::List) => resolve_mihomo_bin::print_arch_list(args.output),
+            None => Ok(()),
+        };
+    }
+
+    // Held until `cli` returns, including on errors
+    let _lock = if args.takes_lock() {
+        Some(lock::acquire(&lock::path()?, args.wait)?)
+    } else {
+        None
+    };
+
+    if let Some(Commands::Init { force }) = &args.command {
+        let config_path = expand_path(&args.mihoro_config);
+        init_config(&config_path, *force, args.dry_run)?;
+        if args.dry_run {
+            return Ok(());
+        }
+        info!(
+            "{} Created default config at {}",
+            "mihoro:".green(),
+            config_path.to_string_lossy().underline().yellow()
+        );
+        info!(
+            "{} Fill in {}, e.g. with {}, then run {}",
+            "->".dimmed(),
+            "remote_config_url".bold(),
+            "mihoro edit".bold().underline(),
+            "mihoro setup".bold().underline()
+        );
+        return Ok(());
+    }
+
+    // Likewise, editing mihoro.toml must work even when it fails to parse
+    if let Some(Commands::Edit { mihomo: false }) = &args.command {
+        return edit::edit_mihoro_config(&args.mihoro_config, "mihoro:");
+    }
+
+    // A bundle brings its own mihoro.toml, which must be in place before it is parsed. Dry runs
+    // read it from the unpacked bundle instead.
+    let bundle = match &args.command {
+        Some(Commands::Setup {
+            from_backup: Some(path),
+            ..
+        }) => Some(Bundle::open(&expand_path(path))?),
+        _ => None,
+    };
+    let mihoro_config = match &bundle {
+        Some(bundle) if args.dry_run => bundle.file(BUNDLE_CONFIG).to_string_lossy().into_owned(),
+        Some(bundle) => {
+            bundle.install_config(&expand_path(&args.mihoro_config), "mihoro:")?;
+            args.mihoro_config.clone()
+        }
+        None => args.mihoro_config.clone(),
+    };
+
+    let mihoro = Mihoro::new(&mihoro_config)?
+        .with_dry_run(args.dry_run)
+        .with_github_mirror(args.mirror.clone())
+        .with_network_timeout(args.timeout)
+        .with_no_proxy(args.no_proxy)
+        .with_ip_version(args.force_ipv4, args.force_ipv6)
+        .with_systemd_dir(args.systemd_dir.clone())
+        .with_output(args.output);
 
     match &args.command {
-        Some(Commands::Setup { overwrite, arch }) => {
-            mihoro.setup(client, *overwrite, arch.as_deref()).await?
+        Some(Commands::Setup {
+            overwrite,
+            arch,
+            optimize_arch,
+            version,
+            refresh,
+            no_verify,
+            config_url,
+            minimal,
+            skip_service,
+            from_backup: _,
+        }) => {
+            let mihoro = mihoro
+                .with_arch_autotune(*optimize_arch)
+                .with_version_refresh(*refresh)
+                .with_skip_geodata(*minimal)
+                .with_skip_service(*skip_service);
+            match &bundle {
+                Some(bundle) => mihoro.setup_from_bundle(bundle)?,
+                None => {
+                    mihoro
+                        .setup(
+                            mihoro.http_client()?,
+                            *overwrite,
+                            arch.as_deref(),
+                            version.as_deref(),
+                            !*no_verify,
+                            config_url.as_deref(),
+                        )
+                        .await?
+                }
+            }
         }
         Some(Commands::Update {
             config,
@@ -46,119 +205,424 @@ async fn cli() -> Result<()> {
             geodata,
             all,
             arch,
+            optimize_arch,
+            version,
+            refresh,
+            no_verify,
+            force,
+            if_changed,
+            reload,
+            restart,
+            no_restart,
+            config_url,
+            notify,
+            no_notify,
         }) => {
-            if *all {
-                // Update config (without restarting yet)
-                println!(
-                    "{} Updating config...",
-                    mihoro.prefix.magenta().bold().italic()
-                );
-                if let Err(e) = mihoro.update_config(&client, false).await {
-                    eprintln!("{} Failed to update config: {}", mihoro.prefix.yellow(), e);
+            let mihoro = mihoro
+                .with_notify(*notify, *no_notify)
+                .with_restart_method(RestartMethod::from_flags(*reload, *restart))
+                .with_arch_autotune(*optimize_arch)
+                .with_version_refresh(*refresh);
+            let client = mihoro.http_client()?;
+            let component = match (*all, *core, *geodata) {
+                (true, _, _) => "all",
+                (_, true, _) => "core",
+                (_, _, true) => "geodata",
+                _ => "config",
+            };
+            hooks::run_update_hook(&mihoro, HookStage::PreUpdate, component);
+            // Components that failed without failing `--all`, for the notification
+            let mut failed = vec![];
+            let result = async {
+                if *all {
+                    // Update config, geodata and core concurrently (without restarting yet),
+                    // keeping going if one of them fails
+                    info!(
+                        "{} Updating config, geodata and core...",
+                        mihoro.prefix.magenta().bold().italic()
+                    );
+                    let (config_result, geodata_result, core_result) = tokio::join!(
+                        mihoro.update_config(&client, false, *force, false, config_url.as_deref()),
+                        mihoro.update_geodata_unless_skipped(&client, *force),
+                        mihoro.update_core(
+                            &client,
+                            arch.as_deref(),
+                            version.as_deref(),
+                            !*no_verify,
+                            *force,
+                            false,
+                        ),
+                    );
+                    let (core_result, core_update) = match core_result {
+                        Ok(update) => (Ok(()), update),
+                        Err(e) => (Err(e), None),
+                    };
+                    for (name, result) in [
+                        ("config", config_result),
+                        ("geodata", geodata_result),
+                        ("core", core_result),
+                    ] {
+                        if let Err(e) = result {
+                            eprintln!(
+                                "{} Failed to update {}: {}",
+                                mihoro.prefix.yellow(),
+                                name,
+                                e
+                            );
+                            failed.push(format!("{}: {:#}", name, e));
+                        }
+                    }
+                    // Restart service once at the end
+                    if !*no_restart {
+                        mihoro.validate_before_restart(*force)?;
+                        info!(
+                            "{} Restarting {}...",
+                            mihoro.prefix.green().bold().italic(),
+                            mihoro.config.service_name
+                        );
+                        // A new core gets the same health check and rollback as `update --core`
+                        match &core_update {
+                            Some(update) => {
+                                mihoro.restart_onto_core(update)?;
+                                mihoro.record_applied_config();
+                            }
+                            None => {
+                                let status = mihoro
+                                    .systemctl()
+                                    .restart(&mihoro.config.service_name)
+                                    .execute()?;
+                                if status.success() {
+                                    mihoro.record_applied_config();
+                                }
+                            }
+                        }
+                    }
+                } else if *core {
+                    mihoro
+                        .update_core(
+                            &client,
+                            arch.as_deref(),
+                            version.as_deref(),
+                            !*no_verify,
+                            *force,
+                            !*no_restart,
+                        )
+                        .await?;
+                } else if *geodata {
+                    mihoro.update_geodata(&client, *force).await?;
+                } else if *config || (!*core && !*geodata) {
+                    // Explicit --config or default (no flags)
+                    mihoro
+                        .update_config(
+                            &client,
+                            !*no_restart,
+                            *force,
+                            *if_changed,
+                            config_url.as_deref(),
+                        )
+                        .await?;
                 }
-                // Update geodata
-                println!(
-                    "{} Updating geodata...",
-                    mihoro.prefix.magenta().bold().italic()
-                );
-                if let Err(e) = mihoro.update_geodata(&client).await {
-                    eprintln!("{} Failed to update geodata: {}", mihoro.prefix.yellow(), e);
+                // `--geodata` alone never restarts, so there is nothing left pending
+                if *no_restart && !*geodata {
+                    info!(
+                        "{} Not restarting {}, run `mihoro apply --no-download` to load the update",
+                        mihoro.prefix.yellow(),
+                        mihoro.config.service_name
+                    );
                 }
-                // Update core (without restarting yet)
-                println!(
-                    "{} Updating core...",
-                    mihoro.prefix.magenta().bold().italic()
-                );
-                if let Err(e) = mihoro.update_core(&client, arch.as_deref(), false).await {
-                    eprintln!("{} Failed to update core: {}", mihoro.prefix.yellow(), e);
+                hooks::run_update_hook(&mihoro, HookStage::PostUpdate, component);
+                anyhow::Ok(())
+            }
+            .await;
+            notify::notify_update(&mihoro, component, result.as_ref().err(), &failed);
+            result?;
+        }
+        Some(Commands::Apply {
+            diff: true,
+            merge_file,
+            ..
+        }) => mihoro.with_merge_files(merge_file).apply_diff()?,
+        Some(Commands::Apply {
+            force,
+            diff: false,
+            if_changed,
+            reload,
+            restart,
+            merge_file,
+            no_download: false,
+        }) => {
+            mihoro
+                .with_merge_files(merge_file)
+                .with_restart_method(RestartMethod::from_flags(*reload, *restart))
+                .apply(*force, *if_changed)
+                .await?
+        }
+        Some(Commands::Apply {
+            force,
+            merge_file,
+            no_download: true,
+            ..
+        }) => mihoro
+            .with_merge_files(merge_file)
+            .apply_no_download(*force)?,
+        Some(Commands::Restore { backup }) => mihoro.restore(backup.as_deref())?,
+        Some(Commands::Uninstall { yes, keep, purge }) => {
+            mihoro.uninstall(&args.mihoro_config, *yes, *keep, *purge)?
+        }
+        Some(Commands::Init { .. })
+        | Some(Commands::Doctor)
+        | Some(Commands::Where)
+        | Some(Commands::Arch { .. }) => {
+            unreachable!("handled before parsing config")
+        }
+        Some(Commands::TestUrl { url }) => {
+            mihoro
+                .test_url(&mihoro.http_client()?, url.as_deref())
+                .await?
+        }
+        Some(Commands::Proxies { proxies }) => api::proxies_commands(&mihoro, proxies).await?,
+        Some(Commands::Dashboard { ui, open }) => dashboard::dashboard(&mihoro, *ui, *open)?,
+        Some(Commands::Benchmark { mirrors, save }) => {
+            benchmark::benchmark(&mihoro, mirrors, *save, &args.mihoro_config).await?
+        }
+        Some(Commands::Edit { mihomo: false }) => unreachable!("handled before parsing config"),
+        Some(Commands::Edit { mihomo: true }) => edit::edit_mihomo_config(&mihoro).await?,
+        Some(Commands::Version) => {
+            let mihomo_version = mihoro.mihomo_version();
+            let channel = mihoro.config.mihomo_channel.as_str();
+            match args.output {
+                OutputFormat::Json => print_json(&json!({
+                    "mihoro_version": env!("CARGO_PKG_VERSION"),
+                    "git_commit": env!("MIHORO_GIT_COMMIT"),
+                    "rustc_version": env!("MIHORO_RUSTC_VERSION"),
+                    "target": env!("MIHORO_TARGET"),
+                    "mihomo_version": mihomo_version,
+                    "mihomo_channel": channel,
+                    "mihomo_install": mihoro.install_state(),
+                }))?,
+                OutputFormat::Human => {
+                    println!(
+                        "mihoro {} ({})",
+                        env!("CARGO_PKG_VERSION").bold(),
+                        env!("MIHORO_GIT_COMMIT")
+                    );
+                    println!("{:<8} {}", "rustc", env!("MIHORO_RUSTC_VERSION"));
+                    println!("{:<8} {}", "target", env!("MIHORO_TARGET"));
+                    println!(
+                        "{:<8} {}",
+                        "mihomo",
+                        mihomo_version
+                            .unwrap_or_else(|| "not installed".to_string())
+                            .bold()
+                    );
+                    println!("{:<8} {}", "channel", channel);
+                    if let Some(state) = mihoro.install_state() {
+                        println!("{:<8} {}", "install", state.summary(utils::unix_now()));
+                    }
                 }
-                // Restart service once at the end
-                println!(
-                    "{} Restarting mihomo.service...",
-                    mihoro.prefix.green().bold().italic()
-                );
-                Systemctl::new().restart("mihomo.service").execute()?;
-            } else if *core {
-                mihoro.update_core(&client, arch.as_deref(), true).await?;
-            } else if *geodata {
-                mihoro.update_geodata(&client).await?;
-            } else if *config || (!*core && !*geodata) {
-                // Explicit --config or default (no flags)
-                mihoro.update_config(&client, true).await?;
             }
         }
-        Some(Commands::Apply) => mihoro.apply().await?,
-        Some(Commands::Uninstall) => mihoro.uninstall()?,
-        Some(Commands::Proxy { proxy }) => mihoro.proxy_commands(proxy)?,
+        Some(Commands::Proxy {
+            shell,
+            no_clipboard,
+            ipv6,
+            proxy,
+        }) => mihoro.proxy_commands(proxy, *shell, *no_clipboard, *ipv6)?,
 
-        Some(Commands::Start) => Systemctl::new()
-            .start("mihomo.service")
+        Some(Commands::Start) => mihoro
+            .systemctl()
+            .start(&mihoro.config.service_name)
             .execute()
             .map(|_| {
-                println!("{} Started mihomo.service", mihoro.prefix.green());
+                info!(
+                    "{} Started {}",
+                    mihoro.prefix.green(),
+                    mihoro.config.service_name
+                );
             })?,
 
-        Some(Commands::Status) => {
-            Systemctl::new().status("mihomo.service").execute()?;
+        Some(Commands::Run { args }) => mihoro.run_foreground(args)?,
+
+        Some(Commands::Status { watch: true }) => {
+            if args.output == OutputFormat::Json {
+                anyhow::bail!("`--watch` does not support `--output json`");
+            }
+            watch::watch_status(&mihoro).await?
         }
+        Some(Commands::Status { watch: false }) => match args.output {
+            OutputFormat::Json => {
+                let active_state = mihoro
+                    .systemctl()
+                    .active_state(&mihoro.config.service_name)?;
+                let enabled = mihoro.systemctl().is_enabled(&mihoro.config.service_name)?;
+                let subscription = load_subscription_info(&mihoro.subscription_info_path());
+                print_json(&json!({
+                    "service": mihoro.config.service_name,
+                    "active_state": active_state,
+                    "active": active_state == "active",
+                    "enabled": enabled,
+                    "mihomo_version": mihoro.mihomo_version(),
+                    "mihomo_install": mihoro.install_state(),
+                    "subscription": subscription,
+                }))?;
+            }
+            OutputFormat::Human => {
+                mihoro
+                    .systemctl()
+                    .status(&mihoro.config.service_name)
+                    .execute()?;
+                if let Some(summary) = mihoro.installed_core_summary() {
+                    println!("{} {}", "mihomo:".cyan().bold(), summary);
+                }
+                if let Some(info) = load_subscription_info(&mihoro.subscription_info_path()) {
+                    print_subscription_info(&info);
+                }
+            }
+        },
 
-        Some(Commands::Stop) => Systemctl::new().stop("mihomo.service").execute().map(|_| {
-            println!("{} Stopped mihomo.service", mihoro.prefix.green());
-        })?,
+        Some(Commands::Stop) => mihoro
+            .systemctl()
+            .stop(&mihoro.config.service_name)
+            .execute()
+            .map(|_| {
+                info!(
+                    "{} Stopped {}",
+                    mihoro.prefix.green(),
+                    mihoro.config.service_name
+                );
+            })?,
 
-        Some(Commands::Restart) => {
-            Systemctl::new()
-                .restart("mihomo.service")
-                .execute()
-                .map(|_| {
-                    println!("{} Restarted mihomo.service", mihoro.prefix.green());
-                })?
-        }
+        Some(Commands::Restart) => mihoro
+            .systemctl()
+            .restart(&mihoro.config.service_name)
+            .execute()
+            .map(|_| {
+                info!(
+                    "{} Restarted {}",
+                    mihoro.prefix.green(),
+                    mihoro.config.service_name
+                );
+            })?,
 
-        Some(Commands::Log) => {
-            Command::new("journalctl")
-                .arg("--user")
-                .arg("-xeu")
-                .arg("mihomo.service")
-                .arg("-n")
-                .arg("10")
-                .arg("-f")
-                .spawn()
-                .expect("failed to execute process")
-                .wait()?;
+        Some(Commands::Log {
+            lines,
+            since,
+            export: Some(path),
+            ..
+        }) => report::export_report(
+            &mihoro,
+            &expand_path(path),
+            lines.unwrap_or(200),
+            since.as_deref(),
+        )?,
+        Some(Commands::Log {
+            lines,
+            no_follow,
+            since,
+            export: None,
+        }) => {
+            let args = mihoro.journalctl_args(lines.unwrap_or(10), !*no_follow, since.as_deref());
+            debug!("running `journalctl {}`", args.join(" "));
+            mihoro
+                .runner
+                .status("journalctl", &args)
+                .context("failed to execute journalctl")?;
         }
 
-        Some(Commands::Completions { shell }) => match shell {
-            Some(ClapShell::Bash) => {
-                generate(Bash, &mut Args::command(), "mihoro", &mut io::stdout())
-            }
-            Some(ClapShell::Zsh) => {
-                generate(Zsh, &mut Args::command(), "mihoro", &mut io::stdout())
-            }
-            Some(ClapShell::Fish) => {
-                generate(Fish, &mut Args::command(), "mihoro", &mut io::stdout())
+        Some(Commands::Completions { install, shell }) => {
+            // Fall back to detecting the current shell from $SHELL
+            let shell = match shell {
+                Some(shell) => Shell::from(*shell),
+                None => Shell::from_env().ok_or_else(|| {
+                    anyhow!("failed to detect shell from $SHELL, specify one explicitly")
+                })?,
+            };
+            if *install {
+                completions::install_completions(&mihoro, shell)?
+            } else {
+                io::stdout().write_all(&completions::completion_script(shell))?
             }
-            _ => (),
-        },
+        }
+
+        Some(Commands::Config { config }) => mihoro.config_commands(config)?,
 
+        Some(Commands::Channel { channel }) => {
+            mihoro
+                .switch_channel(
+                    &mihoro.http_client()?,
+                    &args.mihoro_config,
+                    (*channel).into(),
+                )
+                .await?
+        }
+        Some(Commands::Profile { profile }) => {
+            mihoro
+                .profile_commands(&mihoro.http_client()?, &args.mihoro_config, profile)
+                .await?
+        }
         Some(Commands::Cron { cron }) => mihoro.cron_commands(cron)?,
+        Some(Commands::Geodata {
+            geodata: Some(GeodataCommands::Status { offline }),
+        }) => geodata::status(&mihoro, *offline).await?,
+        Some(Commands::Geodata { geodata: None }) => {}
 
         #[cfg(feature = "self_update")]
-        Some(Commands::Upgrade { yes, check, target }) => {
-            if *check {
-                match upgrade::check_for_update().await? {
-                    Some(version) => {
-                        println!(
-                            "{} New version available: {}",
-                            mihoro.prefix.yellow(),
-                            version.bold().green()
-                        );
-                        println!(
-                            "{} Run {} to update",
-                            "->".dimmed(),
-                            "mihoro upgrade".bold().underline()
-                        );
-                    }
+        Some(Commands::Upgrade {
+            yes,
+            check,
+            to,
+            target,
+            verify_signature,
+        }) => {
+            if let (true, Some(to)) = (*check, to) {
+                let available = upgrade::check_version_available(
+                    mihoro.config.github_mirror.clone(),
+                    to.clone(),
+                    target.clone(),
+                    mihoro.config.github_token(),
+                )
+                .await?;
+                match args.output {
+                    OutputFormat::Json => print_json(&json!({
+                        "current_version": env!("CARGO_PKG_VERSION"),
+                        "target_version": to,
+                        "available": available,
+                    }))?,
+                    OutputFormat::Human if available => println!(
+                        "{} mihoro {} is available, run {} to install it",
+                        mihoro.prefix.green(),
+                        to.bold(),
+                        format!("mihoro upgrade --to {}", to).bold().underline()
+                    ),
+                    OutputFormat::Human => println!(
+                        "{} mihoro {} is not available for this platform",
+                        mihoro.prefix.yellow(),
+                        to.bold()
+                    ),
+                }
+            } else if *check && args.output == OutputFormat::Json {
+                let latest = upgrade::check_for_update(
+                    mihoro.config.github_mirror.clone(),
+                    mihoro.config.github_token(),
+                )
+                .await?;
+                print_json(&json!({
+                    "current_version": env!("CARGO_PKG_VERSION"),
+                    "latest_version": latest
+                        .as_ref()
+                        .map_or(env!("CARGO_PKG_VERSION"), |latest| latest.version.as_str()),
+                    "update_available": latest.is_some(),
+                    "release_notes": latest.as_ref().and_then(|latest| latest.notes.as_deref()),
+                }))?;
+            } else if *check {
+                info!("{} Checking for available updates...", mihoro.prefix.cyan());
+                match upgrade::check_for_update(
+                    mihoro.config.github_mirror.clone(),
+                    mihoro.config.github_token(),
+                )
+                .await?
+                {
+                    Some(update) => upgrade::print_available_update(&update),
                     None => {
                         println!(
                             "{} You're running the latest version",
@@ -167,7 +631,17 @@ async fn cli() -> Result<()> {
                     }
                 }
             } else {
-                upgrade::run_upgrade(*yes, target.clone()).await?;
+                upgrade::run_upgrade(
+                    *yes,
+                    target.clone(),
+                    mihoro.config.github_mirror.clone(),
+                    mihoro.show_progress(),
+                    *verify_signature,
+                    to.clone(),
+                    mihoro.config.github_token(),
+                    args.dry_run,
+                )
+                .await?;
             }
         }
 
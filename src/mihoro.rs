@@ -1,25 +1,67 @@
-use crate::cmd::{CronCommands, ProxyCommands};
-use crate::config::{apply_mihomo_override, parse_config, Config};
+use crate::api::MihomoApi;
+use crate::applied_config;
+use crate::backup::{backup_file, list_backups};
+use crate::bundle::{Bundle, BUNDLE_BINARY, BUNDLE_MIHOMO_CONFIG};
+use crate::cmd::{
+    ConfigCommands, CronCommands, CronInterval, OutputFormat, ProfileCommands, ProxyCommands,
+    RestartMethod, UninstallKeep,
+};
+use crate::config::{
+    override_mihomo_yaml, parse_config, parse_mihomo_lan, read_mihomo_allow_lan, read_mihomo_ports,
+    set_active_profile, set_mihomo_channel, validate_url, Config, CronBackend, IpVersion,
+    MihomoChannel, ReloadMethod, SystemdMode,
+};
 use crate::cron;
-use crate::proxy::{proxy_export_cmd, proxy_unset_cmd};
-use crate::resolve_mihomo_bin;
+use crate::error::MihoroError;
+use crate::geodata::{check_formats, CacheValidators};
+use crate::install_state::{is_current, InstallState};
+use crate::log::{self, debug, einfo, info, Verbosity};
+use crate::proxy::{
+    clipboard_available, copy_to_clipboard, detect_lan_ip, detect_shell, lan_access_problems,
+    local_addresses, loopback_ip, proxy_export_cmd, proxy_export_socks_cmd, proxy_unset_cmd,
+    url_host,
+};
+use crate::report::redact_value;
+use crate::resolve_mihomo_bin::{self, fetch_expected_sha256, github_auth_headers, ResolvedBinary};
+use crate::rules::{insert_rules, rule_problem};
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::subscription::{
+    check_subscription, json_config_to_yaml, print_subscription_info, save_subscription_info,
+    SubscriptionInfo, USERINFO_HEADER,
+};
 use crate::systemctl::Systemctl;
 use crate::utils::{
-    create_parent_dir, delete_file, download_file, extract_gzip, try_decode_base64_file_inplace,
+    atomic_write, atomic_write_with, build_client, confirm, create_parent_dir, delete_file,
+    dir_writable, download_file, download_with_retry, env_https_proxy, expand_path,
+    extract_binary_to, new_temp_file_for, on_path, output_with_timeout, persist_replacing,
+    read_binary_archive, sha256_file, try_decode_base64_file_inplace, unix_now, verify_sha256,
+    xdg_dir, RETRY_BASE_DELAY,
 };
 
+use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::net::IpAddr;
 use std::os::unix::prelude::PermissionsExt;
-use std::path::Path;
-
-use anyhow::{anyhow, Result};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap_complete::Shell;
 use colored::Colorize;
-use local_ip_address::local_ip;
+use futures_util::future::join_all;
+use indicatif::MultiProgress;
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
 use reqwest::Client;
-use shellexpand::tilde;
+use serde_yaml::Value;
+use similar::TextDiff;
 use tempfile::NamedTempFile;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mihoro {
     // global mihoro config
     pub prefix: String,
@@ -30,24 +72,369 @@ pub struct Mihoro {
     pub mihomo_target_config_root: String,
     pub mihomo_target_config_path: String,
     pub mihomo_target_service_path: String,
+
+    // print side-effecting actions instead of executing them
+    pub dry_run: bool,
+    pub output: OutputFormat,
+
+    // bypass any proxy for downloads
+    pub no_proxy: bool,
+
+    // how mihomo is made to load an updated config
+    pub restart_method: RestartMethod,
+
+    // YAML files deep-merged into config.yaml before `overrides`: `merge_files` resolved from the
+    // directory of mihoro.toml, then any passed with `--merge-file`
+    pub merge_files: Vec<PathBuf>,
+
+    // set up without a systemd service, as in containers without systemd
+    pub skip_service: bool,
+
+    // runs systemctl, journalctl and mihomo, overridable for tests
+    pub runner: Arc<dyn CommandRunner>,
+
+    // progress bars of concurrent downloads, drawn together
+    pub progress: MultiProgress,
+
+    // HTTP client shared by all downloads, built on first use
+    client: OnceLock<Client>,
+}
+
+/// Directory for system-wide systemd unit files, used when `systemd_mode = "system"`.
+const SYSTEM_SYSTEMD_ROOT: &str = "/etc/systemd/system";
+
+/// Number of times the service is checked to still be active after updating the core binary.
+const HEALTH_CHECK_ATTEMPTS: u32 = 3;
+/// Delay before each post-update health check.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time allowed for a newly downloaded binary to print its version.
+const BINARY_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Geodata files downloaded into the mihomo config root by `update --geodata`.
+pub const GEODATA_FILES: [&str; 5] = [
+    "geoip.dat",
+    "geosite.dat",
+    "country.mmdb",
+    "geoip.metadb",
+    "ASN.mmdb",
+];
+
+/// A core binary swapped in by `update_core`, with the binary it replaced to roll back to.
+pub struct CoreUpdate {
+    backup: Option<PathBuf>,
+    /// Copy of the replaced binary when backups are disabled, removed when dropped.
+    rollback_copy: Option<NamedTempFile>,
+}
+
+impl CoreUpdate {
+    /// The binary replaced by the update, if it was kept.
+    fn previous(&self) -> Option<&Path> {
+        self.backup
+            .as_deref()
+            .or(self.rollback_copy.as_ref().map(|copy| copy.path()))
+    }
+}
+
+/// Something `mihoro uninstall` removes, and whether it is chosen to be removed.
+struct UninstallComponent {
+    name: &'static str,
+    target: UninstallTarget,
+    remove: bool,
+}
+
+enum UninstallTarget {
+    Files(Vec<String>),
+    AutoUpdate,
+}
+
+/// mihoro's own cache and state directories, `~/.cache/mihoro` and `~/.local/state/mihoro`,
+/// removed with `uninstall --purge`.
+///
+/// A directory is skipped, with a warning, if its XDG base is `/` or the home directory, or if it
+/// is a symlink, so a misconfigured `$XDG_CACHE_HOME` or `$XDG_STATE_HOME` never gets an unrelated
+/// directory removed.
+fn purge_dirs(get_env: &impl Fn(&str) -> Option<String>) -> Vec<PathBuf> {
+    let home = get_env("HOME").map(PathBuf::from);
+    [("CACHE", ".cache"), ("STATE", ".local/state")]
+        .into_iter()
+        .filter_map(|(name, fallback)| xdg_dir(get_env, name, fallback).ok())
+        .filter_map(|base| {
+            let dir = base.join("mihoro");
+            if base.parent().is_none() || Some(&base) == home.as_ref() || dir.is_symlink() {
+                einfo!(
+                    "{} Not removing {}, as it is not in a cache or state directory",
+                    "warning:".yellow(),
+                    dir.to_string_lossy()
+                );
+                return None;
+            }
+            Some(dir)
+        })
+        .collect()
 }
 
 impl Mihoro {
-    pub fn new(config_path: &String) -> Result<Mihoro> {
-        let config = parse_config(tilde(&config_path).as_ref())?;
-        Ok(Mihoro {
+    pub fn new(config_path: &str) -> Result<Mihoro> {
+        let config = parse_config(&expand(config_path))?;
+        let config_dir = expand_path(config_path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let mihoro = Mihoro {
             prefix: String::from("mihoro:"),
             config: config.clone(),
-            mihomo_target_binary_path: tilde(&config.mihomo_binary_path).to_string(),
-            mihomo_target_config_root: tilde(&config.mihomo_config_root).to_string(),
-            mihomo_target_config_path: tilde(&format!("{}/config.yaml", config.mihomo_config_root))
-                .to_string(),
-            mihomo_target_service_path: tilde(&format!(
-                "{}/mihomo.service",
-                config.user_systemd_root
-            ))
-            .to_string(),
-        })
+            mihomo_target_binary_path: expand(&config.mihomo_binary_path),
+            mihomo_target_config_root: expand(&config.mihomo_config_root),
+            mihomo_target_config_path: expand(&format!(
+                "{}/config.yaml",
+                config.mihomo_config_root
+            )),
+            mihomo_target_service_path: service_path(&config),
+            dry_run: false,
+            output: OutputFormat::Human,
+            no_proxy: false,
+            restart_method: RestartMethod::default(),
+            merge_files: config
+                .merge_files
+                .iter()
+                .map(|file| config_dir.join(expand_path(file)))
+                .collect(),
+            skip_service: false,
+            runner: Arc::new(SystemRunner),
+            progress: MultiProgress::new(),
+            client: OnceLock::new(),
+        };
+        debug!(
+            "using mihomo binary {}, config {}, service {}",
+            mihoro.mihomo_target_binary_path,
+            mihoro.mihomo_target_config_path,
+            mihoro.mihomo_target_service_path
+        );
+        Ok(mihoro)
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Override the configured `github_mirror`, if a mirror is provided.
+    pub fn with_github_mirror(mut self, mirror: Option<String>) -> Self {
+        if mirror.is_some() {
+            self.config.github_mirror = mirror;
+        }
+        self
+    }
+
+    /// Override the configured `systemd_unit_dir`, if a directory is provided.
+    pub fn with_systemd_dir(mut self, dir: Option<String>) -> Self {
+        if dir.is_some() {
+            self.config.systemd_unit_dir = dir;
+            self.mihomo_target_service_path = service_path(&self.config);
+        }
+        self
+    }
+
+    /// Unit to enable, the unit file path if it is outside systemd's search path so systemd links
+    /// it, or the service name otherwise.
+    fn enable_unit(&self) -> &str {
+        match self.config.systemd_unit_dir {
+            Some(_) => &self.mihomo_target_service_path,
+            None => &self.config.service_name,
+        }
+    }
+
+    /// Merge these YAML files into config.yaml after the configured `merge_files`.
+    pub fn with_merge_files(mut self, files: &[String]) -> Self {
+        self.merge_files
+            .extend(files.iter().map(|file| expand_path(file)));
+        self
+    }
+
+    pub fn with_restart_method(mut self, restart_method: RestartMethod) -> Self {
+        self.restart_method = restart_method;
+        self
+    }
+
+    pub fn with_skip_service(mut self, skip_service: bool) -> Self {
+        self.skip_service = skip_service;
+        self
+    }
+
+    pub fn with_no_proxy(mut self, no_proxy: bool) -> Self {
+        self.no_proxy = no_proxy;
+        self
+    }
+
+    /// Restrict downloads to IPv4 or IPv6, if requested with `--force-ipv4` or `--force-ipv6`.
+    pub fn with_ip_version(mut self, force_ipv4: bool, force_ipv6: bool) -> Self {
+        if force_ipv4 {
+            self.config.download_ip_version = IpVersion::Ipv4;
+        } else if force_ipv6 {
+            self.config.download_ip_version = IpVersion::Ipv6;
+        }
+        self
+    }
+
+    /// HTTP client for downloads, see `build_client`, using `download_proxy` or the proxy
+    /// environment variables unless `--no-proxy` is passed.
+    ///
+    /// The client is built on the first call and shared by later ones, so connections are reused.
+    pub fn http_client(&self) -> Result<Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client.clone());
+        }
+        let client = build_client(&self.config, self.no_proxy)?;
+
+        let proxy = if self.no_proxy {
+            None
+        } else if let Some(proxy) = self.config.download_proxy() {
+            Some(format!("{} (from `download_proxy`)", proxy))
+        } else {
+            env_https_proxy(|var| env::var(var).ok())
+                .map(|(var, url)| format!("{} (from ${})", url, var))
+        };
+        if let Some(proxy) = proxy {
+            if self.output == OutputFormat::Human {
+                info!("{} Using proxy {}", self.prefix.cyan(), proxy.underline());
+            }
+        }
+        Ok(self.client.get_or_init(|| client).clone())
+    }
+
+    /// Enable `arch_autotune`, if requested with `--optimize-arch`.
+    pub fn with_arch_autotune(mut self, arch_autotune: bool) -> Self {
+        if arch_autotune {
+            self.config.arch_autotune = true;
+        }
+        self
+    }
+
+    /// Ignore the cached latest mihomo version, if requested with `--refresh`.
+    pub fn with_version_refresh(mut self, refresh: bool) -> Self {
+        if refresh {
+            self.config.version_cache_ttl_secs = 0;
+        }
+        self
+    }
+
+    /// Enable `skip_geodata`, if requested with `setup --minimal`.
+    pub fn with_skip_geodata(mut self, skip_geodata: bool) -> Self {
+        if skip_geodata {
+            self.config.skip_geodata = true;
+        }
+        self
+    }
+
+    /// Override the configured `notify_on_update`, if `--notify` or `--no-notify` is passed.
+    pub fn with_notify(mut self, notify: bool, no_notify: bool) -> Self {
+        if notify || no_notify {
+            self.config.notify_on_update = notify;
+        }
+        self
+    }
+
+    /// Override the configured `network_timeout_secs`, if a timeout is provided.
+    pub fn with_network_timeout(mut self, timeout_secs: Option<u64>) -> Self {
+        if let Some(timeout_secs) = timeout_secs {
+            self.config.network_timeout_secs = timeout_secs;
+        }
+        self
+    }
+
+    /// Create a `Systemctl` builder honoring mihoro's global flags.
+    pub fn systemctl(&self) -> Systemctl {
+        let mut systemctl = Systemctl::new();
+        systemctl
+            .runner(self.runner.clone())
+            .system(self.config.systemd_mode == SystemdMode::System)
+            .dry_run(self.dry_run);
+        systemctl
+    }
+
+    /// Build the `journalctl` arguments showing the last `lines` lines of the service's logs,
+    /// optionally only those since `since` (e.g. `1 hour ago`), and following new ones if `follow`.
+    pub fn journalctl_args(&self, lines: u32, follow: bool, since: Option<&str>) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.config.systemd_mode == SystemdMode::User {
+            args.push("--user".to_string());
+        }
+        args.extend([
+            "-xeu".to_string(),
+            self.config.service_name.clone(),
+            "-n".to_string(),
+            lines.to_string(),
+        ]);
+        if let Some(since) = since {
+            args.extend(["--since".to_string(), since.to_string()]);
+        }
+        if follow {
+            args.push("-f".to_string());
+        } else {
+            // Print and exit instead of opening a pager
+            args.push("--no-pager".to_string());
+        }
+        args
+    }
+
+    /// Print the action that would be performed in dry-run mode, returning whether the caller
+    /// should skip it.
+    pub fn skip_in_dry_run(&self, action: &str) -> bool {
+        if self.dry_run {
+            println!("{} would: {}", self.prefix.cyan(), action);
+        }
+        self.dry_run
+    }
+
+    /// Download a file with the configured user agent, skipped in dry-run mode.
+    ///
+    /// Returns the response headers, which are empty in dry-run mode.
+    async fn download(&self, client: &Client, url: &str, path: &Path) -> Result<HeaderMap> {
+        let headers = github_auth_headers(url, self.config.github_token().as_deref());
+        Ok(self
+            .download_with(client, url, path, &headers)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Download a file with extra request `headers`, e.g. to make the request conditional.
+    ///
+    /// Returns the response headers, or `None` if the server answered 304 Not Modified and the
+    /// file was left untouched, or in dry-run mode.
+    async fn download_with(
+        &self,
+        client: &Client,
+        url: &str,
+        path: &Path,
+        headers: &HeaderMap,
+    ) -> Result<Option<HeaderMap>> {
+        if self.skip_in_dry_run(&format!("download {} to {}", url, path.to_string_lossy())) {
+            return Ok(None);
+        }
+        download_file(
+            client,
+            url,
+            path,
+            headers,
+            self.config.download_retries,
+            self.config.download_timeout(),
+            &self.prefix,
+            self.show_progress().then_some(&self.progress),
+        )
+        .await
+    }
+
+    /// Whether to render download progress bars, only for human output to a terminal.
+    pub fn show_progress(&self) -> bool {
+        self.output == OutputFormat::Human
+            && io::stdout().is_terminal()
+            && log::enabled(Verbosity::Normal)
     }
 
     pub async fn setup(
@@ -55,23 +442,32 @@ impl Mihoro {
         client: Client,
         overwrite_binary: bool,
         arch_override: Option<&str>,
+        version_override: Option<&str>,
+        verify: bool,
+        config_url: Option<&str>,
     ) -> Result<()> {
-        println!(
+        let remote_config_url = self.remote_config_url(config_url)?;
+        info!(
             "{} Setting up mihomo's binary, config, and systemd service...",
             &self.prefix.cyan()
         );
+        self.check_binary_dir_writable()?;
+        self.warn_binary_dir_off_path();
 
         // Setup mihomo binary at `mihomo_target_binary_path`
         let binary_exists = fs::metadata(&self.mihomo_target_binary_path).is_ok();
-        if binary_exists && !overwrite_binary {
-            println!(
+        let changed_channel = self.changed_channel();
+        if binary_exists && !overwrite_binary && changed_channel.is_none() {
+            info!(
                 "{} Assuming mihomo binary already installed at {}, skipping setup",
                 self.prefix.yellow(),
                 self.mihomo_target_binary_path.underline().green()
             );
         } else {
-            if binary_exists {
-                println!(
+            if let Some(installed) = &changed_channel {
+                self.log_channel_switch(installed);
+            } else if binary_exists {
+                info!(
                     "{} Overwriting existing mihomo binary at {}",
                     self.prefix.yellow(),
                     self.mihomo_target_binary_path.underline().green()
@@ -79,72 +475,148 @@ impl Mihoro {
             }
 
             // Resolve binary URL (auto-detect from GitHub or use configured URL)
-            let binary_url = resolve_mihomo_bin::resolve_binary_url(
+            let binary = resolve_mihomo_bin::resolve_binary_url(
                 &client,
                 &self.config,
                 arch_override,
+                version_override,
                 &self.prefix,
             )
             .await?;
+            let binary_url = &binary.url;
 
             // Create a temporary file for downloading
             let temp_file = NamedTempFile::new()?;
             let temp_path = temp_file.path();
 
             // Download mihomo binary and set permission to executable
-            download_file(
-                &client,
-                &binary_url,
-                temp_path,
-                &self.config.mihoro_user_agent,
-            )
-            .await?;
+            self.download(&client, binary_url, temp_path).await?;
 
-            // Try to extract the binary, handle "Text file busy" error if overwriting
-            match extract_gzip(temp_path, &self.mihomo_target_binary_path, &self.prefix) {
-                Ok(_) => {
-                    // Set executable permission
-                    let executable = fs::Permissions::from_mode(0o755);
-                    fs::set_permissions(&self.mihomo_target_binary_path, executable)?;
-                }
-                Err(e) => {
-                    // Handle "Text file busy" error
-                    return Err(if e.to_string().contains("Text file busy") {
-                        anyhow!("Failed to overwrite as `mihomo` is in use, stop the service first")
-                    } else {
-                        e
-                    });
-                }
-            };
+            // Verify downloaded archive before replacing anything
+            if verify && !self.dry_run {
+                self.verify_binary(&client, binary_url, temp_path).await?;
+            }
+
+            // Extract the binary, renamed over any existing one so this works even while the
+            // service is running
+            if !self.skip_in_dry_run(&format!("extract to {}", self.mihomo_target_binary_path)) {
+                extract_binary_to(
+                    temp_path,
+                    binary_url,
+                    &self.mihomo_target_binary_path,
+                    Some(0o755),
+                    &self.prefix,
+                )?;
+                self.record_install(&binary);
+            }
         }
 
         // Download remote mihomo config and apply override
-        download_file(
-            &client,
-            &self.config.remote_config_url,
-            Path::new(&self.mihomo_target_config_path),
-            &self.config.mihoro_user_agent,
-        )
-        .await?;
+        let headers = self
+            .download_with(
+                &client,
+                remote_config_url,
+                Path::new(&self.mihomo_target_config_path),
+                &self.subscription_headers(config_url)?,
+            )
+            .await?
+            .unwrap_or_default();
+        self.cache_subscription_info(&headers)?;
+        self.apply_override()?;
 
-        // Try to decode base64 file in place if file is base64 encoding, otherwise do nothing
-        try_decode_base64_file_inplace(&self.mihomo_target_config_path)?;
+        // Download geodata
+        self.update_geodata_unless_skipped(&client, false).await?;
+        self.install_service(false)
+    }
 
-        apply_mihomo_override(&self.mihomo_target_config_path, &self.config.mihomo_config)?;
+    /// Restore mihomo's binary, config and geodata from a state `bundle`, whose `mihoro.toml` is
+    /// already installed and parsed into `self`, then set up and restart the service.
+    pub fn setup_from_bundle(&self, bundle: &Bundle) -> Result<()> {
+        info!(
+            "{} Restoring mihomo's binary, config, and systemd service from {}...",
+            self.prefix.cyan(),
+            bundle.path.to_string_lossy().underline()
+        );
+        self.check_binary_dir_writable()?;
+        self.warn_binary_dir_off_path();
+
+        let root = Path::new(&self.mihomo_target_config_root);
+        let mut files = vec![
+            (
+                BUNDLE_BINARY,
+                PathBuf::from(&self.mihomo_target_binary_path),
+                Some(0o755),
+            ),
+            (
+                BUNDLE_MIHOMO_CONFIG,
+                PathBuf::from(&self.mihomo_target_config_path),
+                None,
+            ),
+        ];
+        files.extend(
+            bundle
+                .geodata
+                .iter()
+                .map(|file| (*file, root.join(file), None)),
+        );
+        for (name, target, mode) in files {
+            let target_str = target.to_string_lossy();
+            if self.skip_in_dry_run(&format!("install {} to {}", name, target_str)) {
+                continue;
+            }
+            bundle.install(name, &target, mode)?;
+            info!(
+                "{} Restored {} to {}",
+                self.prefix.green(),
+                name,
+                target_str.underline().yellow()
+            );
+        }
+        // The binary may be a different build than the one running, if any
+        self.install_service(true)
+    }
 
-        // Download geodata
-        self.update_geodata(&client).await?;
+    /// Create, enable and start the systemd service, restarting it if `restart`, or explain how to
+    /// run mihomo without one if it is skipped or systemd is unavailable.
+    fn install_service(&self, restart: bool) -> Result<()> {
+        if self.skip_service {
+            self.print_manual_start("Skipping the systemd service");
+            return Ok(());
+        }
+        if let Some(reason) = self.systemctl().unavailable() {
+            self.print_manual_start(&format!(
+                "systemd is not available ({}), skipping the service",
+                reason.lines().next().unwrap_or_default()
+            ));
+            return Ok(());
+        }
 
-        // Create mihomo.service systemd file
-        create_mihomo_service(
-            &self.mihomo_target_binary_path,
-            &self.mihomo_target_config_root,
-            &self.mihomo_target_service_path,
-            &self.prefix,
-        )?;
+        // Create mihomo systemd service file
+        if !self.skip_in_dry_run(&format!("create {}", self.mihomo_target_service_path)) {
+            create_mihomo_service(
+                &self.mihomo_target_binary_path,
+                &self.mihomo_target_config_root,
+                &self.mihomo_target_service_path,
+                &self.config.systemd_mode,
+                &self.prefix,
+            )?;
+        }
 
-        Systemctl::new().enable("mihomo.service").execute()?;
-        Systemctl::new().start("mihomo.service").execute()?;
+        self.systemctl().daemon_reload().execute()?;
+        self.systemctl().enable(self.enable_unit()).execute()?;
+        let status = match restart {
+            true => self
+                .systemctl()
+                .restart(&self.config.service_name)
+                .execute()?,
+            false => self
+                .systemctl()
+                .start(&self.config.service_name)
+                .execute()?,
+        };
+        if status.success() {
+            self.record_applied_config();
+        }
         Ok(())
     }
 
@@ -152,9 +624,12 @@ impl Mihoro {
         &self,
         client: &Client,
         arch_override: Option<&str>,
+        version_override: Option<&str>,
+        verify: bool,
+        force: bool,
         restart: bool,
-    ) -> Result<()> {
-        println!("{} Updating mihomo core binary...", &self.prefix.cyan());
+    ) -> Result<Option<CoreUpdate>> {
+        info!("{} Updating mihomo core binary...", &self.prefix.cyan());
 
         // Check if binary exists
         let binary_exists = fs::metadata(&self.mihomo_target_binary_path).is_ok();
@@ -164,366 +639,2927 @@ impl Mihoro {
                 self.mihomo_target_binary_path
             ));
         }
+        self.check_binary_dir_writable()?;
+
+        let changed_channel = self.changed_channel();
+        if let Some(installed) = &changed_channel {
+            self.log_channel_switch(installed);
+        }
 
         // Resolve binary URL (auto-detect from GitHub or use configured URL)
-        let binary_url = resolve_mihomo_bin::resolve_binary_url(
+        let binary = resolve_mihomo_bin::resolve_binary_url(
             client,
             &self.config,
             arch_override,
+            version_override,
             &self.prefix,
         )
         .await?;
+        let (binary_url, expected_version) = (&binary.url, &binary.version);
+
+        // Skip the download if the binary already is the resolved version, falling back to what
+        // it reports if it was not installed by mihoro
+        if let Some(latest) = expected_version.as_deref().filter(|_| !force) {
+            let state = self.install_state();
+            let reported = state.is_none().then(|| self.mihomo_version()).flatten();
+            if changed_channel.is_none()
+                && is_current(
+                    state.as_ref(),
+                    reported.as_deref(),
+                    &self.config.mihomo_channel,
+                    latest,
+                    binary.arch.as_deref(),
+                )
+            {
+                info!(
+                    "{} mihomo {} is already installed, pass {} to reinstall it",
+                    self.prefix.green(),
+                    latest.bold(),
+                    "--force".bold()
+                );
+                return Ok(None);
+            }
+        }
 
         // Create a temporary file for downloading
         let temp_file = NamedTempFile::new()?;
         let temp_path = temp_file.path();
 
-        // Download mihomo binary first (before stopping service)
-        download_file(
-            client,
-            &binary_url,
-            temp_path,
-            &self.config.mihoro_user_agent,
-        )
-        .await?;
-
-        // Stop the service before overwriting binary to avoid "Text file busy" error
-        println!(
-            "{} Stopping mihomo.service before overwriting...",
-            self.prefix.yellow()
-        );
-        Systemctl::new().stop("mihomo.service").execute()?;
-
-        // Extract and overwrite the binary
-        extract_gzip(temp_path, &self.mihomo_target_binary_path, &self.prefix)?;
+        // Download mihomo binary first, while the service keeps running
+        self.download(client, binary_url, temp_path).await?;
 
-        // Set executable permission
-        let executable = fs::Permissions::from_mode(0o755);
-        fs::set_permissions(&self.mihomo_target_binary_path, executable)?;
+        // Verify downloaded archive before replacing the binary
+        if verify && !self.dry_run {
+            self.verify_binary(client, binary_url, temp_path).await?;
+        }
 
-        println!(
-            "{} Updated mihomo binary at {}",
-            self.prefix.green(),
-            self.mihomo_target_binary_path.underline().yellow()
-        );
+        // Extract next to the live binary, so it can be renamed over it on the same filesystem,
+        // and make sure it runs before touching the live binary
+        let new_binary = if self.skip_in_dry_run(&format!(
+            "extract and check new binary next to {}",
+            self.mihomo_target_binary_path
+        )) {
+            None
+        } else {
+            let (mut file, new_binary) =
+                new_temp_file_for(Path::new(&self.mihomo_target_binary_path))?.into_parts();
+            file.write_all(&read_binary_archive(temp_path, binary_url)?)?;
+            file.sync_all()?;
+            // Close the file before running it, executing a file open for writing fails
+            drop(file);
+            fs::set_permissions(&new_binary, fs::Permissions::from_mode(0o755))?;
+
+            let version = check_binary_runs(
+                &new_binary,
+                expected_version.as_deref(),
+                BINARY_CHECK_TIMEOUT,
+            )?;
+            info!(
+                "{} New mihomo binary runs: {}",
+                self.prefix.green(),
+                version.dimmed()
+            );
+            Some(new_binary)
+        };
+
+        // Back up the current binary before overwriting, keeping a temporary copy to roll back to
+        // if backups are disabled
+        let backup =
+            self.backup_before_update(&self.mihomo_target_binary_path, &self.backup_dir())?;
+        let rollback_copy = match backup {
+            None if !self.dry_run => {
+                let copy = NamedTempFile::new()?;
+                fs::copy(&self.mihomo_target_binary_path, copy.path())?;
+                Some(copy)
+            }
+            _ => None,
+        };
+        let update = CoreUpdate {
+            backup,
+            rollback_copy,
+        };
+
+        // Atomically swap the checked binary in, renamed over the live one so the running service
+        // keeps its old binary until restarted
+        if let Some(new_binary) = new_binary {
+            persist_replacing(
+                new_binary,
+                Path::new(&self.mihomo_target_binary_path),
+                Some(0o755),
+            )?;
+            self.record_install(&binary);
+
+            info!(
+                "{} Updated mihomo binary at {}",
+                self.prefix.green(),
+                self.mihomo_target_binary_path.underline().yellow()
+            );
+        }
 
         // Restart the service if requested
         if restart {
-            println!("{} Restarting mihomo.service...", self.prefix.green());
-            Systemctl::new().start("mihomo.service").execute()?;
+            info!(
+                "{} Restarting {}...",
+                self.prefix.green(),
+                self.config.service_name
+            );
+            self.restart_core_or_rollback(update.previous(), HEALTH_CHECK_INTERVAL)?;
         }
 
-        Ok(())
+        Ok(Some(update))
     }
 
-    pub async fn update_config(&self, client: &Client, restart: bool) -> Result<()> {
-        // Download remote mihomo config and apply override
-        download_file(
-            client,
-            &self.config.remote_config_url,
-            Path::new(&self.mihomo_target_config_path),
-            &self.config.mihoro_user_agent,
-        )
-        .await?;
+    /// Restart the service onto a core installed by `update_core` without restarting, rolling
+    /// back to the binary it replaced if the service does not stay active.
+    pub fn restart_onto_core(&self, update: &CoreUpdate) -> Result<()> {
+        self.restart_core_or_rollback(update.previous(), HEALTH_CHECK_INTERVAL)
+    }
 
-        // Try to decode base64 file in place if file is base64 encoding, otherwise do nothing
-        try_decode_base64_file_inplace(&self.mihomo_target_config_path)?;
+    /// What mihoro recorded when installing the binary at `mihomo_target_binary_path`, if it did.
+    pub fn install_state(&self) -> Option<InstallState> {
+        InstallState::load(&InstallState::path().ok()?)
+            .filter(|state| state.binary == self.mihomo_target_binary_path)
+    }
+
+    /// The installed binary as recorded by mihoro, or as reported by `mihomo -v` otherwise.
+    pub fn installed_core_summary(&self) -> Option<String> {
+        match self.install_state() {
+            Some(state) => Some(state.summary(unix_now())),
+            None => self.mihomo_version(),
+        }
+    }
 
-        apply_mihomo_override(&self.mihomo_target_config_path, &self.config.mihomo_config)?;
-        println!(
-            "{} Updated and applied config overrides",
-            self.prefix.yellow()
+    /// The channel the installed binary came from, if `mihomo_channel` has changed since.
+    fn changed_channel(&self) -> Option<MihomoChannel> {
+        self.install_state()?
+            .changed_channel(&self.mihomo_target_binary_path, &self.config.mihomo_channel)
+            .cloned()
+    }
+
+    fn log_channel_switch(&self, installed: &MihomoChannel) {
+        info!(
+            "{} Installed mihomo is from the {} channel, reinstalling from {}",
+            self.prefix.yellow(),
+            installed.as_str().bold(),
+            self.config.mihomo_channel.as_str().bold()
         );
+    }
 
-        // Restart mihomo systemd service if requested
-        if restart {
-            println!("{} Restart mihomo.service", self.prefix.green());
-            Systemctl::new().restart("mihomo.service").execute()?;
+    /// Record the channel, version and architecture of a freshly installed binary, warning if it
+    /// can't be saved, as the install itself succeeded.
+    fn record_install(&self, binary: &ResolvedBinary) {
+        let state = InstallState {
+            binary: self.mihomo_target_binary_path.clone(),
+            channel: self.config.mihomo_channel.clone(),
+            version: binary.version.clone(),
+            arch: binary.arch.clone(),
+            installed_at: unix_now(),
+        };
+        if let Err(e) = InstallState::path().and_then(|path| state.save(&path)) {
+            einfo!(
+                "{} failed to record the installed mihomo channel: {}",
+                "warning:".yellow(),
+                e
+            );
         }
-        Ok(())
     }
 
-    pub async fn update_geodata(&self, client: &Client) -> Result<()> {
-        if let Some(geox_url) = self.config.mihomo_config.geox_url.clone() {
-            // Download geodata files based on `geodata_mode`
-            let geodata_mode = self.config.mihomo_config.geodata_mode.unwrap_or(false);
-            if geodata_mode {
-                download_file(
-                    client,
-                    &geox_url.geoip,
-                    &Path::new(&self.mihomo_target_config_root).join("geoip.dat"),
-                    &self.config.mihoro_user_agent,
-                )
-                .await?;
-                download_file(
-                    client,
-                    &geox_url.geosite,
-                    &Path::new(&self.mihomo_target_config_root).join("geosite.dat"),
-                    &self.config.mihoro_user_agent,
-                )
-                .await?;
-            } else {
-                download_file(
-                    client,
-                    &geox_url.mmdb,
-                    &Path::new(&self.mihomo_target_config_root).join("country.mmdb"),
-                    &self.config.mihoro_user_agent,
-                )
-                .await?;
-            }
+    /// Switch `mihomo_channel` in `mihoro.toml` and reinstall mihomo from that channel.
+    pub async fn switch_channel(
+        &self,
+        client: &Client,
+        config_path: &str,
+        channel: MihomoChannel,
+    ) -> Result<()> {
+        let mut switched = self.clone();
+        switched.config.mihomo_channel = channel;
+        let channel = switched.config.mihomo_channel.as_str();
+        if self.config.mihomo_channel == switched.config.mihomo_channel
+            && switched.changed_channel().is_none()
+        {
+            info!(
+                "{} Already on the {} channel",
+                self.prefix.green(),
+                channel.bold()
+            );
+            return Ok(());
+        }
 
-            println!("{} Downloaded and updated geodata", self.prefix.green());
-        } else {
-            println!(
-                "{} `geox_url` undefined, refer to {}",
-                self.prefix.yellow(),
-                "'https://wiki.metacubex.one/config/general/#geo_3'"
-                    .bold()
-                    .underline()
+        if !self.skip_in_dry_run(&format!("set `mihomo_channel = \"{}\"`", channel)) {
+            set_mihomo_channel(&expand(config_path), channel)?;
+            info!(
+                "{} Switched to the {} channel",
+                self.prefix.green(),
+                channel.bold()
+            );
+        }
+
+        if !Path::new(&self.mihomo_target_binary_path).exists() {
+            info!(
+                "{} mihomo is not installed yet, run {} to install it",
+                "->".dimmed(),
+                "mihoro setup".bold().underline()
             );
+            return Ok(());
         }
+        switched
+            .update_core(client, None, None, true, false, true)
+            .await?;
         Ok(())
     }
 
-    pub async fn apply(&self) -> Result<()> {
-        // Apply mihomo config override
-        apply_mihomo_override(&self.mihomo_target_config_path, &self.config.mihomo_config).map(
-            |_| {
-                println!(
-                    "{} Applied mihomo config overrides",
-                    self.prefix.green().bold()
-                );
-            },
-        )?;
+    /// Restart the service after a core update, rolling back to the `previous` binary if the
+    /// service does not stay active.
+    fn restart_core_or_rollback(&self, previous: Option<&Path>, interval: Duration) -> Result<()> {
+        let service = &self.config.service_name;
+        self.systemctl().restart(service).execute()?;
+        if self.dry_run || self.wait_until_active(interval)? {
+            return Ok(());
+        }
 
-        // Restart mihomo systemd service
-        Systemctl::new()
-            .restart("mihomo.service")
-            .execute()
-            .map(|_| {
-                println!("{} Restarted mihomo.service", self.prefix.green().bold());
-            })?;
-        Ok(())
+        let Some(previous) = previous else {
+            bail!(
+                "{} failed to start with the new mihomo binary and no previous binary is available to roll back to",
+                service
+            );
+        };
+
+        info!(
+            "{} {} failed to start with the new binary, rolling back to {}",
+            self.prefix.red(),
+            service,
+            previous.to_string_lossy().underline()
+        );
+        self.systemctl().stop(service).execute()?;
+        copy_atomically(previous, &self.mihomo_target_binary_path, Some(0o755))?;
+        self.systemctl().start(service).execute()?;
+
+        if !self.wait_until_active(interval)? {
+            bail!(
+                "{} failed to start even after rolling back the mihomo binary, check `mihoro log`",
+                service
+            );
+        }
+        bail!(
+            "mihomo core update rolled back, {} is running the previous binary",
+            service
+        )
     }
 
-    pub fn uninstall(&self) -> Result<()> {
-        Systemctl::new().stop("mihomo.service").execute()?;
-        Systemctl::new().disable("mihomo.service").execute()?;
+    /// Poll the service, returning whether it stayed active for every health check.
+    fn wait_until_active(&self, interval: Duration) -> Result<bool> {
+        for _ in 0..HEALTH_CHECK_ATTEMPTS {
+            sleep(interval);
+            if !self.systemctl().is_active(&self.config.service_name)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 
-        delete_file(&self.mihomo_target_service_path, &self.prefix)?;
-        delete_file(&self.mihomo_target_config_path, &self.prefix)?;
+    /// Path of the cached subscription traffic and expiry info.
+    pub fn subscription_info_path(&self) -> PathBuf {
+        Path::new(&self.mihomo_target_config_root).join("subscription.json")
+    }
 
-        Systemctl::new().daemon_reload().execute()?;
-        Systemctl::new().reset_failed().execute()?;
-        println!(
-            "{} Disabled and reloaded systemd services",
-            self.prefix.green()
-        );
+    /// Cache subscription info from the `subscription-userinfo` header of a config download.
+    ///
+    /// Stale info is removed if the provider doesn't send the header, so it is never shown for
+    /// a different subscription.
+    fn cache_subscription_info(&self, headers: &HeaderMap) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
 
-        // Disable and remove cron job
-        cron::disable_auto_update(&self.prefix)?;
+        let path = self.subscription_info_path();
+        let info = headers
+            .get(USERINFO_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(SubscriptionInfo::parse);
+        match info {
+            Some(info) => save_subscription_info(&path, &info),
+            None if path.exists() => Ok(fs::remove_file(&path)?),
+            None => Ok(()),
+        }
+    }
 
-        println!(
-            "{} You may need to remove mihomo binary and config directory manually",
-            self.prefix.yellow()
-        );
+    /// Verify a downloaded mihomo archive against the SHA256 checksum published next to it.
+    async fn verify_binary(&self, client: &Client, binary_url: &str, path: &Path) -> Result<()> {
+        let expected = fetch_expected_sha256(
+            client,
+            binary_url,
+            self.config.github_token().as_deref(),
+            self.config.download_retries,
+            self.config.network_timeout(),
+            &self.prefix,
+        )
+        .await?;
+        verify_sha256(path, &expected)?;
+        info!("{} Verified SHA256 checksum", self.prefix.green());
+        Ok(())
+    }
 
-        let remove_cmd = format!(
-            "rm -R {} {}",
-            self.mihomo_target_binary_path, self.mihomo_target_config_root
+    /// The subscription url to download the config from, `config_url` if passed with
+    /// `--config-url` for this invocation, or the active one from `mihoro.toml` otherwise.
+    fn remote_config_url<'a>(&'a self, config_url: Option<&'a str>) -> Result<&'a str> {
+        let Some(config_url) = config_url else {
+            return Ok(self.config.active_remote_config_url());
+        };
+        validate_url("--config-url", config_url)?;
+        info!(
+            "{} Using {} for this run only, {} is unchanged",
+            self.prefix.yellow(),
+            "--config-url".bold(),
+            "remote_config_url".bold()
         );
-        println!("{} `{}`", "->".dimmed(), remove_cmd.underline().bold());
-        Ok(())
+        Ok(config_url)
     }
 
-    pub fn proxy_commands(&self, proxy: &Option<ProxyCommands>) -> Result<()> {
-        // `mixed_port` takes precedence over `port` and `socks_port` for proxy export
-        let port = self
-            .config
-            .mihomo_config
-            .mixed_port
-            .as_ref()
-            .unwrap_or(&self.config.mihomo_config.port);
-        let socks_port = self
-            .config
-            .mihomo_config
-            .mixed_port
-            .as_ref()
-            .unwrap_or(&self.config.mihomo_config.socks_port);
+    /// Headers for the subscription request, authorization from `config_auth` unless the url was
+    /// overridden with `--config-url`, as the credentials belong to the configured subscription, and
+    /// the `subscription_user_agent`.
+    fn subscription_headers(&self, config_url: Option<&str>) -> Result<HeaderMap> {
+        let mut headers = match self.config.active_config_auth() {
+            Some(auth) if config_url.is_none() => {
+                debug!(
+                    "authenticating subscription request with {}",
+                    auth.describe()
+                );
+                auth.header()?
+            }
+            _ => HeaderMap::new(),
+        };
+        // Replaces the client's default `mihoro_user_agent` for this request
+        if let Some(user_agent) = self.config.subscription_user_agent() {
+            debug!("requesting subscription as `{}`", user_agent);
+            headers.insert(
+                USER_AGENT,
+                HeaderValue::from_str(user_agent).with_context(|| {
+                    format!("invalid `subscription_user_agent` `{}`", user_agent)
+                })?,
+            );
+        }
+        Ok(headers)
+    }
 
-        match proxy {
-            Some(ProxyCommands::Export) => {
-                println!("{}", proxy_export_cmd("127.0.0.1", port, socks_port))
+    /// Run `mihoro test-url`, downloading the subscription at `url`, or the active one, and
+    /// reporting what it contains without writing anything.
+    ///
+    /// `config_auth` is only sent to the active subscription, like for `--config-url`.
+    pub async fn test_url(&self, client: &Client, url: Option<&str>) -> Result<()> {
+        let active = self.config.active_remote_config_url();
+        let url = match url {
+            Some(url) => {
+                validate_url("url", url)?;
+                url
             }
-            Some(ProxyCommands::ExportLan) => {
-                if !self.config.mihomo_config.allow_lan.unwrap_or(false) {
-                    println!(
-                        "{} `{}` is false, proxy is not available for LAN",
-                        "warning:".yellow(),
-                        "allow_lan".bold()
-                    );
-                }
+            None => active,
+        };
+        let headers = self.subscription_headers((url != active).then_some(url))?;
+        einfo!("{} Fetching {}", self.prefix.cyan(), url.underline());
 
+        let response = download_with_retry(
+            client,
+            url,
+            &headers,
+            self.config.download_retries,
+            RETRY_BASE_DELAY,
+            self.config.download_timeout(),
+            &self.prefix,
+        )
+        .await?;
+        let info = response
+            .headers()
+            .get(USERINFO_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(SubscriptionInfo::parse);
+        let body = response.bytes().await?;
+        let check = check_subscription(&body, self.config.config_format)
+            .with_context(|| format!("`{}` did not return a valid mihomo config", url))?;
+
+        match self.output {
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "url": url,
+                    "config": check,
+                    "subscription": info,
+                }))?
+            ),
+            OutputFormat::Human => {
                 println!(
-                    "{}",
-                    proxy_export_cmd(&local_ip()?.to_string(), port, socks_port)
+                    "{} {} returned a valid mihomo config{}",
+                    "✔".green(),
+                    url,
+                    match (check.base64, check.json) {
+                        (true, true) => " (base64 encoded JSON)",
+                        (true, false) => " (base64 encoded)",
+                        (false, true) => " (JSON)",
+                        (false, false) => "",
+                    }
                 );
+                println!("{:<16} {}", "proxies", check.proxies.to_string().bold());
+                println!("{:<16} {}", "proxy groups", check.proxy_groups);
+                if check.proxy_providers > 0 {
+                    println!("{:<16} {}", "proxy providers", check.proxy_providers);
+                }
+                println!("{:<16} {}", "rules", check.rules);
+                match &info {
+                    Some(info) => print_subscription_info(info),
+                    None => println!(
+                        "{} No {} header sent",
+                        "subscription:".cyan().bold(),
+                        USERINFO_HEADER
+                    ),
+                }
             }
-            Some(ProxyCommands::Unset) => {
-                println!("{}", proxy_unset_cmd())
-            }
-            _ => (),
         }
         Ok(())
     }
 
-    pub fn cron_commands(&self, command: &Option<CronCommands>) -> Result<()> {
-        match command {
-            Some(CronCommands::Enable) => {
-                cron::enable_auto_update(self.config.auto_update_interval, &self.prefix)
+    pub async fn update_config(
+        &self,
+        client: &Client,
+        restart: bool,
+        force: bool,
+        if_changed: bool,
+        config_url: Option<&str>,
+    ) -> Result<()> {
+        let remote_config_url = self.remote_config_url(config_url)?;
+
+        // Back up the current config before overwriting
+        self.backup_before_update(
+            &self.mihomo_target_config_path,
+            Path::new(&self.mihomo_target_config_root),
+        )?;
+
+        // Download remote mihomo config and apply override
+        let headers = self
+            .download_with(
+                client,
+                remote_config_url,
+                Path::new(&self.mihomo_target_config_path),
+                &self.subscription_headers(config_url)?,
+            )
+            .await?
+            .unwrap_or_default();
+        self.cache_subscription_info(&headers)?;
+        if self.apply_override()? {
+            info!(
+                "{} Updated and applied config overrides",
+                self.prefix.yellow()
+            );
+        }
+
+        // Restart mihomo systemd service if requested
+        if restart {
+            self.reload_or_restart(force, if_changed).await?;
+        }
+        Ok(())
+    }
+
+    /// Validate `config.yaml` and have mihomo load it, unless `if_changed` and it is the config
+    /// mihomo last loaded.
+    ///
+    /// The config is hot-reloaded through mihomo's API, or with `systemctl reload` if `reload_method`
+    /// is `signal`, which keeps open connections, falling back to restarting the service if that
+    /// fails, unless `restart_method` forces either.
+    async fn reload_or_restart(&self, force: bool, if_changed: bool) -> Result<()> {
+        let hash = sha256_file(Path::new(&self.mihomo_target_config_path)).ok();
+        if let Some(hash) = hash.as_deref().filter(|_| if_changed) {
+            let applied = applied_config::path()
+                .ok()
+                .and_then(|path| applied_config::load(&path));
+            if !applied_config::needs_restart(applied.as_deref(), hash) {
+                info!(
+                    "{} Config unchanged, not reloading {}",
+                    self.prefix.green(),
+                    self.config.service_name
+                );
+                return Ok(());
+            }
+        }
+
+        self.validate_before_restart(force)?;
+        if self.restart_method != RestartMethod::Restart {
+            let reloaded = match self.config.reload_method {
+                ReloadMethod::Api => self.reload_through_api().await,
+                ReloadMethod::Signal => self.reload_through_systemctl(),
+            };
+            match reloaded {
+                Ok(()) => {
+                    info!(
+                        "{} Reloaded config.yaml without restarting {}",
+                        self.prefix.green().bold(),
+                        self.config.service_name
+                    );
+                    self.record_applied_config();
+                    return Ok(());
+                }
+                Err(e) if self.restart_method == RestartMethod::Reload => {
+                    return Err(e.context("failed to reload config.yaml"));
+                }
+                Err(e) => debug!("hot reload failed, restarting instead: {:#}", e),
+            }
+        }
+
+        self.restart_service()
+    }
+
+    /// Restart the service, so mihomo loads `config.yaml` from scratch.
+    fn restart_service(&self) -> Result<()> {
+        let status = self
+            .systemctl()
+            .restart(&self.config.service_name)
+            .execute()?;
+        // systemctl reports its own errors, but a failed restart must not count as applied
+        if status.success() {
+            info!(
+                "{} Restarted {}",
+                self.prefix.green().bold(),
+                self.config.service_name
+            );
+            self.record_applied_config();
+        }
+        Ok(())
+    }
+
+    /// Have mihomo reload `config.yaml` from disk through its API.
+    async fn reload_through_api(&self) -> Result<()> {
+        if self.skip_in_dry_run("reload config.yaml through the mihomo API") {
+            return Ok(());
+        }
+        MihomoApi::from_mihoro(self)?
+            .reload_config(&self.mihomo_target_config_path)
+            .await
+    }
+
+    /// Have mihomo reload `config.yaml` with `systemctl reload`, which signals it through the unit's
+    /// `ExecReload=`.
+    fn reload_through_systemctl(&self) -> Result<()> {
+        self.systemctl()
+            .reload(&self.config.service_name)
+            .execute_checked()
+    }
+
+    /// Whether `config.yaml` differs from the one mihomo was last (re)started with by mihoro, which
+    /// is unknown until it first was.
+    fn applied_config_outdated(&self) -> bool {
+        let applied = applied_config::path()
+            .ok()
+            .and_then(|path| applied_config::load(&path));
+        let hash = sha256_file(Path::new(&self.mihomo_target_config_path)).ok();
+        matches!((applied, hash), (Some(applied), Some(hash)) if applied != hash)
+    }
+
+    /// Remember the `config.yaml` mihomo just loaded, for `--if-changed`, warning if it can't be
+    /// saved, as the reload or restart itself succeeded.
+    pub fn record_applied_config(&self) {
+        if self.dry_run {
+            return;
+        }
+        let recorded = sha256_file(Path::new(&self.mihomo_target_config_path))
+            .and_then(|hash| applied_config::save(&applied_config::path()?, &hash));
+        if let Err(e) = recorded {
+            einfo!(
+                "{} failed to record the applied config: {}",
+                "warning:".yellow(),
+                e
+            );
+        }
+    }
+
+    /// Directory holding mihomo binary backups.
+    fn backup_dir(&self) -> PathBuf {
+        Path::new(&self.mihomo_target_config_root).join("backups")
+    }
+
+    /// Back up a file into `backup_dir` before it is overwritten, if `backup_before_update` is set.
+    fn backup_before_update(&self, path: &str, backup_dir: &Path) -> Result<Option<PathBuf>> {
+        if !self.config.backup_before_update || self.skip_in_dry_run(&format!("back up {}", path)) {
+            return Ok(None);
+        }
+
+        let backup = backup_file(Path::new(path), backup_dir, self.config.backup_keep)?;
+        if let Some(backup) = &backup {
+            info!(
+                "{} Backed up {} to {}",
+                self.prefix.cyan(),
+                path.underline(),
+                backup.to_string_lossy().underline().yellow()
+            );
+        }
+        Ok(backup)
+    }
+
+    /// List available config and binary backups, or restore the named backup and restart the
+    /// service.
+    pub fn restore(&self, backup: Option<&str>) -> Result<()> {
+        let config_root = Path::new(&self.mihomo_target_config_root);
+        let config_backups =
+            list_backups(config_root, &file_name(&self.mihomo_target_config_path))?;
+        let binary_backups = list_backups(
+            &self.backup_dir(),
+            &file_name(&self.mihomo_target_binary_path),
+        )?;
+
+        let Some(backup) = backup else {
+            if config_backups.is_empty() && binary_backups.is_empty() {
+                println!("{} No backups found", self.prefix.yellow());
+                return Ok(());
+            }
+            for (kind, backups) in [("config", &config_backups), ("binary", &binary_backups)] {
+                println!("{} Available {} backups:", self.prefix.cyan(), kind);
+                for path in backups {
+                    println!("{} {}", "->".dimmed(), file_name(&path.to_string_lossy()));
+                }
+            }
+            println!(
+                "{} Run {} to restore a backup",
+                "->".dimmed(),
+                "mihoro restore <backup>".bold().underline()
+            );
+            return Ok(());
+        };
+
+        let is_named = |path: &&PathBuf| file_name(&path.to_string_lossy()) == backup;
+        if let Some(path) = config_backups.iter().find(is_named) {
+            if !self.skip_in_dry_run(&format!("restore {}", self.mihomo_target_config_path)) {
+                copy_atomically(path, &self.mihomo_target_config_path, None)?;
+            }
+        } else if let Some(path) = binary_backups.iter().find(is_named) {
+            // Stop the service before replacing the binary
+            self.systemctl().stop(&self.config.service_name).execute()?;
+            if !self.skip_in_dry_run(&format!("restore {}", self.mihomo_target_binary_path)) {
+                copy_atomically(path, &self.mihomo_target_binary_path, Some(0o755))?;
+            }
+        } else {
+            bail!(
+                "backup `{}` not found, run `mihoro restore` to list backups",
+                backup
+            );
+        }
+
+        info!(
+            "{} Restored {}, restarting {}",
+            self.prefix.green(),
+            backup.underline(),
+            self.config.service_name
+        );
+        self.systemctl()
+            .restart(&self.config.service_name)
+            .execute()?;
+        Ok(())
+    }
+
+    /// Decode the downloaded config if base64 encoded, convert it to YAML if JSON, and apply
+    /// overrides from `mihoro.toml`.
+    ///
+    /// Returns `false` if skipped in dry-run mode.
+    fn apply_override(&self) -> Result<bool> {
+        if self.skip_in_dry_run(&format!(
+            "apply config overrides to {}",
+            self.mihomo_target_config_path
+        )) {
+            return Ok(false);
+        }
+
+        // Try to decode base64 file in place if file is base64 encoding, otherwise do nothing
+        try_decode_base64_file_inplace(&self.mihomo_target_config_path)?;
+        let raw = fs::read(&self.mihomo_target_config_path)?;
+        if let Some(yaml) = json_config_to_yaml(&raw, self.config.config_format)? {
+            debug!("converted JSON config to YAML");
+            atomic_write(Path::new(&self.mihomo_target_config_path), yaml)?;
+        }
+
+        self.apply_config_overrides()?;
+        Ok(true)
+    }
+
+    /// Apply `mihomo_config` fields, then the free-form `overrides` table from `mihoro.toml` to
+    /// mihomo's `config.yaml`.
+    ///
+    /// Returns the top-level keys this changed, or `None` if `config.yaml` already had the
+    /// overrides applied and was left as is. Contents are compared parsed, as the order of keys
+    /// mihoro doesn't know about is not kept.
+    fn apply_config_overrides(&self) -> Result<Option<Vec<String>>> {
+        let raw_mihomo_yaml = fs::read_to_string(&self.mihomo_target_config_path)?;
+        let overridden = self.overridden_config(&raw_mihomo_yaml)?;
+        let parse = |raw: &str| serde_yaml::from_str::<Value>(raw).ok();
+        if parse(&overridden).is_some() && parse(&overridden) == parse(&raw_mihomo_yaml) {
+            return Ok(None);
+        }
+        atomic_write(Path::new(&self.mihomo_target_config_path), &overridden)?;
+        if !self.merge_files.is_empty() {
+            info!(
+                "{} Merged {}",
+                self.prefix.green(),
+                self.merge_files
+                    .iter()
+                    .map(|path| path.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        self.warn_lan_access_problems(&overridden);
+        Ok(Some(changed_keys(&raw_mihomo_yaml, &overridden)))
+    }
+
+    /// Parse the `merge_files` in order, failing on files that aren't a YAML mapping.
+    fn merge_fragments(&self) -> Result<Vec<Value>> {
+        self.merge_files
+            .iter()
+            .map(|path| {
+                let display_path = path.to_string_lossy();
+                let raw = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read merge file `{}`", display_path))?;
+                let fragment: Value = serde_yaml::from_str(&raw)
+                    .with_context(|| format!("failed to parse merge file `{}`", display_path))?;
+                match fragment {
+                    // An empty file merges nothing
+                    Value::Null => Ok(Value::Mapping(Default::default())),
+                    Value::Mapping(_) => Ok(fragment),
+                    _ => bail!("merge file `{}` is not a YAML mapping", display_path),
+                }
+            })
+            .collect()
+    }
+
+    /// Warn about `allow-lan` and `bind-address` in the written config that break LAN access.
+    fn warn_lan_access_problems(&self, raw_mihomo_yaml: &str) {
+        let Ok(lan) = parse_mihomo_lan(raw_mihomo_yaml) else {
+            return;
+        };
+        let problems = lan_access_problems(
+            lan.allow_lan.unwrap_or(false),
+            lan.bind_address.as_deref(),
+            &local_addresses(),
+        );
+        for problem in problems {
+            einfo!("{} {}", "warning:".yellow(), problem);
+        }
+    }
+
+    /// The `config.yaml` contents `apply_config_overrides` writes for `raw_mihomo_yaml`.
+    fn overridden_config(&self, raw_mihomo_yaml: &str) -> Result<String> {
+        let mut mihomo_config = self.config.mihomo_config.clone();
+        if self.config.skip_geodata {
+            // Without local geodata, periodic downloads by mihomo would defeat `skip_geodata`
+            mihomo_config.geo_auto_update = Some(false);
+        }
+        let raw_mihomo_yaml = override_mihomo_yaml(raw_mihomo_yaml, &mihomo_config)?;
+        let fragments = self.merge_fragments()?;
+
+        // Top-level overrides first, then the active profile's on top
+        let overrides: Vec<&toml::Table> = [
+            Some(&self.config.overrides),
+            self.config
+                .current_profile()
+                .map(|profile| &profile.overrides),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|overrides| !overrides.is_empty())
+        .collect();
+        let prepend = valid_rules(&self.config.extra_rules_prepend, "extra_rules_prepend");
+        let append = valid_rules(&self.config.extra_rules_append, "extra_rules_append");
+        if fragments.is_empty()
+            && overrides.is_empty()
+            && prepend.is_empty()
+            && append.is_empty()
+            && !self.config.ensure_allow_lan
+        {
+            return Ok(raw_mihomo_yaml);
+        }
+
+        let mut mihomo_yaml: Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        // Merge files before the overrides, which have the last word
+        for fragment in &fragments {
+            apply_overrides(&mut mihomo_yaml, fragment);
+        }
+        for overrides in overrides {
+            apply_overrides(&mut mihomo_yaml, &serde_yaml::to_value(overrides)?);
+        }
+        // After the overrides, so replacing `rules` in `[overrides]` doesn't drop them
+        insert_rules(&mut mihomo_yaml, &prepend, &append);
+        // Last, so no override can turn LAN access off again
+        if self.config.ensure_allow_lan {
+            if let Value::Mapping(mapping) = &mut mihomo_yaml {
+                mapping.insert(Value::from("allow-lan"), Value::Bool(true));
+            }
+        }
+        Ok(serde_yaml::to_string(&mihomo_yaml)?)
+    }
+
+    /// Print the changes `apply` would make to mihomo's `config.yaml`, without writing it or
+    /// restarting the service.
+    pub fn apply_diff(&self) -> Result<()> {
+        let path = &self.mihomo_target_config_path;
+        let current = fs::read_to_string(path)
+            .with_context(|| format!("failed to read `{}`, run `mihoro setup` first", path))?;
+        let diff = unified_diff(
+            &current,
+            &self.overridden_config(&current)?,
+            path,
+            &format!("{} (applied)", path),
+        );
+        if diff.is_empty() {
+            info!(
+                "{} No changes, {} already has the overrides applied",
+                self.prefix.green(),
+                path
+            );
+        } else {
+            print_diff(&diff);
+        }
+        Ok(())
+    }
+
+    /// Download geodata as `update_geodata` does, unless `skip_geodata` is set.
+    pub async fn update_geodata_unless_skipped(&self, client: &Client, force: bool) -> Result<()> {
+        if !self.config.skip_geodata {
+            return self.update_geodata(client, force).await;
+        }
+        info!(
+            "{} Skipping geodata ({}), rules using GEOIP or GEOSITE need mihomo to download it",
+            self.prefix.yellow(),
+            "skip_geodata".bold()
+        );
+        Ok(())
+    }
+
+    /// Download the configured geodata files, skipping those the server reports unchanged since
+    /// the last download unless `force` is set.
+    pub async fn update_geodata(&self, client: &Client, force: bool) -> Result<()> {
+        let mut sources = self.config.geodata_sources();
+        if sources.is_empty() {
+            info!(
+                "{} No geodata sources, set `geox_url` or `[geodata]`, refer to {}",
+                self.prefix.yellow(),
+                "'https://wiki.metacubex.one/config/general/#geo_3'"
+                    .bold()
+                    .underline()
+            );
+            return Ok(());
+        }
+        let root = Path::new(&self.mihomo_target_config_root);
+        let version = self.mihomo_version();
+        for warning in check_formats(&self.config, &mut sources, version.as_deref(), root) {
+            einfo!("{} {}", "warning:".yellow(), warning);
+        }
+
+        // Download all files concurrently, each with its own progress bar, finishing the others if
+        // one fails
+        let downloads = sources.iter().map(|source| async move {
+            let path = root.join(source.file);
+            let validators = if force {
+                CacheValidators::default()
+            } else {
+                CacheValidators::load(&path)
+            };
+            debug!("geodata {} validators: {:?}", source.file, validators);
+
+            let headers = self
+                .download_with(
+                    client,
+                    &source.url,
+                    &path,
+                    &validators.conditional_headers(),
+                )
+                .await?;
+            if let Some(headers) = &headers {
+                CacheValidators::from_headers(headers).save(&path)?;
+            }
+            anyhow::Ok(headers.is_some())
+        });
+
+        let mut updated = vec![];
+        let mut unchanged = vec![];
+        let mut failed = vec![];
+        for (source, result) in sources.iter().zip(join_all(downloads).await) {
+            match result {
+                Ok(true) => updated.push(source.file),
+                Ok(false) if !self.dry_run => unchanged.push(source.file),
+                Ok(false) => {}
+                Err(e) => failed.push(format!("{}: {:#}", source.file, e)),
+            }
+        }
+
+        if !updated.is_empty() {
+            info!(
+                "{} Updated geodata: {}",
+                self.prefix.green(),
+                updated.join(", ")
+            );
+        }
+        if !unchanged.is_empty() {
+            info!(
+                "{} Geodata up to date, skipped: {}",
+                self.prefix.green(),
+                unchanged.join(", ")
+            );
+        }
+        if !failed.is_empty() {
+            bail!(
+                "failed to download {} of {} geodata files: {}",
+                failed.len(),
+                sources.len(),
+                failed.join("; ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Explain why mihomo was installed without a service, and how to run it instead.
+    fn print_manual_start(&self, reason: &str) {
+        info!(
+            "{} {}, run mihomo yourself with `mihoro run`, or:\n  {} {}",
+            self.prefix.yellow(),
+            reason,
+            self.mihomo_target_binary_path,
+            self.run_args(&[]).join(" ")
+        );
+    }
+
+    /// Arguments running mihomo with the installed config, followed by `extra` ones.
+    fn run_args(&self, extra: &[String]) -> Vec<String> {
+        [
+            "-d",
+            &self.mihomo_target_config_root,
+            "-f",
+            &self.mihomo_target_config_path,
+        ]
+        .into_iter()
+        .map(String::from)
+        .chain(extra.iter().cloned())
+        .collect()
+    }
+
+    /// Replace mihoro with the installed mihomo, running in the foreground with the installed
+    /// config, so its output goes straight to the terminal and signals like Ctrl-C reach it.
+    pub fn run_foreground(&self, extra: &[String]) -> Result<()> {
+        let binary = &self.mihomo_target_binary_path;
+        if !Path::new(binary).exists() {
+            bail!(
+                "mihomo binary not found at `{}`, run `mihoro setup` first",
+                binary
+            );
+        }
+        let args = self.run_args(extra);
+        if self.skip_in_dry_run(&format!("run `{} {}`", binary, args.join(" "))) {
+            return Ok(());
+        }
+        // Two instances would fight over the same ports
+        if self
+            .systemctl()
+            .is_active(&self.config.service_name)
+            .unwrap_or(false)
+        {
+            einfo!(
+                "{} {} is running, stop it with `mihoro stop` if mihomo fails to bind its ports",
+                "warning:".yellow(),
+                self.config.service_name
+            );
+        }
+
+        debug!("running `{} {}`", binary, args.join(" "));
+        let err = Command::new(binary).args(&args).exec();
+        Err(err).with_context(|| format!("failed to run `{}`", binary))
+    }
+
+    pub async fn apply(&self, force: bool, if_changed: bool) -> Result<()> {
+        // Apply mihomo config override
+        if self.dry_run {
+            self.skip_in_dry_run(&format!(
+                "apply config overrides to {}",
+                self.mihomo_target_config_path
+            ));
+            self.systemctl()
+                .restart(&self.config.service_name)
+                .execute()?;
+            return Ok(());
+        }
+
+        let changed = self.apply_and_report_config_overrides()?;
+
+        // A moved binary needs the service restarted, reloading would keep running the old one
+        if self.sync_service_exec_start()? {
+            self.validate_before_restart(force)?;
+            return self.restart_service();
+        }
+
+        // Leave mihomo running, unless it was last started with another config.yaml, e.g. one
+        // edited by hand since, or `--restart` asks for a restart anyway, e.g. onto a new core
+        if changed.is_none()
+            && self.restart_method != RestartMethod::Restart
+            && !self.applied_config_outdated()
+        {
+            info!(
+                "{} No changes, {} untouched",
+                self.prefix.green(),
+                self.config.service_name
+            );
+            return Ok(());
+        }
+
+        // Reload or restart mihomo to load it
+        self.reload_or_restart(force, if_changed).await
+    }
+
+    /// Load what `update --no-restart` wrote: apply overrides to the config.yaml on disk and
+    /// restart the service onto it and the installed core, rolling back to the latest binary
+    /// backup if the service does not stay active.
+    pub fn apply_no_download(&self, force: bool) -> Result<()> {
+        self.apply_no_download_checked_every(force, HEALTH_CHECK_INTERVAL)
+    }
+
+    fn apply_no_download_checked_every(&self, force: bool, interval: Duration) -> Result<()> {
+        if !self.skip_in_dry_run(&format!(
+            "apply config overrides to {}",
+            self.mihomo_target_config_path
+        )) {
+            self.apply_and_report_config_overrides()?;
+            self.sync_service_exec_start()?;
+            self.validate_before_restart(force)?;
+        }
+
+        let backup = list_backups(
+            &self.backup_dir(),
+            &file_name(&self.mihomo_target_binary_path),
+        )?
+        .into_iter()
+        .next();
+        info!(
+            "{} Restarting {}...",
+            self.prefix.green(),
+            self.config.service_name
+        );
+        self.restart_core_or_rollback(backup.as_deref(), interval)?;
+        self.record_applied_config();
+        Ok(())
+    }
+
+    /// Apply overrides as `apply_config_overrides` does, reporting the changed keys.
+    fn apply_and_report_config_overrides(&self) -> Result<Option<Vec<String>>> {
+        let changed = self.apply_config_overrides()?;
+        match changed.as_deref() {
+            Some([]) => info!(
+                "{} Applied mihomo config overrides",
+                self.prefix.green().bold()
+            ),
+            Some(keys) => info!(
+                "{} Applied mihomo config overrides, changed: {}",
+                self.prefix.green().bold(),
+                keys.join(", ")
+            ),
+            None => {}
+        }
+        Ok(changed)
+    }
+
+    /// Point the unit's `ExecStart=` at the configured `mihomo_binary_path` and
+    /// `mihomo_config_root`, if either changed since the unit was written.
+    ///
+    /// Returns whether the unit was updated, which needs a restart to take effect.
+    fn sync_service_exec_start(&self) -> Result<bool> {
+        let path = &self.mihomo_target_service_path;
+        let Ok(unit) = fs::read_to_string(path) else {
+            return Ok(false);
+        };
+        let exec_start = exec_start(
+            &self.mihomo_target_binary_path,
+            &self.mihomo_target_config_root,
+        );
+        let Some(updated) = replace_exec_start(&unit, &exec_start) else {
+            return Ok(false);
+        };
+        atomic_write(Path::new(path), updated)
+            .with_context(|| format!("failed to update `{}`", path))?;
+        info!(
+            "{} Updated {} to run {}",
+            self.prefix.green(),
+            path.underline().yellow(),
+            self.mihomo_target_binary_path
+        );
+        self.systemctl().daemon_reload().execute()?;
+        Ok(true)
+    }
+
+    /// Check that the directory of `mihomo_binary_path` can be installed to.
+    fn check_binary_dir_writable(&self) -> Result<()> {
+        let dir = self.binary_dir();
+        // Missing directories are created when installing
+        if dir.is_dir() && !dir_writable(dir) {
+            return Err(MihoroError::Permission(format!(
+                "{} is not writable, set `mihomo_binary_path` to a writable location or run \
+                 mihoro as root",
+                dir.to_string_lossy()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Warn if the directory of `mihomo_binary_path` is not on `$PATH`, as the service still finds
+    /// mihomo there, but a shell won't.
+    fn warn_binary_dir_off_path(&self) {
+        let dir = self.binary_dir();
+        if !on_path(dir, env::var_os("PATH").as_deref()) {
+            einfo!(
+                "{} {} is not on $PATH, the service runs mihomo from there but `mihomo` won't be \
+                 found in your shell",
+                "warning:".yellow(),
+                dir.to_string_lossy()
+            );
+        }
+    }
+
+    fn binary_dir(&self) -> &Path {
+        Path::new(&self.mihomo_target_binary_path)
+            .parent()
+            .unwrap_or(Path::new("/"))
+    }
+
+    /// Validate mihomo's `config.yaml` by running `mihomo -t` against it.
+    ///
+    /// Returns an error with mihomo's output if the config fails to parse.
+    pub fn validate_config(&self) -> Result<()> {
+        let args = [
+            "-t",
+            "-d",
+            &self.mihomo_target_config_root,
+            "-f",
+            &self.mihomo_target_config_path,
+        ]
+        .map(String::from);
+        let output = self
+            .runner
+            .output(&self.mihomo_target_binary_path, &args)
+            .with_context(|| {
+                format!(
+                    "failed to run mihomo at `{}`",
+                    self.mihomo_target_binary_path
+                )
+            })?;
+
+        if !output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!(
+                "invalid mihomo config at `{}`\n{}",
+                self.mihomo_target_config_path,
+                format!("{}\n{}", stdout.trim(), stderr.trim()).trim()
+            );
+        }
+
+        info!(
+            "{} Validated mihomo config at {}",
+            self.prefix.green(),
+            self.mihomo_target_config_path.underline().yellow()
+        );
+        Ok(())
+    }
+
+    /// Validate config before restarting the service, refusing to continue on failure unless
+    /// `force` is set. Skipped if the mihomo binary is not installed yet.
+    pub fn validate_before_restart(&self, force: bool) -> Result<()> {
+        if !Path::new(&self.mihomo_target_binary_path).exists() {
+            info!(
+                "{} mihomo binary not found at {}, skipping config validation",
+                self.prefix.yellow(),
+                self.mihomo_target_binary_path.underline()
+            );
+            return Ok(());
+        }
+
+        if let Err(e) = self.validate_config() {
+            if !force {
+                bail!(
+                    "{}\n{} refusing to restart {}, use --force to restart anyway",
+                    e,
+                    "->".dimmed(),
+                    self.config.service_name
+                );
+            }
+            einfo!(
+                "{} {}\n{} restarting anyway (--force)",
+                self.prefix.yellow(),
+                e,
+                "->".dimmed()
+            );
+        }
+        Ok(())
+    }
+
+    /// Query the installed mihomo version via `mihomo -v`, if the binary is present.
+    pub fn mihomo_version(&self) -> Option<String> {
+        let output = self
+            .runner
+            .output(&self.mihomo_target_binary_path, &["-v".to_string()])
+            .ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout
+            .lines()
+            .next()
+            .map(|line| line.trim().to_string())
+            .filter(|line| output.status.success() && !line.is_empty())
+    }
+
+    /// Stop and disable the service, then remove the components confirmed on the terminal, or all
+    /// but the kept ones with `yes`.
+    pub fn uninstall(
+        &self,
+        mihoro_config_path: &str,
+        yes: bool,
+        keep: UninstallKeep,
+        purge: bool,
+    ) -> Result<()> {
+        let ask = !yes && !self.dry_run;
+        if ask && !io::stdin().is_terminal() {
+            bail!("no terminal to confirm uninstalling on, pass `-y` to uninstall anyway");
+        }
+
+        let mut components = Vec::new();
+        for component in self.uninstall_components(mihoro_config_path, keep, purge) {
+            let remove = match (&component.target, ask) {
+                (_, false) => component.remove,
+                (UninstallTarget::AutoUpdate, true) => {
+                    confirm("Disable auto-update?", component.remove)?
+                }
+                (UninstallTarget::Files(paths), true) => confirm(
+                    &format!("Remove {} ({})?", component.name, paths.join(", ")),
+                    component.remove,
+                )?,
+            };
+            components.push(UninstallComponent {
+                remove,
+                ..component
+            });
+        }
+        if ask
+            && !confirm(
+                &format!(
+                    "Stop and disable {}, and remove the selected components?",
+                    self.config.service_name
+                ),
+                false,
+            )?
+        {
+            bail!("uninstall cancelled");
+        }
+
+        // Without systemd there is no service to stop, but files can still be removed
+        let no_systemd = self.systemctl().unavailable();
+        if no_systemd.is_none() {
+            self.systemctl().stop(&self.config.service_name).execute()?;
+            self.systemctl()
+                .disable(&self.config.service_name)
+                .execute()?;
+        }
+
+        let mut removed = Vec::new();
+        for component in components.iter().filter(|component| component.remove) {
+            match &component.target {
+                UninstallTarget::AutoUpdate => {
+                    // Uninstalling the rest should not fail on a missing `crontab`
+                    match self.cron_commands(&Some(CronCommands::Disable)) {
+                        Ok(()) => removed.push(component.name.to_string()),
+                        Err(e) => einfo!(
+                            "{} Failed to disable auto-update: {}",
+                            self.prefix.yellow(),
+                            e
+                        ),
+                    }
+                }
+                UninstallTarget::Files(paths) => {
+                    for path in paths {
+                        if !self.skip_in_dry_run(&format!("remove {}", path)) && delete_file(path)?
+                        {
+                            removed.push(path.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        if no_systemd.is_none() {
+            self.systemctl().daemon_reload().execute()?;
+            self.systemctl().reset_failed().execute()?;
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+
+        match no_systemd {
+            None => info!(
+                "{} Stopped and disabled {}",
+                self.prefix.green(),
+                self.config.service_name
+            ),
+            Some(_) => info!(
+                "{} systemd is not available, stop mihomo yourself if it is running",
+                self.prefix.yellow()
+            ),
+        }
+        if removed.is_empty() {
+            info!("{} Nothing removed", self.prefix.yellow());
+        } else {
+            info!("{} Removed:", self.prefix.green());
+            for item in &removed {
+                info!("{} {}", "->".dimmed(), item);
+            }
+        }
+        let kept: Vec<&UninstallComponent> = components
+            .iter()
+            .filter(|component| !component.remove)
+            .collect();
+        if !kept.is_empty() {
+            info!("{} Kept:", self.prefix.cyan());
+            for component in kept {
+                match &component.target {
+                    UninstallTarget::AutoUpdate => info!("{} {}", "->".dimmed(), component.name),
+                    UninstallTarget::Files(paths) => paths
+                        .iter()
+                        .for_each(|path| info!("{} {}", "->".dimmed(), path)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Components `uninstall` may remove, skipping files that do not exist, with whether each is
+    /// removed by default. Backups, caches and state are only included with `purge`.
+    fn uninstall_components(
+        &self,
+        mihoro_config_path: &str,
+        keep: UninstallKeep,
+        purge: bool,
+    ) -> Vec<UninstallComponent> {
+        let existing = |paths: Vec<String>| -> Vec<String> {
+            paths
+                .into_iter()
+                .filter(|path| Path::new(path).exists())
+                .collect()
+        };
+        let geodata = GEODATA_FILES
+            .iter()
+            .flat_map(|file| {
+                let path = Path::new(&self.mihomo_target_config_root).join(file);
+                [CacheValidators::path_for(&path), path]
+            })
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+
+        let files = [
+            (
+                "systemd unit",
+                vec![self.mihomo_target_service_path.clone()],
+                true,
+            ),
+            (
+                "mihomo binary",
+                [self.mihomo_target_binary_path.clone()]
+                    .into_iter()
+                    .chain(InstallState::path().map(|path| path.to_string_lossy().into_owned()))
+                    .collect(),
+                !keep.keep_binary,
+            ),
+            (
+                "config",
+                vec![
+                    self.mihomo_target_config_path.clone(),
+                    expand(mihoro_config_path),
+                ],
+                !keep.keep_config,
+            ),
+            ("geodata", geodata, !keep.keep_geodata),
+        ];
+        let purged = [
+            (
+                "backups",
+                list_backups(
+                    Path::new(&self.mihomo_target_config_root),
+                    &file_name(&self.mihomo_target_config_path),
+                )
+                .unwrap_or_default()
+                .into_iter()
+                .chain([self.backup_dir()])
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+                true,
+            ),
+            (
+                "cache and state",
+                [self.subscription_info_path()]
+                    .into_iter()
+                    .chain(purge_dirs(&|var| env::var(var).ok()))
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+                true,
+            ),
+        ];
+        let files = files
+            .into_iter()
+            .chain(purged.into_iter().filter(|_| purge));
+
+        let mut components = vec![UninstallComponent {
+            name: "auto-update",
+            target: UninstallTarget::AutoUpdate,
+            remove: !keep.keep_cron,
+        }];
+        for (name, paths, remove) in files {
+            let paths = existing(paths);
+            if !paths.is_empty() {
+                components.push(UninstallComponent {
+                    name,
+                    target: UninstallTarget::Files(paths),
+                    remove,
+                });
+            }
+        }
+        components
+    }
+
+    pub fn proxy_commands(
+        &self,
+        proxy: &Option<ProxyCommands>,
+        shell_override: Option<Shell>,
+        no_clipboard: bool,
+        ipv6: bool,
+    ) -> Result<()> {
+        let shell = detect_shell(shell_override);
+
+        // Ports from the live mihomo config take precedence, falling back to `mihoro.toml`
+        let live_ports = read_mihomo_ports(&self.mihomo_target_config_path).unwrap_or_default();
+        let (port, socks_port) = live_ports.resolve(&self.config.mihomo_config);
+
+        let lan_host = |bind: &Option<IpAddr>| -> Result<String> {
+            self.warn_if_lan_disabled();
+            Ok(url_host(bind.map_or_else(|| detect_lan_ip(ipv6), Ok)?))
+        };
+        let loopback_host = url_host(loopback_ip(ipv6));
+
+        let cmd = match proxy {
+            Some(ProxyCommands::Export) => {
+                proxy_export_cmd(shell, &loopback_host, &port, &socks_port)
+            }
+            Some(ProxyCommands::ExportLan { bind }) => {
+                proxy_export_cmd(shell, &lan_host(bind)?, &port, &socks_port)
+            }
+            Some(ProxyCommands::ExportSocks) => {
+                proxy_export_socks_cmd(shell, &loopback_host, &socks_port)
+            }
+            Some(ProxyCommands::ExportSocksLan { bind }) => {
+                proxy_export_socks_cmd(shell, &lan_host(bind)?, &socks_port)
+            }
+            Some(ProxyCommands::Unset) => proxy_unset_cmd(shell),
+            _ => return Ok(()),
+        };
+
+        println!("{}", cmd);
+        if !no_clipboard {
+            self.copy_proxy_commands(&cmd);
+        }
+        Ok(())
+    }
+
+    /// Copy proxy commands to the clipboard, noting on stderr when that is not possible.
+    ///
+    /// Skipped silently over SSH or without a display, where no clipboard is reachable.
+    fn copy_proxy_commands(&self, cmd: &str) {
+        if !clipboard_available() {
+            return;
+        }
+
+        if copy_to_clipboard(cmd) {
+            einfo!("{} Copied to clipboard", self.prefix.green());
+        } else {
+            einfo!(
+                "{} Clipboard unavailable (install wl-copy, xclip or xsel), copy the command above",
+                self.prefix.yellow()
+            );
+        }
+    }
+
+    /// Warn if mihomo does not accept LAN connections, per `allow-lan` in the live `config.yaml`,
+    /// falling back to `allow_lan` in `mihoro.toml`.
+    fn warn_if_lan_disabled(&self) {
+        let allow_lan = read_mihomo_allow_lan(&self.mihomo_target_config_path)
+            .ok()
+            .flatten()
+            .or(self.config.mihomo_config.allow_lan)
+            .unwrap_or(false);
+        if !allow_lan {
+            einfo!(
+                "{} `{}` is false, proxy is not available for LAN, set `{}` in mihoro.toml and \
+                 run `mihoro apply`",
+                "warning:".yellow(),
+                "allow-lan".bold(),
+                "ensure_allow_lan = true".bold()
+            );
+        }
+    }
+
+    pub fn config_commands(&self, command: &Option<ConfigCommands>) -> Result<()> {
+        match command {
+            Some(ConfigCommands::Validate) => self.validate_config(),
+            Some(ConfigCommands::Show { reveal }) => self.show_config(*reveal),
+            _ => Ok(()),
+        }
+    }
+
+    /// Print the parsed `mihoro.toml` with defaults filled in, as TOML or JSON, with secrets
+    /// redacted unless `reveal`.
+    fn show_config(&self, reveal: bool) -> Result<()> {
+        let mut value = toml::Value::try_from(&self.config)?;
+        if !reveal {
+            redact_value(&mut value, "");
+        }
+        if self.output == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&value)?);
+        } else {
+            print!("{}", toml::to_string_pretty(&value)?);
+        }
+        Ok(())
+    }
+
+    pub async fn profile_commands(
+        &self,
+        client: &Client,
+        config_path: &str,
+        command: &Option<ProfileCommands>,
+    ) -> Result<()> {
+        match command {
+            Some(ProfileCommands::List) => {
+                if self.config.profiles.is_empty() {
+                    println!(
+                        "{} No profiles defined, using top-level `remote_config_url`",
+                        self.prefix.yellow()
+                    );
+                    return Ok(());
+                }
+                for (name, profile) in &self.config.profiles {
+                    let active = self.config.active_profile.as_ref() == Some(name);
+                    println!(
+                        "{} {} {}",
+                        if active { "*".green() } else { " ".normal() },
+                        if active { name.bold() } else { name.normal() },
+                        profile.remote_config_url.dimmed()
+                    );
+                }
+                Ok(())
+            }
+            Some(ProfileCommands::Current) => {
+                match &self.config.active_profile {
+                    Some(name) => println!("{}", name),
+                    None => println!(
+                        "{} No active profile, using top-level `remote_config_url`",
+                        self.prefix.yellow()
+                    ),
+                }
+                Ok(())
+            }
+            Some(ProfileCommands::Use { name, force }) => {
+                if !self.config.profiles.contains_key(name) {
+                    let available: Vec<&str> =
+                        self.config.profiles.keys().map(String::as_str).collect();
+                    bail!(
+                        "profile `{}` not found, available profiles: {}",
+                        name,
+                        if available.is_empty() {
+                            String::from("(none)")
+                        } else {
+                            available.join(", ")
+                        }
+                    );
+                }
+
+                if !self.skip_in_dry_run(&format!("set `active_profile = \"{}\"`", name)) {
+                    set_active_profile(&expand(config_path), name)?;
+                    info!(
+                        "{} Switched to profile {}",
+                        self.prefix.green(),
+                        name.bold()
+                    );
+                }
+
+                let mut switched = self.clone();
+                switched.config.active_profile = Some(name.clone());
+                switched
+                    .update_config(client, true, *force, false, None)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cron_commands(&self, command: &Option<CronCommands>) -> Result<()> {
+        let system = self.config.systemd_mode == SystemdMode::System;
+        // Timer units go on systemd's search path even with `systemd_unit_dir`, as they are
+        // enabled by name
+        let unit_dir = &expand_path(default_systemd_root(&self.config));
+        let timer = self.config.cron_backend == CronBackend::SystemdTimer;
+
+        match command {
+            Some(CronCommands::Enable {
+                schedule,
+                interval,
+                scope,
+            }) => {
+                let schedule = interval
+                    .map(CronInterval::schedule)
+                    .or(schedule.as_deref())
+                    .or(self.config.cron_schedule.as_deref());
+                if let Some(schedule) = schedule {
+                    cron::validate_schedule(schedule)?;
+                    if timer {
+                        cron::to_on_calendar(schedule)?;
+                    }
+                }
+                if self.skip_in_dry_run("enable auto-update cron job") {
+                    return Ok(());
+                }
+                let interval = self.config.auto_update_interval;
+                if timer {
+                    cron::enable_timer_auto_update(
+                        schedule,
+                        interval,
+                        *scope,
+                        unit_dir,
+                        system,
+                        &self.prefix,
+                    )
+                } else {
+                    cron::enable_auto_update(schedule, interval, *scope, &self.prefix)
+                }
+            }
+            Some(CronCommands::Disable) => {
+                if self.skip_in_dry_run("disable auto-update cron job") {
+                    return Ok(());
+                }
+                if timer {
+                    cron::disable_timer_auto_update(unit_dir, system, &self.prefix)
+                } else {
+                    cron::disable_auto_update(&self.prefix)
+                }
+            }
+            Some(CronCommands::Status) => {
+                if timer {
+                    cron::get_timer_status(unit_dir, system, &self.mihomo_target_config_path)
+                } else {
+                    cron::get_cron_status(&self.prefix, &self.mihomo_target_config_path)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Deep-merge `overrides` into a mihomo YAML value.
+///
+/// Mappings are merged recursively, so only keys present in `overrides` are replaced and all other
+/// keys are kept as is. Any other value, including sequences, replaces the original value entirely
+/// (sequences are not appended to).
+pub fn apply_overrides(yaml: &mut Value, overrides: &Value) {
+    match (yaml, overrides) {
+        (Value::Mapping(base), Value::Mapping(patch)) => {
+            for (key, value) in patch {
+                match base.get_mut(key) {
+                    Some(existing) => apply_overrides(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, patch) => *base = patch.clone(),
+    }
+}
+
+/// Top-level keys of the YAML mapping `old` that are added, removed or changed in `new`, in the
+/// order of `new`, followed by removed keys. Keys are marked `(added)` or `(removed)`.
+pub fn changed_keys(old: &str, new: &str) -> Vec<String> {
+    let mapping = |raw: &str| match serde_yaml::from_str(raw) {
+        Ok(Value::Mapping(mapping)) => mapping,
+        _ => serde_yaml::Mapping::new(),
+    };
+    let (old, new) = (mapping(old), mapping(new));
+    let name = |key: &Value| {
+        key.as_str().map(String::from).unwrap_or_else(|| {
+            serde_yaml::to_string(key)
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        })
+    };
+
+    let mut keys: Vec<String> = new
+        .iter()
+        .filter_map(|(key, value)| match old.get(key) {
+            None => Some(format!("{} (added)", name(key))),
+            Some(old_value) if old_value != value => Some(name(key)),
+            Some(_) => None,
+        })
+        .collect();
+    keys.extend(
+        old.keys()
+            .filter(|key| !new.contains_key(*key))
+            .map(|key| format!("{} (removed)", name(key))),
+    );
+    keys
+}
+
+/// The rules of `field` (e.g. `extra_rules_prepend`) that look well-formed, warning about the rest,
+/// which would stop mihomo from loading the config.
+fn valid_rules<'a>(rules: &'a [String], field: &str) -> Vec<&'a str> {
+    rules
+        .iter()
+        .map(|rule| rule.trim())
+        .filter(|rule| match rule_problem(rule) {
+            Some(problem) => {
+                einfo!(
+                    "{} skipping `{}` in `{}`, it {}",
+                    "warning:".yellow(),
+                    rule,
+                    field,
+                    problem
+                );
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Expand `~` and environment variables in a path from the config, see `expand_path`.
+fn expand(path: &str) -> String {
+    expand_path(path).to_string_lossy().into_owned()
+}
+
+/// Copy `from` over `to`, atomically replacing it, see `atomic_write_with`.
+fn copy_atomically(from: &Path, to: &str, mode: Option<u32>) -> Result<()> {
+    atomic_write_with(Path::new(to), mode, |file| {
+        io::copy(&mut fs::File::open(from)?, file)?;
+        Ok(())
+    })
+}
+
+/// Check that a mihomo binary runs and reports the `expected` version, returning its version line.
+///
+/// Catches binaries built for the wrong architecture or corrupted in transit before they are
+/// swapped in.
+fn check_binary_runs(path: &Path, expected: Option<&str>, timeout: Duration) -> Result<String> {
+    let output = output_with_timeout(Command::new(path).arg("-v"), timeout).context(
+        "downloaded mihomo binary failed to run, it may be built for another architecture (try `--arch`)",
+    )?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.lines().next().unwrap_or("").trim().to_string();
+    if !output.status.success() || version.is_empty() {
+        bail!(
+            "downloaded mihomo binary failed to report its version ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if let Some(expected) = expected {
+        if !version.split_whitespace().any(|word| word == expected) {
+            bail!(
+                "downloaded mihomo binary reports `{}`, expected version {}",
+                version,
+                expected
+            );
+        }
+    }
+    Ok(version)
+}
+
+/// Lines of unchanged context shown around each change by `apply --diff`.
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Render a unified diff between `old` and `new`, labelled `old_name` and `new_name`, or an empty
+/// string if they are identical.
+fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(DIFF_CONTEXT_LINES)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+/// Print a unified diff, colored by line kind.
+fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        if line.starts_with("+++") || line.starts_with("---") {
+            println!("{}", line.bold());
+        } else if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Returns the final path component of a path, or the path itself if it has none.
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Unit directory on systemd's search path for `systemd_mode`.
+fn default_systemd_root(config: &Config) -> &str {
+    match config.systemd_mode {
+        SystemdMode::User => &config.user_systemd_root,
+        SystemdMode::System => SYSTEM_SYSTEMD_ROOT,
+    }
+}
+
+/// Path of the unit file, under `systemd_unit_dir` if set, or the default for `systemd_mode`.
+fn service_path(config: &Config) -> String {
+    let systemd_root = config
+        .systemd_unit_dir
+        .as_deref()
+        .unwrap_or_else(|| default_systemd_root(config));
+    expand(&format!("{}/{}", systemd_root, config.service_name))
+}
+
+/// The unit's `ExecStart=` line, running mihomo at `binary` with its config in `config_root`.
+fn exec_start(binary: &str, config_root: &str) -> String {
+    format!("ExecStart={} -d {}", binary, config_root)
+}
+
+/// `unit` with its `ExecStart=` line replaced by `exec_start`, or `None` if it already matches or
+/// has none.
+///
+/// Only that line is replaced, so other changes made to the unit are kept.
+fn replace_exec_start(unit: &str, exec_start: &str) -> Option<String> {
+    let current = unit.lines().find(|line| line.starts_with("ExecStart="))?;
+    (current != exec_start).then(|| unit.replacen(current, exec_start, 1))
+}
+
+/// Create a systemd service file for running mihomo as a service.
+///
+/// By default, user systemd services are created under `~/.config/systemd/user/mihomo.service` and
+/// invoked with `systemctl --user start mihomo.service`. In system mode, the service is created
+/// under `/etc/systemd/system/` and wanted by `multi-user.target` instead. Directory is created if
+/// not present.
+///
+/// Reference: https://wiki.metacubex.one/startup/service/
+fn create_mihomo_service(
+    mihomo_binary_path: &str,
+    mihomo_config_root: &str,
+    mihomo_service_path: &str,
+    systemd_mode: &SystemdMode,
+    prefix: &str,
+) -> Result<()> {
+    let wanted_by = match systemd_mode {
+        SystemdMode::User => "default.target",
+        SystemdMode::System => "multi-user.target",
+    };
+    let service = format!(
+        "[Unit]
+Description=mihomo Daemon, Another Clash Kernel.
+After=network.target NetworkManager.service systemd-networkd.service iwd.service
+
+[Service]
+Type=simple
+LimitNPROC=4096
+LimitNOFILE=65536
+Restart=always
+ExecStartPre=/usr/bin/sleep 1s
+{}
+ExecReload=/bin/kill -HUP $MAINPID
+
+[Install]
+WantedBy={}",
+        exec_start(mihomo_binary_path, mihomo_config_root),
+        wanted_by
+    );
+
+    // Create mihomo service directory if not exists
+    create_parent_dir(Path::new(mihomo_service_path))?;
+
+    // Write service contents to file
+    fs::write(mihomo_service_path, service)?;
+
+    info!(
+        "{} Created systemd service at {}",
+        prefix.green(),
+        mihomo_service_path.underline().yellow()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockResponse, MockRunner};
+    use crate::utils::{received_user_agent, serve_once};
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Test that Mihoro::new correctly parses config and derives paths
+    #[test]
+    fn test_mihoro_new_parses_config_and_derives_paths() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        // Write a valid config file
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "/tmp/test/mihomo"
+            mihomo_config_root = "/tmp/test/mihomo"
+            user_systemd_root = "/tmp/test/systemd"
+        "#;
+        fs::write(&config_path, toml_content)?;
+
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?;
+
+        assert_eq!(mihoro.mihomo_target_binary_path, "/tmp/test/mihomo");
+        assert_eq!(mihoro.mihomo_target_config_root, "/tmp/test/mihomo");
+        assert_eq!(
+            mihoro.mihomo_target_config_path,
+            "/tmp/test/mihomo/config.yaml"
+        );
+        assert_eq!(
+            mihoro.mihomo_target_service_path,
+            "/tmp/test/systemd/mihomo.service"
+        );
+
+        Ok(())
+    }
+
+    /// Test that proxy_commands uses mixed_port when set
+    #[test]
+    fn test_proxy_commands_uses_mixed_port_when_set() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "/tmp/test/mihomo"
+            mihomo_config_root = "/tmp/test/mihomo"
+            user_systemd_root = "/tmp/test/systemd"
+
+            [mihomo_config]
+            port = 7891
+            socks_port = 7892
+            mixed_port = 7890
+        "#;
+        fs::write(&config_path, toml_content)?;
+
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?;
+
+        // Test Export command (should use mixed_port 7890)
+        let cmd = mihoro.proxy_commands(&Some(ProxyCommands::Export), None, true, false);
+        assert!(cmd.is_ok());
+
+        Ok(())
+    }
+
+    /// Test that proxy_commands falls back to port/socks_port when mixed_port is None
+    #[test]
+    fn test_proxy_commands_fallback_to_port_when_mixed_port_none() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "/tmp/test/mihomo"
+            mihomo_config_root = "/tmp/test/mihomo"
+            user_systemd_root = "/tmp/test/systemd"
+
+            [mihomo_config]
+            port = 7891
+            socks_port = 7892
+        "#;
+        fs::write(&config_path, toml_content)?;
+
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?;
+
+        let cmd = mihoro.proxy_commands(&Some(ProxyCommands::Export), None, true, false);
+        assert!(cmd.is_ok());
+
+        Ok(())
+    }
+
+    /// Test that uninstall in dry-run mode leaves files in place
+    #[test]
+    fn test_uninstall_dry_run_keeps_files() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "{root}/mihomo"
+            mihomo_config_root = "{root}"
+            user_systemd_root = "{root}"
+        "#;
+        fs::write(
+            &config_path,
+            toml_content.replace("{root}", dir.path().to_str().unwrap()),
+        )?;
+
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?.with_dry_run(true);
+        fs::write(&mihoro.mihomo_target_service_path, "[Unit]")?;
+        fs::write(&mihoro.mihomo_target_config_path, "port: 7890")?;
+
+        mihoro.uninstall(
+            config_path.to_str().unwrap(),
+            false,
+            UninstallKeep::default(),
+            false,
+        )?;
+
+        assert!(Path::new(&mihoro.mihomo_target_service_path).exists());
+        assert!(Path::new(&mihoro.mihomo_target_config_path).exists());
+
+        Ok(())
+    }
+
+    /// Test that uninstall with `-y` removes everything but the kept components
+    #[test]
+    fn test_uninstall_keeps_selected_components() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        let runner = mock_systemctl(&mihoro.mihomo_target_binary_path);
+        mihoro.runner = runner.clone();
+        let config_path = dir.path().join("test.toml");
+        let geoip = dir.path().join("geoip.dat");
+        for path in [
+            &mihoro.mihomo_target_service_path,
+            &mihoro.mihomo_target_binary_path,
+            &mihoro.mihomo_target_config_path,
+        ] {
+            fs::write(path, "")?;
+        }
+        fs::write(&geoip, "")?;
+
+        // Auto-update is kept, as disabling it would touch the real crontab
+        let keep = UninstallKeep {
+            keep_config: true,
+            keep_cron: true,
+            ..UninstallKeep::default()
+        };
+        mihoro.uninstall(config_path.to_str().unwrap(), true, keep, false)?;
+
+        assert!(!Path::new(&mihoro.mihomo_target_service_path).exists());
+        assert!(!Path::new(&mihoro.mihomo_target_binary_path).exists());
+        assert!(!geoip.exists());
+        assert!(Path::new(&mihoro.mihomo_target_config_path).exists());
+        assert!(config_path.exists());
+
+        // The service is stopped and disabled before anything is removed
+        let log = systemctl_log(&runner);
+        assert!(log.starts_with(
+            "--user show-environment\n--user stop mihomo.service\n--user disable mihomo.service\n"
+        ));
+        Ok(())
+    }
+
+    /// Test that backups and cached files are only uninstalled with `--purge`
+    #[test]
+    fn test_uninstall_purge_components() -> Result<()> {
+        let dir = tempdir()?;
+        let mihoro = setup_test_mihoro(dir.path())?;
+        let config_backup = format!("{}.bak.1700000000", mihoro.mihomo_target_config_path);
+        fs::write(&config_backup, "")?;
+        fs::create_dir_all(mihoro.backup_dir())?;
+        fs::write(mihoro.subscription_info_path(), "{}")?;
+        let config_path = dir.path().join("test.toml");
+
+        let names = |purge| -> Vec<&str> {
+            mihoro
+                .uninstall_components(
+                    config_path.to_str().unwrap(),
+                    UninstallKeep::default(),
+                    purge,
+                )
+                .iter()
+                .map(|component| component.name)
+                .collect()
+        };
+        assert!(!names(false).contains(&"backups"));
+
+        let components = mihoro.uninstall_components(
+            config_path.to_str().unwrap(),
+            UninstallKeep::default(),
+            true,
+        );
+        let paths = |name| match components.iter().find(|c| c.name == name) {
+            Some(UninstallComponent {
+                target: UninstallTarget::Files(paths),
+                remove: true,
+                ..
+            }) => paths.clone(),
+            _ => panic!("{} not removed", name),
+        };
+        assert_eq!(
+            paths("backups"),
+            [
+                config_backup,
+                mihoro.backup_dir().to_string_lossy().into_owned()
+            ]
+        );
+        assert!(paths("cache and state").contains(
+            &mihoro
+                .subscription_info_path()
+                .to_string_lossy()
+                .into_owned()
+        ));
+        Ok(())
+    }
+
+    /// Test that `--purge` only removes `mihoro` directories in proper XDG base directories
+    #[test]
+    fn test_purge_dirs() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(
+            purge_dirs(&env(&[("HOME", "/home/u")])),
+            [
+                PathBuf::from("/home/u/.cache/mihoro"),
+                PathBuf::from("/home/u/.local/state/mihoro")
+            ]
+        );
+        // A relative base is ignored as XDG requires, `/` and the home directory are refused
+        let dirs = purge_dirs(&env(&[
+            ("HOME", "/home/u"),
+            ("XDG_CACHE_HOME", "/home/u/"),
+            ("XDG_STATE_HOME", "/"),
+        ]));
+        assert!(dirs.is_empty());
+        assert_eq!(
+            purge_dirs(&env(&[
+                ("HOME", "/home/u"),
+                ("XDG_CACHE_HOME", "cache"),
+                ("XDG_STATE_HOME", "/var/lib/u"),
+            ])),
+            [
+                PathBuf::from("/home/u/.cache/mihoro"),
+                PathBuf::from("/var/lib/u/mihoro")
+            ]
+        );
+    }
+
+    /// Test that the unit is written to, and uninstalled from, the overridden directory
+    #[test]
+    fn test_systemd_dir_override() -> Result<()> {
+        let dir = tempdir()?;
+        let unit_dir = dir.path().join("units");
+        let mut mihoro = setup_test_mihoro(dir.path())?
+            .with_systemd_dir(Some(unit_dir.to_string_lossy().into_owned()));
+        let runner = mock_systemctl(&mihoro.mihomo_target_binary_path);
+        mihoro.runner = runner.clone();
+        let service_path = unit_dir.join("mihomo.service");
+        assert_eq!(
+            mihoro.mihomo_target_service_path,
+            service_path.to_string_lossy()
+        );
+        assert_eq!(mihoro.enable_unit(), service_path.to_string_lossy());
+
+        create_mihomo_service(
+            &mihoro.mihomo_target_binary_path,
+            &mihoro.mihomo_target_config_root,
+            &mihoro.mihomo_target_service_path,
+            &mihoro.config.systemd_mode,
+            &mihoro.prefix,
+        )?;
+        assert!(service_path.exists());
+        assert!(!dir.path().join("mihomo.service").exists());
+
+        let keep = UninstallKeep {
+            keep_binary: true,
+            keep_config: true,
+            keep_geodata: true,
+            keep_cron: true,
+        };
+        mihoro.uninstall(
+            dir.path().join("test.toml").to_str().unwrap(),
+            true,
+            keep,
+            false,
+        )?;
+        assert!(!service_path.exists());
+        let log = systemctl_log(&runner);
+        assert!(log.contains("--user daemon-reload\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_exec_start() {
+        let unit = "[Service]\nExecStart=/old/mihomo -d /cfg\nEnvironment=A=1\n";
+        assert_eq!(
+            replace_exec_start(unit, "ExecStart=/new/mihomo -d /cfg").as_deref(),
+            Some("[Service]\nExecStart=/new/mihomo -d /cfg\nEnvironment=A=1\n")
+        );
+        assert_eq!(
+            replace_exec_start(unit, "ExecStart=/old/mihomo -d /cfg"),
+            None
+        );
+        assert_eq!(
+            replace_exec_start("[Service]\n", "ExecStart=/new/mihomo"),
+            None
+        );
+    }
+
+    /// Test that a changed `mihomo_binary_path` is written to the existing unit
+    #[test]
+    fn test_sync_service_exec_start() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        let runner = mock_systemctl(&mihoro.mihomo_target_binary_path);
+        mihoro.runner = runner.clone();
+        // Nothing to update before setup wrote the unit
+        assert!(!mihoro.sync_service_exec_start()?);
+
+        create_mihomo_service(
+            &mihoro.mihomo_target_binary_path,
+            &mihoro.mihomo_target_config_root,
+            &mihoro.mihomo_target_service_path,
+            &mihoro.config.systemd_mode,
+            &mihoro.prefix,
+        )?;
+        assert!(!mihoro.sync_service_exec_start()?);
+
+        let moved = dir.path().join("bin/mihomo").to_string_lossy().into_owned();
+        mihoro.mihomo_target_binary_path = moved.clone();
+        assert!(mihoro.sync_service_exec_start()?);
+        let unit = fs::read_to_string(&mihoro.mihomo_target_service_path)?;
+        assert!(unit.contains(&format!(
+            "\nExecStart={} -d {}\n",
+            moved, mihoro.mihomo_target_config_root
+        )));
+        assert!(unit.contains("ExecReload="));
+        let log = systemctl_log(&runner);
+        assert_eq!(log, "--user daemon-reload\n");
+        Ok(())
+    }
+
+    /// Test that validation failures block restarts unless forced
+    #[test]
+    fn test_validate_before_restart_respects_force() -> Result<()> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("test.toml");
+
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "{root}/mihomo"
+            mihomo_config_root = "{root}"
+            user_systemd_root = "{root}"
+        "#;
+        fs::write(
+            &config_path,
+            toml_content.replace("{root}", dir.path().to_str().unwrap()),
+        )?;
+
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?;
+
+        // Fake mihomo binary that always fails config test
+        fs::write(
+            &mihoro.mihomo_target_binary_path,
+            "#!/bin/sh\necho 'yaml: line 1: did not find expected key'\nexit 1\n",
+        )?;
+        fs::set_permissions(
+            &mihoro.mihomo_target_binary_path,
+            fs::Permissions::from_mode(0o755),
+        )?;
+
+        let err = mihoro.validate_config().unwrap_err().to_string();
+        assert!(err.contains("did not find expected key"));
+        assert!(mihoro.validate_before_restart(false).is_err());
+        assert!(mihoro.validate_before_restart(true).is_ok());
+
+        Ok(())
+    }
+
+    /// Create a `Mihoro` with all paths under `root`
+    fn setup_test_mihoro(root: &Path) -> Result<Mihoro> {
+        let config_path = root.join("test.toml");
+        let toml_content = r#"
+            remote_config_url = "http://example.com/config.yaml"
+            mihomo_binary_path = "{root}/mihomo"
+            mihomo_config_root = "{root}"
+            user_systemd_root = "{root}"
+        "#;
+        fs::write(
+            &config_path,
+            toml_content.replace("{root}", root.to_str().unwrap()),
+        )?;
+        Mihoro::new(config_path.to_str().unwrap())
+    }
+
+    /// Test that a failed geodata download doesn't stop the others, and is reported by file
+    #[tokio::test]
+    async fn test_update_geodata_partial_failure() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        let (url, _) = serve_once("mmdb").await;
+        mihoro.config.download_retries = 1;
+        mihoro.config.geodata.mmdb = Some(format!("{}country.mmdb", url));
+        // Nothing listens on port 1
+        mihoro.config.geodata.asn = Some(String::from("http://127.0.0.1:1/ASN.mmdb"));
+
+        let err = mihoro
+            .update_geodata(&Client::new(), false)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("failed to download 1 of 2 geodata files: ASN.mmdb: "));
+        assert_eq!(fs::read_to_string(dir.path().join("country.mmdb"))?, "mmdb");
+        assert!(!dir.path().join("ASN.mmdb").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_args() -> Result<()> {
+        let dir = tempdir()?;
+        let mihoro = setup_test_mihoro(dir.path())?;
+        let root = dir.path().to_string_lossy();
+
+        assert_eq!(
+            mihoro.run_args(&["-t".to_string()]),
+            ["-d", &root, "-f", &format!("{}/config.yaml", root), "-t"]
+        );
+        // A missing binary is reported before trying to run it
+        let err = mihoro.run_foreground(&[]).unwrap_err();
+        assert!(err.to_string().contains("run `mihoro setup` first"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_journalctl_args() -> Result<()> {
+        let dir = tempdir()?;
+        let mihoro = setup_test_mihoro(dir.path())?;
+
+        assert_eq!(
+            mihoro.journalctl_args(10, true, None),
+            ["--user", "-xeu", "mihomo.service", "-n", "10", "-f"]
+        );
+        assert_eq!(
+            mihoro.journalctl_args(200, false, Some("1 hour ago")),
+            [
+                "--user",
+                "-xeu",
+                "mihomo.service",
+                "-n",
+                "200",
+                "--since",
+                "1 hour ago",
+                "--no-pager"
+            ]
+        );
+        Ok(())
+    }
+
+    /// Test that subscription info is cached from headers and cleared when the header is omitted
+    #[test]
+    fn test_cache_subscription_info() -> Result<()> {
+        let dir = tempdir()?;
+        let mihoro = setup_test_mihoro(dir.path())?;
+        let path = mihoro.subscription_info_path();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            USERINFO_HEADER,
+            "upload=1; download=2; total=10; expire=0".parse()?,
+        );
+        mihoro.cache_subscription_info(&headers)?;
+        let info = crate::subscription::load_subscription_info(&path).unwrap();
+        assert_eq!(info.remaining(), 7);
+
+        mihoro.cache_subscription_info(&HeaderMap::new())?;
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    /// Test that `--config-url` overrides the configured subscription url after validation
+    #[test]
+    fn test_remote_config_url_override() -> Result<()> {
+        let dir = tempdir()?;
+        let mihoro = setup_test_mihoro(dir.path())?;
+        assert_eq!(
+            mihoro.remote_config_url(None)?,
+            mihoro.config.active_remote_config_url()
+        );
+        assert_eq!(
+            mihoro.remote_config_url(Some("https://example.com/new"))?,
+            "https://example.com/new"
+        );
+        assert!(mihoro.remote_config_url(Some("ftp://example.com")).is_err());
+        Ok(())
+    }
+
+    /// Test that a new binary must run and report the expected version
+    #[test]
+    fn test_check_binary_runs() -> Result<()> {
+        let dir = tempdir()?;
+        let binary = dir.path().join("mihomo");
+        let timeout = Duration::from_secs(5);
+
+        fs::write(
+            &binary,
+            "#!/bin/sh\necho 'Mihoro Meta v1.19.0 linux amd64 with go1.23.4'\n",
+        )?;
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))?;
+        let version = check_binary_runs(&binary, Some("v1.19.0"), timeout)?;
+        assert!(version.contains("v1.19.0"));
+        assert!(check_binary_runs(&binary, None, timeout).is_ok());
+        assert!(check_binary_runs(&binary, Some("v1.19"), timeout).is_err());
+
+        // Not a valid executable, like a binary for another architecture
+        fs::write(&binary, [0x7f, b'E', b'L', b'F', 0, 0, 0, 0])?;
+        let err = check_binary_runs(&binary, None, timeout).unwrap_err();
+        assert!(err.to_string().contains("failed to run"));
+
+        // Hangs instead of printing its version
+        fs::write(&binary, "#!/bin/sh\nsleep 5\n")?;
+        let err = check_binary_runs(&binary, None, Duration::from_millis(100)).unwrap_err();
+        assert!(format!("{:#}", err).contains("timed out"));
+        Ok(())
+    }
+
+    /// Test that the mihomo version is the first line `mihomo -v` prints, if it succeeds
+    #[test]
+    fn test_mihomo_version() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        mihoro.runner = Arc::new(MockRunner::new(|_| {
+            MockResponse::ok("Mihomo Meta v1.19.0 linux amd64\nUse tags: with_gvisor\n")
+        }));
+        assert_eq!(
+            mihoro.mihomo_version().as_deref(),
+            Some("Mihomo Meta v1.19.0 linux amd64")
+        );
+
+        mihoro.runner = Arc::new(MockRunner::new(|_| {
+            MockResponse::fail(1, "exec format error")
+        }));
+        assert_eq!(mihoro.mihomo_version(), None);
+        Ok(())
+    }
+
+    /// Mock systemctl that reports the service active only while the binary contains `healthy`
+    fn mock_systemctl(binary_path: &str) -> Arc<MockRunner> {
+        let binary_path = binary_path.to_string();
+        Arc::new(MockRunner::new(move |command| {
+            if !command.contains("is-active") {
+                return MockResponse::default();
             }
-            Some(CronCommands::Disable) => cron::disable_auto_update(&self.prefix),
-            Some(CronCommands::Status) => {
-                cron::get_cron_status(&self.prefix, &self.mihomo_target_config_path)
+            match fs::read_to_string(&binary_path) {
+                Ok(binary) if binary.contains("healthy") => MockResponse::ok("active\n"),
+                _ => MockResponse::ok("failed\n"),
             }
-            _ => Ok(()),
-        }
+        }))
     }
-}
 
-/// Create a systemd service file for running mihomo as a service.
-///
-/// By default, user systemd services are created under `~/.config/systemd/user/mihomo.service` and
-/// invoked with `systemctl --user start mihomo.service`. Directory is created if not present.
-///
-/// Reference: https://wiki.metacubex.one/startup/service/
-fn create_mihomo_service(
-    mihomo_binary_path: &str,
-    mihomo_config_root: &str,
-    mihomo_service_path: &str,
-    prefix: &str,
-) -> Result<()> {
-    let service = format!(
-        "[Unit]
-Description=mihomo Daemon, Another Clash Kernel.
-After=network.target NetworkManager.service systemd-networkd.service iwd.service
+    /// The systemctl arguments run through `runner`, one command per line
+    fn systemctl_log(runner: &MockRunner) -> String {
+        runner
+            .calls()
+            .iter()
+            .filter_map(|command| command.strip_prefix("systemctl "))
+            .map(|args| format!("{}\n", args))
+            .collect()
+    }
 
-[Service]
-Type=simple
-LimitNPROC=4096
-LimitNOFILE=65536
-Restart=always
-ExecStartPre=/usr/bin/sleep 1s
-ExecStart={} -d {}
-ExecReload=/bin/kill -HUP $MAINPID
+    /// Test that a core update that starts fine is kept, restarting the running service onto it
+    #[test]
+    fn test_restart_core_keeps_working_binary() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        let runner = mock_systemctl(&mihoro.mihomo_target_binary_path);
+        mihoro.runner = runner.clone();
 
-[Install]
-WantedBy=default.target",
-        mihomo_binary_path, mihomo_config_root
-    );
+        let previous = dir.path().join("mihomo.bak");
+        fs::write(&previous, "healthy old")?;
+        fs::write(&mihoro.mihomo_target_binary_path, "healthy new")?;
 
-    // Create mihomo service directory if not exists
-    create_parent_dir(Path::new(mihomo_service_path))?;
+        mihoro.restart_core_or_rollback(Some(&previous), Duration::ZERO)?;
 
-    // Write mihomo.service contents to file
-    fs::write(mihomo_service_path, service)?;
+        assert_eq!(
+            fs::read_to_string(&mihoro.mihomo_target_binary_path)?,
+            "healthy new"
+        );
+        let log = systemctl_log(&runner);
+        assert!(log.starts_with("--user restart mihomo.service\n"));
+        assert!(!log.contains("stop"));
+        Ok(())
+    }
 
-    println!(
-        "{} Created mihomo.service at {}",
-        prefix.green(),
-        mihomo_service_path.underline().yellow()
-    );
-    Ok(())
-}
+    /// Test that a core update that fails to start is rolled back to the previous binary
+    #[test]
+    fn test_restart_core_rolls_back_failed_binary() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        let runner = mock_systemctl(&mihoro.mihomo_target_binary_path);
+        mihoro.runner = runner.clone();
+
+        let previous = dir.path().join("mihomo.bak");
+        fs::write(&previous, "healthy old")?;
+        fs::write(&mihoro.mihomo_target_binary_path, "crashing new")?;
+
+        let err = mihoro
+            .restart_core_or_rollback(Some(&previous), Duration::ZERO)
+            .unwrap_err();
+        assert!(err.to_string().contains("rolled back"));
+        assert_eq!(
+            fs::read_to_string(&mihoro.mihomo_target_binary_path)?,
+            "healthy old"
+        );
+        let log = systemctl_log(&runner);
+        assert!(log.starts_with("--user restart mihomo.service\n"));
+        assert!(log.contains("--user stop mihomo.service\n--user start mihomo.service\n"));
+
+        // Without a previous binary there is nothing to roll back to
+        fs::write(&mihoro.mihomo_target_binary_path, "crashing new")?;
+        let err = mihoro
+            .restart_core_or_rollback(None, Duration::ZERO)
+            .unwrap_err();
+        assert!(err.to_string().contains("no previous binary"));
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::tempdir;
+    /// Test that a core update rolls back to its backup, or else to the temporary copy
+    #[test]
+    fn test_core_update_previous() -> Result<()> {
+        let copy = NamedTempFile::new()?;
+        let copy_path = copy.path().to_path_buf();
+        let update = CoreUpdate {
+            backup: None,
+            rollback_copy: Some(copy),
+        };
+        assert_eq!(update.previous(), Some(copy_path.as_path()));
+
+        let backup = PathBuf::from("/backups/mihomo.bak.1");
+        let update = CoreUpdate {
+            backup: Some(backup.clone()),
+            rollback_copy: None,
+        };
+        assert_eq!(update.previous(), Some(backup.as_path()));
+        Ok(())
+    }
 
-    /// Test that Mihoro::new correctly parses config and derives paths
+    /// Test that `apply --no-download` rolls a core that fails to start back to the latest backup
     #[test]
-    fn test_mihoro_new_parses_config_and_derives_paths() -> Result<()> {
+    fn test_apply_no_download_rolls_back_to_backup() -> Result<()> {
         let dir = tempdir()?;
-        let config_path = dir.path().join("test.toml");
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        let runner = mock_systemctl(&mihoro.mihomo_target_binary_path);
+        mihoro.runner = runner.clone();
+        fs::write(&mihoro.mihomo_target_config_path, "mixed-port: 7890\n")?;
+
+        let binary = Path::new(&mihoro.mihomo_target_binary_path);
+        fs::write(binary, "healthy old")?;
+        backup_file(binary, &mihoro.backup_dir(), 3)?;
+        fs::write(binary, "crashing new")?;
+
+        let err = mihoro
+            .apply_no_download_checked_every(true, Duration::ZERO)
+            .unwrap_err();
+        assert!(err.to_string().contains("rolled back"));
+        assert_eq!(fs::read_to_string(binary)?, "healthy old");
+        let log = systemctl_log(&runner);
+        assert!(log.starts_with("--user restart mihomo.service\n"));
+        assert!(log.contains("--user stop mihomo.service\n--user start mihomo.service\n"));
+        Ok(())
+    }
 
-        // Write a valid config file
-        let toml_content = r#"
-            remote_config_url = "http://example.com/config.yaml"
-            mihomo_binary_path = "/tmp/test/mihomo"
-            mihomo_config_root = "/tmp/test/mihomo"
-            user_systemd_root = "/tmp/test/systemd"
-        "#;
-        fs::write(&config_path, toml_content)?;
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "old", "new"), "");
 
-        let mihoro = Mihoro::new(&config_path.to_str().unwrap().to_string())?;
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n11\n12\n13\n";
+        assert_eq!(
+            unified_diff(old, new, "config.yaml", "config.yaml (applied)"),
+            "--- config.yaml\n\
+             +++ config.yaml (applied)\n\
+             @@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n\
+             @@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n"
+        );
+    }
 
-        assert_eq!(mihoro.mihomo_target_binary_path, "/tmp/test/mihomo");
-        assert_eq!(mihoro.mihomo_target_config_root, "/tmp/test/mihomo");
+    /// Test that nested mappings are deep-merged while sequences and scalars are replaced
+    #[test]
+    fn test_apply_overrides_nested_merge() -> Result<()> {
+        let mut yaml: Value = serde_yaml::from_str(
+            r#"
+            dns:
+              enable: false
+              nameserver: [1.1.1.1]
+              fallback-filter:
+                geoip: true
+                geoip-code: CN
+            rules: [MATCH,DIRECT]
+            "#,
+        )?;
+        let overrides: Value = serde_yaml::from_str(
+            r#"
+            dns:
+              enable: true
+              nameserver: [223.5.5.5, 119.29.29.29]
+              fallback-filter:
+                geoip: false
+            tun:
+              enable: true
+            "#,
+        )?;
+
+        apply_overrides(&mut yaml, &overrides);
+
+        assert_eq!(yaml["dns"]["enable"], Value::Bool(true));
         assert_eq!(
-            mihoro.mihomo_target_config_path,
-            "/tmp/test/mihomo/config.yaml"
+            yaml["dns"]["nameserver"],
+            serde_yaml::from_str::<Value>("[223.5.5.5, 119.29.29.29]")?
         );
+        assert_eq!(yaml["dns"]["fallback-filter"]["geoip"], Value::Bool(false));
         assert_eq!(
-            mihoro.mihomo_target_service_path,
-            "/tmp/test/systemd/mihomo.service"
+            yaml["dns"]["fallback-filter"]["geoip-code"],
+            Value::String("CN".to_string())
+        );
+        assert_eq!(yaml["tun"]["enable"], Value::Bool(true));
+        assert_eq!(
+            yaml["rules"],
+            serde_yaml::from_str::<Value>("[MATCH,DIRECT]")?
         );
 
         Ok(())
     }
 
-    /// Test that proxy_commands uses mixed_port when set
+    /// Test that the `overrides` table in mihoro.toml is merged into config.yaml
     #[test]
-    fn test_proxy_commands_uses_mixed_port_when_set() -> Result<()> {
+    fn test_apply_config_overrides_from_toml_table() -> Result<()> {
         let dir = tempdir()?;
         let config_path = dir.path().join("test.toml");
 
         let toml_content = r#"
             remote_config_url = "http://example.com/config.yaml"
-            mihomo_binary_path = "/tmp/test/mihomo"
-            mihomo_config_root = "/tmp/test/mihomo"
-            user_systemd_root = "/tmp/test/systemd"
+            mihomo_binary_path = "{root}/mihomo"
+            mihomo_config_root = "{root}"
+            user_systemd_root = "{root}"
 
-            [mihomo_config]
-            port = 7891
-            socks_port = 7892
-            mixed_port = 7890
+            [overrides.dns]
+            enable = true
+            enhanced-mode = "fake-ip"
         "#;
-        fs::write(&config_path, toml_content)?;
+        fs::write(
+            &config_path,
+            toml_content.replace("{root}", dir.path().to_str().unwrap()),
+        )?;
 
-        let mihoro = Mihoro::new(&config_path.to_str().unwrap().to_string())?;
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?;
+        fs::write(
+            &mihoro.mihomo_target_config_path,
+            "dns:\n  enable: false\n  ipv6: false\nproxies: []\n",
+        )?;
 
-        // Test Export command (should use mixed_port 7890)
-        let cmd = mihoro.proxy_commands(&Some(ProxyCommands::Export));
-        assert!(cmd.is_ok());
+        // Previewing the changes leaves config.yaml untouched
+        mihoro.apply_diff()?;
+        assert_eq!(
+            fs::read_to_string(&mihoro.mihomo_target_config_path)?,
+            "dns:\n  enable: false\n  ipv6: false\nproxies: []\n"
+        );
+
+        mihoro.apply_config_overrides()?;
+
+        let yaml: Value =
+            serde_yaml::from_str(&fs::read_to_string(&mihoro.mihomo_target_config_path)?)?;
+        assert_eq!(yaml["dns"]["enable"], Value::Bool(true));
+        assert_eq!(yaml["dns"]["ipv6"], Value::Bool(false));
+        assert_eq!(
+            yaml["dns"]["enhanced-mode"],
+            Value::String("fake-ip".to_string())
+        );
+        assert_eq!(yaml["port"], Value::Number(7891.into()));
+        assert_eq!(yaml["geo-auto-update"], Value::Bool(true));
+
+        // `setup --minimal` also stops mihomo from updating geodata itself
+        let mihoro = mihoro.with_skip_geodata(true);
+        mihoro.apply_config_overrides()?;
+        let yaml: Value =
+            serde_yaml::from_str(&fs::read_to_string(&mihoro.mihomo_target_config_path)?)?;
+        assert_eq!(yaml["geo-auto-update"], Value::Bool(false));
 
         Ok(())
     }
 
-    /// Test that proxy_commands falls back to port/socks_port when mixed_port is None
+    /// Test that applying the same overrides twice leaves config.yaml as is the second time
     #[test]
-    fn test_proxy_commands_fallback_to_port_when_mixed_port_none() -> Result<()> {
+    fn test_apply_config_overrides_unchanged() -> Result<()> {
         let dir = tempdir()?;
-        let config_path = dir.path().join("test.toml");
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        mihoro.config.overrides = toml::from_str("tcp-concurrent = true")?;
+        fs::write(
+            &mihoro.mihomo_target_config_path,
+            "port: 1
+proxies: []
+",
+        )?;
 
-        let toml_content = r#"
-            remote_config_url = "http://example.com/config.yaml"
-            mihomo_binary_path = "/tmp/test/mihomo"
-            mihomo_config_root = "/tmp/test/mihomo"
-            user_systemd_root = "/tmp/test/systemd"
+        let changed = mihoro.apply_config_overrides()?.unwrap();
+        assert!(changed.contains(&"port".to_string()));
+        assert!(changed.contains(&"tcp-concurrent (added)".to_string()));
+        assert_eq!(mihoro.apply_config_overrides()?, None);
 
-            [mihomo_config]
-            port = 7891
-            socks_port = 7892
-        "#;
-        fs::write(&config_path, toml_content)?;
+        mihoro.config.overrides = toml::from_str("tcp-concurrent = false")?;
+        assert_eq!(
+            mihoro.apply_config_overrides()?,
+            Some(vec!["tcp-concurrent".to_string()])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        assert_eq!(
+            changed_keys(
+                "port: 1
+mode: rule
+rules: [MATCH,DIRECT]
+",
+                "port: 2
+mode: rule
+dns: {enable: true}
+"
+            ),
+            ["port", "dns (added)", "rules (removed)"]
+        );
+        assert!(changed_keys(
+            "port: 1
+", "port: 1
+"
+        )
+        .is_empty());
+        // Reformatting alone changes no key
+        assert!(changed_keys(
+            "{port: 1}",
+            "port: 1
+"
+        )
+        .is_empty());
+    }
+
+    /// Test that `ensure_allow_lan` wins over `allow_lan` and free-form overrides
+    #[test]
+    fn test_ensure_allow_lan() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        mihoro.config.overrides = toml::from_str(r#"allow-lan = false"#)?;
+        fs::write(&mihoro.mihomo_target_config_path, "proxies: []\n")?;
+
+        mihoro.apply_config_overrides()?;
+        let lan = read_mihomo_allow_lan(&mihoro.mihomo_target_config_path)?;
+        assert_eq!(lan, Some(false));
+
+        mihoro.config.ensure_allow_lan = true;
+        mihoro.apply_config_overrides()?;
+        let lan = read_mihomo_allow_lan(&mihoro.mihomo_target_config_path)?;
+        assert_eq!(lan, Some(true));
+
+        // Also without any overrides
+        mihoro.config.overrides = toml::Table::new();
+        mihoro.config.mihomo_config.allow_lan = None;
+        mihoro.apply_config_overrides()?;
+        let lan = read_mihomo_allow_lan(&mihoro.mihomo_target_config_path)?;
+        assert_eq!(lan, Some(true));
+        Ok(())
+    }
 
-        let mihoro = Mihoro::new(&config_path.to_str().unwrap().to_string())?;
+    /// Test that merge files are merged in order, before the TOML overrides
+    /// Test that a JSON subscription is written as YAML with the overrides applied
+    #[test]
+    fn test_apply_override_json_config() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        mihoro.config.overrides = toml::from_str("tcp-concurrent = true")?;
+        fs::write(
+            &mihoro.mihomo_target_config_path,
+            "{\n\t\"proxies\": [{\"name\": \"a\", \"type\": \"ss\"}],\n\t\"rules\": [\"MATCH,a\"]\n}",
+        )?;
+        mihoro.apply_override()?;
 
-        let cmd = mihoro.proxy_commands(&Some(ProxyCommands::Export));
-        assert!(cmd.is_ok());
+        let raw = fs::read_to_string(&mihoro.mihomo_target_config_path)?;
+        assert!(raw.contains("proxies:\n- name: a\n  type: ss\n"), "{}", raw);
+        let config: Value = serde_yaml::from_str(&raw)?;
+        assert_eq!(config["rules"][0], Value::from("MATCH,a"));
+        assert_eq!(config["tcp-concurrent"], Value::Bool(true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_files() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        fs::write(
+            &mihoro.mihomo_target_config_path,
+            "proxies: []\ndns:\n  enable: false\n  ipv6: true\nrules:\n  - MATCH,DIRECT\n",
+        )?;
+        fs::create_dir(dir.path().join("fragments"))?;
+        fs::write(
+            dir.path().join("fragments/dns.yaml"),
+            "dns:\n  enable: true\n  listen: 0.0.0.0:53\nrules:\n  - DOMAIN,a.com,DIRECT\n",
+        )?;
+        let extra = dir.path().join("extra.yaml");
+        fs::write(&extra, "dns:\n  listen: 127.0.0.1:53\n  ipv6: false\n")?;
+        mihoro.config.overrides = toml::from_str("dns = { ipv6 = true }")?;
+
+        // `merge_files` are relative to mihoro.toml, `--merge-file` ones are merged after them
+        mihoro.config.merge_files = vec!["fragments/dns.yaml".to_string()];
+        fs::write(
+            dir.path().join("test.toml"),
+            toml::to_string(&mihoro.config)?,
+        )?;
+        let mihoro = Mihoro::new(dir.path().join("test.toml").to_str().unwrap())?
+            .with_merge_files(&[extra.to_string_lossy().into_owned()]);
+        assert_eq!(mihoro.merge_files[0], dir.path().join("fragments/dns.yaml"));
+        mihoro.apply_config_overrides()?;
+
+        let config: Value =
+            serde_yaml::from_str(&fs::read_to_string(&mihoro.mihomo_target_config_path)?)?;
+        assert_eq!(config["dns"]["enable"], Value::Bool(true));
+        assert_eq!(config["dns"]["listen"], Value::from("127.0.0.1:53"));
+        assert_eq!(config["dns"]["ipv6"], Value::Bool(true));
+        // Lists are replaced, as with `[overrides]`
+        assert_eq!(
+            config["rules"],
+            Value::Sequence(vec![Value::from("DOMAIN,a.com,DIRECT")])
+        );
+
+        fs::write(&extra, "- not a mapping\n")?;
+        let err = mihoro.apply_config_overrides().unwrap_err();
+        assert!(err.to_string().contains("is not a YAML mapping"));
+        Ok(())
+    }
 
+    /// Test that extra rules survive `[overrides]` replacing `rules`, and malformed ones are skipped
+    #[test]
+    fn test_extra_rules() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?;
+        fs::write(
+            &mihoro.mihomo_target_config_path,
+            "rules:\n  - MATCH,Proxy\n",
+        )?;
+        mihoro.config.overrides = toml::from_str(r#"rules = ["GEOIP,CN,DIRECT", "MATCH,Auto"]"#)?;
+        mihoro.config.extra_rules_prepend = vec!["DOMAIN,a.com,DIRECT".to_string()];
+        mihoro.config.extra_rules_append = vec![
+            "DOMAIN-SUFFIX,lan,DIRECT".to_string(),
+            "DOMAIN-SUFFIX lan DIRECT".to_string(),
+        ];
+
+        for _ in 0..2 {
+            mihoro.apply_config_overrides()?;
+            let config: Value =
+                serde_yaml::from_str(&fs::read_to_string(&mihoro.mihomo_target_config_path)?)?;
+            assert_eq!(
+                config["rules"],
+                serde_yaml::to_value([
+                    "DOMAIN,a.com,DIRECT",
+                    "GEOIP,CN,DIRECT",
+                    "DOMAIN-SUFFIX,lan,DIRECT",
+                    "MATCH,Auto"
+                ])?
+            );
+        }
+        Ok(())
+    }
+
+    /// Test that only subscription downloads are sent as `subscription_user_agent`
+    #[tokio::test]
+    async fn test_subscription_user_agent() -> Result<()> {
+        let dir = tempdir()?;
+        let mut mihoro = setup_test_mihoro(dir.path())?.with_no_proxy(true);
+        mihoro.config.subscription_user_agent = Some(String::from("clash.meta"));
+        let client = mihoro.http_client()?;
+
+        let (url, head) = serve_once("proxies: []\n").await;
+        mihoro
+            .update_config(&client, false, false, false, Some(&url))
+            .await?;
+        assert_eq!(received_user_agent(&head.await?), Some("clash.meta"));
+        assert!(fs::read_to_string(&mihoro.mihomo_target_config_path)?.contains("proxies"));
+
+        let (url, head) = serve_once("").await;
+        client.get(&url).send().await?;
+        assert_eq!(
+            received_user_agent(&head.await?),
+            Some(crate::config::DEFAULT_USER_AGENT)
+        );
         Ok(())
     }
 
@@ -564,8 +3600,12 @@ mod tests {
         fs::write(&yaml_path, yaml_content)?;
 
         // Create Mihoro instance and apply override
-        let mihoro = Mihoro::new(&config_path.to_str().unwrap().to_string())?;
-        apply_mihomo_override(yaml_path.to_str().unwrap(), &mihoro.config.mihomo_config)?;
+        let mihoro = Mihoro::new(config_path.to_str().unwrap())?;
+        let overridden = override_mihomo_yaml(
+            &fs::read_to_string(&yaml_path)?,
+            &mihoro.config.mihomo_config,
+        )?;
+        fs::write(&yaml_path, overridden)?;
 
         // Verify override was applied
         let updated_content = fs::read_to_string(&yaml_path)?;
@@ -0,0 +1,160 @@
+use crate::log::debug;
+use crate::mihoro::Mihoro;
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+/// Program sending desktop notifications, from libnotify.
+const NOTIFY_SEND: &str = "notify-send";
+
+/// Find the executable `program` in the directories of `path_var`, i.e. `$PATH`.
+fn find_on_path(program: &str, path_var: Option<&OsStr>) -> Option<PathBuf> {
+    env::split_paths(path_var?)
+        .map(|dir| dir.join(program))
+        .find(|path| {
+            fs::metadata(path)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+        })
+}
+
+/// Title, body and urgency of the notification for updating `component` (`config`, `core`,
+/// `geodata` or `all`), given the error that failed the update, and the components that failed
+/// without failing it, as `update --all` keeps going.
+fn notification(
+    component: &str,
+    error: Option<&anyhow::Error>,
+    failed: &[String],
+) -> (String, String, &'static str) {
+    let label = match component {
+        "all" => "config, geodata and core",
+        "core" => "mihomo core",
+        component => component,
+    };
+    match error {
+        Some(e) => (
+            format!("mihoro failed to update {}", label),
+            format!("{:#}", e),
+            "critical",
+        ),
+        None if !failed.is_empty() => (
+            format!("mihoro updated {} with errors", label),
+            failed.join("\n"),
+            "critical",
+        ),
+        None => (
+            format!("mihoro updated {}", label),
+            String::from("`mihoro update` finished without errors"),
+            "normal",
+        ),
+    }
+}
+
+/// `DBUS_SESSION_BUS_ADDRESS` of the user's session bus, which notifications are sent over, if
+/// unset in the environment, as for cron jobs.
+fn session_bus_address() -> Option<String> {
+    if env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some() {
+        return None;
+    }
+    let runtime_dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        // The owner of `/proc/self` is the user running mihoro
+        None => PathBuf::from(format!(
+            "/run/user/{}",
+            fs::metadata("/proc/self").ok()?.uid()
+        )),
+    };
+    let bus = runtime_dir.join("bus");
+    bus.exists()
+        .then(|| format!("unix:path={}", bus.to_string_lossy()))
+}
+
+/// Send a desktop notification with the result of `mihoro update`, if `notify_on_update` is set.
+///
+/// Does nothing where `notify-send` is not installed, e.g. on headless servers, and a
+/// notification that fails to send, e.g. without a desktop session, is only logged with `-v`.
+pub fn notify_update(
+    mihoro: &Mihoro,
+    component: &str,
+    error: Option<&anyhow::Error>,
+    failed: &[String],
+) {
+    if !mihoro.config.notify_on_update {
+        return;
+    }
+    let Some(notify_send) = find_on_path(NOTIFY_SEND, env::var_os("PATH").as_deref()) else {
+        debug!("{} not found, not sending a notification", NOTIFY_SEND);
+        return;
+    };
+    if mihoro.skip_in_dry_run("send a desktop notification") {
+        return;
+    }
+
+    let (title, body, urgency) = notification(component, error, failed);
+    let mut args = vec![];
+    if let Some(address) = session_bus_address() {
+        args.push(format!("DBUS_SESSION_BUS_ADDRESS={}", address));
+    }
+    args.extend([
+        notify_send.to_string_lossy().to_string(),
+        format!("--urgency={}", urgency),
+        String::from("--app-name=mihoro"),
+        title,
+        body,
+    ]);
+    let output = mihoro.runner.output("env", &args);
+    match output {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => debug!(
+            "{} failed: {}",
+            NOTIFY_SEND,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => debug!("failed to run {}: {}", NOTIFY_SEND, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_find_on_path() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path_var = env::join_paths([dir.path().join("missing"), dir.path().to_path_buf()])?;
+        assert_eq!(find_on_path(NOTIFY_SEND, Some(&path_var)), None);
+
+        // Only executable files count
+        let program = dir.path().join(NOTIFY_SEND);
+        fs::write(&program, "#!/bin/sh\n")?;
+        assert_eq!(find_on_path(NOTIFY_SEND, Some(&path_var)), None);
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755))?;
+        assert_eq!(find_on_path(NOTIFY_SEND, Some(&path_var)), Some(program));
+        assert_eq!(find_on_path(NOTIFY_SEND, None), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_notification() {
+        let (title, body, urgency) = notification("config", None, &[]);
+        assert_eq!(title, "mihoro updated config");
+        assert_eq!(body, "`mihoro update` finished without errors");
+        assert_eq!(urgency, "normal");
+
+        let error = anyhow::anyhow!("timed out").context("failed to download config");
+        let (title, body, urgency) = notification("core", Some(&error), &[]);
+        assert_eq!(title, "mihoro failed to update mihomo core");
+        assert_eq!(body, "failed to download config: timed out");
+        assert_eq!(urgency, "critical");
+
+        let failed = [String::from("geodata: timed out")];
+        let (title, body, urgency) = notification("all", None, &failed);
+        assert_eq!(title, "mihoro updated config, geodata and core with errors");
+        assert_eq!(body, "geodata: timed out");
+        assert_eq!(urgency, "critical");
+    }
+}
@@ -1,39 +1,405 @@
+use std::{
+    env,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
 use clap_complete::shells::Shell;
+use local_ip_address::list_afinet_netifas;
+
+/// Environment variables cleared by proxy unset commands.
+const PROXY_ENV_VARS: [&str; 4] = ["https_proxy", "http_proxy", "all_proxy", "ALL_PROXY"];
+
+/// Clipboard helper commands tried in order, as `(program, args)`.
+const CLIPBOARD_COMMANDS: [(&str, &[&str]); 3] = [
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+/// Whether a graphical clipboard is likely reachable, i.e. not over SSH and with a display.
+pub fn clipboard_available() -> bool {
+    env::var_os("SSH_CONNECTION").is_none()
+        && (env::var_os("DISPLAY").is_some() || env::var_os("WAYLAND_DISPLAY").is_some())
+}
+
+/// Copy text to the clipboard with the first working helper among `wl-copy`, `xclip` and `xsel`.
+///
+/// Returns `false` if none of them are available or all of them failed.
+pub fn copy_to_clipboard(text: &str) -> bool {
+    CLIPBOARD_COMMANDS.iter().any(|(program, args)| {
+        let Ok(mut child) = Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            return false;
+        };
+
+        // Close stdin after writing so the helper does not wait for more input
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        child.wait().is_ok_and(|status| status.success()) && written
+    })
+}
+
+/// Resolves the shell to generate proxy commands for.
+///
+/// An explicit `--shell` takes precedence, otherwise the shell is detected from `$SHELL`, falling
+/// back to POSIX (bash) syntax.
+pub fn detect_shell(shell_override: Option<Shell>) -> Shell {
+    shell_override
+        .or_else(Shell::from_env)
+        .unwrap_or(Shell::Bash)
+}
+
+/// Addresses in the documentation ranges, never routed, used to ask the kernel for the source
+/// address of the default route. Connecting a UDP socket sends no packets.
+const ROUTE_PROBE_V4: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 0, 2, 1), 80);
+const ROUTE_PROBE_V6: (Ipv6Addr, u16) = (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80);
+
+/// Whether `ip` can be reached from other LAN hosts, i.e. is not loopback, unspecified or
+/// link-local, which would need a zone index to be usable.
+fn is_lan_reachable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_unspecified() && !ip.is_link_local(),
+        IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_unspecified() && !ip.is_unicast_link_local(),
+    }
+}
+
+/// Source address of the default route, i.e. the IP other LAN hosts reach this machine at.
+fn default_route_ip(ipv6: bool) -> Option<IpAddr> {
+    let socket = if ipv6 {
+        let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+        socket.connect(ROUTE_PROBE_V6).ok()?;
+        socket
+    } else {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+        socket.connect(ROUTE_PROBE_V4).ok()?;
+        socket
+    };
+    Some(socket.local_addr().ok()?.ip()).filter(is_lan_reachable)
+}
+
+/// Pick the primary LAN address of the requested family among interface addresses, preferring
+/// private (IPv4) or unique local (IPv6) addresses over public ones and skipping loopback and
+/// link-local addresses.
+fn pick_lan_ip(addresses: &[(String, IpAddr)], ipv6: bool) -> Option<IpAddr> {
+    let candidates: Vec<IpAddr> = addresses
+        .iter()
+        .map(|(_, ip)| *ip)
+        .filter(|ip| ip.is_ipv6() == ipv6 && is_lan_reachable(ip))
+        .collect();
+    let is_private = |ip: &&IpAddr| match ip {
+        IpAddr::V4(ip) => ip.is_private(),
+        IpAddr::V6(ip) => ip.is_unique_local(),
+    };
+    candidates
+        .iter()
+        .find(is_private)
+        .or(candidates.first())
+        .copied()
+}
+
+/// Addresses of this machine's network interfaces, empty if they can't be listed.
+pub fn local_addresses() -> Vec<IpAddr> {
+    list_afinet_netifas()
+        .map(|addresses| addresses.into_iter().map(|(_, ip)| ip).collect())
+        .unwrap_or_default()
+}
+
+/// Problems with LAN access to mihomo, given its `allow-lan` and `bind-address` and this
+/// machine's interface `addresses`.
+///
+/// A `bind-address` of one of the machine's LAN addresses signals the proxy is meant for the LAN,
+/// a wildcard or missing one is ambiguous, so `allow-lan: false` alone is not a problem.
+pub fn lan_access_problems(
+    allow_lan: bool,
+    bind_address: Option<&str>,
+    addresses: &[IpAddr],
+) -> Vec<String> {
+    let Some(bind) = bind_address
+        .filter(|bind| *bind != "*")
+        .and_then(|bind| bind.parse::<IpAddr>().ok())
+    else {
+        return vec![];
+    };
+
+    let mut problems = vec![];
+    if bind.is_loopback() {
+        if allow_lan {
+            problems.push(format!(
+                "`bind-address` is {}, a loopback address, so LAN clients can't connect even with \
+                 `allow-lan`",
+                bind
+            ));
+        }
+        return problems;
+    }
+    if !allow_lan {
+        problems.push(format!(
+            "`bind-address` is {}, but `allow-lan` is false, so mihomo only accepts connections \
+             from this machine, set `ensure_allow_lan = true` in mihoro.toml to share it",
+            bind
+        ));
+    }
+    if !bind.is_unspecified() && !addresses.is_empty() && !addresses.contains(&bind) {
+        problems.push(format!(
+            "`bind-address` {} is not an address of this machine, mihomo can't listen on it",
+            bind
+        ));
+    }
+    problems
+}
+
+/// Detect the primary LAN address of this machine, the source address of the default route, or
+/// the first private interface address on hosts without one. Detects an IPv6 address if `ipv6`.
+pub fn detect_lan_ip(ipv6: bool) -> Result<IpAddr> {
+    default_route_ip(ipv6)
+        .or_else(|| pick_lan_ip(&list_afinet_netifas().ok()?, ipv6))
+        .ok_or_else(|| {
+            anyhow!(
+                "failed to detect LAN {} address, pass it with `--bind <ip>`",
+                if ipv6 { "IPv6" } else { "IPv4" }
+            )
+        })
+}
+
+/// Loopback address to export, `127.0.0.1` or `::1` if `ipv6`.
+pub fn loopback_ip(ipv6: bool) -> IpAddr {
+    if ipv6 {
+        IpAddr::V6(Ipv6Addr::LOCALHOST)
+    } else {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+}
+
+/// Format `ip` as the host of a url, wrapping IPv6 addresses in brackets, e.g. `[::1]`.
+pub fn url_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+pub fn proxy_export_cmd(shell: Shell, hostname: &str, http_port: &u16, socks_port: &u16) -> String {
+    export_vars_cmd(
+        shell,
+        &[
+            ("https_proxy", format!("http://{hostname}:{http_port}")),
+            ("http_proxy", format!("http://{hostname}:{http_port}")),
+            ("all_proxy", format!("socks5://{hostname}:{socks_port}")),
+        ],
+    )
+}
+
+/// Export commands for a SOCKS5-only proxy via `all_proxy`.
+pub fn proxy_export_socks_cmd(shell: Shell, hostname: &str, socks_port: &u16) -> String {
+    export_vars_cmd(
+        shell,
+        &[("all_proxy", format!("socks5://{hostname}:{socks_port}"))],
+    )
+}
+
+/// Generate shell-specific commands to export the given environment variables.
+fn export_vars_cmd(shell: Shell, vars: &[(&str, String)]) -> String {
+    let vars = vars.iter();
 
-pub fn proxy_export_cmd(hostname: &str, http_port: &u16, socks_port: &u16) -> String {
-    // Check current shell
-    let shell = Shell::from_env().unwrap_or(Shell::Bash);
     match shell {
         Shell::Fish => {
             // For fish, use `set -gx $ENV_VAR value` to set environment variables
-            format!(
-                "set -gx https_proxy http://{hostname}:{http_port} \
-                set -gx http_proxy http://{hostname}:{http_port} \
-                set -gx all_proxy socks5://{hostname}:{socks_port}"
-            )
+            vars.map(|(var, value)| format!("set -gx {var} {value}"))
+                .collect::<Vec<_>>()
+                .join("; ")
+        }
+        Shell::PowerShell => {
+            // For powershell, use `$env:ENV_VAR = "value"` to set environment variables
+            vars.map(|(var, value)| format!("$env:{var} = \"{value}\""))
+                .collect::<Vec<_>>()
+                .join("; ")
+        }
+        Shell::Elvish => {
+            // For elvish, use `set-env ENV_VAR value` to set environment variables
+            vars.map(|(var, value)| format!("set-env {var} {value}"))
+                .collect::<Vec<_>>()
+                .join("; ")
         }
         _ => {
             // For all other shells (bash/zsh), use `export $ENV_VAR=value`
-            format!(
-                "export https_proxy=http://{hostname}:{http_port} \
-                http_proxy=http://{hostname}:{http_port} \
-                all_proxy=socks5://{hostname}:{socks_port}"
-            )
+            let assignments = vars
+                .map(|(var, value)| format!("{var}={value}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            format!("export {assignments}")
         }
     }
 }
 
-pub fn proxy_unset_cmd() -> String {
-    // Check current shell
-    let shell = Shell::from_env().unwrap_or(Shell::Bash);
+pub fn proxy_unset_cmd(shell: Shell) -> String {
     match shell {
         Shell::Fish => {
             // For fish, use `set -e $ENV_VAR` to unset environment variables
-            "set -e https_proxy http_proxy all_proxy".to_owned()
+            format!("set -e {}", PROXY_ENV_VARS.join(" "))
+        }
+        Shell::PowerShell => {
+            // For powershell, remove variables from the `env:` drive
+            PROXY_ENV_VARS
+                .iter()
+                .map(|var| format!("Remove-Item Env:{var} -ErrorAction SilentlyContinue"))
+                .collect::<Vec<_>>()
+                .join("; ")
+        }
+        Shell::Elvish => {
+            // For elvish, use `unset-env ENV_VAR` to unset environment variables
+            PROXY_ENV_VARS
+                .iter()
+                .map(|var| format!("unset-env {var}"))
+                .collect::<Vec<_>>()
+                .join("; ")
         }
         _ => {
             // For all other shells (bash/zsh), use `unset $ENV_VAR`
-            "unset https_proxy http_proxy all_proxy".to_owned()
+            format!("unset {}", PROXY_ENV_VARS.join(" "))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lan_access_problems() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let addresses = [ip("127.0.0.1"), ip("192.168.1.23")];
+        let problems = |allow_lan, bind| lan_access_problems(allow_lan, bind, &addresses);
+
+        // Wildcard or missing bind addresses are fine either way
+        assert!(problems(false, None).is_empty());
+        assert!(problems(false, Some("*")).is_empty());
+        assert!(problems(true, Some("*")).is_empty());
+        assert!(problems(true, Some("192.168.1.23")).is_empty());
+        assert!(problems(false, Some("127.0.0.1")).is_empty());
+
+        // A LAN bind address without `allow-lan`
+        let found = problems(false, Some("192.168.1.23"));
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("`allow-lan` is false"));
+        // A bind address of another machine
+        let found = problems(true, Some("10.0.0.5"));
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("not an address of this machine"));
+        // Loopback with `allow-lan`
+        assert!(problems(true, Some("::1"))[0].contains("loopback"));
+        // Unknown interfaces, e.g. in containers, don't count as a mismatch
+        assert!(lan_access_problems(true, Some("10.0.0.5"), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_pick_lan_ip() {
+        let address = |name: &str, ip: &str| (name.to_string(), ip.parse().unwrap());
+        let addresses = [
+            address("lo", "127.0.0.1"),
+            address("lo", "::1"),
+            address("eth0", "fe80::1"),
+            address("eth0", "169.254.3.4"),
+            address("wan0", "203.0.113.7"),
+            address("wan0", "2001:db8::7"),
+            address("wlan0", "192.168.1.23"),
+            address("wlan0", "fd12::23"),
+        ];
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        assert_eq!(pick_lan_ip(&addresses, false), ip("192.168.1.23"));
+        assert_eq!(pick_lan_ip(&addresses, true), ip("fd12::23"));
+        assert_eq!(pick_lan_ip(&addresses[..6], false), ip("203.0.113.7"));
+        assert_eq!(pick_lan_ip(&addresses[..6], true), ip("2001:db8::7"));
+        assert_eq!(pick_lan_ip(&addresses[..4], false), None);
+        assert_eq!(pick_lan_ip(&addresses[..4], true), None);
+    }
+
+    #[test]
+    fn test_url_host_brackets_ipv6() {
+        assert_eq!(url_host(loopback_ip(false)), "127.0.0.1");
+        assert_eq!(url_host(loopback_ip(true)), "[::1]");
+        assert_eq!(
+            proxy_export_cmd(Shell::Bash, &url_host(loopback_ip(true)), &7890, &7891),
+            "export https_proxy=http://[::1]:7890 http_proxy=http://[::1]:7890 \
+             all_proxy=socks5://[::1]:7891"
+        );
+        let global: IpAddr = "2001:db8:85a3::8a2e:370:7334".parse().unwrap();
+        assert_eq!(
+            proxy_export_socks_cmd(Shell::Fish, &url_host(global), &7891),
+            "set -gx all_proxy socks5://[2001:db8:85a3::8a2e:370:7334]:7891"
+        );
+    }
+
+    #[test]
+    fn test_detect_shell_prefers_override() {
+        assert_eq!(detect_shell(Some(Shell::Fish)), Shell::Fish);
+    }
+
+    #[test]
+    fn test_proxy_export_cmd_posix() {
+        let expected = "export https_proxy=http://127.0.0.1:7890 \
+                        http_proxy=http://127.0.0.1:7890 all_proxy=socks5://127.0.0.1:7890";
+        assert_eq!(
+            proxy_export_cmd(Shell::Bash, "127.0.0.1", &7890, &7890),
+            expected
+        );
+        assert_eq!(
+            proxy_export_cmd(Shell::Zsh, "127.0.0.1", &7890, &7890),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_proxy_export_cmd_fish() {
+        assert_eq!(
+            proxy_export_cmd(Shell::Fish, "127.0.0.1", &7890, &7891),
+            "set -gx https_proxy http://127.0.0.1:7890; \
+             set -gx http_proxy http://127.0.0.1:7890; \
+             set -gx all_proxy socks5://127.0.0.1:7891"
+        );
+    }
+
+    #[test]
+    fn test_proxy_export_cmd_powershell() {
+        assert_eq!(
+            proxy_export_cmd(Shell::PowerShell, "127.0.0.1", &7890, &7891),
+            "$env:https_proxy = \"http://127.0.0.1:7890\"; \
+             $env:http_proxy = \"http://127.0.0.1:7890\"; \
+             $env:all_proxy = \"socks5://127.0.0.1:7891\""
+        );
+    }
+
+    #[test]
+    fn test_proxy_export_socks_cmd() {
+        assert_eq!(
+            proxy_export_socks_cmd(Shell::Bash, "127.0.0.1", &7891),
+            "export all_proxy=socks5://127.0.0.1:7891"
+        );
+        assert_eq!(
+            proxy_export_socks_cmd(Shell::Fish, "192.168.1.2", &7891),
+            "set -gx all_proxy socks5://192.168.1.2:7891"
+        );
+    }
+
+    #[test]
+    fn test_proxy_unset_cmd() {
+        assert_eq!(
+            proxy_unset_cmd(Shell::Bash),
+            "unset https_proxy http_proxy all_proxy ALL_PROXY"
+        );
+        assert_eq!(
+            proxy_unset_cmd(Shell::Fish),
+            "set -e https_proxy http_proxy all_proxy ALL_PROXY"
+        );
+        assert!(proxy_unset_cmd(Shell::PowerShell).contains("Remove-Item Env:http_proxy"));
+    }
+}
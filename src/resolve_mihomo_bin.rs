@@ -1,33 +1,84 @@
-use crate::config::{Config, MihomoChannel};
+use crate::cmd::OutputFormat;
+use crate::config::{levenshtein, Config, MihomoChannel};
+use crate::error::MihoroError;
+use crate::log::{debug, info};
+use crate::utils::{download_with_retry, unix_now, RETRY_BASE_DELAY};
+use crate::version_cache::VersionCache;
 
 use anyhow::{bail, Context, Result};
 use colored::Colorize;
-use reqwest::Client;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client,
+};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::time::Duration;
 
 const STABLE_VERSION_URL: &str =
     "https://github.com/MetaCubeX/mihomo/releases/latest/download/version.txt";
 const ALPHA_VERSION_URL: &str =
     "https://github.com/MetaCubeX/mihomo/releases/download/Prerelease-Alpha/version.txt";
 
+/// Whether `url` is served by GitHub itself rather than a mirror.
+fn is_github_url(url: &str) -> bool {
+    url.starts_with("https://github.com/") || url.starts_with("https://api.github.com")
+}
+
+/// Headers authenticating a request to `url` with `github_token`, only if it goes to GitHub
+/// itself, so tokens are never sent to mirrors or other hosts.
+pub fn github_auth_headers(url: &str, github_token: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let value = github_token
+        .filter(|_| is_github_url(url))
+        .and_then(|token| HeaderValue::from_str(&format!("Bearer {}", token)).ok());
+    if let Some(mut value) = value {
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    headers
+}
+
+/// Rewrites a GitHub URL to go through a mirror, e.g. `https://ghfast.top/https://github.com/...`.
+///
+/// URLs not hosted on `github.com` or `api.github.com` are returned unchanged.
+pub fn mirror_github_url(url: &str, mirror: Option<&str>) -> String {
+    match mirror {
+        Some(mirror) if !mirror.is_empty() && is_github_url(url) => {
+            format!("{}/{}", mirror.trim_end_matches('/'), url)
+        }
+        _ => url.to_string(),
+    }
+}
+
 /// Fetches the latest Mihomo version from GitHub based on the release channel.
+#[allow(clippy::too_many_arguments)]
 pub async fn fetch_latest_version(
     client: &Client,
     channel: &MihomoChannel,
-    user_agent: &str,
+    mirror: Option<&str>,
+    github_token: Option<&str>,
+    max_attempts: u32,
+    timeout: Duration,
+    prefix: &str,
 ) -> Result<String> {
     let url = match channel {
         MihomoChannel::Stable => STABLE_VERSION_URL,
         MihomoChannel::Alpha => ALPHA_VERSION_URL,
     };
+    let url = mirror_github_url(url, mirror);
 
-    let response = client
-        .get(url)
-        .header("User-Agent", user_agent)
-        .send()
-        .await
-        .with_context(|| format!("failed to fetch version from '{}'", url))?;
-
-    response.error_for_status_ref()?;
+    let response = download_with_retry(
+        client,
+        &url,
+        &github_auth_headers(&url, github_token),
+        max_attempts,
+        RETRY_BASE_DELAY,
+        timeout,
+        prefix,
+    )
+    .await
+    .with_context(|| format!("failed to fetch version from '{}'", url))?;
 
     let version = response
         .text()
@@ -43,6 +94,104 @@ pub async fn fetch_latest_version(
     Ok(version)
 }
 
+/// Returns the latest Mihomo version of the configured channel, reusing the one cached by a
+/// previous lookup within `version_cache_ttl_secs`.
+async fn latest_version(client: &Client, config: &Config, prefix: &str) -> Result<String> {
+    let channel = &config.mihomo_channel;
+    let now = unix_now();
+    let cache_path = VersionCache::path().ok();
+    let mut cache = cache_path
+        .as_deref()
+        .map(VersionCache::load)
+        .unwrap_or_default();
+    if let Some(version) = cache.get(channel, now, config.version_cache_ttl_secs) {
+        info!(
+            "{} Found mihomo version: {} (cached, pass {} to look it up again)",
+            prefix.green(),
+            version.bold(),
+            "--refresh".bold()
+        );
+        return Ok(version.to_string());
+    }
+
+    let version = fetch_latest_version(
+        client,
+        channel,
+        config.github_mirror.as_deref(),
+        config.github_token().as_deref(),
+        config.download_retries,
+        config.network_timeout(),
+        prefix,
+    )
+    .await?;
+    info!(
+        "{} Found mihomo version: {}",
+        prefix.green(),
+        version.bold()
+    );
+
+    // The cache only saves a lookup, failing to write it is not worth failing the update over
+    cache.insert(channel, &version, now);
+    if let Some(path) = cache_path {
+        if let Err(e) = cache.save(&path) {
+            debug!(
+                "failed to cache mihomo version to {}: {}",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+    Ok(version)
+}
+
+/// Fetches the expected SHA256 digest published alongside a Mihomo binary download.
+///
+/// The checksum file is expected at `<binary_url>.sha256`, containing the hex digest optionally
+/// followed by the file name (`sha256sum` output format).
+pub async fn fetch_expected_sha256(
+    client: &Client,
+    binary_url: &str,
+    github_token: Option<&str>,
+    max_attempts: u32,
+    timeout: Duration,
+    prefix: &str,
+) -> Result<String> {
+    let url = format!("{}.sha256", binary_url);
+
+    let response = download_with_retry(
+        client,
+        &url,
+        &github_auth_headers(&url, github_token),
+        max_attempts,
+        RETRY_BASE_DELAY,
+        timeout,
+        prefix,
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "failed to fetch checksum from '{}' (use --no-verify to skip verification)",
+            url
+        )
+    })?;
+
+    let body = response
+        .text()
+        .await
+        .with_context(|| "failed to read checksum response")?;
+
+    parse_sha256(&body)
+}
+
+/// Parses a hex SHA256 digest from the contents of a checksum file.
+pub fn parse_sha256(content: &str) -> Result<String> {
+    let digest = content.split_whitespace().next().unwrap_or("");
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid checksum file content: '{}'", content.trim());
+    }
+    Ok(digest.to_lowercase())
+}
+
 /// Detects the current system architecture and maps it to Mihomo's asset naming convention.
 ///
 /// Maps Rust's std::env::consts::ARCH to Mihomo's default variant for each architecture.
@@ -81,15 +230,63 @@ pub fn detect_arch() -> Result<String> {
         "s390x" => Ok("s390x".to_string()),
         // LoongArch 64-bit
         "loongarch64" => Ok("loong64-abi2".to_string()),
-        _ => bail!(
-            "unsupported architecture: {} (use --arch to specify manually)",
-            arch
-        ),
+        _ => Err(MihoroError::Arch(format!("{} (use --arch to specify manually)", arch)).into()),
     }
 }
 
+/// CPU flags (as named in `/proc/cpuinfo`) required by the x86-64-v2 microarchitecture level.
+const AMD64_V2_FLAGS: &[&str] = &["cx16", "lahf_lm", "popcnt", "sse4_1", "sse4_2", "ssse3"];
+
+/// CPU flags required by x86-64-v3 on top of v2, where `abm` indicates LZCNT support.
+const AMD64_V3_FLAGS: &[&str] = &[
+    "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "abm", "movbe", "xsave",
+];
+
+/// Parses the x86-64 microarchitecture level (1, 2 or 3) from `/proc/cpuinfo` contents.
+///
+/// Only the first `flags` line is considered. Returns `None` if no flags are found, in which case
+/// the level is uncertain.
+pub fn parse_amd64_level(cpuinfo: &str) -> Option<u8> {
+    let flags: Vec<&str> = cpuinfo
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == "flags").then_some(value)
+        })?
+        .split_whitespace()
+        .collect();
+    let has_all = |required: &[&str]| required.iter().all(|flag| flags.contains(flag));
+
+    Some(match (has_all(AMD64_V2_FLAGS), has_all(AMD64_V3_FLAGS)) {
+        (true, true) => 3,
+        (true, false) => 2,
+        _ => 1,
+    })
+}
+
+/// Picks the fastest amd64 build the CPU supports from `/proc/cpuinfo` contents.
+///
+/// Falls back to `amd64-compatible` for baseline CPUs or when detection is uncertain.
+fn amd64_arch_for(cpuinfo: Option<&str>) -> String {
+    match cpuinfo.and_then(parse_amd64_level) {
+        Some(3) => "amd64-v3".to_string(),
+        Some(2) => "amd64-v2".to_string(),
+        _ => "amd64-compatible".to_string(),
+    }
+}
+
+/// Like `detect_arch`, but picks an optimized `amd64-v2`/`amd64-v3` build on x86_64 CPUs that
+/// support it.
+pub fn detect_arch_optimized() -> Result<String> {
+    if std::env::consts::ARCH != "x86_64" {
+        return detect_arch();
+    }
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok();
+    Ok(amd64_arch_for(cpuinfo.as_deref()))
+}
+
 /// List of all supported Mihomo architectures.
-const SUPPORTED_ARCHS: &[&str] = &[
+pub const SUPPORTED_ARCHS: &[&str] = &[
     "386",
     "386-go120",
     "386-go123",
@@ -122,6 +319,150 @@ const SUPPORTED_ARCHS: &[&str] = &[
     "s390x",
 ];
 
+/// Family of an architecture in `SUPPORTED_ARCHS`, e.g. `x86_64` for `amd64-v3`.
+pub fn arch_family(arch: &str) -> &'static str {
+    match arch.split('-').next().unwrap_or(arch) {
+        "386" => "x86",
+        "amd64" => "x86_64",
+        "arm64" | "armv5" | "armv6" | "armv7" => "ARM",
+        "loong64" => "LoongArch",
+        base if base.starts_with("mips") => "MIPS",
+        _ => "Others",
+    }
+}
+
+/// Supported architectures of a family, as listed by `mihoro arch list`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ArchFamily {
+    pub family: &'static str,
+    pub archs: Vec<&'static str>,
+}
+
+/// `SUPPORTED_ARCHS` grouped by family, in order of first appearance.
+pub fn arch_families() -> Vec<ArchFamily> {
+    let mut families: Vec<ArchFamily> = Vec::new();
+    for &arch in SUPPORTED_ARCHS {
+        let family = arch_family(arch);
+        match families.iter_mut().find(|f| f.family == family) {
+            Some(f) => f.archs.push(arch),
+            None => families.push(ArchFamily {
+                family,
+                archs: vec![arch],
+            }),
+        }
+    }
+    families
+}
+
+/// Run `mihoro arch list`, printing supported architectures by family and the ones detected for
+/// this machine, plainly and with `--optimize-arch`.
+pub fn print_arch_list(output: OutputFormat) -> Result<()> {
+    let detected = detect_arch().ok();
+    let optimized = detect_arch_optimized().ok();
+    let families = arch_families();
+
+    match output {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "detected": detected,
+                "optimized": optimized,
+                "families": families,
+            }))?
+        ),
+        OutputFormat::Human => {
+            let width = families.iter().map(|f| f.family.len()).max().unwrap_or(0);
+            for family in &families {
+                let archs: Vec<String> = family
+                    .archs
+                    .iter()
+                    .map(|&arch| match arch {
+                        _ if Some(arch) == detected.as_deref() => arch.green().bold().to_string(),
+                        _ if Some(arch) == optimized.as_deref() => arch.green().to_string(),
+                        _ => arch.to_string(),
+                    })
+                    .collect();
+                println!(
+                    "{:<width$}  {}",
+                    family.family.bold(),
+                    archs.join(", "),
+                    width = width
+                );
+            }
+            println!();
+            match (&detected, &optimized) {
+                (Some(detected), Some(optimized)) if detected != optimized => println!(
+                    "Detected: {} ({} with --optimize-arch)",
+                    detected.green().bold(),
+                    optimized.green()
+                ),
+                (Some(detected), _) => println!("Detected: {}", detected.green().bold()),
+                (None, _) => println!(
+                    "Detected: {} ({}), pass one of the above with --arch",
+                    "unsupported".red(),
+                    std::env::consts::ARCH
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names other tools use for an architecture, e.g. `uname -m`, with the mihomo build they mean.
+const ARCH_ALIASES: &[(&str, &str)] = &[
+    ("x86_64", "amd64-compatible"),
+    ("x86-64", "amd64-compatible"),
+    ("x64", "amd64"),
+    ("aarch64", "arm64"),
+    ("armv8", "arm64"),
+    ("armhf", "armv7"),
+    ("i386", "386"),
+    ("i686", "386"),
+    ("loongarch64", "loong64-abi2"),
+];
+
+/// Up to 3 supported architectures `arch` was likely meant to be: the mihomo name for a common
+/// alias first, then the closest by edit distance, preferring those sharing a longer prefix.
+///
+/// An architecture `arch` is a truncated form of, like `mipsle` of `mipsle-softfloat`, counts as
+/// one edit away.
+fn suggest_archs(arch: &str) -> Vec<&'static str> {
+    let arch = arch.to_lowercase();
+    let alias = ARCH_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == arch)
+        .map(|(_, target)| *target);
+
+    let common_prefix = |candidate: &str| {
+        arch.chars()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+    let max_distance = (arch.chars().count() / 2).max(2);
+    let mut ranked: Vec<(usize, Reverse<usize>, &'static str)> = SUPPORTED_ARCHS
+        .iter()
+        .map(|&candidate| {
+            let truncated: String = candidate.chars().take(arch.chars().count()).collect();
+            let distance = levenshtein(&arch, candidate).min(levenshtein(&arch, &truncated) + 1);
+            (distance, Reverse(common_prefix(candidate)), candidate)
+        })
+        .filter(|(distance, _, _)| *distance <= max_distance)
+        .collect();
+    ranked.sort();
+
+    alias
+        .into_iter()
+        .chain(
+            ranked
+                .into_iter()
+                .map(|(_, _, candidate)| candidate)
+                .filter(|candidate| Some(*candidate) != alias),
+        )
+        .take(3)
+        .collect()
+}
+
 /// Validates that the architecture is supported by Mihomo.
 ///
 /// Returns the architecture if valid, or an error with suggestions if invalid.
@@ -131,57 +472,78 @@ pub fn validate_arch(arch: &str) -> Result<String> {
     }
 
     // Find similar architectures for helpful error message
-    let suggestions: Vec<&str> = SUPPORTED_ARCHS
-        .iter()
-        .filter(|a| a.starts_with(&arch[..arch.len().min(3)]))
-        .copied()
-        .collect();
+    let suggestions = suggest_archs(arch);
 
-    if suggestions.is_empty() {
-        bail!(
-            "unsupported architecture: '{}'\nSupported: {}",
-            arch,
-            SUPPORTED_ARCHS.join(", ")
-        );
+    let message = if suggestions.is_empty() {
+        format!("'{}'\nSupported: {}", arch, SUPPORTED_ARCHS.join(", "))
     } else {
-        bail!(
-            "unsupported architecture: '{}'\nDid you mean: {}",
-            arch,
-            suggestions.join(", ")
-        );
-    }
+        format!("'{}'\nDid you mean: {}", arch, suggestions.join(", "))
+    };
+    Err(MihoroError::Arch(message).into())
 }
 
 /// Constructs the download URL for a specific Mihomo version and architecture.
-pub fn build_download_url(version: &str, arch: &str, channel: &MihomoChannel) -> String {
+///
+/// Pinned stable versions are fetched from their own release tag (`releases/download/<tag>`)
+/// instead of `releases/latest`, as a pinned version is not necessarily the latest one.
+pub fn build_download_url(
+    version: &str,
+    arch: &str,
+    channel: &MihomoChannel,
+    pinned: bool,
+    mirror: Option<&str>,
+) -> String {
     let base = match channel {
-        MihomoChannel::Stable => "https://github.com/MetaCubeX/mihomo/releases/latest/download",
+        MihomoChannel::Stable if pinned => {
+            format!(
+                "https://github.com/MetaCubeX/mihomo/releases/download/{}",
+                version
+            )
+        }
+        MihomoChannel::Stable => {
+            "https://github.com/MetaCubeX/mihomo/releases/latest/download".to_string()
+        }
         MihomoChannel::Alpha => {
-            "https://github.com/MetaCubeX/mihomo/releases/download/Prerelease-Alpha"
+            "https://github.com/MetaCubeX/mihomo/releases/download/Prerelease-Alpha".to_string()
         }
     };
-    format!("{}/mihomo-linux-{}-{}.gz", base, arch, version)
+    let url = format!("{}/mihomo-linux-{}-{}.gz", base, arch, version);
+    mirror_github_url(&url, mirror)
+}
+
+/// A mihomo binary to download, with the version and architecture it is expected to be, which are
+/// unknown for configured URLs.
+pub struct ResolvedBinary {
+    pub url: String,
+    pub version: Option<String>,
+    pub arch: Option<String>,
 }
 
 /// Resolves the Mihomo binary download URL.
 ///
 /// If `remote_mihomo_binary_url` is set in the config, returns it directly.
-/// Otherwise, auto-detects the architecture and fetches the latest version from GitHub.
+/// Otherwise, auto-detects the architecture and fetches the latest version from GitHub, unless a
+/// `version` is pinned, in which case the GitHub version lookup is skipped.
 pub async fn resolve_binary_url(
     client: &Client,
     config: &Config,
     arch_override: Option<&str>,
+    version_override: Option<&str>,
     prefix: &str,
-) -> Result<String> {
+) -> Result<ResolvedBinary> {
     // If a URL is explicitly configured, use it directly
     if let Some(ref url) = config.remote_mihomo_binary_url {
         if !url.is_empty() {
-            println!(
+            info!(
                 "{} Using configured binary URL: {}",
                 prefix.cyan(),
                 url.underline()
             );
-            return Ok(url.clone());
+            return Ok(ResolvedBinary {
+                url: url.clone(),
+                version: None,
+                arch: None,
+            });
         }
     }
 
@@ -190,33 +552,61 @@ pub async fn resolve_binary_url(
         validate_arch(arch)?
     } else if let Some(ref arch) = config.mihomo_arch {
         validate_arch(arch)?
+    } else if config.arch_autotune {
+        detect_arch_optimized()?
     } else {
         detect_arch()?
     };
 
     let channel = &config.mihomo_channel;
-    let channel_name = match channel {
-        MihomoChannel::Stable => "stable",
-        MihomoChannel::Alpha => "alpha",
-    };
+    let channel_name = channel.as_str();
+
+    // Use pinned version if provided, skipping the latest version lookup
+    if let Some(version) = version_override {
+        info!(
+            "{} Using pinned mihomo {} version {} for {}",
+            prefix.cyan(),
+            channel_name.bold(),
+            version.bold(),
+            format!("linux-{}", arch).bold()
+        );
+        let url = build_download_url(
+            version,
+            &arch,
+            channel,
+            true,
+            config.github_mirror.as_deref(),
+        );
+        debug!("resolved mihomo binary url {}", url);
+        return Ok(ResolvedBinary {
+            url,
+            version: Some(version.to_string()),
+            arch: Some(arch),
+        });
+    }
 
-    println!(
+    info!(
         "{} Fetching latest mihomo {} release for {}...",
         prefix.cyan(),
         channel_name.bold(),
         format!("linux-{}", arch).bold()
     );
 
-    let version = fetch_latest_version(client, channel, &config.mihoro_user_agent).await?;
+    let version = latest_version(client, config, prefix).await?;
 
-    println!(
-        "{} Found mihomo version: {}",
-        prefix.green(),
-        version.bold()
+    let url = build_download_url(
+        &version,
+        &arch,
+        channel,
+        false,
+        config.github_mirror.as_deref(),
     );
-
-    let url = build_download_url(&version, &arch, channel);
-    Ok(url)
+    debug!("resolved mihomo binary url {}", url);
+    Ok(ResolvedBinary {
+        url,
+        version: Some(version),
+        arch: Some(arch),
+    })
 }
 
 #[cfg(test)]
@@ -233,9 +623,53 @@ mod tests {
         assert!(SUPPORTED_ARCHS.contains(&arch.as_str()));
     }
 
+    const CPUINFO_V3: &str = "processor\t: 0
+vendor_id\t: GenuineIntel
+flags\t\t: fpu vme de pse tsc msr pae mce cx8 apic sep mtrr pge mca cmov pat pse36 clflush mmx fxsr sse sse2 ss ht syscall nx pdpe1gb rdtscp lm constant_tsc pni pclmulqdq ssse3 fma cx16 pcid sse4_1 sse4_2 x2apic movbe popcnt aes xsave avx f16c rdrand hypervisor lahf_lm abm 3dnowprefetch fsgsbase bmi1 avx2 smep bmi2 erms invpcid
+bugs\t\t: spectre_v1
+
+processor\t: 1
+flags\t\t: fpu
+";
+
+    #[test]
+    fn test_parse_amd64_level() {
+        assert_eq!(parse_amd64_level(CPUINFO_V3), Some(3));
+
+        // Nehalem-era CPU without AVX
+        let cpuinfo_v2 = "flags : fpu sse sse2 ssse3 cx16 sse4_1 sse4_2 popcnt lahf_lm";
+        assert_eq!(parse_amd64_level(cpuinfo_v2), Some(2));
+
+        // AVX2 but missing BMI2 and MOVBE is not v3
+        let cpuinfo_partial = format!("{} avx avx2 bmi1 f16c fma abm xsave", cpuinfo_v2);
+        assert_eq!(parse_amd64_level(&cpuinfo_partial), Some(2));
+
+        assert_eq!(parse_amd64_level("flags : fpu sse sse2 lm"), Some(1));
+        assert_eq!(parse_amd64_level("processor : 0"), None);
+        assert_eq!(parse_amd64_level(""), None);
+    }
+
+    #[test]
+    fn test_amd64_arch_for() {
+        assert_eq!(amd64_arch_for(Some(CPUINFO_V3)), "amd64-v3");
+        assert_eq!(
+            amd64_arch_for(Some("flags : ssse3 cx16 sse4_1 sse4_2 popcnt lahf_lm")),
+            "amd64-v2"
+        );
+        assert_eq!(amd64_arch_for(Some("flags : sse2")), "amd64-compatible");
+        assert_eq!(amd64_arch_for(Some("")), "amd64-compatible");
+        assert_eq!(amd64_arch_for(None), "amd64-compatible");
+    }
+
+    #[test]
+    fn test_detect_arch_optimized_returns_valid_value() {
+        let arch = detect_arch_optimized().unwrap();
+        assert!(SUPPORTED_ARCHS.contains(&arch.as_str()));
+    }
+
     #[test]
     fn test_build_download_url_stable() {
-        let url = build_download_url("v1.19.0", "amd64", &MihomoChannel::Stable);
+        let url = build_download_url("v1.19.0", "amd64", &MihomoChannel::Stable, false, None);
         assert_eq!(
 			url,
 			"https://github.com/MetaCubeX/mihomo/releases/latest/download/mihomo-linux-amd64-v1.19.0.gz"
@@ -244,7 +678,7 @@ mod tests {
 
     #[test]
     fn test_build_download_url_alpha() {
-        let url = build_download_url("alpha-abc123", "arm64", &MihomoChannel::Alpha);
+        let url = build_download_url("alpha-abc123", "arm64", &MihomoChannel::Alpha, false, None);
         assert_eq!(
 			url,
 			"https://github.com/MetaCubeX/mihomo/releases/download/Prerelease-Alpha/mihomo-linux-arm64-alpha-abc123.gz"
@@ -253,13 +687,119 @@ mod tests {
 
     #[test]
     fn test_build_download_url_compatible_arch() {
-        let url = build_download_url("v1.19.0", "amd64-compatible", &MihomoChannel::Stable);
+        let url = build_download_url(
+            "v1.19.0",
+            "amd64-compatible",
+            &MihomoChannel::Stable,
+            false,
+            None,
+        );
         assert_eq!(
 			url,
 			"https://github.com/MetaCubeX/mihomo/releases/latest/download/mihomo-linux-amd64-compatible-v1.19.0.gz"
 		);
     }
 
+    #[test]
+    fn test_build_download_url_pinned_stable() {
+        let url = build_download_url("v1.18.10", "amd64", &MihomoChannel::Stable, true, None);
+        assert_eq!(
+			url,
+			"https://github.com/MetaCubeX/mihomo/releases/download/v1.18.10/mihomo-linux-amd64-v1.18.10.gz"
+		);
+    }
+
+    #[test]
+    fn test_build_download_url_pinned_alpha() {
+        let url = build_download_url("alpha-abc123", "arm64", &MihomoChannel::Alpha, true, None);
+        assert_eq!(
+			url,
+			"https://github.com/MetaCubeX/mihomo/releases/download/Prerelease-Alpha/mihomo-linux-arm64-alpha-abc123.gz"
+		);
+    }
+
+    #[test]
+    fn test_build_download_url_mirror_stable() {
+        let url = build_download_url(
+            "v1.19.0",
+            "amd64",
+            &MihomoChannel::Stable,
+            false,
+            Some("https://ghfast.top/"),
+        );
+        assert_eq!(
+			url,
+			"https://ghfast.top/https://github.com/MetaCubeX/mihomo/releases/latest/download/mihomo-linux-amd64-v1.19.0.gz"
+		);
+    }
+
+    #[test]
+    fn test_build_download_url_mirror_alpha() {
+        let url = build_download_url(
+            "alpha-abc123",
+            "arm64",
+            &MihomoChannel::Alpha,
+            false,
+            Some("https://mirror.ghproxy.com"),
+        );
+        assert_eq!(
+			url,
+			"https://mirror.ghproxy.com/https://github.com/MetaCubeX/mihomo/releases/download/Prerelease-Alpha/mihomo-linux-arm64-alpha-abc123.gz"
+		);
+    }
+
+    #[test]
+    fn test_mirror_github_url() {
+        let mirror = Some("https://ghfast.top");
+        assert_eq!(
+            mirror_github_url(STABLE_VERSION_URL, mirror),
+            format!("https://ghfast.top/{}", STABLE_VERSION_URL)
+        );
+        assert_eq!(
+            mirror_github_url(ALPHA_VERSION_URL, mirror),
+            format!("https://ghfast.top/{}", ALPHA_VERSION_URL)
+        );
+        assert_eq!(
+            mirror_github_url("https://api.github.com", mirror),
+            "https://ghfast.top/https://api.github.com"
+        );
+        assert_eq!(
+            mirror_github_url("https://example.com/mihomo.gz", mirror),
+            "https://example.com/mihomo.gz"
+        );
+        assert_eq!(
+            mirror_github_url(STABLE_VERSION_URL, None),
+            STABLE_VERSION_URL
+        );
+    }
+
+    #[test]
+    fn test_github_auth_headers() {
+        let headers = github_auth_headers(
+            "https://api.github.com/repos/MetaCubeX/mihomo/releases",
+            Some("ghp_abc"),
+        );
+        assert_eq!(headers.get(AUTHORIZATION).unwrap(), "Bearer ghp_abc");
+        assert!(headers.get(AUTHORIZATION).unwrap().is_sensitive());
+
+        // Tokens are never sent to mirrors
+        let mirrored = mirror_github_url(STABLE_VERSION_URL, Some("https://ghfast.top"));
+        assert!(github_auth_headers(&mirrored, Some("ghp_abc")).is_empty());
+        assert!(github_auth_headers(STABLE_VERSION_URL, None).is_empty());
+    }
+
+    #[test]
+    fn test_parse_sha256() {
+        let digest = "6ae8a75555209fd6c44157c0aed8016e763ff435a19cf186f76863140143ff72";
+        assert_eq!(parse_sha256(digest).unwrap(), digest);
+        assert_eq!(
+            parse_sha256(&format!("{}  mihomo-linux-amd64-v1.19.0.gz\n", digest)).unwrap(),
+            digest
+        );
+        assert!(parse_sha256("not a checksum").is_err());
+        assert!(parse_sha256("").is_err());
+    }
+
     #[test]
     fn test_validate_arch_accepts_valid_archs() {
         assert!(validate_arch("amd64").is_ok());
@@ -285,5 +825,41 @@ mod tests {
         let error = result.unwrap_err().to_string();
         assert!(error.contains("Did you mean"));
         assert!(error.contains("amd64"));
+
+        // Typos are ranked by edit distance, not only by their first characters
+        assert_eq!(suggest_archs("arm64-v8")[0], "arm64");
+        assert_eq!(suggest_archs("amd64v3")[0], "amd64-v3");
+        assert_eq!(
+            suggest_archs("mipsle")[..2],
+            ["mipsle-hardfloat", "mipsle-softfloat"]
+        );
+
+        // Names used by other tools suggest the matching mihomo build first
+        assert_eq!(suggest_archs("x86_64")[0], "amd64-compatible");
+        assert_eq!(suggest_archs("aarch64")[0], "arm64");
+        assert_eq!(suggest_archs("x64")[0], "amd64");
+        let error = validate_arch("x86_64").unwrap_err().to_string();
+        assert!(error.contains("Did you mean: amd64-compatible"));
+
+        // Nothing close lists every supported architecture
+        let error = validate_arch("sparc").unwrap_err().to_string();
+        assert!(error.contains("Supported: 386"));
+    }
+
+    #[test]
+    fn test_arch_families() {
+        let families = arch_families();
+        let names: Vec<&str> = families.iter().map(|f| f.family).collect();
+        assert_eq!(
+            names,
+            ["x86", "x86_64", "ARM", "LoongArch", "MIPS", "Others"]
+        );
+        assert_eq!(families[2].archs, ["arm64", "armv5", "armv6", "armv7"]);
+        assert_eq!(families[5].archs, ["ppc64le", "riscv64", "s390x"]);
+
+        // Every supported architecture is listed exactly once
+        let listed: Vec<&str> = families.into_iter().flat_map(|f| f.archs).collect();
+        assert_eq!(listed.len(), SUPPORTED_ARCHS.len());
+        assert!(SUPPORTED_ARCHS.iter().all(|arch| listed.contains(arch)));
     }
 }
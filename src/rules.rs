@@ -0,0 +1,136 @@
+use serde_yaml::Value;
+
+/// Why a rule like `DOMAIN-SUFFIX,example.com,DIRECT` is malformed, if it obviously is.
+///
+/// Only the shape is checked: an uppercase rule type, then a payload and a target, or just a
+/// target for `MATCH`. Commas in the parenthesized payload of `AND`/`OR`/`NOT` rules are fine.
+pub fn rule_problem(rule: &str) -> Option<&'static str> {
+    let parts: Vec<&str> = rule.split(',').map(str::trim).collect();
+    let kind = parts[0];
+    if kind.is_empty()
+        || !kind
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Some("does not start with an uppercase rule type, e.g. `DOMAIN-SUFFIX`");
+    }
+    if parts.iter().any(|part| part.is_empty()) {
+        return Some("has an empty field");
+    }
+    match (kind, parts.len()) {
+        ("MATCH", 2) => None,
+        ("MATCH", _) => Some("is not `MATCH,<target>`"),
+        (_, 0..=2) => Some("is not `<type>,<payload>,<target>`"),
+        _ => None,
+    }
+}
+
+/// Insert user rules into the `rules` of a mihomo config: `prepend` at the top, and `append` at
+/// the bottom, but before a trailing `MATCH` rule, which would catch everything before them.
+///
+/// Copies of the inserted rules are removed from the existing ones first, so inserting again into
+/// a config that already has them, as `apply` does, leaves it unchanged.
+pub fn insert_rules(yaml: &mut Value, prepend: &[&str], append: &[&str]) {
+    if prepend.is_empty() && append.is_empty() {
+        return;
+    }
+    let Value::Mapping(mapping) = yaml else {
+        return;
+    };
+    let existing = match mapping.get("rules") {
+        Some(Value::Sequence(rules)) => rules.clone(),
+        _ => vec![],
+    };
+
+    let inserted = |rule: &Value| {
+        rule.as_str()
+            .is_some_and(|rule| prepend.contains(&rule) || append.contains(&rule))
+    };
+    let mut rest: Vec<Value> = existing
+        .into_iter()
+        .filter(|rule| !inserted(rule))
+        .collect();
+    let catch_all = rest
+        .last()
+        .and_then(Value::as_str)
+        .is_some_and(|rule| rule.trim_start().starts_with("MATCH,"))
+        .then(|| rest.pop())
+        .flatten();
+
+    let rules: Vec<Value> = prepend
+        .iter()
+        .map(|rule| Value::from(*rule))
+        .chain(rest)
+        .chain(append.iter().map(|rule| Value::from(*rule)))
+        .chain(catch_all)
+        .collect();
+    mapping.insert(Value::from("rules"), Value::Sequence(rules));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(yaml: &Value) -> Vec<&str> {
+        yaml["rules"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|rule| rule.as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_insert_rules() {
+        let mut yaml: Value = serde_yaml::from_str(
+            "rules:\n  - DOMAIN,b.com,Proxy\n  - DOMAIN,a.com,DIRECT\n  - MATCH,Proxy\n",
+        )
+        .unwrap();
+        let prepend = ["DOMAIN,a.com,DIRECT", "GEOIP,CN,DIRECT"];
+        let append = ["DOMAIN-SUFFIX,local,DIRECT"];
+        insert_rules(&mut yaml, &prepend, &append);
+        let expected = [
+            "DOMAIN,a.com,DIRECT",
+            "GEOIP,CN,DIRECT",
+            "DOMAIN,b.com,Proxy",
+            "DOMAIN-SUFFIX,local,DIRECT",
+            "MATCH,Proxy",
+        ];
+        assert_eq!(rules(&yaml), expected);
+
+        // Inserting again changes nothing
+        insert_rules(&mut yaml, &prepend, &append);
+        assert_eq!(rules(&yaml), expected);
+    }
+
+    #[test]
+    fn test_insert_rules_without_base_rules() {
+        for base in ["proxies: []\n", "rules: []\n", "rules:\n"] {
+            let mut yaml: Value = serde_yaml::from_str(base).unwrap();
+            insert_rules(&mut yaml, &["GEOIP,CN,DIRECT"], &["MATCH,DIRECT"]);
+            assert_eq!(rules(&yaml), ["GEOIP,CN,DIRECT", "MATCH,DIRECT"]);
+        }
+    }
+
+    #[test]
+    fn test_rule_problem() {
+        for rule in [
+            "DOMAIN-SUFFIX,example.com,DIRECT",
+            "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve",
+            "AND,((DOMAIN,baidu.com),(NETWORK,UDP)),REJECT",
+            "MATCH,Proxy",
+        ] {
+            assert_eq!(rule_problem(rule), None, "{}", rule);
+        }
+        for rule in [
+            "",
+            "domain,example.com,DIRECT",
+            "DOMAIN,example.com",
+            "DOMAIN,,DIRECT",
+            "MATCH",
+            "MATCH,Proxy,extra",
+        ] {
+            assert!(rule_problem(rule).is_some(), "{}", rule);
+        }
+    }
+}
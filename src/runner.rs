@@ -0,0 +1,134 @@
+use std::fmt;
+use std::io;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+/// Runs external commands like `systemctl`, `journalctl` and `mihomo`, so code invoking them can
+/// be tested against `MockRunner` instead of the real system.
+pub trait CommandRunner: Send + Sync {
+    /// Run `program` to completion, capturing its stdout and stderr.
+    fn output(&self, program: &str, args: &[String]) -> io::Result<Output>;
+
+    /// Run `program` to completion with the terminal attached to stdin and stdout, capturing only
+    /// its stderr, e.g. for `systemctl status`.
+    fn run_captured_stderr(&self, program: &str, args: &[String]) -> io::Result<Output>;
+
+    /// Run `program` to completion with the terminal attached, e.g. to follow `journalctl -f`.
+    fn status(&self, program: &str, args: &[String]) -> io::Result<ExitStatus>;
+}
+
+impl fmt::Debug for dyn CommandRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CommandRunner")
+    }
+}
+
+/// Runs commands as child processes.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, program: &str, args: &[String]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+
+    fn run_captured_stderr(&self, program: &str, args: &[String]) -> io::Result<Output> {
+        Command::new(program)
+            .args(args)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::piped())
+            .output()
+    }
+
+    fn status(&self, program: &str, args: &[String]) -> io::Result<ExitStatus> {
+        Command::new(program).args(args).status()
+    }
+}
+
+#[cfg(test)]
+pub use mock::{MockResponse, MockRunner};
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::Mutex;
+
+    /// Exit code, stdout and stderr a `MockRunner` answers a command with.
+    #[derive(Default)]
+    pub struct MockResponse {
+        pub code: i32,
+        pub stdout: String,
+        pub stderr: String,
+    }
+
+    impl MockResponse {
+        pub fn ok(stdout: &str) -> Self {
+            Self {
+                stdout: stdout.to_string(),
+                ..Self::default()
+            }
+        }
+
+        pub fn fail(code: i32, stderr: &str) -> Self {
+            Self {
+                code,
+                stderr: stderr.to_string(),
+                ..Self::default()
+            }
+        }
+    }
+
+    type Respond = dyn Fn(&str) -> MockResponse + Send + Sync;
+
+    /// Records the command lines it is asked to run, e.g. `systemctl --user start mihomo.service`,
+    /// answering each with `respond`.
+    pub struct MockRunner {
+        respond: Box<Respond>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl MockRunner {
+        pub fn new(respond: impl Fn(&str) -> MockResponse + Send + Sync + 'static) -> Self {
+            Self {
+                respond: Box::new(respond),
+                calls: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// Command lines run so far, in order.
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn answer(&self, program: &str, args: &[String]) -> MockResponse {
+            let command_line = [program.to_string()]
+                .into_iter()
+                .chain(args.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.lock().unwrap().push(command_line.clone());
+            (self.respond)(&command_line)
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn output(&self, program: &str, args: &[String]) -> io::Result<Output> {
+            let response = self.answer(program, args);
+            Ok(Output {
+                status: ExitStatus::from_raw(response.code << 8),
+                stdout: response.stdout.into_bytes(),
+                stderr: response.stderr.into_bytes(),
+            })
+        }
+
+        fn run_captured_stderr(&self, program: &str, args: &[String]) -> io::Result<Output> {
+            let mut output = self.output(program, args)?;
+            output.stdout.clear();
+            Ok(output)
+        }
+
+        fn status(&self, program: &str, args: &[String]) -> io::Result<ExitStatus> {
+            Ok(self.output(program, args)?.status)
+        }
+    }
+}
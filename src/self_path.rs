@@ -0,0 +1,66 @@
+use crate::cmd::OutputFormat;
+use crate::utils::dir_writable;
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::Serialize;
+
+/// The running mihoro binary, and whether `mihoro upgrade` can replace it.
+#[derive(Serialize, Debug)]
+pub struct SelfPath {
+    pub path: PathBuf,
+    pub writable: bool,
+}
+
+impl SelfPath {
+    /// Locate the running binary, resolving symlinks so the installed file is reported.
+    pub fn locate() -> Result<SelfPath> {
+        let exe = env::current_exe().context("failed to locate the running mihoro binary")?;
+        let path = exe.canonicalize().unwrap_or(exe);
+        let writable = path.parent().is_some_and(dir_writable);
+        Ok(SelfPath { path, writable })
+    }
+}
+
+/// How to upgrade a binary whose directory is not writable.
+pub fn permission_hint(path: &Path) -> String {
+    format!(
+        "{} is not writable, run `sudo mihoro upgrade`, upgrade with the package manager that \
+         installed mihoro, or reinstall mihoro to a user-writable location such as ~/.local/bin",
+        path.parent().unwrap_or(path).to_string_lossy()
+    )
+}
+
+/// Run `mihoro where`, printing the path of the running binary and whether it is writable.
+pub fn print_self_path(output: OutputFormat) -> Result<()> {
+    let self_path = SelfPath::locate()?;
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&self_path)?),
+        OutputFormat::Human => {
+            println!("{}", self_path.path.to_string_lossy());
+            if self_path.writable {
+                println!("{} writable, `mihoro upgrade` can replace it", "✔".green());
+            } else {
+                println!("{} not writable", "✘".red());
+                println!("{} {}", "hint:".yellow(), permission_hint(&self_path.path));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() -> Result<()> {
+        let self_path = SelfPath::locate()?;
+        assert!(self_path.path.is_absolute());
+        assert!(self_path.path.is_file());
+        Ok(())
+    }
+}
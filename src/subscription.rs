@@ -0,0 +1,371 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+use crate::config::ConfigFormat;
+use crate::utils::try_decode_base64;
+
+/// Response header carrying traffic usage and expiry, sent by most Clash subscription providers.
+pub const USERINFO_HEADER: &str = "subscription-userinfo";
+
+/// Seconds in a day, for computing days until expiry.
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Traffic usage and expiry of a subscription, parsed from the `subscription-userinfo` header.
+///
+/// Traffic figures are in bytes, `expire` is a Unix timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionInfo {
+    pub upload: u64,
+    pub download: u64,
+    pub total: u64,
+    pub expire: Option<u64>,
+}
+
+impl SubscriptionInfo {
+    /// Parse a header value like `upload=123; download=456; total=1073741824; expire=1700000000`.
+    ///
+    /// Unknown keys are ignored. Returns `None` if no known fields are present.
+    pub fn parse(header: &str) -> Option<SubscriptionInfo> {
+        let mut info = SubscriptionInfo::default();
+        let mut found = false;
+
+        for field in header.split(';') {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            // Some providers send figures as floats, e.g. `total=1.073741824e9`
+            let value = value.trim();
+            let Some(value) = value
+                .parse::<u64>()
+                .ok()
+                .or_else(|| value.parse::<f64>().ok().map(|value| value as u64))
+            else {
+                continue;
+            };
+
+            match key.trim().to_ascii_lowercase().as_str() {
+                "upload" => info.upload = value,
+                "download" => info.download = value,
+                "total" => info.total = value,
+                // Providers without an expiry send `expire=0` or leave it empty
+                "expire" => info.expire = (value > 0).then_some(value),
+                _ => continue,
+            }
+            found = true;
+        }
+
+        found.then_some(info)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.upload.saturating_add(self.download)
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.total.saturating_sub(self.used())
+    }
+
+    /// Whole days from `now` until expiry, negative if already expired.
+    pub fn days_until_expiry(&self, now: u64) -> Option<i64> {
+        self.expire
+            .map(|expire| (expire as i64 - now as i64).div_euclid(DAY_SECS as i64))
+    }
+}
+
+/// What a subscription returned, as checked by `mihoro test-url`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SubscriptionCheck {
+    pub proxies: usize,
+    pub proxy_groups: usize,
+    pub proxy_providers: usize,
+    pub rules: usize,
+    /// Whether the config was base64 encoded, which mihoro decodes when updating
+    pub base64: bool,
+    /// Whether the config was JSON, which mihoro converts to YAML when updating
+    pub json: bool,
+}
+
+/// Convert a config in `format` to YAML, returning `None` if it already is YAML.
+///
+/// With `ConfigFormat::Auto`, a config is taken for JSON if it starts with `{` and parses as JSON,
+/// as a YAML flow mapping may start with `{` too.
+pub fn json_config_to_yaml(body: &[u8], format: ConfigFormat) -> Result<Option<String>> {
+    let json = match format {
+        ConfigFormat::Yaml => return Ok(None),
+        ConfigFormat::Json => serde_json::from_slice(body).context("invalid JSON config")?,
+        ConfigFormat::Auto => {
+            if body.trim_ascii_start().first() != Some(&b'{') {
+                return Ok(None);
+            }
+            match serde_json::from_slice::<serde_json::Value>(body) {
+                Ok(json) => json,
+                Err(_) => return Ok(None),
+            }
+        }
+    };
+    let yaml: Value = serde_yaml::to_value(json)?;
+    Ok(Some(serde_yaml::to_string(&yaml)?))
+}
+
+/// Check that `body` is a mihomo config with proxies, decoding it first if it is base64 encoded
+/// and converting it if it is JSON.
+pub fn check_subscription(body: &[u8], format: ConfigFormat) -> Result<SubscriptionCheck> {
+    let decoded = try_decode_base64(body);
+    let body = decoded.as_deref().unwrap_or(body);
+    let converted = json_config_to_yaml(body, format)?;
+    let yaml = match &converted {
+        Some(yaml) => yaml.into(),
+        None => String::from_utf8_lossy(body),
+    };
+    let config: Value = serde_yaml::from_str(&yaml)?;
+    if !config.is_mapping() {
+        // e.g. a login page, or a plain list of share links
+        bail!("expected a YAML mapping of mihomo config keys");
+    }
+
+    let count = |key: &str| match &config[key] {
+        Value::Sequence(items) => items.len(),
+        Value::Mapping(items) => items.len(),
+        _ => 0,
+    };
+    let check = SubscriptionCheck {
+        proxies: count("proxies"),
+        proxy_groups: count("proxy-groups"),
+        proxy_providers: count("proxy-providers"),
+        rules: count("rules"),
+        base64: decoded.is_some(),
+        json: converted.is_some(),
+    };
+    if check.proxies == 0 && check.proxy_providers == 0 {
+        bail!("no `proxies` or `proxy-providers` found");
+    }
+    Ok(check)
+}
+
+/// Format a byte count with binary units, e.g. `1.50 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Cache subscription info to `path`, so it can be shown without re-fetching.
+pub fn save_subscription_info(path: &Path, info: &SubscriptionInfo) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(info)?)?;
+    Ok(())
+}
+
+/// Load cached subscription info from `path`, if any.
+pub fn load_subscription_info(path: &Path) -> Option<SubscriptionInfo> {
+    let raw_info = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&raw_info).ok()
+}
+
+/// Print remaining traffic and days until expiry.
+pub fn print_subscription_info(info: &SubscriptionInfo) {
+    if info.total > 0 {
+        println!(
+            "{} Traffic: {} remaining ({} of {} used)",
+            "subscription:".cyan().bold(),
+            format_bytes(info.remaining()).bold(),
+            format_bytes(info.used()),
+            format_bytes(info.total)
+        );
+    } else {
+        println!(
+            "{} Traffic: {} used",
+            "subscription:".cyan().bold(),
+            format_bytes(info.used()).bold()
+        );
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    match info.days_until_expiry(now) {
+        Some(days) if days < 0 => println!(
+            "{} Expired {} day(s) ago",
+            "subscription:".cyan().bold(),
+            (-days).to_string().red()
+        ),
+        Some(days) => println!(
+            "{} Expires in {} day(s)",
+            "subscription:".cyan().bold(),
+            if days <= 7 {
+                days.to_string().yellow()
+            } else {
+                days.to_string().bold()
+            }
+        ),
+        None => println!("{} No expiry", "subscription:".cyan().bold()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_subscription_info() {
+        let info = SubscriptionInfo::parse(
+            "upload=1073741824; download=2147483648; total=10737418240; expire=1700000000",
+        )
+        .unwrap();
+        assert_eq!(
+            info,
+            SubscriptionInfo {
+                upload: 1073741824,
+                download: 2147483648,
+                total: 10737418240,
+                expire: Some(1700000000),
+            }
+        );
+        assert_eq!(info.used(), 3221225472);
+        assert_eq!(info.remaining(), 7516192768);
+
+        // Missing or zero expiry, floats, odd spacing and unknown keys
+        let info =
+            SubscriptionInfo::parse("upload=0;download=1.5e3 ; total=2048; expire=; plan=pro")
+                .unwrap();
+        assert_eq!(info.download, 1500);
+        assert_eq!(info.expire, None);
+        assert_eq!(
+            SubscriptionInfo::parse("total=1; expire=0").unwrap().expire,
+            None
+        );
+
+        assert_eq!(SubscriptionInfo::parse(""), None);
+        assert_eq!(SubscriptionInfo::parse("plan=pro"), None);
+    }
+
+    #[test]
+    fn test_check_subscription() -> Result<()> {
+        let config = "proxies:\n  - {name: a, type: ss}\n  - {name: b, type: ss}\n\
+                      proxy-groups:\n  - {name: auto, type: select, proxies: [a, b]}\n\
+                      rules:\n  - MATCH,auto\n";
+        let expected = SubscriptionCheck {
+            proxies: 2,
+            proxy_groups: 1,
+            proxy_providers: 0,
+            rules: 1,
+            base64: false,
+            json: false,
+        };
+        assert_eq!(
+            check_subscription(config.as_bytes(), ConfigFormat::Auto)?,
+            expected
+        );
+
+        let encoded = BASE64_STANDARD.encode(config);
+        assert_eq!(
+            check_subscription(encoded.as_bytes(), ConfigFormat::Auto)?,
+            SubscriptionCheck {
+                base64: true,
+                ..expected
+            }
+        );
+
+        let providers = "proxy-providers:\n  sub: {type: http, url: http://a}\n";
+        assert_eq!(
+            check_subscription(providers.as_bytes(), ConfigFormat::Auto)?.proxy_providers,
+            1
+        );
+
+        // Login pages, empty configs and configs without proxies are rejected
+        assert!(check_subscription(b"<html>please log in</html>", ConfigFormat::Auto).is_err());
+        assert!(check_subscription(b"", ConfigFormat::Auto).is_err());
+        assert!(check_subscription(b"mixed-port: 7890\n", ConfigFormat::Auto).is_err());
+        assert!(check_subscription(b"proxies: [a: [", ConfigFormat::Auto).is_err());
+
+        let json = r#"{"proxies": [{"name": "a", "type": "ss"}], "rules": ["MATCH,a"]}"#;
+        let check = check_subscription(json.as_bytes(), ConfigFormat::Auto)?;
+        assert!(check.json);
+        assert_eq!((check.proxies, check.rules), (1, 1));
+        Ok(())
+    }
+
+    /// Test that JSON subscriptions are written as the same config in YAML
+    #[test]
+    fn test_json_config_to_yaml() -> Result<()> {
+        let json = r#"
+            {
+            	"mixed-port": 7890,
+            	"proxies": [{"name": "a\/b", "type": "ss", "udp": true}],
+            	"rules": ["MATCH,DIRECT"]
+            }"#;
+        let yaml = json_config_to_yaml(json.as_bytes(), ConfigFormat::Auto)?.unwrap();
+        assert_eq!(
+            yaml,
+            "mixed-port: 7890\nproxies:\n- name: a/b\n  type: ss\n  udp: true\nrules:\n- MATCH,DIRECT\n"
+        );
+        let round_trip: serde_json::Value = serde_yaml::from_str(&yaml)?;
+        assert_eq!(round_trip, serde_json::from_str::<serde_json::Value>(json)?);
+
+        // YAML, including flow mappings that aren't JSON, is kept as is unless JSON is forced
+        assert_eq!(
+            json_config_to_yaml(b"mixed-port: 7890\n", ConfigFormat::Auto)?,
+            None
+        );
+        assert_eq!(
+            json_config_to_yaml(b"{mixed-port: 7890}", ConfigFormat::Auto)?,
+            None
+        );
+        assert_eq!(
+            json_config_to_yaml(json.as_bytes(), ConfigFormat::Yaml)?,
+            None
+        );
+        assert!(json_config_to_yaml(b"mixed-port: 7890\n", ConfigFormat::Json).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_days_until_expiry() {
+        let info = SubscriptionInfo {
+            expire: Some(10 * DAY_SECS),
+            ..Default::default()
+        };
+        assert_eq!(info.days_until_expiry(0), Some(10));
+        assert_eq!(info.days_until_expiry(DAY_SECS / 2), Some(9));
+        assert_eq!(info.days_until_expiry(11 * DAY_SECS), Some(-1));
+        assert_eq!(SubscriptionInfo::default().days_until_expiry(0), None);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+        assert_eq!(format_bytes(10737418240), "10.00 GiB");
+    }
+
+    #[test]
+    fn test_save_and_load_subscription_info() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("subscription.json");
+        assert_eq!(load_subscription_info(&path), None);
+
+        let info = SubscriptionInfo::parse("upload=1; download=2; total=3").unwrap();
+        save_subscription_info(&path, &info)?;
+        assert_eq!(load_subscription_info(&path), Some(info));
+        Ok(())
+    }
+}
@@ -1,62 +1,476 @@
-use std::process::{Command, ExitStatus};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
+use colored::Colorize;
+
+use crate::error::MihoroError;
+use crate::log::{debug, einfo, trace};
+use crate::runner::{CommandRunner, SystemRunner};
 
 pub struct Systemctl {
-    systemctl: Command,
+    runner: Arc<dyn CommandRunner>,
+    args: Vec<String>,
+    system: bool,
+    dry_run: bool,
 }
 
 impl Systemctl {
     pub fn new() -> Self {
         Self {
-            systemctl: Command::new("systemctl"),
+            runner: Arc::new(SystemRunner),
+            args: Vec::new(),
+            system: false,
+            dry_run: false,
         }
     }
 
-    pub fn enable(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("enable").arg(service);
+    /// Run systemctl through a different runner, e.g. a `MockRunner` in tests.
+    pub fn runner(&mut self, runner: Arc<dyn CommandRunner>) -> &mut Self {
+        self.runner = runner;
         self
     }
 
-    pub fn start(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("start").arg(service);
+    /// Target the system service manager instead of the per-user one (drops `--user`).
+    pub fn system(&mut self, system: bool) -> &mut Self {
+        self.system = system;
         self
     }
 
-    pub fn stop(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("stop").arg(service);
+    /// Print the systemctl command instead of executing it.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
         self
     }
 
-    pub fn restart(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("restart").arg(service);
+    fn action(&mut self, action: &str, service: Option<&str>) -> &mut Self {
+        self.args.push(action.to_string());
+        if let Some(service) = service {
+            self.args.push(service.to_string());
+        }
         self
     }
 
+    pub fn enable(&mut self, service: &str) -> &mut Self {
+        self.action("enable", Some(service))
+    }
+
+    pub fn start(&mut self, service: &str) -> &mut Self {
+        self.action("start", Some(service))
+    }
+
+    pub fn stop(&mut self, service: &str) -> &mut Self {
+        self.action("stop", Some(service))
+    }
+
+    pub fn restart(&mut self, service: &str) -> &mut Self {
+        self.action("restart", Some(service))
+    }
+
+    /// Run the unit's `ExecReload=`, which has mihomo re-read its config without restarting.
+    pub fn reload(&mut self, service: &str) -> &mut Self {
+        self.action("reload", Some(service))
+    }
+
     pub fn status(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("status").arg(service);
-        self
+        self.action("status", Some(service))
     }
 
     pub fn disable(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("disable").arg(service);
+        self.action("disable", Some(service))
+    }
+
+    /// Enable and immediately start a unit (`enable --now`).
+    pub fn enable_now(&mut self, unit: &str) -> &mut Self {
+        self.args
+            .extend(["enable", "--now", unit].map(String::from));
         self
     }
 
-    pub fn daemon_reload(&mut self) -> &mut Self {
-        self.systemctl.arg("--user").arg("daemon-reload");
+    /// Disable and immediately stop a unit (`disable --now`).
+    pub fn disable_now(&mut self, unit: &str) -> &mut Self {
+        self.args
+            .extend(["disable", "--now", unit].map(String::from));
         self
     }
 
+    pub fn daemon_reload(&mut self) -> &mut Self {
+        self.action("daemon-reload", None)
+    }
+
     pub fn reset_failed(&mut self) -> &mut Self {
-        self.systemctl.arg("--user").arg("reset-failed");
-        self
+        self.action("reset-failed", None)
+    }
+
+    /// Query the `ActiveState` of a service (e.g. `active`, `inactive`, `failed`).
+    ///
+    /// Always runs, even in dry-run mode, as it does not change any state.
+    pub fn active_state(&mut self, service: &str) -> Result<String> {
+        self.property(service, "ActiveState")
+    }
+
+    /// Query a unit property, e.g. `ActiveEnterTimestampMonotonic`, with `show --value`.
+    ///
+    /// Always runs, even in dry-run mode, as it does not change any state.
+    pub fn property(&mut self, unit: &str, property: &str) -> Result<String> {
+        self.args.extend([
+            "show".to_string(),
+            format!("--property={}", property),
+            "--value".to_string(),
+            unit.to_string(),
+        ]);
+        self.query()
+    }
+
+    /// Whether a unit is currently active, as reported by `is-active`.
+    ///
+    /// Always runs, even in dry-run mode, as it does not change any state.
+    pub fn is_active(&mut self, unit: &str) -> Result<bool> {
+        self.args.extend(["is-active", unit].map(String::from));
+        Ok(self.query()? == "active")
+    }
+
+    /// Whether a unit is enabled to start automatically, as reported by `is-enabled`.
+    ///
+    /// Only `enabled` counts, so `static`, `masked` or `enabled-runtime` units are not enabled.
+    /// Always runs, even in dry-run mode, as it does not change any state.
+    pub fn is_enabled(&mut self, unit: &str) -> Result<bool> {
+        self.args.extend(["is-enabled", unit].map(String::from));
+        Ok(self.query()? == "enabled")
+    }
+
+    /// Capture the output of `status` for a service, e.g. for bug reports.
+    ///
+    /// Always runs, even in dry-run mode, as it does not change any state.
+    pub fn status_output(&mut self, service: &str) -> Result<String> {
+        self.args
+            .extend(["status", "--no-pager", service].map(String::from));
+        self.query()
+    }
+
+    /// Why systemd cannot manage units here, if it cannot: systemctl is missing, the system was
+    /// not booted with systemd, as in most containers and WSL, or there is no user manager for
+    /// `--user`, as without a login session. Probed with `show-environment`.
+    ///
+    /// Always runs, even in dry-run mode, as it does not change any state.
+    pub fn unavailable(&mut self) -> Option<String> {
+        self.args.push("show-environment".to_string());
+        debug!("running `{}`", self.command_line());
+        match self.runner.output("systemctl", &self.full_args()) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Some("`systemctl` not found".to_string())
+            }
+            Ok(output) if !output.status.success() => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                is_systemd_unavailable(&stderr).then(|| stderr.trim().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Run the command without printing, capturing and returning its trimmed stdout.
+    ///
+    /// Queries like `is-active` exit non-zero for negative answers, so the exit status is ignored
+    /// and only the reported state is used.
+    fn query(&self) -> Result<String> {
+        debug!("running `{}`", self.command_line());
+        let output = self
+            .runner
+            .output("systemctl", &self.full_args())
+            .with_context(|| "failed to execute systemctl")?;
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        trace!(
+            "`{}` printed `{}` ({})",
+            self.command_line(),
+            stdout,
+            output.status
+        );
+        Ok(stdout)
+    }
+
+    /// Full list of arguments passed to systemctl, including `--user` in user mode.
+    fn full_args(&self) -> Vec<String> {
+        let mut args = Vec::with_capacity(self.args.len() + 1);
+        if !self.system {
+            args.push("--user".to_string());
+        }
+        args.extend(self.args.iter().cloned());
+        args
+    }
+
+    /// Render the full systemctl command line, e.g. `systemctl --user start mihomo.service`.
+    pub fn command_line(&self) -> String {
+        format!("systemctl {}", self.full_args().join(" "))
     }
 
+    /// Run the command, returning its exit status. systemctl reports its own errors.
     pub fn execute(&mut self) -> Result<ExitStatus> {
-        self.systemctl
-            .spawn()?
-            .wait()
-            .with_context(|| "failed to execute systemctl")
+        let (status, stderr) = self.run()?;
+        eprint!("{}", stderr);
+        Ok(status)
+    }
+
+    /// Run the command, failing with what systemctl printed if it exits unsuccessfully.
+    pub fn execute_checked(&mut self) -> Result<()> {
+        let (status, stderr) = self.run()?;
+        if !status.success() {
+            return Err(MihoroError::Systemctl {
+                command: self.command_line(),
+                status,
+                stderr: stderr.trim().to_string(),
+            }
+            .into());
+        }
+        eprint!("{}", stderr);
+        Ok(())
+    }
+
+    /// Run the command, returning its exit status and what it printed to stderr.
+    ///
+    /// If systemd does not know the unit yet, e.g. right after its unit file was written, units
+    /// are reloaded with `daemon-reload` and the command is retried once.
+    fn run(&self) -> Result<(ExitStatus, String)> {
+        if self.dry_run {
+            println!("{} would: run `{}`", "mihoro:".cyan(), self.command_line());
+            return Ok((ExitStatus::from_raw(0), String::new()));
+        }
+
+        let (status, stderr) = self.spawn()?;
+        if !status.success() && is_systemd_unavailable(&stderr) {
+            return Err(MihoroError::NoSystemd(stderr.trim().to_string()).into());
+        }
+        if status.success()
+            || !is_unit_not_found(&stderr)
+            || self
+                .args
+                .first()
+                .is_some_and(|action| action == "daemon-reload")
+        {
+            return Ok((status, stderr));
+        }
+
+        einfo!(
+            "{} {}, reloading systemd units and retrying",
+            "mihoro:".yellow(),
+            stderr.trim()
+        );
+        let mut daemon_reload = Systemctl::new();
+        daemon_reload
+            .runner(self.runner.clone())
+            .system(self.system)
+            .daemon_reload();
+        let (reloaded, reload_stderr) = daemon_reload.spawn()?;
+        if !reloaded.success() {
+            eprint!("{}", reload_stderr);
+        }
+        self.spawn()
+    }
+
+    /// Run the command once, capturing stderr while the terminal stays attached to stdin and
+    /// stdout, e.g. for `status`.
+    fn spawn(&self) -> Result<(ExitStatus, String)> {
+        debug!("running `{}`", self.command_line());
+        let output = match self
+            .runner
+            .run_captured_stderr("systemctl", &self.full_args())
+        {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(MihoroError::NoSystemd("`systemctl` not found".to_string()).into())
+            }
+            result => result.with_context(|| "failed to execute systemctl")?,
+        };
+        debug!("`{}` exited with {}", self.command_line(), output.status);
+        Ok((
+            output.status,
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+/// Whether systemctl failed because systemd does not know the unit, as reported by e.g. `start`
+/// ("Unit mihomo.service not found.") or `enable` ("Unit file mihomo.service does not exist.").
+fn is_unit_not_found(stderr: &str) -> bool {
+    stderr.lines().any(|line| {
+        line.contains("Unit ") && (line.contains(" not found") || line.contains(" does not exist"))
+    })
+}
+
+/// Whether systemctl failed because there is no systemd to talk to, e.g. "System has not been
+/// booted with systemd as init system (PID 1). Can't operate." or "Failed to connect to bus: No
+/// medium found" for `--user` without a user manager.
+fn is_systemd_unavailable(stderr: &str) -> bool {
+    stderr.lines().any(|line| {
+        line.contains("not been booted with systemd")
+            || line.contains("Failed to connect to bus")
+            || line.contains("Failed to connect to user scope bus")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{MockResponse, MockRunner};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Mock systemctl that doesn't know any unit before `daemon-reload` and fails `restart` with
+    /// `restart_error`.
+    fn mock_systemctl(restart_error: &'static str) -> Arc<MockRunner> {
+        let reloaded = AtomicBool::new(false);
+        Arc::new(MockRunner::new(move |command| {
+            if command.contains("daemon-reload") {
+                reloaded.store(true, Ordering::SeqCst);
+                MockResponse::default()
+            } else if command.contains("restart") {
+                MockResponse::fail(1, restart_error)
+            } else if !reloaded.load(Ordering::SeqCst) {
+                MockResponse::fail(
+                    5,
+                    "Failed to start mihomo.service: Unit mihomo.service not found.\n",
+                )
+            } else {
+                MockResponse::default()
+            }
+        }))
+    }
+
+    #[test]
+    fn test_retries_unknown_unit_after_daemon_reload() -> Result<()> {
+        let runner = mock_systemctl("");
+
+        let status = Systemctl::new()
+            .runner(runner.clone())
+            .start("mihomo.service")
+            .execute()?;
+        assert!(status.success());
+        assert_eq!(
+            runner.calls(),
+            [
+                "systemctl --user start mihomo.service",
+                "systemctl --user daemon-reload",
+                "systemctl --user start mihomo.service"
+            ]
+        );
+
+        // A known unit is not retried
+        Systemctl::new()
+            .runner(runner.clone())
+            .system(true)
+            .stop("mihomo.service")
+            .execute_checked()?;
+        assert_eq!(
+            runner.calls().last().map(String::as_str),
+            Some("systemctl stop mihomo.service")
+        );
+        assert_eq!(runner.calls().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_checked_reports_stderr() {
+        let runner = mock_systemctl(
+            "Job for mihomo.service failed because the control process exited with error code.",
+        );
+
+        let err = Systemctl::new()
+            .runner(runner.clone())
+            .restart("mihomo.service")
+            .execute_checked()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`systemctl --user restart mihomo.service` failed (exit status: 1): Job for \
+             mihomo.service failed because the control process exited with error code."
+        );
+        // Other failures are not retried
+        assert_eq!(runner.calls(), ["systemctl --user restart mihomo.service"]);
+    }
+
+    #[test]
+    fn test_dry_run_runs_nothing() -> Result<()> {
+        let runner = mock_systemctl("");
+        let status = Systemctl::new()
+            .runner(runner.clone())
+            .dry_run(true)
+            .start("mihomo.service")
+            .execute()?;
+        assert!(status.success());
+        assert!(runner.calls().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_queries() -> Result<()> {
+        let runner = Arc::new(MockRunner::new(|command| match command {
+            "systemctl --user is-active mihomo.service" => MockResponse::ok("active\n"),
+            "systemctl --user is-enabled mihomo.service" => MockResponse {
+                code: 1,
+                stdout: "disabled\n".to_string(),
+                ..MockResponse::default()
+            },
+            _ => MockResponse::fail(3, ""),
+        }));
+        let systemctl = || {
+            let mut systemctl = Systemctl::new();
+            systemctl.runner(runner.clone()).dry_run(true);
+            systemctl
+        };
+
+        // Queries run even in dry-run mode
+        assert!(systemctl().is_active("mihomo.service")?);
+        assert!(!systemctl().is_enabled("mihomo.service")?);
+        assert!(!systemctl().is_active("other.service")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_unit_not_found() {
+        assert!(is_unit_not_found(
+            "Failed to start mihomo.service: Unit mihomo.service not found.\n"
+        ));
+        assert!(is_unit_not_found(
+            "Failed to enable unit: Unit file mihomo.service does not exist.\n"
+        ));
+        assert!(!is_unit_not_found(
+            "Failed to connect to bus: No medium found\n"
+        ));
+    }
+
+    /// Test that a missing systemd is detected, and reported as such by commands
+    #[test]
+    fn test_systemd_unavailable() {
+        const NOT_BOOTED: &str = "System has not been booted with systemd as init system (PID 1). \
+                                  Can't operate.\nFailed to connect to bus: Host is down\n";
+        let runner = Arc::new(MockRunner::new(|command| match command {
+            "systemctl show-environment" => MockResponse::ok("LANG=C.UTF-8\n"),
+            _ => MockResponse::fail(1, NOT_BOOTED),
+        }));
+
+        assert_eq!(
+            Systemctl::new().runner(runner.clone()).unavailable(),
+            Some(NOT_BOOTED.trim().to_string())
+        );
+        assert_eq!(
+            Systemctl::new()
+                .runner(runner.clone())
+                .system(true)
+                .unavailable(),
+            None
+        );
+
+        let err = Systemctl::new()
+            .runner(runner.clone())
+            .start("mihomo.service")
+            .execute()
+            .unwrap_err();
+        assert_eq!(
+            crate::error::kind(&err),
+            Some(crate::error::ErrorKind::NoSystemd)
+        );
+
+        // Other failures are not mistaken for a missing systemd
+        let runner = Arc::new(MockRunner::new(|_| MockResponse::fail(1, "Access denied")));
+        assert_eq!(Systemctl::new().runner(runner).unavailable(), None);
     }
 }
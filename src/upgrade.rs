@@ -1,91 +1,407 @@
-use anyhow::Result;
+use crate::error::{self, ErrorKind, MihoroError};
+use crate::log::info;
+use crate::resolve_mihomo_bin::{github_auth_headers, mirror_github_url, parse_sha256};
+use crate::utils::{confirm, verify_sha256};
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use colored::Colorize;
-use self_update::cargo_crate_version;
+use reqwest::header::{HeaderValue, ACCEPT};
+use self_update::backends::github::ReleaseList;
+use self_update::update::{Release, ReleaseAsset};
+use self_update::version::bump_is_greater;
+use self_update::{cargo_crate_version, get_target, self_replace, Download, Extract};
+use zipsign_api::verify::{collect_keys, verify_tar};
+use zipsign_api::PUBLIC_KEY_LENGTH;
 
-/// Perform the upgrade to the latest version
-pub async fn run_upgrade(no_confirm: bool, target: Option<String>) -> Result<()> {
-    let prefix = "mihoro:";
+const GITHUB_API_URL: &str = "https://api.github.com";
 
-    println!("{} Checking for mihoro updates...", prefix.cyan());
+const PREFIX: &str = "mihoro:";
 
-    let result = tokio::task::spawn_blocking(move || {
-        let mut builder = self_update::backends::github::Update::configure();
-        builder
-            .repo_owner("spencerwooo")
-            .repo_name("mihoro")
-            .bin_name("mihoro")
-            .show_download_progress(true)
-            .show_output(true)
-            .no_confirm(no_confirm)
-            .current_version(cargo_crate_version!());
-
-        // Override target if provided
-        if let Some(target) = target {
-            builder.target(&target);
+/// Name of the mihoro binary inside release archives.
+const BIN_NAME: &str = "mihoro";
+
+/// Base64 ed25519 public key release archives are signed with using `zipsign`, embedded by
+/// release builds through the `MIHORO_RELEASE_PUBLIC_KEY` environment variable.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("MIHORO_RELEASE_PUBLIC_KEY");
+
+/// Download a release asset to `dest`, authenticated with `github_token` if it is on GitHub.
+///
+/// self_update makes its own blocking requests, which don't share `Mihoro::http_client()` or its
+/// `download_proxy` and `download_ip_version` settings.
+fn download_asset(
+    url: &str,
+    dest: impl Write,
+    show_progress: bool,
+    github_token: Option<&str>,
+) -> Result<()> {
+    let mut headers = github_auth_headers(url, github_token);
+    headers.insert(ACCEPT, HeaderValue::from_static("application/octet-stream"));
+    let mut download = Download::from_url(url);
+    download.set_headers(headers).show_progress(show_progress);
+    download.download_to(dest)?;
+    Ok(())
+}
+
+/// Verify `archive` against the `<asset>.sha256` checksum published with the release, if any.
+fn verify_checksum(
+    release: &Release,
+    asset: &ReleaseAsset,
+    archive: &Path,
+    github_token: Option<&str>,
+) -> Result<()> {
+    let checksum_name = format!("{}.sha256", asset.name);
+    let Some(checksum) = release.assets.iter().find(|a| a.name == checksum_name) else {
+        info!(
+            "{} No checksum published for {}, skipping checksum verification",
+            PREFIX.yellow(),
+            asset.name
+        );
+        return Ok(());
+    };
+
+    let mut body = vec![];
+    download_asset(&checksum.download_url, &mut body, false, github_token)
+        .with_context(|| format!("failed to download checksum `{}`", checksum_name))?;
+    verify_sha256(archive, &parse_sha256(&String::from_utf8_lossy(&body))?)?;
+    info!("{} Verified SHA256 checksum", PREFIX.green());
+    Ok(())
+}
+
+/// Verify the `zipsign` signature embedded in `archive` against the release public key.
+fn verify_signature(archive: &Path, asset_name: &str) -> Result<()> {
+    let Some(public_key) = RELEASE_PUBLIC_KEY else {
+        bail!("this mihoro build has no release public key embedded, cannot verify signatures");
+    };
+    let key: [u8; PUBLIC_KEY_LENGTH] = BASE64_STANDARD
+        .decode(public_key.trim())?
+        .try_into()
+        .map_err(|_| anyhow!("invalid embedded release public key"))?;
+    let keys = collect_keys([Ok(key)]).map_err(|e| anyhow!("invalid release public key: {}", e))?;
+
+    let mut file = File::open(archive)?;
+    verify_tar(&mut file, &keys, Some(asset_name.as_bytes()))
+        .map_err(|e| anyhow!("signature verification of `{}` failed: {}", asset_name, e))?;
+    info!("{} Verified release signature", PREFIX.green());
+    Ok(())
+}
+
+/// Fetch mihoro releases, newest first.
+///
+/// `github_token` raises the API rate limit, it is only sent to GitHub itself, not to a mirror.
+fn fetch_releases(mirror: Option<&str>, github_token: Option<&str>) -> Result<Vec<Release>> {
+    let mut builder = ReleaseList::configure();
+    builder.repo_owner("spencerwooo").repo_name("mihoro");
+    // Route GitHub API requests through mirror if provided
+    if mirror.is_some() {
+        builder.with_url(&mirror_github_url(GITHUB_API_URL, mirror));
+    } else if let Some(token) = github_token {
+        builder.auth_token(token);
+    }
+    builder.build()?.fetch().map_err(|e| {
+        // Unauthenticated GitHub API requests are limited to 60 an hour. self_update only reports
+        // the status, so the reset time from the `X-RateLimit-Reset` header is unknown here.
+        if e.to_string().contains("403") || e.to_string().contains("rate limit") {
+            anyhow!(
+                "GitHub API rate limit exceeded, try again later, set `github_token` or \
+                 GITHUB_TOKEN to raise the limit, or set `github_mirror`"
+            )
+        } else {
+            e.into()
         }
+    })
+}
+
+/// Find the release of `version`, with or without a leading `v`.
+fn find_release<'a>(releases: &'a [Release], version: &str) -> Option<&'a Release> {
+    let version = version.trim_start_matches('v');
+    releases.iter().find(|release| release.version == version)
+}
+
+/// Download, verify and install the latest release, or the release of version `to`, returning
+/// the installed version, or `None` if already running it.
+///
+/// Installing an older version than the running one is allowed with `to`. The running binary is
+/// only replaced once the archive passed the checksum published with the release, and the
+/// signature if `check_signature`. A dry run only looks up the release, returning the version it
+/// would install.
+#[allow(clippy::too_many_arguments)]
+fn install_release(
+    no_confirm: bool,
+    target: Option<String>,
+    mirror: Option<String>,
+    show_progress: bool,
+    check_signature: bool,
+    to: Option<String>,
+    github_token: Option<String>,
+    dry_run: bool,
+) -> Result<Option<String>> {
+    let current = cargo_crate_version!();
+    let github_token = github_token.as_deref();
+    let releases = fetch_releases(mirror.as_deref(), github_token)?;
+    let release = match to.as_deref() {
+        Some(to) => find_release(&releases, to)
+            .ok_or_else(|| anyhow!("mihoro release `{}` not found", to))?,
+        None => match releases.first() {
+            Some(latest) if bump_is_greater(current, &latest.version)? => latest,
+            _ => return Ok(None),
+        },
+    };
+    if release.version == current {
+        return Ok(None);
+    }
+    if bump_is_greater(&release.version, current)? {
+        info!(
+            "{} Downgrading mihoro from v{} to v{}, newer config fields may not be understood",
+            "warning:".yellow(),
+            current,
+            release.version
+        );
+    }
+
+    let target = target.as_deref().unwrap_or(get_target());
+    let asset = release
+        .asset_for(target, None)
+        .ok_or_else(|| anyhow!("no mihoro {} release found for {}", release.version, target))?;
+    info!(
+        "{} Installing v{} --> v{} ({})",
+        PREFIX.cyan(),
+        current,
+        release.version,
+        asset.name
+    );
+    if dry_run {
+        println!(
+            "{} would: download {} and replace {}",
+            PREFIX.cyan(),
+            asset.name,
+            env::current_exe()?.to_string_lossy()
+        );
+        return Ok(Some(release.version.clone()));
+    }
+    if !no_confirm && !confirm("Download and replace the running mihoro binary?", true)? {
+        bail!("upgrade cancelled");
+    }
+
+    let temp_dir = tempfile::tempdir()?;
+    let archive = temp_dir.path().join(&asset.name);
+    let download_url = mirror_github_url(&asset.download_url, mirror.as_deref());
+    download_asset(
+        &download_url,
+        File::create(&archive)?,
+        show_progress,
+        github_token,
+    )?;
 
-        builder.build()?.update()
+    // Verify before extracting, the running binary is untouched if anything fails
+    verify_checksum(release, &asset, &archive, github_token)?;
+    if check_signature {
+        verify_signature(&archive, &asset.name)?;
+    }
+
+    Extract::from_source(&archive).extract_file(temp_dir.path(), BIN_NAME)?;
+    self_replace::self_replace(temp_dir.path().join(BIN_NAME))?;
+    Ok(Some(release.version.clone()))
+}
+
+/// Perform the upgrade to the latest version, or to version `to`
+#[allow(clippy::too_many_arguments)]
+pub async fn run_upgrade(
+    no_confirm: bool,
+    target: Option<String>,
+    mirror: Option<String>,
+    show_progress: bool,
+    check_signature: bool,
+    to: Option<String>,
+    github_token: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    info!("{} Checking for mihoro updates...", PREFIX.cyan());
+
+    let pinned = to.is_some();
+    let result = tokio::task::spawn_blocking(move || {
+        install_release(
+            no_confirm,
+            target,
+            mirror,
+            show_progress,
+            check_signature,
+            to,
+            github_token,
+            dry_run,
+        )
     })
     .await?;
 
     match result {
-        Ok(status) => {
-            // Add newline to separate from self_update output
-            println!();
-            if status.updated() {
-                println!(
-                    "{} Updated to version {}",
-                    prefix.green().bold(),
-                    status.version().to_string().underline().green()
-                );
-                println!(
-                    "{} Please restart mihoro for the new version to take effect",
-                    prefix.yellow()
-                );
-            } else {
-                println!(
-                    "{} Already running the latest version ({})",
-                    prefix.green(),
-                    status.version().to_string().bold()
-                );
-            }
+        Ok(Some(_)) if dry_run => {}
+        Ok(Some(version)) => {
+            info!(
+                "{} Updated to version {}",
+                PREFIX.green().bold(),
+                version.underline().green()
+            );
+            info!(
+                "{} Please restart mihoro for the new version to take effect",
+                PREFIX.yellow()
+            );
         }
-        Err(e) if e.to_string().contains("permission") => {
-            anyhow::bail!(
-                "Permission denied. Ensure you have write access to the mihoro binary location."
+        Ok(None) => {
+            info!(
+                "{} Already running {} ({})",
+                PREFIX.green(),
+                if pinned {
+                    "this version"
+                } else {
+                    "the latest version"
+                },
+                cargo_crate_version!().bold()
             );
         }
-        Err(e) if e.to_string().contains("network") || e.to_string().contains("connection") => {
-            anyhow::bail!("Network error. Please check your internet connection and try again.");
+        Err(e) if error::kind(&e) == Some(ErrorKind::Permission) => {
+            return Err(e.context(MihoroError::Permission(
+                "cannot replace the mihoro binary, run `mihoro where` to see where it is \
+                 installed and how to upgrade it"
+                    .to_string(),
+            )));
+        }
+        // self_update reports failed requests without the reqwest error behind them
+        Err(e)
+            if error::kind(&e) == Some(ErrorKind::Network)
+                || matches!(
+                    e.downcast_ref::<self_update::errors::Error>(),
+                    Some(self_update::errors::Error::Network(_))
+                ) =>
+        {
+            return Err(e.context(MihoroError::Network(
+                "failed to reach GitHub to upgrade mihoro".to_string(),
+            )));
         }
-        Err(e) => return Err(e.into()),
+        Err(e) => return Err(e),
     }
 
     Ok(())
 }
 
-/// Check if a new version is available without installing
-pub async fn check_for_update() -> Result<Option<String>> {
-    let prefix = "mihoro:";
+/// Lines of release notes shown by `mihoro upgrade --check`.
+const RELEASE_NOTES_LINES: usize = 15;
 
-    println!("{} Checking for available updates...", prefix.cyan());
+/// A newer mihoro release found by `check_for_update`.
+pub struct AvailableUpdate {
+    pub version: String,
+    /// Release notes from the GitHub release body, if any.
+    pub notes: Option<String>,
+}
 
-    let result = tokio::task::spawn_blocking(move || {
-        let releases = self_update::backends::github::ReleaseList::configure()
-            .repo_owner("spencerwooo")
-            .repo_name("mihoro")
-            .build()?
-            .fetch()?;
-
-        if let Some(latest) = releases.first() {
-            let current = cargo_crate_version!();
-            if latest.version != current {
-                return Ok(Some(latest.version.clone()));
-            }
+impl AvailableUpdate {
+    /// Url of the release on GitHub.
+    pub fn url(&self) -> String {
+        format!(
+            "https://github.com/spencerwooo/mihoro/releases/tag/v{}",
+            self.version
+        )
+    }
+}
+
+/// Summarize release notes to their first `max_lines` non-empty lines, noting if any were cut.
+pub fn summarize_notes(notes: &str, max_lines: usize) -> String {
+    let lines: Vec<&str> = notes
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let mut summary = lines
+        .iter()
+        .take(max_lines)
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n");
+    if lines.len() > max_lines {
+        summary.push_str(&format!("\n... ({} more lines)", lines.len() - max_lines));
+    }
+    summary
+}
+
+/// Check if a new version is available without installing, with its release notes.
+pub async fn check_for_update(
+    mirror: Option<String>,
+    github_token: Option<String>,
+) -> Result<Option<AvailableUpdate>> {
+    tokio::task::spawn_blocking(move || {
+        let releases = fetch_releases(mirror.as_deref(), github_token.as_deref())?;
+        Ok(releases
+            .first()
+            .filter(|latest| latest.version != cargo_crate_version!())
+            .map(|latest| AvailableUpdate {
+                version: latest.version.clone(),
+                notes: latest.body.clone().filter(|notes| !notes.trim().is_empty()),
+            }))
+    })
+    .await?
+}
+
+/// Print an available update with a summary of its release notes.
+pub fn print_available_update(update: &AvailableUpdate) {
+    println!(
+        "{} New version available: {}",
+        PREFIX.yellow(),
+        update.version.bold().green()
+    );
+    if let Some(notes) = &update.notes {
+        println!();
+        for line in summarize_notes(notes, RELEASE_NOTES_LINES).lines() {
+            println!("  {}", line);
         }
-        Ok(None)
+        println!();
+        println!(
+            "{} Full release notes: {}",
+            "->".dimmed(),
+            update.url().underline()
+        );
+    }
+    println!(
+        "{} Run {} to update",
+        "->".dimmed(),
+        "mihoro upgrade".bold().underline()
+    );
+}
+
+/// Check if `version` was released with a build for `target` (this machine's by default),
+/// without installing it.
+pub async fn check_version_available(
+    mirror: Option<String>,
+    version: String,
+    target: Option<String>,
+    github_token: Option<String>,
+) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let releases = fetch_releases(mirror.as_deref(), github_token.as_deref())?;
+        let target = target.as_deref().unwrap_or(get_target());
+        Ok(find_release(&releases, &version)
+            .is_some_and(|release| release.asset_for(target, None).is_some()))
     })
-    .await?;
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    result
+    #[test]
+    fn test_summarize_notes() {
+        let notes = "## What's Changed\r\n\r\n* Add foo\r\n* Fix bar\r\n\r\n**Full Changelog**: x";
+        assert_eq!(
+            summarize_notes(notes, 10),
+            "## What's Changed\n* Add foo\n* Fix bar\n**Full Changelog**: x"
+        );
+        assert_eq!(
+            summarize_notes(notes, 2),
+            "## What's Changed\n* Add foo\n... (2 more lines)"
+        );
+    }
 }
@@ -1,19 +1,100 @@
 use std::{
     cmp::min,
+    env,
+    ffi::OsStr,
     fs::{self, File},
-    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
-    path::Path,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    os::unix::fs::{chown, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use base64::{prelude::BASE64_STANDARD, Engine};
 use colored::Colorize;
 use flate2::read::GzDecoder;
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::Client;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::{header::HeaderMap, Certificate, Client, Proxy, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempPath};
 use truncatable::Truncatable;
 
+use crate::config::Config;
+use crate::error::MihoroError;
+use crate::log::{debug, info, trace};
+
+/// Expands `~` to `$HOME`, and `$VAR` or `${VAR}` to environment variables in a path.
+///
+/// References to unset variables are kept as is.
+pub fn expand_path(path: &str) -> PathBuf {
+    expand_path_with(path, |var| env::var(var).ok())
+}
+
+fn expand_path_with(path: &str, get_env: impl Fn(&str) -> Option<String>) -> PathBuf {
+    let expanded = shellexpand::full_with_context_no_errors(path, || get_env("HOME"), &get_env);
+    PathBuf::from(expanded.as_ref())
+}
+
+/// `$XDG_<name>_HOME` if set, ignoring relative paths as the XDG base directory spec requires.
+fn xdg_home(get_env: &impl Fn(&str) -> Option<String>, name: &str) -> Option<String> {
+    get_env(&format!("XDG_{}_HOME", name)).filter(|dir| Path::new(dir).is_absolute())
+}
+
+/// `$XDG_<name>_HOME`, or `fallback` under the home directory if unset.
+pub fn xdg_dir(
+    get_env: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    fallback: &str,
+) -> Result<PathBuf> {
+    if let Some(dir) = xdg_home(get_env, name) {
+        return Ok(PathBuf::from(dir));
+    }
+    let home = get_env("HOME").ok_or_else(|| anyhow!("failed to locate home directory"))?;
+    Ok(Path::new(&home).join(fallback))
+}
+
+/// Whether files can be created in `dir`, as replacing a binary writes a new file next to it.
+///
+/// This tries it rather than checking permission bits, which misses e.g. read-only mounts.
+pub fn dir_writable(dir: &Path) -> bool {
+    tempfile::Builder::new()
+        .prefix(".mihoro-write-test")
+        .tempfile_in(dir)
+        .is_ok()
+}
+
+/// Whether `dir` is one of the directories in `path_var`, the value of `$PATH`.
+pub fn on_path(dir: &Path, path_var: Option<&OsStr>) -> bool {
+    let canonical = dir.canonicalize().ok();
+    path_var.is_some_and(|paths| {
+        env::split_paths(paths).any(|entry| {
+            entry == dir || (canonical.is_some() && entry.canonicalize().ok() == canonical)
+        })
+    })
+}
+
+/// Default path `rest` under `$XDG_<name>_HOME`, or under `~/<fallback>` if unset.
+///
+/// The home directory is kept as `~`, so defaults read as e.g. `~/.config/mihoro.toml` in help
+/// output and are expanded with `expand_path` when used.
+pub fn xdg_default_path(name: &str, fallback: &str, rest: &str) -> String {
+    xdg_default_path_with(|var| env::var(var).ok(), name, fallback, rest)
+}
+
+fn xdg_default_path_with(
+    get_env: impl Fn(&str) -> Option<String>,
+    name: &str,
+    fallback: &str,
+    rest: &str,
+) -> String {
+    match xdg_home(&get_env, name) {
+        Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), rest),
+        None => format!("~/{}/{}", fallback, rest),
+    }
+}
+
 /// Creates the parent directory for a given path if it does not exist.
 ///
 /// # Arguments
@@ -30,38 +111,314 @@ pub fn create_parent_dir(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Base delay between download retries, doubled on each subsequent attempt.
+pub const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Current time as a Unix timestamp in seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Compute the exponential backoff delay before retrying after the given (1-indexed) attempt.
+///
+/// The delay is `base * 2^(attempt - 1)` plus up to 50% random jitter.
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let backoff = base * 2u32.saturating_pow(attempt.saturating_sub(1));
+    let jitter_range = backoff.as_millis() as u64 / 2 + 1;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    backoff + Duration::from_millis(nanos % jitter_range)
+}
+
+/// Send a GET request to url, retrying with exponential backoff on transient failures.
+///
+/// Each attempt, including reading the response body, must finish within `timeout`. Connection
+/// errors, timeouts, and 5xx responses are retried up to `max_attempts` attempts in total. Other
+/// errors, including 4xx responses, fail immediately.
+///
+/// `headers` are sent along, e.g. `If-None-Match` for a conditional request.
+#[allow(clippy::too_many_arguments)]
+pub async fn download_with_retry(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    max_attempts: u32,
+    base_delay: Duration,
+    timeout: Duration,
+    prefix: &str,
+) -> Result<Response> {
+    let max_attempts = max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        debug!("GET {}", url);
+        let reason = match client
+            .get(url)
+            .headers(headers.clone())
+            .timeout(timeout)
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_server_error() => format!("server error {}", res.status()),
+            Ok(res) => {
+                debug!("GET {} returned {}", url, res.status());
+                trace!("response headers: {:?}", res.headers());
+                if let Some(message) =
+                    github_rate_limit_error(res.status(), res.headers(), unix_now())
+                {
+                    bail!(message);
+                }
+                res.error_for_status_ref()?;
+                return Ok(res);
+            }
+            Err(e) if e.is_timeout() => timed_out_reason(timeout),
+            Err(e) if e.is_connect() || e.is_request() => e.to_string(),
+            Err(e) => return Err(e).with_context(|| format!("failed to GET from '{}'", url)),
+        };
+
+        if attempt >= max_attempts {
+            return Err(MihoroError::Network(format!(
+                "failed to GET from '{}' after {} attempt(s): {}",
+                url, max_attempts, reason
+            ))
+            .into());
+        }
+
+        let delay = retry_delay(base_delay, attempt);
+        info!(
+            "{} Request failed ({}), retrying in {:.1}s ({}/{})",
+            prefix.yellow(),
+            reason,
+            delay.as_secs_f32(),
+            attempt,
+            max_attempts - 1
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Explain a response rejected by GitHub's rate limit, which GitHub answers with 403 or 429 and
+/// `X-RateLimit-Remaining: 0`, including when the limit resets given the current Unix time `now`.
+pub fn github_rate_limit_error(
+    status: StatusCode,
+    headers: &HeaderMap,
+    now: u64,
+) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let limited = matches!(
+        status,
+        StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+    ) && header("x-ratelimit-remaining") == Some("0");
+    if !limited {
+        return None;
+    }
+
+    let resets = header("x-ratelimit-reset")
+        .and_then(|reset| reset.parse::<u64>().ok())
+        .map(|reset| {
+            format!(
+                ", resets in {} minute(s)",
+                reset.saturating_sub(now).div_ceil(60)
+            )
+        })
+        .unwrap_or_default();
+    Some(format!(
+        "GitHub rate limit exceeded{}, set `github_token` or GITHUB_TOKEN to raise the limit",
+        resets
+    ))
+}
+
+/// Environment variables reqwest reads the proxy for HTTPS requests from, in order of precedence.
+const HTTPS_PROXY_ENV_VARS: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"];
+
+/// Find the proxy that reqwest picks up from the environment for HTTPS requests, as `(var, url)`.
+///
+/// Downloads are all over HTTPS, so `HTTP_PROXY` is not considered.
+pub fn env_https_proxy(get_env: impl Fn(&str) -> Option<String>) -> Option<(&'static str, String)> {
+    HTTPS_PROXY_ENV_VARS.iter().find_map(|var| {
+        get_env(var)
+            .filter(|url| !url.is_empty())
+            .map(|url| (*var, url))
+    })
+}
+
+/// Read the root certificates of the PEM bundle at `path`, for `tls_ca_cert`.
+fn load_ca_certs(path: &str) -> Result<Vec<Certificate>> {
+    let pem = fs::read(expand_path(path))
+        .with_context(|| format!("failed to read `tls_ca_cert` `{}`", path))?;
+    let certs = Certificate::from_pem_bundle(&pem)
+        .with_context(|| format!("invalid `tls_ca_cert` `{}`", path))?;
+    if certs.is_empty() {
+        bail!("no PEM certificates in `tls_ca_cert` `{}`", path);
+    }
+    Ok(certs)
+}
+
+/// Build the HTTP client for mihoro's own requests, with the user agent, connect timeout, proxy,
+/// IP version and TLS settings from `config`, or connecting directly if `no_proxy`.
+///
+/// The client pools connections, so it should be built once and shared to reuse them.
+pub fn build_client(config: &Config, no_proxy: bool) -> Result<Client> {
+    // Per-request timeouts are set on each request, as downloads need longer than lookups
+    let mut builder = Client::builder()
+        .user_agent(config.user_agent())
+        .connect_timeout(config.network_timeout());
+    if let Some(address) = config.download_ip_version.local_address() {
+        debug!("binding downloads to {}", address);
+        builder = builder.local_address(address);
+    }
+    if no_proxy {
+        builder = builder.no_proxy();
+    } else if let Some(proxy) = config.download_proxy() {
+        builder = builder.proxy(
+            Proxy::all(proxy).with_context(|| format!("invalid `download_proxy` `{}`", proxy))?,
+        );
+    }
+    if let Some(path) = &config.tls_ca_cert {
+        for cert in load_ca_certs(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+        debug!("trusting root certificates from {}", path);
+    }
+    if config.tls_danger_accept_invalid_certs {
+        eprintln!(
+            "{} `tls_danger_accept_invalid_certs` is set, TLS certificates are not verified and \
+             downloads can be read or replaced by anyone on the network path",
+            "warning:".yellow().bold()
+        );
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.build()?)
+}
+
+/// Answer one HTTP request on localhost with `body`, returning the url served and a handle
+/// resolving to the request head, e.g. to check the headers mihoro sends.
+#[cfg(test)]
+pub async fn serve_once(body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buf).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            head.extend_from_slice(&buf[..read]);
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&head).into_owned()
+    });
+    (url, handle)
+}
+
+/// Compress `contents` with gzip.
+#[cfg(test)]
+pub fn gzip(contents: &[u8]) -> Vec<u8> {
+    use flate2::{write::GzEncoder, Compression};
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Build a gzipped tarball of `(path, contents)` files.
+#[cfg(test)]
+pub fn tar_gz(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *contents).unwrap();
+    }
+    gzip(&builder.into_inner().unwrap())
+}
+
+/// Value of the `User-Agent` header in a request head received by `serve_once`.
+#[cfg(test)]
+pub fn received_user_agent(head: &str) -> Option<&str> {
+    head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("user-agent")
+            .then(|| value.trim())
+    })
+}
+
+/// Describe a timed out request, e.g. `operation timed out after 30s`.
+pub fn timed_out_reason(timeout: Duration) -> String {
+    format!("operation timed out after {}s", timeout.as_secs())
+}
+
 /// Download file from url to path with a reusable http client.
 ///
 /// Renders a progress bar if content-length is available from the url headers provided. If not,
-/// renders a spinner to indicate that something is downloading.
+/// renders a spinner to indicate that something is downloading. Bars are added to `progress`, so
+/// concurrent downloads render below each other instead of interleaving, and hidden entirely if
+/// it is `None` (e.g. in JSON output mode or when stdout is not a terminal).
 ///
 /// With reference from:
 /// * https://github.com/mihaigalos/tutorials/blob/800d5acbc333fd4068622e9b3d870cb5b7d34e12/rust/download_with_progressbar/src/main.rs
 /// * https://github.com/console-rs/indicatif/blob/2954b1a24ac5f1900a7861992e4825bff643c9e2/examples/yarnish.rs
 ///
+/// Transient request failures are retried up to `max_attempts` times, see `download_with_retry`.
+/// Returns the response headers for callers interested in metadata sent alongside the file, or
+/// `None` if the server answered a conditional request in `headers` with 304 Not Modified, in
+/// which case `path` is left as is.
+///
 /// Note: Allow `clippy::unused_io_amount` because we are writing downloaded chunks on the fly.
-#[allow(clippy::unused_io_amount)]
+#[allow(clippy::unused_io_amount, clippy::too_many_arguments)]
 pub async fn download_file(
     client: &Client,
     url: &str,
     path: &Path,
-    user_agent: &str,
-) -> Result<()> {
+    headers: &HeaderMap,
+    max_attempts: u32,
+    timeout: Duration,
+    prefix: &str,
+    progress: Option<&MultiProgress>,
+) -> Result<Option<HeaderMap>> {
     // Create parent directory for download destination if not exists
     create_parent_dir(path)?;
+    debug!("downloading {} to {}", url, path.to_string_lossy());
 
     // Create shared http client for multiple downloads when possible
-    let res = client
-        .get(url)
-        .header("User-Agent", user_agent)
-        .send()
-        .await
-        .with_context(|| format!("failed to GET from '{}'", &url))?;
-    res.error_for_status_ref()?;
+    let res = download_with_retry(
+        client,
+        url,
+        headers,
+        max_attempts,
+        RETRY_BASE_DELAY,
+        timeout,
+        prefix,
+    )
+    .await?;
+    if res.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+
+    let headers = res.headers().clone();
 
     // If content length is not available or 0, use a spinner instead of a progress bar
     let total_size = res.content_length().unwrap_or(0);
-    let pb = ProgressBar::new(total_size);
+    let pb = match progress {
+        Some(progress) => progress.add(ProgressBar::new(total_size)),
+        None => ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden()),
+    };
 
     let bar_style = ProgressStyle::with_template(
         "{prefix:.blue}: {msg}\n          {elapsed_precise} [{bar:30.white/blue}] \
@@ -70,68 +427,241 @@ pub async fn download_file(
     .progress_chars("-  ");
     let spinner_style = ProgressStyle::with_template(
         "{prefix:.blue}: {wide_msg}\n        \
-         {spinner} {elapsed_precise} - Download speed {bytes_per_sec}",
+         {spinner} {elapsed_precise} - Downloaded {bytes} ({bytes_per_sec})",
     )?;
 
     if total_size == 0 {
         pb.set_style(spinner_style);
+        // Keep the spinner moving while waiting on slow chunks
+        pb.enable_steady_tick(Duration::from_millis(120));
     } else {
         pb.set_style(bar_style);
     }
     pb.set_prefix("download");
 
+    // Name the file first, as concurrent downloads from one release differ only in the url's end
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
     let truncated_url = Truncatable::from(url)
         .truncator("...".into())
         .truncate(64)
         .underline();
-    pb.set_message(format!("Downloading {truncated_url}"));
-
-    // Start file download and update progress bar when new data chunk is received
-    let mut file = File::create(path)?;
-    let mut downloaded: u64 = 0;
-    let mut stream = res.bytes_stream();
-
-    while let Some(item) = stream.next().await {
-        let chunk = item.with_context(|| "error while downloading file")?;
-
-        file.write(&chunk)
-            .with_context(|| "error while writing to file")?;
-        if total_size != 0 {
-            let new = min(downloaded + (chunk.len() as u64), total_size);
-            downloaded = new;
-            pb.set_position(new);
-        } else {
-            pb.inc(chunk.len() as u64);
+    pb.set_message(format!(
+        "Downloading {} from {truncated_url}",
+        file_name.bold()
+    ));
+
+    // Start file download and update progress bar when new data chunk is received, into a
+    // temporary file so an interrupted download never replaces `path` with a partial file
+    let result = async {
+        let (mut file, temp_path) = new_temp_file_for(path)?.into_parts();
+        let mut downloaded: u64 = 0;
+        let mut stream = res.bytes_stream();
+
+        while let Some(item) = stream.next().await {
+            let chunk = item.map_err(|e| match e.is_timeout() {
+                true => anyhow!("{} while downloading '{}'", timed_out_reason(timeout), url),
+                false => anyhow!(e).context("error while downloading file"),
+            })?;
+
+            file.write(&chunk)
+                .with_context(|| "error while writing to file")?;
+            if total_size != 0 {
+                let new = min(downloaded + (chunk.len() as u64), total_size);
+                downloaded = new;
+                pb.set_position(new);
+            } else {
+                pb.inc(chunk.len() as u64);
+            }
         }
+
+        file.sync_all()?;
+        drop(file);
+        persist_replacing(temp_path, path, None)
+    }
+    .await;
+    if let Err(e) = result {
+        // Leave the failed bar in place, among those of other downloads still running
+        pb.abandon_with_message(
+            format!("Failed to download {}", file_name)
+                .red()
+                .to_string(),
+        );
+        return Err(e);
     }
 
     pb.finish_with_message(format!(
         "Downloaded to {}",
         path.to_str().unwrap().underline()
     ));
-    Ok(())
+    Ok(Some(headers))
 }
 
-pub fn delete_file(path: &str, prefix: &str) -> Result<()> {
-    // Delete file if exists
-    if Path::new(path).exists() {
-        fs::remove_file(path).map(|_| {
-            println!("{} Removed {}", prefix.cyan(), path.underline().yellow());
-        })?;
+/// Delete the file at `path` if it exists, returning whether it did. A directory is deleted with
+/// everything in it, while a symlink is deleted itself, not what it points to.
+pub fn delete_file(path: &str) -> Result<bool> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(false);
+    };
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
     }
-    Ok(())
+    .with_context(|| format!("failed to remove `{}`", path))?;
+    Ok(true)
+}
+
+/// Archive formats the mihomo binary may be downloaded in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    /// A single gzipped file, as in official mihomo releases.
+    Gzip,
+    TarGz,
+    Zip,
 }
 
-pub fn extract_gzip(from_path: &Path, to_path: &str, prefix: &str) -> Result<()> {
-    // Create parent directory for extraction dest if not exists
-    create_parent_dir(Path::new(to_path))?;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+impl ArchiveFormat {
+    /// Detect the archive format from the suffix of `url`, falling back to the magic bytes at the
+    /// start of the archive for unknown suffixes.
+    pub fn detect(url: &str, bytes: &[u8]) -> Result<ArchiveFormat> {
+        // Ignore query strings and fragments, e.g. of signed download urls
+        let path = url
+            .split(['?', '#'])
+            .next()
+            .unwrap_or(url)
+            .to_ascii_lowercase();
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            return Ok(ArchiveFormat::TarGz);
+        }
+        if path.ends_with(".zip") {
+            return Ok(ArchiveFormat::Zip);
+        }
+        if path.ends_with(".gz") {
+            return Ok(ArchiveFormat::Gzip);
+        }
 
-    // Extract gzip file
-    let mut archive = GzDecoder::new(File::open(from_path)?);
-    let mut file = File::create(to_path)?;
-    io::copy(&mut archive, &mut file)?;
-    // fs::remove_file(gzip_path)?;
-    println!(
+        if bytes.starts_with(ZIP_MAGIC) {
+            return Ok(ArchiveFormat::Zip);
+        }
+        if bytes.starts_with(GZIP_MAGIC) {
+            // Tarballs have the `ustar` magic at offset 257 of their first header
+            let mut header = [0u8; 262];
+            let is_tar = GzDecoder::new(bytes).read_exact(&mut header).is_ok()
+                && &header[257..262] == b"ustar";
+            return Ok(if is_tar {
+                ArchiveFormat::TarGz
+            } else {
+                ArchiveFormat::Gzip
+            });
+        }
+        bail!(
+            "unsupported archive format of `{}`, expected .gz, .tar.gz or .zip",
+            url
+        )
+    }
+}
+
+/// Extract the mihomo binary from an archive in the given format.
+///
+/// For `.tar.gz` and `.zip` archives, the file named `mihomo*` is picked, or the only file if
+/// there is just one.
+pub fn extract_binary(bytes: &[u8], format: ArchiveFormat) -> Result<Vec<u8>> {
+    let files = match format {
+        ArchiveFormat::Gzip => {
+            let mut binary = Vec::new();
+            GzDecoder::new(bytes)
+                .read_to_end(&mut binary)
+                .context("failed to decompress gzip archive")?;
+            return Ok(binary);
+        }
+        ArchiveFormat::TarGz => read_tar_gz(bytes).context("failed to read tar.gz archive")?,
+        ArchiveFormat::Zip => read_zip(bytes).context("failed to read zip archive")?,
+    };
+    pick_binary(files)
+}
+
+/// Pick the mihomo binary among `(path, contents)` of files in an archive.
+fn pick_binary(files: Vec<(String, Vec<u8>)>) -> Result<Vec<u8>> {
+    let file_name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+    if files.len() == 1 {
+        return Ok(files.into_iter().next().unwrap().1);
+    }
+
+    let names: Vec<String> = files.iter().map(|(path, _)| path.clone()).collect();
+    files
+        .into_iter()
+        .find(|(path, _)| file_name(path).starts_with("mihomo"))
+        .map(|(_, contents)| contents)
+        .ok_or_else(|| {
+            anyhow!(
+                "no `mihomo` binary found in archive, which contains: {}",
+                names.join(", ")
+            )
+        })
+}
+
+/// Read the regular files of a gzipped tarball as `(path, contents)`.
+pub fn read_tar_gz(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(bytes));
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.push((path, contents));
+    }
+    Ok(files)
+}
+
+/// Read the regular files of a zip archive as `(path, contents)`, supporting stored and deflated
+/// entries. Their CRC-32 is checked as they are read.
+fn read_zip(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes))?;
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let name = entry.name()?.into_owned();
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .with_context(|| format!("failed to read `{}`", name))?;
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+/// Extract the mihomo binary from the archive at `from_path` downloaded from `url`.
+pub fn read_binary_archive(from_path: &Path, url: &str) -> Result<Vec<u8>> {
+    let bytes = fs::read(from_path)?;
+    extract_binary(&bytes, ArchiveFormat::detect(url, &bytes)?)
+}
+
+/// Extract the mihomo binary from the archive at `from_path` to `to_path`, atomically replacing
+/// it, see `atomic_write_with`.
+pub fn extract_binary_to(
+    from_path: &Path,
+    url: &str,
+    to_path: &str,
+    mode: Option<u32>,
+    prefix: &str,
+) -> Result<()> {
+    let binary = read_binary_archive(from_path, url)?;
+    atomic_write_with(
+        Path::new(to_path),
+        mode,
+        |file| Ok(file.write_all(&binary)?),
+    )?;
+    info!(
         "{} Extracted to {}",
         prefix.green(),
         to_path.underline().yellow()
@@ -139,6 +669,113 @@ pub fn extract_gzip(from_path: &Path, to_path: &str, prefix: &str) -> Result<()>
     Ok(())
 }
 
+/// Atomically replace the file at `path` with `contents`, see `atomic_write_with`.
+pub fn atomic_write(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    atomic_write_with(path, None, |file| Ok(file.write_all(contents.as_ref())?))
+}
+
+/// Atomically replace the file at `path` with contents written by `write`.
+///
+/// Contents are written to a temporary file in the same directory, which is then renamed over
+/// `path`, so a crash or error mid-write never leaves a truncated `path` behind. Symlinks are
+/// followed, replacing the file they point to. See `persist_replacing` for permissions and
+/// ownership of the result.
+pub fn atomic_write_with(
+    path: &Path,
+    mode: Option<u32>,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (mut file, temp_path) = new_temp_file_for(&path)?.into_parts();
+    write(&mut file)?;
+    file.sync_all()?;
+    drop(file);
+    persist_replacing(temp_path, &path, mode)
+}
+
+/// Create a temporary file next to `path`, which can be renamed over it atomically.
+pub fn new_temp_file_for(path: &Path) -> Result<NamedTempFile> {
+    create_parent_dir(path)?;
+    let parent_dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    Ok(NamedTempFile::new_in(parent_dir)?)
+}
+
+/// Rename the temporary file `temp_path` over `path`.
+///
+/// The permissions and ownership of an existing `path` are kept, and new files get the usual
+/// `0o644`. Pass `mode` to set permissions explicitly instead, e.g. `0o755` for binaries. The
+/// temporary file must be on the same filesystem as `path`, see `new_temp_file_for`.
+pub fn persist_replacing(temp_path: TempPath, path: &Path, mode: Option<u32>) -> Result<()> {
+    let existing = fs::metadata(path).ok();
+    let permissions = match (mode, &existing) {
+        (Some(mode), _) => fs::Permissions::from_mode(mode),
+        (None, Some(metadata)) => metadata.permissions(),
+        (None, None) => fs::Permissions::from_mode(0o644),
+    };
+    fs::set_permissions(&temp_path, permissions)?;
+    if let Some(metadata) = &existing {
+        // Changing ownership needs root, in which case `path` may belong to another user
+        let _ = chown(&temp_path, Some(metadata.uid()), Some(metadata.gid()));
+    }
+
+    temp_path
+        .persist(path)
+        .map_err(|e| anyhow!(e.error))
+        .with_context(|| format!("failed to replace `{}`", path.to_string_lossy()))?;
+    Ok(())
+}
+
+/// Run a command to completion and capture its output, killing it if it runs longer than `timeout`.
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let deadline = Instant::now() + timeout;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                timed_out_reason(timeout),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    child.wait_with_output()
+}
+
+/// Hex-encoded SHA256 digest of a file.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Verify the SHA256 digest of a file against an expected hex-encoded digest.
+///
+/// Comparison is case-insensitive. Returns an error describing both digests on mismatch.
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<()> {
+    let actual = sha256_file(path)?;
+
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(MihoroError::Checksum {
+            path: path.to_string_lossy().into_owned(),
+            expected: expected.trim().to_string(),
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
 /// Try and decode a base64 encoded file in place.
 ///
 /// Decodes the base64 encoded content of a file in place and writes the decoded content back to the
@@ -148,39 +785,339 @@ pub fn extract_gzip(from_path: &Path, to_path: &str, prefix: &str) -> Result<()>
 ///
 /// * `filepath` - Path to the file to decode base64 content in place.
 pub fn try_decode_base64_file_inplace(filepath: &str) -> Result<()> {
-    // Open the file for reading and writing
-    let mut file = File::options().read(true).write(true).open(filepath)?;
-    let mut base64_buf = Vec::new();
-
-    // Read the file content into the buffer
-    file.read_to_end(&mut base64_buf)?;
-
-    // Try to decode the base64 content
-    match BASE64_STANDARD.decode(&base64_buf) {
-        Ok(decoded_bytes) => {
-            // Truncate the file and seek to the beginning
-            file.set_len(0)?;
-            file.seek(SeekFrom::Start(0))?;
-
-            // Write the decoded bytes back to the file
-            let mut writer = BufWriter::new(&file);
-            writer.write_all(&decoded_bytes)?;
-        }
-        Err(_) => {
-            // If decoding fails, do nothing and return Ok
-            return Ok(());
-        }
-    }
+    let base64_buf = fs::read(filepath)?;
 
+    // Write the decoded bytes back to the file, if it is base64 encoded
+    if let Some(decoded_bytes) = try_decode_base64(&base64_buf) {
+        atomic_write(Path::new(filepath), decoded_bytes)?;
+    }
     Ok(())
 }
 
+/// Decode `buf` if it is entirely base64 encoded, as some subscriptions are.
+pub fn try_decode_base64(buf: &[u8]) -> Option<Vec<u8>> {
+    BASE64_STANDARD.decode(buf).ok()
+}
+
+/// Ask a yes/no question on the terminal, returning `default` on empty input, or `false` without
+/// a terminal to ask on.
+pub fn confirm(question: &str, default: bool) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    print!(
+        "{} {} ",
+        question,
+        if default { "[Y/n]" } else { "[y/N]" }.dimmed()
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(match answer.trim().to_ascii_lowercase().as_str() {
+        "" => default,
+        answer => answer == "y" || answer == "yes",
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_output_with_timeout() {
+        let output = output_with_timeout(
+            Command::new("sh").args(["-c", "echo hello"]),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hello\n");
+
+        let err = output_with_timeout(
+            Command::new("sh").args(["-c", "sleep 5"]),
+            Duration::from_millis(100),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_dir_writable() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(dir_writable(dir.path()));
+        assert!(!dir_writable(&dir.path().join("missing")));
+        // The probe file is cleaned up
+        assert_eq!(dir.path().read_dir()?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin)?;
+        let path_var = env::join_paths(["/usr/bin", bin.to_str().unwrap()])?;
+        assert!(on_path(&bin, Some(&path_var)));
+        // Entries are compared after resolving `..` and symlinks
+        assert!(on_path(&dir.path().join("bin/../bin"), Some(&path_var)));
+        assert!(!on_path(dir.path(), Some(&path_var)));
+        assert!(!on_path(&bin, None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_client_checks_download_proxy() {
+        let mut config = Config {
+            download_proxy: Some(String::from("not a proxy")),
+            ..Config::default()
+        };
+        let err = build_client(&config, false).unwrap_err();
+        assert_eq!(err.to_string(), "invalid `download_proxy` `not a proxy`");
+        // `--no-proxy` ignores it, and an empty one is unset
+        assert!(build_client(&config, true).is_ok());
+        config.download_proxy = Some(String::new());
+        assert!(build_client(&config, false).is_ok());
+    }
+
+    /// Self-signed certificate in PEM, as a corporate root CA would be.
+    fn self_signed_pem() -> Result<Vec<u8>> {
+        use openssl::asn1::Asn1Time;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", "mihoro test CA")?;
+        let name = name.build();
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok(builder.build().to_pem()?)
+    }
+
+    #[test]
+    fn test_build_client_with_ca_cert() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ca.pem");
+        let mut config = Config {
+            tls_ca_cert: Some(path.to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+
+        let err = build_client(&config, true).unwrap_err();
+        assert!(err.to_string().starts_with("failed to read `tls_ca_cert`"));
+
+        fs::write(&path, "not a certificate\n")?;
+        let err = build_client(&config, true).unwrap_err();
+        assert!(
+            err.to_string().starts_with("no PEM certificates"),
+            "{}",
+            err
+        );
+
+        fs::write(&path, self_signed_pem()?)?;
+        assert!(build_client(&config, true).is_ok());
+
+        config.tls_ca_cert = None;
+        config.tls_danger_accept_invalid_certs = true;
+        assert!(build_client(&config, true).is_ok());
+        Ok(())
+    }
+
+    /// Test that lookups and file downloads send the configured user agent, or the default one
+    #[tokio::test]
+    async fn test_requests_send_user_agent() -> Result<()> {
+        let mut config = Config::default();
+        let client = build_client(&config, true)?;
+        let (url, head) = serve_once("v1.19.0").await;
+        download_with_retry(
+            &client,
+            &url,
+            &HeaderMap::new(),
+            1,
+            Duration::ZERO,
+            Duration::from_secs(5),
+            "mihoro:",
+        )
+        .await?;
+        assert_eq!(
+            received_user_agent(&head.await?),
+            Some(crate::config::DEFAULT_USER_AGENT)
+        );
+
+        config.mihoro_user_agent = String::from("my-agent/1.0");
+        let client = build_client(&config, true)?;
+        let (url, head) = serve_once("geodata").await;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("geoip.dat");
+        download_file(
+            &client,
+            &url,
+            &path,
+            &HeaderMap::new(),
+            1,
+            Duration::from_secs(5),
+            "mihoro:",
+            None,
+        )
+        .await?;
+        assert_eq!(received_user_agent(&head.await?), Some("my-agent/1.0"));
+        assert_eq!(fs::read_to_string(&path)?, "geodata");
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_https_proxy() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        assert_eq!(env_https_proxy(env(&[])), None);
+        assert_eq!(env_https_proxy(env(&[("HTTP_PROXY", "http://a:1")])), None);
+        assert_eq!(
+            env_https_proxy(env(&[
+                ("all_proxy", "http://b:2"),
+                ("https_proxy", "http://a:1")
+            ])),
+            Some(("https_proxy", String::from("http://a:1")))
+        );
+        assert_eq!(
+            env_https_proxy(env(&[("HTTPS_PROXY", ""), ("ALL_PROXY", "http://b:2")])),
+            Some(("ALL_PROXY", String::from("http://b:2")))
+        );
+    }
+
+    #[test]
+    fn test_github_rate_limit_error() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        let limited = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1000600"),
+        ]);
+
+        let message = github_rate_limit_error(StatusCode::FORBIDDEN, &limited, 1000001).unwrap();
+        assert!(message.contains("resets in 10 minute(s)"));
+        assert!(message.contains("github_token"));
+        assert!(github_rate_limit_error(StatusCode::TOO_MANY_REQUESTS, &limited, 0).is_some());
+        let message = github_rate_limit_error(
+            StatusCode::FORBIDDEN,
+            &headers(&[("x-ratelimit-remaining", "0")]),
+            0,
+        )
+        .unwrap();
+        assert!(!message.contains("resets"));
+
+        // Other 403s, e.g. forbidden private repos, are not rate limits
+        let remaining = headers(&[("x-ratelimit-remaining", "42")]);
+        assert_eq!(
+            github_rate_limit_error(StatusCode::FORBIDDEN, &remaining, 0),
+            None
+        );
+        assert_eq!(
+            github_rate_limit_error(StatusCode::FORBIDDEN, &HeaderMap::new(), 0),
+            None
+        );
+        assert_eq!(github_rate_limit_error(StatusCode::OK, &limited, 0), None);
+    }
     use std::fs;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_expand_path() -> Result<()> {
+        let home = tempdir()?;
+        let home_str = home.path().to_str().unwrap().to_string();
+        let config_home = home.path().join("xdg");
+        let config_home_str = config_home.to_str().unwrap().to_string();
+        let get_env = |var: &str| match var {
+            "HOME" => Some(home_str.clone()),
+            "XDG_CONFIG_HOME" => Some(config_home_str.clone()),
+            _ => None,
+        };
+
+        assert_eq!(expand_path_with("~", get_env), home.path());
+        assert_eq!(
+            expand_path_with("~/.config/mihoro.toml", get_env),
+            home.path().join(".config/mihoro.toml")
+        );
+        assert_eq!(
+            expand_path_with("$XDG_CONFIG_HOME/mihoro.toml", get_env),
+            config_home.join("mihoro.toml")
+        );
+        assert_eq!(
+            expand_path_with("${XDG_CONFIG_HOME}/mihomo", get_env),
+            config_home.join("mihomo")
+        );
+        assert_eq!(
+            expand_path_with("$HOME/bin/mihomo", get_env),
+            home.path().join("bin/mihomo")
+        );
+
+        // Absolute paths, unset variables and `~` not at the start are kept as is
+        assert_eq!(
+            expand_path_with("/usr/local/bin/mihomo", get_env),
+            Path::new("/usr/local/bin/mihomo")
+        );
+        assert_eq!(
+            expand_path_with("$UNSET_VAR/mihomo", get_env),
+            Path::new("$UNSET_VAR/mihomo")
+        );
+        assert_eq!(expand_path_with("/a/~b", get_env), Path::new("/a/~b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_xdg_default_path() {
+        let unset = |_: &str| None;
+        assert_eq!(
+            xdg_default_path_with(unset, "CONFIG", ".config", "mihoro.toml"),
+            "~/.config/mihoro.toml"
+        );
+        assert_eq!(
+            xdg_default_path_with(unset, "BIN", ".local/bin", "mihomo"),
+            "~/.local/bin/mihomo"
+        );
+
+        let xdg = |var: &str| match var {
+            "XDG_CONFIG_HOME" => Some("/xdg/config/".to_string()),
+            "XDG_BIN_HOME" => Some("/xdg/bin".to_string()),
+            "XDG_DATA_HOME" => Some("relative/data".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            xdg_default_path_with(xdg, "CONFIG", ".config", "mihoro.toml"),
+            "/xdg/config/mihoro.toml"
+        );
+        assert_eq!(
+            xdg_default_path_with(xdg, "BIN", ".local/bin", "mihomo"),
+            "/xdg/bin/mihomo"
+        );
+        // Relative paths are invalid per the spec and ignored
+        assert_eq!(
+            xdg_default_path_with(xdg, "DATA", ".local/share", "mihoro"),
+            "~/.local/share/mihoro"
+        );
+    }
+
     #[test]
     fn test_create_parent_dir_creates_directories() -> Result<()> {
         let dir = tempdir()?;
@@ -193,13 +1130,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_retry_delay_backs_off_exponentially() {
+        let base = Duration::from_millis(100);
+        for (attempt, expected) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = retry_delay(base, attempt).as_millis() as u64;
+            assert!(delay >= expected && delay <= expected + expected / 2);
+        }
+    }
+
     #[test]
     fn test_delete_file_removes_existing_file() -> Result<()> {
         let dir = tempdir()?;
         let file_path = dir.path().join("test.txt");
         fs::write(&file_path, "test content")?;
 
-        delete_file(file_path.to_str().unwrap(), "prefix")?;
+        assert!(delete_file(file_path.to_str().unwrap())?);
 
         assert!(!file_path.exists());
         Ok(())
@@ -211,33 +1157,217 @@ mod tests {
         let file_path = dir.path().join("nonexistent.txt");
 
         // Should not error on non-existent file
-        delete_file(file_path.to_str().unwrap(), "prefix")?;
+        assert!(!delete_file(file_path.to_str().unwrap())?);
         assert!(!file_path.exists());
 
         Ok(())
     }
 
     #[test]
-    fn test_extract_gzip() -> Result<()> {
+    fn test_delete_file_removes_directory() -> Result<()> {
+        let dir = tempdir()?;
+        let cache_dir = dir.path().join("mihoro");
+        fs::create_dir_all(cache_dir.join("nested"))?;
+        fs::write(cache_dir.join("nested/version.json"), "{}")?;
+
+        assert!(delete_file(cache_dir.to_str().unwrap())?);
+        assert!(!cache_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_write_interrupted_keeps_target() -> Result<()> {
         let dir = tempdir()?;
-        let gzip_path = dir.path().join("test.gz");
-        let output_path = dir.path().join("output.txt");
+        let path = dir.path().join("config.yaml");
+        fs::write(&path, "port: 7890")?;
 
-        // Create a simple gzip file
-        use flate2::write::GzEncoder;
-        use flate2::Compression;
-        use std::io::Write;
+        // Fail halfway through writing the new contents
+        let result = atomic_write_with(&path, None, |file| {
+            file.write_all(b"port: 78")?;
+            bail!("interrupted")
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path)?, "port: 7890");
+
+        // No temporary files are left behind either
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_write_permissions() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro.toml");
 
-        let gzip_file = fs::File::create(&gzip_path)?;
-        let mut encoder = GzEncoder::new(gzip_file, Compression::default());
-        encoder.write_all(b"test content")?;
-        encoder.finish()?;
+        atomic_write(&path, "new")?;
+        assert_eq!(fs::read_to_string(&path)?, "new");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o644);
 
-        extract_gzip(&gzip_path, output_path.to_str().unwrap(), "prefix")?;
+        // Permissions of an existing file are kept
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        atomic_write(&path, "replaced")?;
+        assert_eq!(fs::read_to_string(&path)?, "replaced");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        // Symlinks are followed instead of replaced
+        let link = dir.path().join("link.toml");
+        std::os::unix::fs::symlink(&path, &link)?;
+        atomic_write(&link, "via link")?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&path)?, "via link");
+        Ok(())
+    }
+
+    /// Build a zip archive, deflating entries if `deflate` is set and storing them otherwise.
+    fn zip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+        use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+        let method = match deflate {
+            true => CompressionMethod::Deflated,
+            false => CompressionMethod::Stored,
+        };
+        let options = SimpleFileOptions::default().compression_method(method);
+        let mut writer = ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_archive_format_detect() -> Result<()> {
+        let detect = ArchiveFormat::detect;
+        let tarball = tar_gz(&[("mihomo", b"binary")]);
+
+        assert_eq!(
+            detect("https://a/mihomo-linux-amd64.gz", &[])?,
+            ArchiveFormat::Gzip
+        );
+        assert_eq!(
+            detect("https://a/mihomo.tar.gz?token=1", &[])?,
+            ArchiveFormat::TarGz
+        );
+        assert_eq!(detect("https://a/mihomo.TGZ", &[])?, ArchiveFormat::TarGz);
+        assert_eq!(detect("https://a/mihomo.zip", &[])?, ArchiveFormat::Zip);
+
+        // Unknown suffixes fall back to magic bytes
+        assert_eq!(
+            detect("https://a/download", &gzip(b"binary"))?,
+            ArchiveFormat::Gzip
+        );
+        assert_eq!(
+            detect("https://a/download", &tarball)?,
+            ArchiveFormat::TarGz
+        );
+        assert_eq!(
+            detect("https://a/download", &zip(&[("mihomo", b"binary")], false))?,
+            ArchiveFormat::Zip
+        );
+        assert!(detect("https://a/download", b"\x7fELF").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_binary() -> Result<()> {
+        let files: &[(&str, &[u8])] = &[
+            ("README.md", b"readme"),
+            ("mihomo-linux-amd64/mihomo", b"binary"),
+            ("LICENSE", b"license"),
+        ];
+
+        assert_eq!(
+            extract_binary(&gzip(b"binary"), ArchiveFormat::Gzip)?,
+            b"binary"
+        );
+        assert_eq!(
+            extract_binary(&tar_gz(files), ArchiveFormat::TarGz)?,
+            b"binary"
+        );
+        assert_eq!(
+            extract_binary(&zip(files, false), ArchiveFormat::Zip)?,
+            b"binary"
+        );
+        assert_eq!(
+            extract_binary(&zip(files, true), ArchiveFormat::Zip)?,
+            b"binary"
+        );
+
+        // A lone file is taken whatever its name
+        let single: &[(&str, &[u8])] = &[("clash", b"binary")];
+        assert_eq!(
+            extract_binary(&zip(single, true), ArchiveFormat::Zip)?,
+            b"binary"
+        );
+
+        let no_binary: &[(&str, &[u8])] = &[("README.md", b"readme"), ("LICENSE", b"license")];
+        let err = extract_binary(&tar_gz(no_binary), ArchiveFormat::TarGz).unwrap_err();
+        assert!(err.to_string().contains("README.md, LICENSE"));
+
+        assert!(extract_binary(b"not an archive", ArchiveFormat::Zip).is_err());
+
+        // Corrupted contents fail the CRC-32 check
+        let mut corrupted = zip(single, false);
+        let at = corrupted.windows(6).position(|w| w == b"binary").unwrap();
+        corrupted[at] = b'B';
+        assert!(extract_binary(&corrupted, ArchiveFormat::Zip).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_binary_to() -> Result<()> {
+        let dir = tempdir()?;
+        let archive_path = dir.path().join("download");
+        let output_path = dir.path().join("mihomo");
+        fs::write(&archive_path, gzip(b"test content"))?;
+
+        extract_binary_to(
+            &archive_path,
+            "https://example.com/mihomo.gz",
+            output_path.to_str().unwrap(),
+            Some(0o755),
+            "prefix",
+        )?;
 
         let content = fs::read_to_string(&output_path)?;
         assert_eq!(content, "test content");
+        assert_eq!(
+            fs::metadata(&output_path)?.permissions().mode() & 0o777,
+            0o755
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_sha256_matches() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("test.txt");
+        fs::write(&file_path, "test content")?;
+
+        // sha256("test content")
+        verify_sha256(
+            &file_path,
+            "6AE8A75555209FD6C44157C0AED8016E763FF435A19CF186F76863140143FF72",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_sha256_mismatch() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("test.txt");
+        fs::write(&file_path, "tampered content")?;
 
+        let result = verify_sha256(
+            &file_path,
+            "6ae8a75555209fd6c44157c0aed8016e763ff435a19cf186f76863140143ff72",
+        );
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("checksum mismatch"));
         Ok(())
     }
 
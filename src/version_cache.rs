@@ -0,0 +1,115 @@
+use crate::config::MihomoChannel;
+use crate::utils::{atomic_write, create_parent_dir, xdg_dir};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// A latest mihomo version fetched from GitHub, and when, as a Unix timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedVersion {
+    pub version: String,
+    pub fetched_at: u64,
+}
+
+/// Latest mihomo versions per release channel, cached so repeated `setup` and `update --core`
+/// runs within `version_cache_ttl_secs` skip the GitHub version lookup.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VersionCache {
+    #[serde(default)]
+    channels: BTreeMap<String, CachedVersion>,
+}
+
+impl VersionCache {
+    /// `$XDG_CACHE_HOME/mihoro/version.json`, or `~/.cache/mihoro/version.json`.
+    pub fn path() -> Result<PathBuf> {
+        Ok(xdg_dir(&|var| env::var(var).ok(), "CACHE", ".cache")?.join("mihoro/version.json"))
+    }
+
+    /// Load the cache at `path`, or an empty one if it is missing or unreadable.
+    pub fn load(path: &Path) -> VersionCache {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        create_parent_dir(path)?;
+        atomic_write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// The version cached for `channel`, if fetched less than `ttl_secs` before `now`.
+    ///
+    /// Entries from the future, e.g. after the clock was turned back, are treated as expired.
+    pub fn get(&self, channel: &MihomoChannel, now: u64, ttl_secs: u64) -> Option<&str> {
+        let cached = self.channels.get(channel.as_str())?;
+        let age = now.checked_sub(cached.fetched_at)?;
+        (age < ttl_secs).then_some(cached.version.as_str())
+    }
+
+    pub fn insert(&mut self, channel: &MihomoChannel, version: &str, now: u64) {
+        self.channels.insert(
+            channel.as_str().to_string(),
+            CachedVersion {
+                version: version.to_string(),
+                fetched_at: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_get_hit_miss_and_expiry() {
+        let mut cache = VersionCache::default();
+        assert_eq!(cache.get(&MihomoChannel::Stable, 1000, 3600), None);
+
+        cache.insert(&MihomoChannel::Stable, "v1.19.0", 1000);
+        assert_eq!(
+            cache.get(&MihomoChannel::Stable, 1000, 3600),
+            Some("v1.19.0")
+        );
+        assert_eq!(
+            cache.get(&MihomoChannel::Stable, 4599, 3600),
+            Some("v1.19.0")
+        );
+        assert_eq!(cache.get(&MihomoChannel::Stable, 4600, 3600), None);
+        // Channels are cached separately
+        assert_eq!(cache.get(&MihomoChannel::Alpha, 1000, 3600), None);
+        // A TTL of 0 disables the cache, and entries from the future are ignored
+        assert_eq!(cache.get(&MihomoChannel::Stable, 1000, 0), None);
+        assert_eq!(cache.get(&MihomoChannel::Stable, 999, 3600), None);
+
+        cache.insert(&MihomoChannel::Stable, "v1.19.1", 5000);
+        assert_eq!(
+            cache.get(&MihomoChannel::Stable, 5000, 3600),
+            Some("v1.19.1")
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mihoro/version.json");
+        assert_eq!(VersionCache::load(&path), VersionCache::default());
+
+        let mut cache = VersionCache::default();
+        cache.insert(&MihomoChannel::Alpha, "alpha-abc123", 1000);
+        cache.save(&path)?;
+        assert_eq!(VersionCache::load(&path), cache);
+
+        // A corrupt cache is ignored
+        fs::write(&path, "not json")?;
+        assert_eq!(VersionCache::load(&path), VersionCache::default());
+        Ok(())
+    }
+}
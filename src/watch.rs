@@ -0,0 +1,240 @@
+use crate::api::{ConnectionsSnapshot, MihomoApi};
+use crate::mihoro::Mihoro;
+use crate::subscription::format_bytes;
+
+use std::fs;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use colored::Colorize;
+
+/// Time between refreshes of `mihoro status --watch`, also the timeout of each API request.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// ANSI escapes clearing the terminal and moving the cursor to the top left.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Format a duration in seconds as its two most significant units, e.g. `2d 3h` or `5m 10s`.
+fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", minutes, secs),
+        (0, _, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Seconds the service has been active, from the system uptime in `/proc/uptime` and the
+/// service's `ActiveEnterTimestampMonotonic` in microseconds since boot, which is 0 if it never
+/// started.
+fn service_uptime(proc_uptime: &str, active_enter_usecs: &str) -> Option<u64> {
+    let system_uptime = proc_uptime.split_whitespace().next()?.parse::<f64>().ok()?;
+    let active_enter = active_enter_usecs.trim().parse::<u64>().ok()?;
+    if active_enter == 0 {
+        return None;
+    }
+    Some((system_uptime - active_enter as f64 / 1e6).max(0.0) as u64)
+}
+
+/// Upload and download rates in bytes per second between two snapshots.
+fn traffic_rates(
+    previous: &ConnectionsSnapshot,
+    current: &ConnectionsSnapshot,
+    elapsed: Duration,
+) -> (u64, u64) {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    let rate =
+        |previous: u64, current: u64| (current.saturating_sub(previous) as f64 / secs) as u64;
+    (
+        rate(previous.upload_total, current.upload_total),
+        rate(previous.download_total, current.download_total),
+    )
+}
+
+/// Render the lines of one refresh of the watch view.
+fn render(
+    service: &str,
+    active_state: &str,
+    uptime: Option<u64>,
+    traffic: Result<(&ConnectionsSnapshot, Option<(u64, u64)>), String>,
+) -> Vec<String> {
+    // systemctl prints nothing for unknown units, or when it cannot reach the service manager
+    let active_state = match active_state {
+        "" => "unknown",
+        active_state => active_state,
+    };
+    let state = match active_state {
+        "active" => active_state.green().bold(),
+        "failed" => active_state.red().bold(),
+        _ => active_state.yellow().bold(),
+    };
+    let uptime = match uptime {
+        Some(uptime) if active_state == "active" => format!(" (up {})", format_duration(uptime)),
+        _ => String::new(),
+    };
+    let mut lines = vec![format!("{:<12} {}{}", service.bold(), state, uptime)];
+
+    match traffic {
+        Ok((snapshot, rates)) => {
+            let (up, down) = match rates {
+                Some((up, down)) => (
+                    format!("{}/s", format_bytes(up)),
+                    format!("{}/s", format_bytes(down)),
+                ),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            lines.push(format!("{:<12} ↑ {}  ↓ {}", "Traffic", up, down));
+            lines.push(format!(
+                "{:<12} ↑ {}  ↓ {}",
+                "Total",
+                format_bytes(snapshot.upload_total),
+                format_bytes(snapshot.download_total)
+            ));
+            lines.push(format!("{:<12} {}", "Connections", snapshot.connections));
+        }
+        Err(reason) => lines.push(format!("{:<12} {}", "Traffic", reason.dimmed())),
+    }
+    lines
+}
+
+/// Refresh the service state, uptime and live traffic every few seconds until Ctrl-C.
+pub async fn watch_status(mihoro: &Mihoro) -> Result<()> {
+    let service = &mihoro.config.service_name;
+    // The API is optional, e.g. when `external-controller` is unset
+    let api = MihomoApi::from_mihoro_with_timeout(mihoro, WATCH_INTERVAL);
+    let mut previous: Option<(ConnectionsSnapshot, Instant)> = None;
+
+    loop {
+        let active_state = mihoro.systemctl().active_state(service)?;
+        let uptime = mihoro
+            .systemctl()
+            .property(service, "ActiveEnterTimestampMonotonic")
+            .ok()
+            .zip(fs::read_to_string("/proc/uptime").ok())
+            .and_then(|(active_enter, proc_uptime)| service_uptime(&proc_uptime, &active_enter));
+
+        let snapshot = match &api {
+            Ok(api) => api.connections().await.map_err(|e| format!("{:#}", e)),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        let now = Instant::now();
+        let traffic = snapshot.as_ref().map_err(Clone::clone).map(|snapshot| {
+            let rates = previous
+                .as_ref()
+                .map(|(previous, at)| traffic_rates(previous, snapshot, now - *at));
+            (snapshot, rates)
+        });
+
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "{}", CLEAR_SCREEN)?;
+        for line in render(service, &active_state, uptime, traffic) {
+            writeln!(stdout, "{}", line)?;
+        }
+        writeln!(
+            stdout,
+            "\n{}",
+            format!(
+                "Refreshing every {}s, press Ctrl-C to exit",
+                WATCH_INTERVAL.as_secs()
+            )
+            .dimmed()
+        )?;
+        stdout.flush()?;
+        drop(stdout);
+        previous = snapshot.ok().map(|snapshot| (snapshot, now));
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            _ = tokio::time::sleep(WATCH_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(310), "5m 10s");
+        assert_eq!(format_duration(7380), "2h 3m");
+        assert_eq!(format_duration(3 * 86400 + 4 * 3600 + 59), "3d 4h");
+    }
+
+    #[test]
+    fn test_service_uptime() {
+        assert_eq!(service_uptime("1000.50 3000.00\n", "400500000"), Some(600));
+        assert_eq!(service_uptime("1000.50 3000.00\n", "0"), None);
+        assert_eq!(service_uptime("", "400500000"), None);
+    }
+
+    #[test]
+    fn test_traffic_rates() {
+        let snapshot = |upload_total, download_total| ConnectionsSnapshot {
+            upload_total,
+            download_total,
+            connections: 0,
+        };
+        assert_eq!(
+            traffic_rates(
+                &snapshot(1000, 5000),
+                &snapshot(3000, 25000),
+                Duration::from_secs(2)
+            ),
+            (1000, 10000)
+        );
+        // Totals reset when mihomo restarts
+        assert_eq!(
+            traffic_rates(
+                &snapshot(3000, 5000),
+                &snapshot(0, 0),
+                Duration::from_secs(2)
+            ),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        colored::control::set_override(false);
+        let snapshot = ConnectionsSnapshot {
+            upload_total: 2048,
+            download_total: 1048576,
+            connections: 3,
+        };
+        assert_eq!(
+            render(
+                "mihomo.service",
+                "active",
+                Some(7380),
+                Ok((&snapshot, Some((1024, 0))))
+            ),
+            [
+                "mihomo.service active (up 2h 3m)",
+                "Traffic      ↑ 1.00 KiB/s  ↓ 0 B/s",
+                "Total        ↑ 2.00 KiB  ↓ 1.00 MiB",
+                "Connections  3",
+            ]
+        );
+        assert_eq!(
+            render(
+                "mihomo.service",
+                "inactive",
+                Some(10),
+                Err("mihomo API unreachable".to_string())
+            ),
+            [
+                "mihomo.service inactive",
+                "Traffic      mihomo API unreachable"
+            ]
+        );
+    }
+}